@@ -30,6 +30,8 @@ use std::{
 use dashmap::DashMap;
 use futures_timer::Delay as sleep;
 use http::HeaderValue;
+#[cfg(feature = "encryption")]
+use http::{Method as HttpMethod, StatusCode};
 use mime::{self, Mime};
 use reqwest::header::InvalidHeaderValue;
 use url::Url;
@@ -45,7 +47,15 @@ use matrix_sdk_base::{BaseClient, BaseClientConfig, Room, Session, StateStore};
 #[cfg(feature = "encryption")]
 use matrix_sdk_base::crypto::{
     decrypt_key_export, encrypt_key_export, olm::InboundGroupSession, store::CryptoStoreError,
-    AttachmentEncryptor, OutgoingRequests, ToDeviceRequest,
+    AttachmentEncryptor, BackupError, DefaultKeyContent, DehydratedDeviceEvents,
+    DehydratedDeviceResponse, EncryptedSecretContent, KeysBackupRequest, OutgoingRequests,
+    OutgoingVerificationRequest, QrVerificationData, RecoveryKey, RoomMessageRequest, SecretName,
+    SecretStorageKey, SecretStorageKeyDescription, ToDeviceRequest, DEFAULT_KEY_EVENT_TYPE,
+};
+#[cfg(feature = "encryption")]
+use serde_json::{
+    json,
+    value::{to_raw_value, RawValue as RawJsonValue},
 };
 
 /// Enum controlling if a loop running callbacks should continue or abort.
@@ -107,6 +117,11 @@ use matrix_sdk_common::{
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::{
     api::r0::{
+        backup::{
+            add_backup_keys, create_backup, delete_backup, get_backup_keys, get_latest_backup,
+            BackupAlgorithm,
+        },
+        config::{get_global_account_data, set_global_account_data},
         keys::{get_keys, upload_keys, upload_signing_keys::Request as UploadSigningKeysRequest},
         to_device::send_event_to_device::{
             Request as RumaToDeviceRequest, Response as ToDeviceResponse,
@@ -124,11 +139,18 @@ use crate::{
 use crate::{
     device::{Device, UserDevices},
     identifiers::DeviceId,
+    qrcode::QrVerification,
     sas::Sas,
+    verification_request::VerificationRequest,
 };
 
 const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// The path of the unstable endpoint that manages our dehydrated device.
+#[cfg(feature = "encryption")]
+const DEHYDRATED_DEVICE_PATH: &str =
+    "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device";
+
 /// An async/await enabled Matrix client.
 ///
 /// All of the state is held in an `Arc` so the `Client` can be cloned freely.
@@ -1148,7 +1170,7 @@ impl Client {
                 // session as using it would end up in undecryptable
                 // messages.
                 if let Err(r) = response {
-                    self.base_client.invalidate_group_session(room_id).await;
+                    self.base_client.invalidate_group_session(room_id).await?;
                     return Err(r);
                 }
             }
@@ -1455,7 +1477,43 @@ impl Client {
     }
 
     #[cfg(feature = "encryption")]
-    async fn send_to_device(&self, request: &ToDeviceRequest) -> Result<ToDeviceResponse> {
+    async fn keys_backup(&self, request_id: &Uuid, request: &KeysBackupRequest) -> Result<()> {
+        let response = self
+            .send(add_backup_keys::Request::new(
+                &request.version,
+                request.rooms.clone(),
+            ))
+            .await?;
+
+        self.base_client
+            .mark_request_as_sent(request_id, &response)
+            .await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    async fn room_message(&self, request_id: &Uuid, request: &RoomMessageRequest) -> Result<()> {
+        let response = self
+            .room_send(
+                &request.room_id,
+                request.content.clone(),
+                Some(request.txn_id),
+            )
+            .await?;
+
+        self.base_client
+            .mark_request_as_sent(request_id, &response)
+            .await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn send_to_device(
+        &self,
+        request: &ToDeviceRequest,
+    ) -> Result<ToDeviceResponse> {
         let txn_id_string = request.txn_id_string();
         let request = RumaToDeviceRequest::new(
             request.event_type.clone(),
@@ -1466,6 +1524,24 @@ impl Client {
         self.send(request).await
     }
 
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn send_verification_request(
+        &self,
+        request: OutgoingVerificationRequest,
+    ) -> Result<()> {
+        match request {
+            OutgoingVerificationRequest::ToDevice(t) => {
+                self.send_to_device(&t).await?;
+            }
+            OutgoingVerificationRequest::InRoom(r) => {
+                self.room_send(&r.room_id, r.content, Some(r.txn_id))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get information of all our own devices.
     ///
     /// # Examples
@@ -1734,6 +1810,16 @@ impl Client {
                                     .unwrap();
                             }
                         }
+                        OutgoingRequests::KeysBackup(request) => {
+                            if let Err(e) = self.keys_backup(r.request_id(), request).await {
+                                warn!("Error while backing up room keys {:?}", e);
+                            }
+                        }
+                        OutgoingRequests::RoomMessage(request) => {
+                            if let Err(e) = self.room_message(r.request_id(), request).await {
+                                warn!("Error while sending a room message {:?}", e);
+                            }
+                        }
                     }
                 }
             }
@@ -1888,7 +1974,147 @@ impl Client {
             .await
             .map(|sas| Sas {
                 inner: sas,
-                http_client: self.http_client.clone(),
+                client: self.clone(),
+            })
+    }
+
+    /// Request an interactive verification with the given user inside of a
+    /// room.
+    ///
+    /// This sends a `m.key.verification.request` message to the room. The
+    /// request can be fetched with
+    /// [`get_verification_request()`](#method.get_verification_request) once
+    /// it comes back down the sync, using the event id of the request as the
+    /// flow id.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room, usually a DM, where the verification
+    /// should take place.
+    ///
+    /// * `user_id` - The id of the user that should be verified.
+    ///
+    /// Returns the event id of the request.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn request_verification_in_room(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<EventId> {
+        let content = self
+            .base_client
+            .verification_request_content(user_id)
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let response = self
+            .room_send(room_id, AnyMessageEventContent::RoomMessage(content), None)
+            .await?;
+
+        Ok(response.event_id)
+    }
+
+    /// Request an interactive verification with the given user.
+    ///
+    /// The request is sent to all the devices of the user. Once one of them
+    /// accepts it, a SAS verification is started with that device and can be
+    /// fetched with [`VerificationRequest::sas()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The id of the user that should be verified.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk::{Client, identifiers::UserId};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # let alice = UserId::try_from("@alice:example.org").unwrap();
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let request = client.request_verification(&alice).await.unwrap();
+    ///
+    /// // Keep syncing, once the other side accepts the request the SAS
+    /// // verification will be available.
+    /// if let Some(sas) = request.sas().await {
+    ///     println!("{:?}", sas.emoji());
+    /// }
+    /// # });
+    /// ```
+    ///
+    /// [`VerificationRequest::sas()`]: struct.VerificationRequest.html#method.sas
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn request_verification(&self, user_id: &UserId) -> Result<VerificationRequest> {
+        let (request, to_device) = self
+            .base_client
+            .request_verification(user_id)
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        self.send_to_device(&to_device).await?;
+
+        Ok(VerificationRequest {
+            inner: request,
+            client: self.clone(),
+        })
+    }
+
+    /// Get a `VerificationRequest` object with the given flow id.
+    ///
+    /// This can be used to accept verification requests other users sent us.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn get_verification_request(&self, flow_id: &str) -> Option<VerificationRequest> {
+        self.base_client
+            .get_verification_request(flow_id)
+            .await
+            .map(|request| VerificationRequest {
+                inner: request,
+                client: self.clone(),
+            })
+    }
+
+    /// Start a QR code verification using a QR code the other side is showing.
+    ///
+    /// Rendering and scanning the QR code is left to the caller, use
+    /// [`QrVerificationData::from_bytes()`] to parse the scanned payload. The
+    /// verification request the QR code belongs to needs to be ready.
+    ///
+    /// Returns `None` if there's no ready verification request for the QR
+    /// code. The returned verification is canceled if the keys in the QR code
+    /// didn't match.
+    ///
+    /// [`QrVerificationData::from_bytes()`]: struct.QrVerificationData.html#method.from_bytes
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn scan_qr_code(&self, data: QrVerificationData) -> Result<Option<QrVerification>> {
+        if let Some((qr, request)) = self.base_client.scan_qr_code(data).await? {
+            self.send_verification_request(request).await?;
+
+            Ok(Some(QrVerification {
+                inner: qr,
+                client: self.clone(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get a `QrVerification` object with the given flow id.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn get_qr_verification(&self, flow_id: &str) -> Option<QrVerification> {
+        self.base_client
+            .get_qr_verification(flow_id)
+            .await
+            .map(|qr| QrVerification {
+                inner: qr,
+                client: self.clone(),
             })
     }
 
@@ -1937,7 +2163,7 @@ impl Client {
 
         Ok(device.map(|d| Device {
             inner: d,
-            http_client: self.http_client.clone(),
+            client: self.clone(),
         }))
     }
 
@@ -2054,7 +2280,7 @@ impl Client {
 
         Ok(UserDevices {
             inner: devices,
-            http_client: self.http_client.clone(),
+            client: self.clone(),
         })
     }
 
@@ -2186,11 +2412,6 @@ impl Client {
         doc(cfg(all(encryption, not(target_arch = "wasm32"))))
     )]
     pub async fn import_keys(&self, path: PathBuf, passphrase: &str) -> Result<(usize, usize)> {
-        let olm = self
-            .base_client
-            .olm_machine()
-            .await
-            .ok_or(Error::AuthenticationRequired)?;
         let passphrase = Zeroizing::new(passphrase.to_owned());
 
         let decrypt = move || {
@@ -2201,7 +2422,514 @@ impl Client {
         let task = tokio::task::spawn_blocking(decrypt);
         let import = task.await.expect("Task join error").unwrap();
 
-        Ok(olm.import_keys(import).await?)
+        Ok(self.base_client.import_keys(import).await?)
+    }
+
+    /// Create a new server-side backup of our room keys and start uploading
+    /// them.
+    ///
+    /// Returns the recovery key of the backup, the recovery key is needed to
+    /// restore the backup on a new device and should be shown to the user so
+    /// they can store it somewhere safe.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let recovery_key = client
+    ///     .enable_backup()
+    ///     .await
+    ///     .expect("Can't create a room key backup");
+    ///
+    /// println!("Your recovery key is {}", recovery_key.to_base58());
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn enable_backup(&self) -> Result<RecoveryKey> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let recovery_key = RecoveryKey::new();
+        let backup_key = recovery_key.public_key();
+
+        let algorithm = olm.backup_algorithm(&backup_key).await;
+        let response = self.send(create_backup::Request::new(algorithm)).await?;
+
+        olm.enable_backup(backup_key, response.version).await?;
+
+        Ok(recovery_key)
+    }
+
+    /// Restore our room keys from the latest server-side backup.
+    ///
+    /// After the room keys are restored, new room keys will be uploaded to
+    /// the same backup version.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found in the
+    /// backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The base58 encoded recovery key that was returned
+    /// when the backup was created.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let (imported, total) = client
+    ///     .restore_backup("EsTc LW2K PGiF wKEA 3As5 g5c4 BXwk qeeJ ZJV8 Q9fu gUMN UE4d")
+    ///     .await
+    ///     .expect("Can't restore the room key backup");
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn restore_backup(&self, recovery_key: &str) -> Result<(usize, usize)> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let recovery_key = RecoveryKey::from_base58(recovery_key)?;
+        let backup_key = recovery_key.public_key();
+
+        let backup = self.send(get_latest_backup::Request::new()).await?;
+
+        match backup.algorithm.deserialize()? {
+            BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 { public_key, .. } => {
+                if public_key != backup_key.public_key() {
+                    return Err(BackupError::KeyMismatch.into());
+                }
+            }
+            #[allow(unreachable_patterns)]
+            _ => return Err(BackupError::UnsupportedAlgorithm.into()),
+        }
+
+        let keys = self
+            .send(get_backup_keys::Request::new(&backup.version))
+            .await?;
+
+        // Enabling a new backup version resets the backup state of all our
+        // room keys, so this needs to happen before the restored keys get
+        // marked as backed up.
+        olm.enable_backup(backup_key, backup.version).await?;
+
+        self.base_client
+            .restore_backup(&recovery_key, &keys.rooms)
+            .await
+    }
+
+    /// Stop backing up our room keys.
+    ///
+    /// This won't delete the backup version on the server, use
+    /// [`delete_backup`] to get rid of the backed up room keys as well.
+    ///
+    /// [`delete_backup`]: #method.delete_backup
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn disable_backup(&self) -> Result<()> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        olm.disable_backup().await?;
+
+        Ok(())
+    }
+
+    /// Stop backing up our room keys and delete the current backup version,
+    /// including all the room keys it contains, from the server.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn delete_backup(&self) -> Result<()> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        if let Some(version) = olm
+            .backup_key()
+            .await
+            .and_then(|k| k.version().map(|v| v.to_owned()))
+        {
+            self.send(delete_backup::Request::new(&version)).await?;
+        }
+
+        olm.disable_backup().await?;
+
+        Ok(())
+    }
+
+    /// Set up secret storage and store our private cross signing keys in it.
+    ///
+    /// A new secret storage key is created and marked as the default key, our
+    /// private cross signing keys are encrypted with it and uploaded to the
+    /// global account data of our user.
+    ///
+    /// Returns the new secret storage key, the base58 encoded version of it
+    /// should be shown to the user if no passphrase was given.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - An optional passphrase the secret storage key should
+    /// be derived from.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let key = client
+    ///     .bootstrap_secret_storage(None)
+    ///     .await
+    ///     .expect("Can't set up secret storage");
+    ///
+    /// println!("Your secret storage key is {}", key.to_base58());
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn bootstrap_secret_storage(
+        &self,
+        passphrase: Option<&str>,
+    ) -> Result<SecretStorageKey> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let key = if let Some(passphrase) = passphrase {
+            SecretStorageKey::new_from_passphrase(passphrase)
+        } else {
+            SecretStorageKey::new()
+        };
+
+        self.set_global_account_data(
+            olm.user_id(),
+            &key.event_type(),
+            to_raw_value(key.description())?,
+        )
+        .await?;
+
+        for (name, content) in olm.export_cross_signing_secrets(&key).await {
+            self.set_global_account_data(olm.user_id(), name.as_str(), to_raw_value(&content)?)
+                .await?;
+        }
+
+        self.set_global_account_data(
+            olm.user_id(),
+            DEFAULT_KEY_EVENT_TYPE,
+            to_raw_value(&key.default_key_content())?,
+        )
+        .await?;
+
+        Ok(key)
+    }
+
+    /// Restore our private cross signing keys from secret storage.
+    ///
+    /// The keys are decrypted using the default secret storage key, which is
+    /// restored from the given recovery key or passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_or_passphrase` - The base58 encoded secret storage key or the
+    /// passphrase the key was derived from.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// client
+    ///     .restore_cross_signing_from_secret_storage("It's a secret to everybody")
+    ///     .await
+    ///     .expect("Can't restore our cross signing keys");
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn restore_cross_signing_from_secret_storage(
+        &self,
+        key_or_passphrase: &str,
+    ) -> Result<()> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let default_key: DefaultKeyContent = serde_json::from_str(
+            self.get_global_account_data(olm.user_id(), DEFAULT_KEY_EVENT_TYPE)
+                .await?
+                .get(),
+        )?;
+
+        let description: SecretStorageKeyDescription = serde_json::from_str(
+            self.get_global_account_data(
+                olm.user_id(),
+                &format!("m.secret_storage.key.{}", default_key.key),
+            )
+            .await?
+            .get(),
+        )?;
+
+        let key = match SecretStorageKey::from_base58(
+            key_or_passphrase,
+            &default_key.key,
+            description.clone(),
+        ) {
+            Ok(key) => key,
+            Err(_) => {
+                SecretStorageKey::from_passphrase(key_or_passphrase, &default_key.key, description)?
+            }
+        };
+
+        let mut secrets = Vec::new();
+
+        for name in &[
+            SecretName::CrossSigningMasterKey,
+            SecretName::CrossSigningSelfSigningKey,
+            SecretName::CrossSigningUserSigningKey,
+        ] {
+            let content: EncryptedSecretContent = serde_json::from_str(
+                self.get_global_account_data(olm.user_id(), name.as_str())
+                    .await?
+                    .get(),
+            )?;
+
+            secrets.push(key.decrypt(name, &content)?);
+        }
+
+        olm.import_cross_signing_keys(Some(&secrets[0]), Some(&secrets[1]), Some(&secrets[2]))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Create a new dehydrated device and upload it to the server.
+    ///
+    /// A dehydrated device receives room keys while none of our devices is
+    /// online, the room keys can be recovered using [`rehydrate_device`] once
+    /// we log in again. A dehydrated device that already exists on the server
+    /// is replaced.
+    ///
+    /// The device is signed with our self signing key, so cross signing needs
+    /// to be set up first.
+    ///
+    /// Returns the device id of the new dehydrated device.
+    ///
+    /// # Arguments
+    ///
+    /// * `display_name` - The display name the dehydrated device should have.
+    ///
+    /// * `pickle_key` - The key that will be used to encrypt the account of
+    /// the device, the same key is needed to rehydrate it.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let pickle_key = [0u8; 32];
+    ///
+    /// client
+    ///     .dehydrate_device("Dehydrated device", &pickle_key)
+    ///     .await
+    ///     .expect("Can't upload a dehydrated device");
+    /// # });
+    /// ```
+    ///
+    /// [`rehydrate_device`]: #method.rehydrate_device
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn dehydrate_device(
+        &self,
+        display_name: &str,
+        pickle_key: &[u8],
+    ) -> Result<DeviceIdBox> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let request = olm
+            .create_dehydrated_device(display_name, pickle_key)
+            .await?;
+
+        self.http_client
+            .send_json(
+                HttpMethod::PUT,
+                DEHYDRATED_DEVICE_PATH,
+                Some(&serde_json::to_value(&request)?),
+            )
+            .await?;
+
+        Ok(request.device_id)
+    }
+
+    /// Fetch our dehydrated device from the server and import the room keys
+    /// it received.
+    ///
+    /// Events that were waiting for the room keys are decrypted. Once all the
+    /// to-device events of the device are processed, the device is replaced
+    /// with a fresh dehydrated device, using the same display name and pickle
+    /// key.
+    ///
+    /// Returns the number of room keys that were imported, or `None` if there
+    /// is no dehydrated device on the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `display_name` - The display name the new dehydrated device should
+    /// have.
+    ///
+    /// * `pickle_key` - The key that was used to encrypt the account of the
+    /// device.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let pickle_key = [0u8; 32];
+    ///
+    /// if let Some(count) = client
+    ///     .rehydrate_device("Dehydrated device", &pickle_key)
+    ///     .await
+    ///     .expect("Can't rehydrate our dehydrated device")
+    /// {
+    ///     println!("Imported {} room keys", count);
+    /// }
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn rehydrate_device(
+        &self,
+        display_name: &str,
+        pickle_key: &[u8],
+    ) -> Result<Option<usize>> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let response = match self
+            .http_client
+            .send_json(HttpMethod::GET, DEHYDRATED_DEVICE_PATH, None)
+            .await
+        {
+            Ok(r) => r,
+            Err(Error::UnstableEndpoint(StatusCode::NOT_FOUND, _)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let response: DehydratedDeviceResponse = serde_json::from_value(response)?;
+
+        let device = olm
+            .rehydrate_device(pickle_key, &response.device_id, response.device_data)
+            .await?;
+
+        let path = format!("{}/{}/events", DEHYDRATED_DEVICE_PATH, response.device_id);
+        let mut next_batch: Option<String> = None;
+        let mut count = 0;
+
+        loop {
+            let body = match &next_batch {
+                Some(token) => json!({ "next_batch": token }),
+                None => json!({}),
+            };
+
+            let response: DehydratedDeviceEvents = serde_json::from_value(
+                self.http_client
+                    .send_json(HttpMethod::POST, &path, Some(&body))
+                    .await?,
+            )?;
+
+            if response.events.is_empty() {
+                break;
+            }
+
+            let mut events = response.events;
+            count += self
+                .base_client
+                .receive_dehydrated_device_events(&device, &mut events)
+                .await?;
+
+            if response.next_batch.is_none() {
+                break;
+            }
+
+            next_batch = response.next_batch;
+        }
+
+        self.dehydrate_device(display_name, pickle_key).await?;
+
+        Ok(Some(count))
+    }
+
+    #[cfg(feature = "encryption")]
+    async fn set_global_account_data(
+        &self,
+        user_id: &UserId,
+        event_type: &str,
+        content: Box<RawJsonValue>,
+    ) -> Result<()> {
+        let request = set_global_account_data::Request::new(content, event_type, user_id);
+        self.send(request).await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    async fn get_global_account_data(
+        &self,
+        user_id: &UserId,
+        event_type: &str,
+    ) -> Result<Box<RawJsonValue>> {
+        let request = get_global_account_data::Request::new(user_id, event_type);
+        let response = self.send(request).await?;
+
+        Ok(response.account_data.into_json())
     }
 }
 
@@ -2247,6 +2975,59 @@ mod test {
         client
     }
 
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn dehydrated_device() {
+        let client = logged_in_client().await;
+        let pickle_key = [0u8; 32];
+
+        let _m = mock("GET", super::DEHYDRATED_DEVICE_PATH)
+            .with_status(404)
+            .with_body(
+                json!({
+                    "errcode": "M_NOT_FOUND",
+                    "error": "No dehydrated device found",
+                })
+                .to_string(),
+            )
+            .create();
+
+        assert_eq!(
+            client
+                .rehydrate_device("Dehydrated device", &pickle_key)
+                .await
+                .unwrap(),
+            None
+        );
+
+        // The device can't be signed without our self signing key.
+        assert!(matches!(
+            client
+                .dehydrate_device("Dehydrated device", &pickle_key)
+                .await,
+            Err(crate::Error::DehydrationError(_))
+        ));
+
+        client
+            .base_client
+            .olm_machine()
+            .await
+            .unwrap()
+            .bootstrap_cross_signing(false)
+            .await
+            .unwrap();
+
+        let _m = mock("PUT", super::DEHYDRATED_DEVICE_PATH)
+            .with_status(200)
+            .with_body(json!({ "device_id": "DEHYDRATED" }).to_string())
+            .create();
+
+        client
+            .dehydrate_device("Dehydrated device", &pickle_key)
+            .await
+            .unwrap();
+    }
+
     #[tokio::test]
     async fn login() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
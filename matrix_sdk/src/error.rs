@@ -14,6 +14,7 @@
 
 //! Error conditions.
 
+use http::{Error as HttpError, StatusCode};
 use matrix_sdk_base::Error as MatrixError;
 use matrix_sdk_common::{
     api::{
@@ -28,7 +29,9 @@ use std::io::Error as IoError;
 use thiserror::Error;
 
 #[cfg(feature = "encryption")]
-use matrix_sdk_base::crypto::store::CryptoStoreError;
+use matrix_sdk_base::crypto::{
+    store::CryptoStoreError, BackupError, DehydrationError, SecretStorageError,
+};
 
 /// Result type of the rust-sdk.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -73,6 +76,30 @@ pub enum Error {
     #[error(transparent)]
     CryptoStoreError(#[from] CryptoStoreError),
 
+    /// An error occurred while handling the server-side room key backup.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    BackupError(#[from] BackupError),
+
+    /// An error occurred while handling secret storage.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    SecretStorageError(#[from] SecretStorageError),
+
+    /// An error occurred while handling a dehydrated device.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    DehydrationError(#[from] DehydrationError),
+
+    /// A HTTP request couldn't be built.
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    /// The server returned an error for a request to an endpoint that isn't
+    /// part of the client-server API yet.
+    #[error("the server responded with {0} to a request to an unstable endpoint: {1}")]
+    UnstableEndpoint(StatusCode, String),
+
     /// An error occurred while authenticating.
     ///
     /// When registering or authenticating the Matrix server can send a `UiaaResponse`
@@ -16,6 +16,8 @@ use std::{convert::TryFrom, fmt::Debug, sync::Arc};
 
 use http::{HeaderValue, Method as HttpMethod, Response as HttpResponse};
 use reqwest::{Client, Response};
+#[cfg(feature = "encryption")]
+use serde_json::Value as JsonValue;
 use tracing::trace;
 use url::Url;
 
@@ -143,6 +145,62 @@ impl HttpClient {
 
         Ok(Request::IncomingResponse::try_from(response)?)
     }
+
+    /// Send a JSON request to an endpoint that isn't part of the
+    /// client-server API yet, e.g. an unstable endpoint of a MSC.
+    ///
+    /// Returns the JSON body of the response if the request succeeded, a
+    /// `Error::UnstableEndpoint` containing the status code and the body of
+    /// the response otherwise.
+    #[cfg(feature = "encryption")]
+    pub async fn send_json(
+        &self,
+        method: HttpMethod,
+        path: &str,
+        body: Option<&JsonValue>,
+    ) -> Result<JsonValue> {
+        let access_token = self
+            .session
+            .read()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .access_token
+            .clone();
+
+        let body = if let Some(body) = body {
+            serde_json::to_vec(body)?
+        } else {
+            Vec::new()
+        };
+
+        let request = http::Request::builder()
+            .method(method)
+            .uri(format!(
+                "{}{}",
+                self.homeserver.as_str().trim_end_matches('/'),
+                path
+            ))
+            .header(
+                http::header::AUTHORIZATION,
+                format!("Bearer {}", access_token),
+            )
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body)?;
+
+        let response = self.inner.send_request(request).await?;
+
+        trace!("Got response: {:?}", response);
+
+        if response.status().is_success() {
+            Ok(serde_json::from_slice(response.body())?)
+        } else {
+            Err(Error::UnstableEndpoint(
+                response.status(),
+                String::from_utf8_lossy(response.body()).into_owned(),
+            ))
+        }
+    }
 }
 
 /// Build a client with the specified configuration.
@@ -41,6 +41,9 @@
 //! keys. If this is disabled and `encryption` support is enabled the keys will
 //! by default be stored only in memory and thus lost after the client is
 //! destroyed.
+//! * `sled_cryptostore`: Enables a store for the encryption keys that is based
+//! on the sled embedded database. It is used by default if it is enabled and
+//! `sqlite_cryptostore` is disabled.
 //! * `unstable-synapse-quirks`: Enables support to deal with inconsistencies
 //! of Synapse in compliance with the Matrix API specification.
 //! * `socks`: Enables SOCKS support in reqwest, the default HTTP client.
@@ -65,7 +68,11 @@ compile_error!("only one of 'native-tls' or 'rustls-tls' features can be enabled
 
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
-pub use matrix_sdk_base::crypto::LocalTrust;
+pub use matrix_sdk_base::crypto::{
+    DecryptedEvent, EncryptionInfo, IdentityChange, LocalTrust, QrDecodingError,
+    QrVerificationData, QrVerificationMode, RecoveryKey, SecretStorageKey, ShareStrategy,
+    TrustLevel, VerificationState, WithheldCode,
+};
 #[cfg(not(target_arch = "wasm32"))]
 pub use matrix_sdk_base::JsonStore;
 pub use matrix_sdk_base::{
@@ -87,7 +94,11 @@ mod http_client;
 #[cfg(feature = "encryption")]
 mod device;
 #[cfg(feature = "encryption")]
+mod qrcode;
+#[cfg(feature = "encryption")]
 mod sas;
+#[cfg(feature = "encryption")]
+mod verification_request;
 
 pub use client::{Client, ClientConfig, LoopCtrl, SyncSettings};
 #[cfg(feature = "encryption")]
@@ -97,7 +108,13 @@ pub use error::{Error, Result};
 pub use http_client::HttpSend;
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+pub use qrcode::QrVerification;
+#[cfg(feature = "encryption")]
+#[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use sas::Sas;
+#[cfg(feature = "encryption")]
+#[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+pub use verification_request::VerificationRequest;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
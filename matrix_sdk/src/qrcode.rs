@@ -0,0 +1,95 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use matrix_sdk_base::crypto::{
+    QrVerification as BaseQrVerification, QrVerificationMode, ReadOnlyDevice,
+};
+use matrix_sdk_common::identifiers::RoomId;
+
+use crate::{error::Result, Client};
+
+/// An object controlling an interactive verification flow using QR codes.
+#[derive(Debug, Clone)]
+pub struct QrVerification {
+    pub(crate) inner: BaseQrVerification,
+    pub(crate) client: Client,
+}
+
+impl QrVerification {
+    /// Get the binary payload of the QR code that should be shown to the
+    /// other side.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.inner.to_bytes()
+    }
+
+    /// Get the mode of the QR code.
+    pub fn mode(&self) -> QrVerificationMode {
+        self.inner.mode()
+    }
+
+    /// Has the other side scanned our QR code.
+    pub fn has_been_scanned(&self) -> bool {
+        self.inner.has_been_scanned()
+    }
+
+    /// Confirm that the other side scanned our QR code.
+    pub async fn confirm_scanning(&self) -> Result<()> {
+        let (request, signature) = self.inner.confirm_scanning().await?;
+
+        if let Some(req) = request {
+            self.client.send_verification_request(req).await?;
+        }
+
+        if let Some(s) = signature {
+            self.client.send(s).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Cancel the interactive verification flow.
+    pub async fn cancel(&self) -> Result<()> {
+        if let Some(req) = self.inner.cancel() {
+            self.client.send_verification_request(req).await?;
+        }
+        Ok(())
+    }
+
+    /// Is the verification process done.
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    /// Is the verification process canceled.
+    pub fn is_canceled(&self) -> bool {
+        self.inner.is_canceled()
+    }
+
+    /// Get the other users device that we're verifying.
+    pub fn other_device(&self) -> ReadOnlyDevice {
+        self.inner.other_device()
+    }
+
+    /// Get the unique id of the verification flow.
+    pub fn flow_id(&self) -> &str {
+        self.inner.flow_id()
+    }
+
+    /// Get the id of the room the verification is happening in.
+    ///
+    /// Returns `None` if the verification is done using to-device messages.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.inner.room_id()
+    }
+}
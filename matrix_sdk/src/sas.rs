@@ -13,42 +13,36 @@
 // limitations under the License.
 
 use matrix_sdk_base::crypto::{ReadOnlyDevice, Sas as BaseSas};
-use matrix_sdk_common::api::r0::to_device::send_event_to_device::Request as ToDeviceRequest;
+use matrix_sdk_common::identifiers::RoomId;
 
-use crate::{error::Result, http_client::HttpClient};
+use crate::{error::Result, Client};
 
 /// An object controling the interactive verification flow.
 #[derive(Debug, Clone)]
 pub struct Sas {
     pub(crate) inner: BaseSas,
-    pub(crate) http_client: HttpClient,
+    pub(crate) client: Client,
 }
 
 impl Sas {
     /// Accept the interactive verification flow.
     pub async fn accept(&self) -> Result<()> {
         if let Some(req) = self.inner.accept() {
-            let txn_id_string = req.txn_id_string();
-            let request = ToDeviceRequest::new(req.event_type, &txn_id_string, req.messages);
-
-            self.http_client.send(request).await?;
+            self.client.send_verification_request(req).await?;
         }
         Ok(())
     }
 
     /// Confirm that the short auth strings match on both sides.
     pub async fn confirm(&self) -> Result<()> {
-        let (to_device, signature) = self.inner.confirm().await?;
-
-        if let Some(req) = to_device {
-            let txn_id_string = req.txn_id_string();
-            let request = ToDeviceRequest::new(req.event_type, &txn_id_string, req.messages);
+        let (request, signature) = self.inner.confirm().await?;
 
-            self.http_client.send(request).await?;
+        if let Some(req) = request {
+            self.client.send_verification_request(req).await?;
         }
 
         if let Some(s) = signature {
-            self.http_client.send(s).await?;
+            self.client.send(s).await?;
         }
 
         Ok(())
@@ -57,10 +51,7 @@ impl Sas {
     /// Cancel the interactive verification flow.
     pub async fn cancel(&self) -> Result<()> {
         if let Some(req) = self.inner.cancel() {
-            let txn_id_string = req.txn_id_string();
-            let request = ToDeviceRequest::new(req.event_type, &txn_id_string, req.messages);
-
-            self.http_client.send(request).await?;
+            self.client.send_verification_request(req).await?;
         }
         Ok(())
     }
@@ -89,4 +80,11 @@ impl Sas {
     pub fn other_device(&self) -> ReadOnlyDevice {
         self.inner.other_device()
     }
+
+    /// Get the id of the room the verification is happening in.
+    ///
+    /// Returns `None` if the verification is done using to-device messages.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.inner.room_id()
+    }
 }
@@ -0,0 +1,144 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use matrix_sdk_base::crypto::VerificationRequest as BaseVerificationRequest;
+use matrix_sdk_common::{
+    events::key::verification::VerificationMethod,
+    identifiers::{DeviceIdBox, RoomId, UserId},
+};
+
+use crate::{error::Result, Client, QrVerification, Sas};
+
+/// An object negotiating the verification method before an interactive
+/// verification is started.
+#[derive(Debug, Clone)]
+pub struct VerificationRequest {
+    pub(crate) inner: BaseVerificationRequest,
+    pub(crate) client: Client,
+}
+
+impl VerificationRequest {
+    /// Accept the verification request.
+    pub async fn accept(&self) -> Result<()> {
+        if let Some(req) = self.inner.accept() {
+            self.client.send_verification_request(req).await?;
+        }
+        Ok(())
+    }
+
+    /// Cancel the verification request.
+    pub async fn cancel(&self) -> Result<()> {
+        if let Some(req) = self.inner.cancel() {
+            self.client.send_verification_request(req).await?;
+        }
+        Ok(())
+    }
+
+    /// Get the `Sas` verification that was started for this request.
+    ///
+    /// Returns `None` if the other side didn't accept the request yet.
+    pub async fn sas(&self) -> Option<Sas> {
+        self.client.get_verification(self.flow_id()).await
+    }
+
+    /// Start a `Sas` verification for this request.
+    ///
+    /// Returns `None` if the request isn't ready.
+    pub async fn start_sas(&self) -> Result<Option<Sas>> {
+        if let Some((sas, request)) = self
+            .client
+            .base_client
+            .start_sas_for_request(self.flow_id())
+            .await?
+        {
+            self.client.send_verification_request(request).await?;
+
+            Ok(Some(Sas {
+                inner: sas,
+                client: self.client.clone(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Create a QR code that the other side can scan to verify us.
+    ///
+    /// Returns `None` if the request isn't ready, if the other side can't scan
+    /// QR codes or if one of the users doesn't have a cross signing identity.
+    pub async fn generate_qr_code(&self) -> Result<Option<QrVerification>> {
+        Ok(self
+            .client
+            .base_client
+            .generate_qr_code(self.flow_id())
+            .await?
+            .map(|qr| QrVerification {
+                inner: qr,
+                client: self.client.clone(),
+            }))
+    }
+
+    /// Get the unique id of the verification flow.
+    pub fn flow_id(&self) -> &str {
+        self.inner.flow_id()
+    }
+
+    /// Get the id of the user that sent or received the request.
+    pub fn other_user_id(&self) -> &UserId {
+        self.inner.other_user_id()
+    }
+
+    /// Get the id of the device of the other user that takes part in the
+    /// verification.
+    pub fn other_device_id(&self) -> Option<DeviceIdBox> {
+        self.inner.other_device_id()
+    }
+
+    /// Get the id of the room the request was sent to.
+    ///
+    /// Returns `None` if the request was sent using to-device messages.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.inner.room_id()
+    }
+
+    /// Did we send out the request.
+    pub fn we_started(&self) -> bool {
+        self.inner.we_started()
+    }
+
+    /// Get the verification methods that are offered by the other side.
+    pub fn methods(&self) -> Option<Vec<VerificationMethod>> {
+        self.inner.methods()
+    }
+
+    /// Is the request ready for a verification to be started.
+    pub fn is_ready(&self) -> bool {
+        self.inner.is_ready()
+    }
+
+    /// Has a verification been started for this request.
+    pub fn is_started(&self) -> bool {
+        self.inner.is_started()
+    }
+
+    /// Has the request been canceled.
+    pub fn is_canceled(&self) -> bool {
+        self.inner.is_canceled()
+    }
+
+    /// Has the request timed out.
+    pub fn timed_out(&self) -> bool {
+        self.inner.timed_out()
+    }
+}
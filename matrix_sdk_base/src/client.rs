@@ -22,6 +22,9 @@ use std::{
     sync::Arc,
 };
 
+#[cfg(feature = "encryption")]
+use std::collections::BTreeMap;
+
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::locks::Mutex;
 use matrix_sdk_common::{
@@ -38,22 +41,32 @@ use matrix_sdk_common::{
 };
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::{
-    api::r0::keys::claim_keys::Request as KeysClaimRequest,
-    events::{room::encrypted::EncryptedEventContent, AnyMessageEventContent},
+    api::r0::{backup::RoomKeyBackup, keys::claim_keys::Request as KeysClaimRequest},
+    events::{
+        room::{encrypted::EncryptedEventContent, message::MessageEventContent},
+        AnyMessageEventContent, AnyToDeviceEvent, SyncMessageEvent,
+    },
     identifiers::DeviceId,
     uuid::Uuid,
 };
 #[cfg(feature = "encryption")]
 use matrix_sdk_crypto::{
+    olm::ExportedRoomKey,
     store::{CryptoStore, CryptoStoreError},
-    Device, IncomingResponse, OlmError, OlmMachine, OutgoingRequest, Sas, ToDeviceRequest,
-    UserDevices,
+    DecryptedEvent, Device, EncryptionInfo, IdentityChange, IncomingResponse, MegolmError,
+    OlmError, OlmMachine, OutgoingRequest, OutgoingVerificationRequest, QrVerification,
+    QrVerificationData, RecoveryKey, RehydratedDevice, Sas, ToDeviceRequest, UserDevices,
+    VerificationRequest,
 };
+#[cfg(feature = "encryption")]
+use tracing::warn;
 use zeroize::Zeroizing;
 
 #[cfg(not(target_arch = "wasm32"))]
 use crate::JsonStore;
 
+#[cfg(feature = "encryption")]
+use crate::error::Error;
 use crate::{
     error::Result,
     event_emitter::CustomEvent,
@@ -66,6 +79,20 @@ use crate::{
 
 pub type Token = String;
 
+/// Encrypted timeline events that couldn't be decrypted because the room key
+/// was missing, grouped by the room and the session id of the room key.
+#[cfg(feature = "encryption")]
+type UndecryptedEvents = HashMap<(RoomId, String), Vec<SyncMessageEvent<EncryptedEventContent>>>;
+
+/// The maximum number of undecryptable events that are remembered per room
+/// key.
+#[cfg(feature = "encryption")]
+const MAX_UNDECRYPTED_EVENTS: usize = 100;
+
+/// The maximum number of room keys we remember undecryptable events for.
+#[cfg(feature = "encryption")]
+const MAX_UNDECRYPTED_SESSIONS: usize = 100;
+
 /// A deserialization wrapper for extracting the prev_content field when
 /// found in an `unsigned` field.
 ///
@@ -171,6 +198,34 @@ pub enum RoomState<R> {
     Invited(R),
 }
 
+/// Get the room and session ids of the room keys that were received in the
+/// given to-device events.
+///
+/// The to-device events need to be decrypted by the `OlmMachine` first.
+#[cfg(feature = "encryption")]
+fn received_room_keys(events: &[Raw<AnyToDeviceEvent>]) -> Vec<(RoomId, String)> {
+    events
+        .iter()
+        .filter_map(|e| match e.deserialize() {
+            Ok(AnyToDeviceEvent::RoomKey(e)) => Some((e.content.room_id, e.content.session_id)),
+            Ok(AnyToDeviceEvent::ForwardedRoomKey(e)) => {
+                Some((e.content.room_id, e.content.session_id))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Get the session id of the Megolm session that was used to encrypt the given
+/// event.
+#[cfg(feature = "encryption")]
+fn megolm_session_id(event: &SyncMessageEvent<EncryptedEventContent>) -> Option<&str> {
+    match &event.content {
+        EncryptedEventContent::MegolmV1AesSha2(c) => Some(&c.session_id),
+        _ => None,
+    }
+}
+
 /// A no IO Client implementation.
 ///
 /// This Client is a state machine that receives responses and events and
@@ -205,6 +260,9 @@ pub struct BaseClient {
     olm: Arc<Mutex<Option<OlmMachine>>>,
     #[cfg(feature = "encryption")]
     cryptostore: Arc<Mutex<Option<Box<dyn CryptoStore>>>>,
+    /// Events that will be decrypted once the room key for them arrives.
+    #[cfg(feature = "encryption")]
+    undecrypted_events: Arc<RwLock<UndecryptedEvents>>,
     store_path: Arc<Option<PathBuf>>,
     store_passphrase: Arc<Zeroizing<String>>,
 }
@@ -330,6 +388,8 @@ impl BaseClient {
             olm: Arc::new(Mutex::new(None)),
             #[cfg(feature = "encryption")]
             cryptostore: Arc::new(Mutex::new(config.crypto_store)),
+            #[cfg(feature = "encryption")]
+            undecrypted_events: Arc::new(RwLock::new(HashMap::new())),
             store_path: Arc::new(config.store_path),
             store_passphrase: Arc::new(
                 config
@@ -503,7 +563,20 @@ impl BaseClient {
                         .map_err(OlmError::from)?,
                     );
                 }
-                #[cfg(not(feature = "sqlite_cryptostore"))]
+                #[cfg(all(feature = "sled_cryptostore", not(feature = "sqlite_cryptostore")))]
+                {
+                    *olm = Some(
+                        OlmMachine::new_with_sled_store(
+                            &session.user_id,
+                            &session.device_id,
+                            path,
+                            &self.store_passphrase,
+                        )
+                        .await
+                        .map_err(OlmError::from)?,
+                    );
+                }
+                #[cfg(not(any(feature = "sqlite_cryptostore", feature = "sled_cryptostore")))]
                 {
                     *olm = Some(OlmMachine::new(&session.user_id, &session.device_id));
                 }
@@ -714,6 +787,198 @@ impl BaseClient {
         updated_rooms
     }
 
+    /// Decrypt an encrypted event from a room timeline.
+    ///
+    /// Returns the decrypted event together with information about its
+    /// encryption, or `None` if the client isn't logged in.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The unique id of the room the event belongs to.
+    ///
+    /// * `event` - The event that should be decrypted.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn decrypt_room_event(
+        &self,
+        room_id: &RoomId,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+    ) -> Result<Option<DecryptedEvent>> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(Some(o.decrypt_room_event(event, room_id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the given timeline event with its decrypted version if it's
+    /// encrypted and we manage to decrypt it.
+    ///
+    /// Events that can't be decrypted because the room key is missing are
+    /// remembered and decrypted once the room key arrives.
+    ///
+    /// Returns the encryption info of the event if it was decrypted.
+    #[cfg(feature = "encryption")]
+    async fn decrypt_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event: &mut Raw<AnySyncRoomEvent>,
+    ) -> Option<EncryptionInfo> {
+        if let Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(encrypted))) =
+            event.deserialize()
+        {
+            match self.decrypt_room_event(room_id, &encrypted).await {
+                Ok(Some(decrypted)) => {
+                    *event = decrypted.event;
+                    return Some(decrypted.encryption_info);
+                }
+                Err(Error::MegolmError(MegolmError::MissingSession)) => {
+                    self.queue_undecrypted_event(room_id, encrypted).await
+                }
+                Err(Error::MegolmError(MegolmError::Withheld(_))) => {
+                    // The room key won't arrive, there's no point in waiting
+                    // for it.
+                    if let Some(session_id) = megolm_session_id(&encrypted) {
+                        self.undecrypted_events
+                            .write()
+                            .await
+                            .remove(&(room_id.clone(), session_id.to_owned()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Remember an event that couldn't be decrypted because its room key is
+    /// missing.
+    ///
+    /// If we're already waiting for too many room keys, the room key whose
+    /// latest event is the oldest one is forgotten.
+    #[cfg(feature = "encryption")]
+    async fn queue_undecrypted_event(
+        &self,
+        room_id: &RoomId,
+        event: SyncMessageEvent<EncryptedEventContent>,
+    ) {
+        let key = if let Some(session_id) = megolm_session_id(&event) {
+            (room_id.clone(), session_id.to_owned())
+        } else {
+            return;
+        };
+
+        let mut undecrypted = self.undecrypted_events.write().await;
+
+        if !undecrypted.contains_key(&key) && undecrypted.len() >= MAX_UNDECRYPTED_SESSIONS {
+            let oldest = undecrypted
+                .iter()
+                .min_by_key(|(_, events)| events.iter().map(|e| e.origin_server_ts).max())
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                undecrypted.remove(&oldest);
+            }
+        }
+
+        let events = undecrypted.entry(key).or_insert_with(Vec::new);
+
+        if events.iter().all(|e| e.event_id != event.event_id) {
+            if events.len() >= MAX_UNDECRYPTED_EVENTS {
+                events.remove(0);
+            }
+
+            events.push(event);
+        }
+    }
+
+    /// Try to decrypt the events that were waiting for any of the given room
+    /// keys.
+    #[cfg(feature = "encryption")]
+    async fn retry_decryption_for(
+        &self,
+        keys: impl IntoIterator<Item = (RoomId, String)>,
+    ) -> Result<()> {
+        let waiting: Vec<(RoomId, String)> = {
+            let undecrypted = self.undecrypted_events.read().await;
+            keys.into_iter()
+                .filter(|key| undecrypted.contains_key(key))
+                .collect()
+        };
+
+        for (room_id, session_id) in waiting {
+            self.retry_decryption(&room_id, &session_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Try to decrypt the events that were waiting for the given room key.
+    ///
+    /// The room state is updated with the decrypted events and the
+    /// `on_room_late_decrypted_event` callback of the event emitter is fired
+    /// for each of them.
+    #[cfg(feature = "encryption")]
+    async fn retry_decryption(&self, room_id: &RoomId, session_id: &str) -> Result<()> {
+        let key = (room_id.clone(), session_id.to_owned());
+
+        let events = if let Some(events) = self.undecrypted_events.write().await.remove(&key) {
+            events
+        } else {
+            return Ok(());
+        };
+
+        let mut undecrypted = Vec::new();
+        let mut updated = false;
+
+        for event in events {
+            let decrypted = match self.decrypt_room_event(room_id, &event).await {
+                Ok(Some(d)) => d,
+                Err(Error::MegolmError(MegolmError::MissingSession)) => {
+                    undecrypted.push(event);
+                    continue;
+                }
+                // The event won't become decryptable by waiting longer.
+                _ => continue,
+            };
+
+            if self
+                .handle_joined_timeline_event(room_id, &decrypted.event)
+                .await?
+            {
+                updated = true;
+            }
+
+            if let Ok(e) = decrypted.event.deserialize() {
+                self.emit_late_decrypted_event(room_id, &e, &decrypted.encryption_info)
+                    .await;
+            }
+        }
+
+        if !undecrypted.is_empty() {
+            self.undecrypted_events
+                .write()
+                .await
+                .entry(key)
+                .or_insert_with(Vec::new)
+                .extend(undecrypted);
+        }
+
+        if updated {
+            if let Some(store) = self.state_store.read().await.as_ref() {
+                if let Some(room) = self.get_joined_room(room_id).await {
+                    store
+                        .store_room_state(RoomState::Joined(room.read().await.deref()))
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Receive a timeline event for a joined room and update the client state.
     ///
     /// Returns a bool, true when the `Room` state has been updated.
@@ -731,24 +996,34 @@ impl BaseClient {
         &self,
         room_id: &RoomId,
         event: &mut Raw<AnySyncRoomEvent>,
+    ) -> Result<bool> {
+        #[cfg(feature = "encryption")]
+        self.decrypt_timeline_event(room_id, event).await;
+
+        self.handle_joined_timeline_event(room_id, event).await
+    }
+
+    /// Update the client state with an already decrypted timeline event of a
+    /// joined room.
+    async fn handle_joined_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event: &Raw<AnySyncRoomEvent>,
     ) -> Result<bool> {
         match event.deserialize() {
-            #[allow(unused_mut)]
             Ok(mut e) => {
+                // Pass the, now decrypted, event to the verification machine
+                // in case it's part of an in-room verification.
                 #[cfg(feature = "encryption")]
-                if let AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(
-                    ref mut encrypted_event,
-                )) = e
-                {
-                    let olm = self.olm.lock().await;
-
-                    if let Some(o) = &*olm {
-                        if let Ok(decrypted) = o.decrypt_room_event(&encrypted_event, room_id).await
-                        {
-                            if let Ok(d) = decrypted.deserialize() {
-                                e = d
-                            }
-                            *event = decrypted;
+                if let AnySyncRoomEvent::Message(message) = &e {
+                    if let Some(o) = &*self.olm.lock().await {
+                        // A broken verification shouldn't stop the rest of
+                        // the timeline from being processed.
+                        if let Err(e) = o.receive_verification_event(room_id, message).await {
+                            warn!(
+                                "Error handling a verification event in room {}: {:?}",
+                                room_id, e
+                            );
                         }
                     }
                 }
@@ -763,7 +1038,12 @@ impl BaseClient {
                     // of the room.
                     if changed {
                         #[cfg(feature = "encryption")]
-                        self.invalidate_group_session(room_id).await;
+                        if let Err(e) = self.invalidate_group_session(room_id).await {
+                            warn!(
+                                "Error invalidating the group session of room {}: {:?}",
+                                room_id, e
+                            );
+                        }
                     }
 
                     Ok(changed)
@@ -800,7 +1080,12 @@ impl BaseClient {
             // of the room.
             if changed {
                 #[cfg(feature = "encryption")]
-                self.invalidate_group_session(room_id).await;
+                if let Err(e) = self.invalidate_group_session(room_id).await {
+                    warn!(
+                        "Error invalidating the group session of room {}: {:?}",
+                        room_id, e
+                    );
+                }
             }
 
             Ok(changed)
@@ -984,6 +1269,12 @@ impl BaseClient {
             }
         }
 
+        // Room keys that arrived now might let us decrypt events we
+        // previously failed to decrypt.
+        #[cfg(feature = "encryption")]
+        self.retry_decryption_for(received_room_keys(&response.to_device.events))
+            .await?;
+
         *self.sync_token.write().await = Some(response.next_batch.clone());
 
         // when events change state, updated_* signals to StateStore to update database
@@ -1052,16 +1343,26 @@ impl BaseClient {
                     *event = e;
                 }
 
+                // Decrypt the event here instead of in
+                // `receive_joined_timeline_event` so we can hold on to the
+                // encryption info.
+                #[cfg(feature = "encryption")]
+                let encryption_info = self.decrypt_timeline_event(room_id, &mut event).await;
+
                 // FIXME: receive_* and emit_* methods shouldn't be called in parallel. We
                 // should only pass events to receive_* methods and then let *them* emit.
-                let timeline_update = self
-                    .receive_joined_timeline_event(room_id, &mut event)
-                    .await?;
+                let timeline_update = self.handle_joined_timeline_event(room_id, &event).await?;
                 if timeline_update {
                     updated = true;
                 };
 
                 if let Ok(e) = event.deserialize() {
+                    #[cfg(feature = "encryption")]
+                    if let Some(info) = &encryption_info {
+                        self.emit_decrypted_event(&room_id, &e, info, RoomStateType::Joined)
+                            .await;
+                    }
+
                     self.emit_timeline_event(&room_id, &e, RoomStateType::Joined)
                         .await;
                 } else {
@@ -1339,12 +1640,21 @@ impl BaseClient {
         request_id: &Uuid,
         response: impl Into<IncomingResponse<'a>>,
     ) -> Result<()> {
-        let olm = self.olm.lock().await;
+        let changes = {
+            let olm = self.olm.lock().await;
 
-        match &*olm {
-            Some(o) => Ok(o.mark_request_as_sent(request_id, response).await?),
-            None => Ok(()),
-        }
+            match &*olm {
+                Some(o) => {
+                    o.mark_request_as_sent(request_id, response).await?;
+                    o.identity_changes()
+                }
+                None => return Ok(()),
+            }
+        };
+
+        self.emit_identity_changes(&changes).await;
+
+        Ok(())
     }
 
     /// Get a tuple of device and one-time keys that need to be uploaded.
@@ -1414,12 +1724,15 @@ impl BaseClient {
     /// to invalidate.
     #[cfg(feature = "encryption")]
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
-    pub async fn invalidate_group_session(&self, room_id: &RoomId) -> bool {
+    pub async fn invalidate_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> StdResult<bool, CryptoStoreError> {
         let olm = self.olm.lock().await;
 
         match &*olm {
-            Some(o) => o.invalidate_group_session(room_id),
-            None => false,
+            Some(o) => o.invalidate_group_session(room_id).await,
+            None => Ok(false),
         }
     }
 
@@ -1517,6 +1830,84 @@ impl BaseClient {
         }
     }
 
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn emit_identity_changes(&self, changes: &[IdentityChange]) {
+        if let Some(ee) = self.event_emitter.read().await.as_ref() {
+            for change in changes {
+                ee.on_identity_change(change).await;
+            }
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn emit_late_decrypted_event(
+        &self,
+        room_id: &RoomId,
+        event: &AnySyncRoomEvent,
+        encryption_info: &EncryptionInfo,
+    ) {
+        let lock = self.event_emitter.read().await;
+        let event_emitter = if let Some(ee) = lock.as_ref() {
+            ee
+        } else {
+            return;
+        };
+
+        let room = if let Some(room) = self.get_joined_room(&room_id).await {
+            RoomState::Joined(Arc::clone(&room))
+        } else {
+            return;
+        };
+
+        event_emitter
+            .on_room_late_decrypted_event(room, event, encryption_info)
+            .await;
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn emit_decrypted_event(
+        &self,
+        room_id: &RoomId,
+        event: &AnySyncRoomEvent,
+        encryption_info: &EncryptionInfo,
+        room_state: RoomStateType,
+    ) {
+        let lock = self.event_emitter.read().await;
+        let event_emitter = if let Some(ee) = lock.as_ref() {
+            ee
+        } else {
+            return;
+        };
+
+        let room = match room_state {
+            RoomStateType::Invited => {
+                if let Some(room) = self.get_invited_room(&room_id).await {
+                    RoomState::Invited(Arc::clone(&room))
+                } else {
+                    return;
+                }
+            }
+            RoomStateType::Joined => {
+                if let Some(room) = self.get_joined_room(&room_id).await {
+                    RoomState::Joined(Arc::clone(&room))
+                } else {
+                    return;
+                }
+            }
+            RoomStateType::Left => {
+                if let Some(room) = self.get_left_room(&room_id).await {
+                    RoomState::Left(Arc::clone(&room))
+                } else {
+                    return;
+                }
+            }
+        };
+
+        event_emitter
+            .on_room_decrypted_event(room, event, encryption_info)
+            .await;
+    }
+
     pub(crate) async fn emit_state_event(
         &self,
         room_id: &RoomId,
@@ -1854,6 +2245,130 @@ impl BaseClient {
             .and_then(|o| o.get_verification(flow_id))
     }
 
+    /// Get the content of a `m.key.verification.request` room message that
+    /// will start an in-room verification with the given user.
+    ///
+    /// Returns `None` if the client isn't logged in.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should be verified.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn verification_request_content(
+        &self,
+        user_id: &UserId,
+    ) -> Option<MessageEventContent> {
+        self.olm
+            .lock()
+            .await
+            .as_ref()
+            .map(|o| o.verification_request_content(user_id))
+    }
+
+    /// Request an interactive verification with the given user.
+    ///
+    /// Returns the new `VerificationRequest` and a to-device request that
+    /// needs to be sent out, `None` if the client isn't logged in.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should be verified.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn request_verification(
+        &self,
+        user_id: &UserId,
+    ) -> Option<(VerificationRequest, ToDeviceRequest)> {
+        self.olm
+            .lock()
+            .await
+            .as_ref()
+            .map(|o| o.request_verification(user_id))
+    }
+
+    /// Get a verification request object with the given flow id.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn get_verification_request(&self, flow_id: &str) -> Option<VerificationRequest> {
+        self.olm
+            .lock()
+            .await
+            .as_ref()
+            .and_then(|o| o.get_verification_request(flow_id))
+    }
+
+    /// Start a `Sas` verification for the ready verification request with the
+    /// given flow id.
+    ///
+    /// Returns the new `Sas` object and the request that needs to be sent out,
+    /// `None` if the client isn't logged in or if the request isn't ready.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn start_sas_for_request(
+        &self,
+        flow_id: &str,
+    ) -> StdResult<Option<(Sas, OutgoingVerificationRequest)>, CryptoStoreError> {
+        let olm = self.olm.lock().await;
+
+        if let Some(olm) = olm.as_ref() {
+            olm.start_sas_for_request(flow_id).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Create a QR code for the ready verification request with the given
+    /// flow id.
+    ///
+    /// Returns `None` if the client isn't logged in or if no QR code can be
+    /// created for the request.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn generate_qr_code(
+        &self,
+        flow_id: &str,
+    ) -> StdResult<Option<QrVerification>, CryptoStoreError> {
+        let olm = self.olm.lock().await;
+
+        if let Some(olm) = olm.as_ref() {
+            olm.generate_qr_code(flow_id).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Start a QR code verification using a QR code the other side is showing.
+    ///
+    /// Returns the new `QrVerification` and the request that needs to be sent
+    /// out, `None` if the client isn't logged in or if there's no ready
+    /// verification request for the QR code.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn scan_qr_code(
+        &self,
+        data: QrVerificationData,
+    ) -> StdResult<Option<(QrVerification, OutgoingVerificationRequest)>, CryptoStoreError> {
+        let olm = self.olm.lock().await;
+
+        if let Some(olm) = olm.as_ref() {
+            olm.scan_qr_code(data).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get a QR code verification object with the given flow id.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn get_qr_verification(&self, flow_id: &str) -> Option<QrVerification> {
+        self.olm
+            .lock()
+            .await
+            .as_ref()
+            .and_then(|o| o.get_qr_verification(flow_id))
+    }
+
     /// Get a specific device of a user.
     ///
     /// # Arguments
@@ -1955,6 +2470,110 @@ impl BaseClient {
         }
     }
 
+    /// Handle the to-device events that were sent to a dehydrated device.
+    ///
+    /// The events are decrypted in place and the room keys they contain are
+    /// imported, events that were waiting for those room keys are decrypted
+    /// the same way as if the room keys arrived in a sync response.
+    ///
+    /// Returns the number of room keys that were imported.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The dehydrated device the events were sent to.
+    ///
+    /// * `events` - The to-device events of the dehydrated device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn receive_dehydrated_device_events(
+        &self,
+        device: &RehydratedDevice,
+        events: &mut [Raw<AnyToDeviceEvent>],
+    ) -> Result<usize> {
+        let count = device.receive_events(events).await?;
+        self.retry_decryption_for(received_room_keys(events))
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Import room keys that were exported using `OlmMachine::export_keys()`.
+    ///
+    /// Events that were waiting for one of the imported room keys are
+    /// decrypted the same way as if the room keys arrived in a sync response.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found in the
+    /// key export.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - The decrypted room keys of a key export.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn import_keys(&self, exported_keys: Vec<ExportedRoomKey>) -> Result<(usize, usize)> {
+        let olm = self
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let keys: Vec<(RoomId, String)> = exported_keys
+            .iter()
+            .map(|k| (k.room_id.clone(), k.session_id.clone()))
+            .collect();
+
+        let result = olm
+            .import_keys(exported_keys)
+            .await
+            .map_err(OlmError::from)?;
+        self.retry_decryption_for(keys).await?;
+
+        Ok(result)
+    }
+
+    /// Decrypt and import room keys that were downloaded from the server-side
+    /// key backup.
+    ///
+    /// Events that were waiting for one of the restored room keys are
+    /// decrypted the same way as if the room keys arrived in a sync response.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were successfully
+    /// decrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The private key of the backup.
+    ///
+    /// * `rooms` - The backed up room keys, as returned by the server.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn restore_backup(
+        &self,
+        recovery_key: &RecoveryKey,
+        rooms: &BTreeMap<RoomId, RoomKeyBackup>,
+    ) -> Result<(usize, usize)> {
+        let olm = self
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let result = olm
+            .restore_backup(recovery_key, rooms)
+            .await
+            .map_err(OlmError::from)?;
+
+        self.retry_decryption_for(rooms.iter().flat_map(|(room_id, backup)| {
+            backup
+                .sessions
+                .keys()
+                .map(move |session_id| (room_id.clone(), session_id.clone()))
+        }))
+        .await?;
+
+        Ok(result)
+    }
+
     /// Get the olm machine.
     #[cfg(feature = "encryption")]
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
@@ -2512,6 +3131,174 @@ mod test {
         assert!(client.should_share_group_session(&room_id).await);
         let _ = client.share_group_session(&room_id).await.unwrap();
         assert!(!client.should_share_group_session(&room_id).await);
-        client.invalidate_group_session(&room_id).await;
+        client.invalidate_group_session(&room_id).await.unwrap();
+    }
+
+    #[async_test]
+    #[cfg(feature = "encryption")]
+    async fn test_late_room_key_decryption() {
+        use crate::{EventEmitter, SyncRoom};
+        use matrix_sdk_common::{
+            api::r0::keys::{claim_keys, get_keys},
+            events::{
+                room::message::MessageEventContent, AnyMessageEventContent, AnySyncMessageEvent,
+                AnySyncRoomEvent,
+            },
+            locks::RwLock,
+            uuid::Uuid,
+        };
+        use matrix_sdk_crypto::{EncryptionInfo, OlmMachine, OutgoingRequests};
+        use std::{
+            collections::BTreeMap,
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+        };
+
+        struct EE(Arc<AtomicBool>);
+        #[async_trait]
+        impl EventEmitter for EE {
+            async fn on_room_late_decrypted_event(
+                &self,
+                _: SyncRoom,
+                event: &AnySyncRoomEvent,
+                _: &EncryptionInfo,
+            ) {
+                if let AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(_)) = event {
+                    self.0.swap(true, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let room_id = get_room_id();
+        let passed = Arc::new(AtomicBool::default());
+        let mut client = get_client().await;
+        client.event_emitter = Arc::new(RwLock::new(Some(Box::new(EE(Arc::clone(&passed))))));
+
+        let mut sync_response = EventBuilder::default()
+            .add_state_event(EventsJson::Member)
+            .build_sync_response();
+        client
+            .receive_sync_response(&mut sync_response)
+            .await
+            .unwrap();
+
+        let alice = OlmMachine::new(&user_id!("@alice:localhost"), "ALICEDEVICE".into());
+        let olm = client.olm_machine().await.unwrap();
+
+        // Let Alice learn about our device and claim one of our one-time keys,
+        // so she can send us the room key.
+        let (device_keys, one_time_keys) = olm
+            .outgoing_requests()
+            .await
+            .into_iter()
+            .find_map(|r| match r.request() {
+                OutgoingRequests::KeysUpload(r) => {
+                    Some((r.device_keys.clone()?, r.one_time_keys.clone()?))
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        let keys_query = json!({
+            "device_keys": {
+                olm.user_id().to_string(): {
+                    olm.device_id().to_string(): device_keys,
+                }
+            },
+            "failures": {},
+        });
+        let keys_query = http::Response::builder()
+            .status(200)
+            .body(serde_json::to_vec(&keys_query).unwrap())
+            .unwrap();
+        let keys_query = get_keys::Response::try_from(keys_query).unwrap();
+        alice
+            .mark_request_as_sent(&Uuid::new_v4(), &keys_query)
+            .await
+            .unwrap();
+
+        let one_time_key = one_time_keys.into_iter().next().unwrap();
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(
+            olm.user_id().clone(),
+            vec![(
+                olm.device_id().into(),
+                vec![one_time_key].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        alice
+            .mark_request_as_sent(&Uuid::new_v4(), &claim_keys::Response::new(one_time_keys))
+            .await
+            .unwrap();
+
+        let requests = alice
+            .share_group_session(&room_id, std::iter::once(olm.user_id()), Default::default())
+            .await
+            .unwrap();
+
+        let room_key = json!({
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": serde_json::from_str::<serde_json::Value>(
+                requests[0]
+                    .messages
+                    .values()
+                    .next()
+                    .unwrap()
+                    .values()
+                    .next()
+                    .unwrap()
+                    .get()
+            )
+            .unwrap(),
+        });
+
+        let content = alice
+            .encrypt(
+                &room_id,
+                AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
+                    "It's a secret",
+                )),
+            )
+            .await
+            .unwrap();
+
+        let event = json!({
+            "content": content,
+            "event_id": "$late_decryption:localhost",
+            "origin_server_ts": 159026265,
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+        });
+
+        let mut sync_response = EventBuilder::default()
+            .add_custom_joined_event(&room_id, event)
+            .build_sync_response();
+        client
+            .receive_sync_response(&mut sync_response)
+            .await
+            .unwrap();
+
+        assert_eq!(client.undecrypted_events.read().await.len(), 1);
+        assert!(!passed.load(Ordering::SeqCst));
+
+        // The room key arrives in a later sync, the queued event gets
+        // decrypted.
+        let mut sync_response = EventBuilder::default().build_sync_response();
+        sync_response
+            .to_device
+            .events
+            .push(serde_json::from_value(room_key).unwrap());
+        client
+            .receive_sync_response(&mut sync_response)
+            .await
+            .unwrap();
+
+        assert!(client.undecrypted_events.read().await.is_empty());
+        assert!(passed.load(Ordering::SeqCst));
     }
 }
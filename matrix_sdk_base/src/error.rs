@@ -20,7 +20,7 @@ use std::io::Error as IoError;
 use thiserror::Error;
 
 #[cfg(feature = "encryption")]
-use matrix_sdk_crypto::{MegolmError, OlmError};
+use matrix_sdk_crypto::{DehydrationError, MegolmError, OlmError};
 
 /// Result type of the rust-sdk.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -56,4 +56,10 @@ pub enum Error {
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
     #[error(transparent)]
     MegolmError(#[from] MegolmError),
+
+    /// An error occurred while handling a dehydrated device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    #[error(transparent)]
+    DehydrationError(#[from] DehydrationError),
 }
@@ -14,7 +14,11 @@
 // limitations under the License.
 use std::sync::Arc;
 
+#[cfg(feature = "encryption")]
+use matrix_sdk_common::events::AnySyncRoomEvent;
 use matrix_sdk_common::locks::RwLock;
+#[cfg(feature = "encryption")]
+use matrix_sdk_crypto::{EncryptionInfo, IdentityChange};
 use serde_json::value::RawValue as RawJsonValue;
 
 use crate::{
@@ -287,6 +291,35 @@ pub trait EventEmitter: Send + Sync {
     /// The only guarantee this method can give about the event is that it is in the
     /// shape of a valid matrix event.
     async fn on_custom_event(&self, _: SyncRoom, _: &CustomEvent<'_>) {}
+
+    /// Fires when `Client` successfully decrypts an encrypted room event.
+    ///
+    /// This fires right before the callback for the type of the decrypted
+    /// event, the info can be used to decide how trustworthy the event is,
+    /// e.g. to show a shield next to the message.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    async fn on_room_decrypted_event(&self, _: SyncRoom, _: &AnySyncRoomEvent, _: &EncryptionInfo) {
+    }
+
+    /// Fires when an encrypted room event, that couldn't be decrypted when it
+    /// was received because the room key was missing, gets decrypted after
+    /// the room key arrived.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    async fn on_room_late_decrypted_event(
+        &self,
+        _: SyncRoom,
+        _: &AnySyncRoomEvent,
+        _: &EncryptionInfo,
+    ) {
+    }
+
+    /// Fires when we learn that a device or a user identity changed, e.g. a
+    /// user got a new device or their cross signing master key changed.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    async fn on_identity_change(&self, _: &IdentityChange) {}
 }
 
 #[cfg(test)]
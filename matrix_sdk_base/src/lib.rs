@@ -24,6 +24,9 @@
 //! keys. If this is disabled and `encryption` support is enabled the keys will
 //! by default be stored only in memory and thus lost after the client is
 //! destroyed.
+//! * `sled_cryptostore`: Enables a store for the encryption keys that is based
+//! on the sled embedded database. It is used by default if it is enabled and
+//! `sqlite_cryptostore` is disabled.
 //! * `unstable-synapse-quirks`: Enables support to deal with inconsistencies
 //! of Synapse in compliance with the Matrix API specification.
 #![deny(
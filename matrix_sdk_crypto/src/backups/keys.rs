@@ -0,0 +1,409 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use getrandom::getrandom;
+use olm_rs::{
+    errors::OlmPkDecryptionError,
+    pk::{OlmPkDecryption, OlmPkEncryption, PkMessage},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Error as JsonError;
+use thiserror::Error;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use matrix_sdk_common::{
+    api::r0::backup::{KeyBackupData, RoomKeyBackup, SessionData},
+    identifiers::{DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId},
+};
+
+use crate::{
+    olm::{ExportedGroupSessionKey, ExportedRoomKey, InboundGroupSession},
+    utilities::{decode, encode, DecodeError},
+};
+
+const KEY_SIZE: usize = 32;
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8B, 0x01];
+
+/// The name of the only backup algorithm we support.
+pub const MEGOLM_BACKUP_V1: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+
+/// Error type for the server-side key backup.
+#[derive(Error, Debug)]
+pub enum BackupError {
+    /// The recovery key isn't valid base58.
+    #[error("The recovery key isn't valid base58: {0}")]
+    Base58(String),
+
+    /// The recovery key isn't valid base64.
+    #[error(transparent)]
+    Base64(#[from] DecodeError),
+
+    /// The recovery key has an invalid length, prefix or parity byte.
+    #[error("The recovery key is malformed")]
+    InvalidRecoveryKey,
+
+    /// The recovery key doesn't belong to the backup we tried to restore.
+    #[error("The recovery key doesn't match the public key of the backup")]
+    KeyMismatch,
+
+    /// The backup uses an algorithm we don't support.
+    #[error("The backup uses an unsupported algorithm")]
+    UnsupportedAlgorithm,
+
+    /// A backed up room key failed to be decrypted.
+    #[error(transparent)]
+    Decryption(#[from] OlmPkDecryptionError),
+
+    /// The decrypted room key doesn't contain valid JSON.
+    #[error(transparent)]
+    Json(#[from] JsonError),
+}
+
+/// The private part of a `m.megolm_backup.v1.curve25519-aes-sha2` backup key.
+///
+/// The recovery key is the only thing needed to decrypt the room keys that
+/// were uploaded to the server-side key backup, as such it needs to be kept
+/// secret by the user.
+#[derive(Clone)]
+pub struct RecoveryKey {
+    inner: Arc<Zeroizing<Vec<u8>>>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for RecoveryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecoveryKey").finish()
+    }
+}
+
+impl RecoveryKey {
+    /// Create a new random recovery key.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// create the key.
+    pub fn new() -> Self {
+        let mut key = Zeroizing::new(vec![0u8; KEY_SIZE]);
+        getrandom(&mut key).expect("Can't generate randomness");
+
+        Self {
+            inner: Arc::new(key),
+        }
+    }
+
+    /// Create a recovery key from the raw bytes of the private key.
+    pub fn from_bytes(key: &[u8]) -> Result<Self, BackupError> {
+        if key.len() != KEY_SIZE {
+            return Err(BackupError::InvalidRecoveryKey);
+        }
+
+        Ok(Self {
+            inner: Arc::new(Zeroizing::new(key.to_vec())),
+        })
+    }
+
+    /// Get the raw bytes of the private key.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.inner
+    }
+
+    /// Try to create a recovery key from an unpadded base64 encoded string.
+    pub fn from_base64(key: &str) -> Result<Self, BackupError> {
+        let key = Zeroizing::new(decode(key)?);
+        Self::from_bytes(&key)
+    }
+
+    /// Export the recovery key as an unpadded base64 encoded string.
+    pub fn to_base64(&self) -> String {
+        encode(self.inner.as_slice())
+    }
+
+    /// Try to create a recovery key from the human readable base58 encoding
+    /// that is described in the spec.
+    ///
+    /// Whitespace inside the string is ignored.
+    pub fn from_base58(key: &str) -> Result<Self, BackupError> {
+        let key: String = key.chars().filter(|c| !c.is_whitespace()).collect();
+        let decoded = Zeroizing::new(
+            bs58::decode(key)
+                .into_vec()
+                .map_err(|e| BackupError::Base58(e.to_string()))?,
+        );
+
+        if decoded.len() != RECOVERY_KEY_PREFIX.len() + KEY_SIZE + 1
+            || decoded[..RECOVERY_KEY_PREFIX.len()] != RECOVERY_KEY_PREFIX
+        {
+            return Err(BackupError::InvalidRecoveryKey);
+        }
+
+        // The parity byte is the XOR of all the preceding bytes, XOR-ing the
+        // whole key together needs to give us a zero.
+        if decoded.iter().fold(0, |acc, b| acc ^ b) != 0 {
+            return Err(BackupError::InvalidRecoveryKey);
+        }
+
+        let start = RECOVERY_KEY_PREFIX.len();
+        Self::from_bytes(&decoded[start..start + KEY_SIZE])
+    }
+
+    /// Export the recovery key in the human readable base58 encoding that is
+    /// described in the spec.
+    ///
+    /// The key is split into groups of four characters.
+    pub fn to_base58(&self) -> String {
+        let mut bytes = Zeroizing::new(RECOVERY_KEY_PREFIX.to_vec());
+        bytes.extend_from_slice(&self.inner);
+
+        let parity = bytes.iter().fold(0, |acc, b| acc ^ b);
+        bytes.push(parity);
+
+        let encoded = Zeroizing::new(bs58::encode(bytes.as_slice()).into_string());
+
+        encoded
+            .as_bytes()
+            .chunks(4)
+            .map(|c| String::from_utf8_lossy(c).into_owned())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Get the public part of the backup key, this is the key that will be
+    /// uploaded to the server and used to encrypt room keys.
+    pub fn public_key(&self) -> MegolmV1BackupKey {
+        let public_key = self.get_pk_decryption().public_key().to_owned();
+        MegolmV1BackupKey::new(&public_key, None)
+    }
+
+    fn get_pk_decryption(&self) -> OlmPkDecryption {
+        OlmPkDecryption::from_bytes(self.inner.as_slice())
+            .expect("Can't create a PkDecryption object from our private key")
+    }
+
+    /// Decrypt a single backed up room key.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the room key belongs to.
+    ///
+    /// * `session_id` - The unique id of the room key.
+    ///
+    /// * `data` - The encrypted room key as it was downloaded from the server.
+    pub fn decrypt_room_key(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        data: &KeyBackupData,
+    ) -> Result<ExportedRoomKey, BackupError> {
+        let message = PkMessage::new(
+            data.session_data.ephemeral.clone(),
+            data.session_data.mac.clone(),
+            data.session_data.ciphertext.clone(),
+        );
+
+        let plaintext = Zeroizing::new(self.get_pk_decryption().decrypt(message)?);
+        let key: BackedUpRoomKey = serde_json::from_str(&plaintext)?;
+
+        Ok(key.into_exported(room_id.clone(), session_id.to_owned()))
+    }
+
+    /// Decrypt all the room keys that were downloaded from the server-side key
+    /// backup.
+    ///
+    /// Room keys that fail to be decrypted are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `rooms` - The map of room ids to backed up room keys, as returned by
+    /// the server.
+    pub fn decrypt_backup(&self, rooms: &BTreeMap<RoomId, RoomKeyBackup>) -> Vec<ExportedRoomKey> {
+        let mut keys = Vec::new();
+
+        for (room_id, backup) in rooms {
+            for (session_id, data) in &backup.sessions {
+                match self.decrypt_room_key(room_id, session_id, data) {
+                    Ok(k) => keys.push(k),
+                    Err(e) => warn!(
+                        "Failed to decrypt the backed up room key {} in room {}: {}",
+                        session_id, room_id, e
+                    ),
+                }
+            }
+        }
+
+        keys
+    }
+}
+
+impl Default for RecoveryKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The public part of a `m.megolm_backup.v1.curve25519-aes-sha2` backup key.
+///
+/// This key is used to encrypt room keys before they are uploaded to the
+/// server-side key backup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MegolmV1BackupKey {
+    key: Arc<str>,
+    version: Option<String>,
+}
+
+impl MegolmV1BackupKey {
+    /// Create a new backup key from a public curve25519 key.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_key` - The unpadded base64 encoded curve25519 public key.
+    ///
+    /// * `version` - The backup version, as returned by the server, that this
+    /// key belongs to.
+    pub fn new(public_key: &str, version: Option<String>) -> Self {
+        Self {
+            key: public_key.into(),
+            version,
+        }
+    }
+
+    /// Get the unpadded base64 encoded public key.
+    pub fn public_key(&self) -> &str {
+        &self.key
+    }
+
+    /// Get the backup version this key belongs to, if there is one.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Set the backup version this key belongs to.
+    pub fn set_version(&mut self, version: String) {
+        self.version = Some(version)
+    }
+
+    /// The backup algorithm this key is used with.
+    pub fn backup_algorithm(&self) -> &str {
+        MEGOLM_BACKUP_V1
+    }
+
+    /// Encrypt the given inbound group session so it can be uploaded to the
+    /// server-side key backup.
+    pub(crate) async fn encrypt(&self, session: &InboundGroupSession) -> KeyBackupData {
+        let pk = OlmPkEncryption::new(&self.key);
+
+        let first_message_index = session.first_known_index().into();
+        let key = session.export().await;
+        let forwarded_count = (key.forwarding_curve25519_key_chain.len() as u32).into();
+
+        let key = BackedUpRoomKey::from(key);
+        let plaintext = Zeroizing::new(
+            serde_json::to_string(&key).expect("Can't serialize the backed up room key"),
+        );
+
+        let message = pk.encrypt(&plaintext);
+
+        KeyBackupData {
+            first_message_index,
+            forwarded_count,
+            // TODO mark the key as verified if we received it directly from a
+            // verified device.
+            is_verified: false,
+            session_data: SessionData {
+                ephemeral: message.ephemeral_key,
+                ciphertext: message.ciphertext,
+                mac: message.mac,
+            },
+        }
+    }
+}
+
+/// The plaintext content of a backed up room key.
+///
+/// This is the same as an `ExportedRoomKey` without the room and session id,
+/// those are part of the backup layout.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BackedUpRoomKey {
+    algorithm: EventEncryptionAlgorithm,
+    sender_key: String,
+    session_key: ExportedGroupSessionKey,
+    sender_claimed_keys: BTreeMap<DeviceKeyAlgorithm, String>,
+    forwarding_curve25519_key_chain: Vec<String>,
+}
+
+impl BackedUpRoomKey {
+    fn into_exported(self, room_id: RoomId, session_id: String) -> ExportedRoomKey {
+        ExportedRoomKey {
+            algorithm: self.algorithm,
+            room_id,
+            sender_key: self.sender_key,
+            session_id,
+            session_key: self.session_key,
+            sender_claimed_keys: self.sender_claimed_keys,
+            forwarding_curve25519_key_chain: self.forwarding_curve25519_key_chain,
+        }
+    }
+}
+
+impl From<ExportedRoomKey> for BackedUpRoomKey {
+    fn from(key: ExportedRoomKey) -> Self {
+        Self {
+            algorithm: key.algorithm,
+            sender_key: key.sender_key,
+            session_key: key.session_key,
+            sender_claimed_keys: key.sender_claimed_keys,
+            forwarding_curve25519_key_chain: key.forwarding_curve25519_key_chain,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RecoveryKey, KEY_SIZE, RECOVERY_KEY_PREFIX};
+
+    #[test]
+    fn recovery_key_encoding() {
+        let key = RecoveryKey::new();
+
+        let base58 = key.to_base58();
+        let decoded = RecoveryKey::from_base58(&base58).unwrap();
+        assert_eq!(key.to_base64(), decoded.to_base64());
+
+        let decoded = RecoveryKey::from_base64(&key.to_base64()).unwrap();
+        assert_eq!(key.public_key(), decoded.public_key());
+    }
+
+    #[test]
+    fn invalid_recovery_key() {
+        assert!(RecoveryKey::from_bytes(&[0u8; 10]).is_err());
+        assert!(RecoveryKey::from_base58("EsTc").is_err());
+
+        let mut bytes = RECOVERY_KEY_PREFIX.to_vec();
+        bytes.extend_from_slice(&[1u8; KEY_SIZE]);
+
+        let parity = bytes.iter().fold(0, |acc, b| acc ^ b);
+        let mut invalid = bytes.clone();
+        invalid.push(parity ^ 1);
+        bytes.push(parity);
+
+        let valid = bs58::encode(bytes).into_string();
+        let invalid = bs58::encode(invalid).into_string();
+
+        assert!(RecoveryKey::from_base58(&valid).is_ok());
+        assert!(RecoveryKey::from_base58(&invalid).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server-side backups of room keys.
+//!
+//! Room keys are encrypted using the `m.megolm_backup.v1.curve25519-aes-sha2`
+//! algorithm and uploaded to the server, a client that logs in on a new device
+//! can download them and decrypt them with the [`RecoveryKey`].
+
+mod keys;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, info, warn};
+
+use matrix_sdk_common::{
+    api::r0::backup::{BackupAlgorithm, RoomKeyBackup},
+    identifiers::{DeviceKeyAlgorithm, DeviceKeyId, RoomId},
+    locks::RwLock,
+    uuid::Uuid,
+};
+
+pub use keys::{BackupError, MegolmV1BackupKey, RecoveryKey, MEGOLM_BACKUP_V1};
+
+use crate::{
+    olm::{InboundGroupSession, ReadOnlyAccount},
+    requests::{KeysBackupRequest, OutgoingRequest},
+    store::{Changes, Result as StoreResult, Store},
+};
+
+/// The key under which the currently enabled backup key is stored.
+const BACKUP_KEY: &str = "backup_key_v1";
+
+/// The maximum number of room keys that will be uploaded in a single request.
+const BACKUP_BATCH_SIZE: usize = 100;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredBackupKey {
+    public_key: String,
+    version: String,
+}
+
+/// A backup request that is currently in flight.
+#[derive(Clone, Debug)]
+struct PendingBackup {
+    request_id: Uuid,
+    request: KeysBackupRequest,
+    sessions: BTreeMap<RoomId, BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl PendingBackup {
+    fn as_outgoing_request(&self) -> OutgoingRequest {
+        OutgoingRequest {
+            request_id: self.request_id,
+            request: Arc::new(self.request.clone().into()),
+        }
+    }
+}
+
+/// State machine that uploads our room keys to the server-side key backup.
+#[derive(Clone, Debug)]
+pub(crate) struct BackupMachine {
+    account: ReadOnlyAccount,
+    store: Store,
+    backup_key: Arc<RwLock<Option<MegolmV1BackupKey>>>,
+    pending_backup: Arc<RwLock<Option<PendingBackup>>>,
+}
+
+impl BackupMachine {
+    pub fn new(account: ReadOnlyAccount, store: Store) -> Self {
+        Self {
+            account,
+            store,
+            backup_key: Arc::new(RwLock::new(None)),
+            pending_backup: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Restore a previously enabled backup key from the store.
+    pub async fn load_backup_key(&self) -> StoreResult<()> {
+        if let Some(key) = self.store.get_object::<StoredBackupKey>(BACKUP_KEY).await? {
+            debug!("Restored the backup key for backup version {}", key.version);
+            *self.backup_key.write().await =
+                Some(MegolmV1BackupKey::new(&key.public_key, Some(key.version)));
+        }
+
+        Ok(())
+    }
+
+    /// Is a backup currently enabled.
+    pub async fn enabled(&self) -> bool {
+        self.backup_key.read().await.is_some()
+    }
+
+    /// Get the currently enabled backup key.
+    pub async fn backup_key(&self) -> Option<MegolmV1BackupKey> {
+        self.backup_key.read().await.clone()
+    }
+
+    /// Enable the backup of room keys using the given key and backup version.
+    ///
+    /// All room keys will be marked as not backed up, since they need to be
+    /// uploaded to the new backup version.
+    pub async fn enable_backup(
+        &self,
+        mut key: MegolmV1BackupKey,
+        version: String,
+    ) -> StoreResult<()> {
+        let mut backup_key = self.backup_key.write().await;
+
+        if backup_key.as_ref().and_then(|k| k.version()) != Some(version.as_str()) {
+            self.store.reset_backup_state().await?;
+        }
+
+        self.store
+            .save_object(
+                BACKUP_KEY,
+                &StoredBackupKey {
+                    public_key: key.public_key().to_owned(),
+                    version: version.clone(),
+                },
+            )
+            .await?;
+
+        info!("Enabling the room key backup for version {}", version);
+        key.set_version(version);
+
+        *backup_key = Some(key);
+        self.pending_backup.write().await.take();
+
+        Ok(())
+    }
+
+    /// Disable the backup of room keys.
+    pub async fn disable_backup(&self) -> StoreResult<()> {
+        let mut backup_key = self.backup_key.write().await;
+
+        info!("Disabling the room key backup");
+
+        self.store.delete_object(BACKUP_KEY).await?;
+        self.store.reset_backup_state().await?;
+
+        *backup_key = None;
+        self.pending_backup.write().await.take();
+
+        Ok(())
+    }
+
+    /// Create the auth data for a new backup version, signed by our own
+    /// device.
+    pub async fn backup_algorithm(&self, key: &MegolmV1BackupKey) -> BackupAlgorithm {
+        let auth_data = json!({
+            "public_key": key.public_key(),
+        });
+
+        let signature = self.account.sign_json(auth_data).await;
+
+        let mut signatures = BTreeMap::new();
+        signatures
+            .entry(self.account.user_id().to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(
+                DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, self.account.device_id())
+                    .to_string(),
+                signature,
+            );
+
+        BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 {
+            public_key: key.public_key().to_owned(),
+            signatures,
+        }
+    }
+
+    /// Get the request that uploads room keys that aren't backed up yet.
+    ///
+    /// Returns the already pending request if one is in flight.
+    pub async fn backup(&self) -> StoreResult<Option<OutgoingRequest>> {
+        let mut pending = self.pending_backup.write().await;
+
+        if let Some(pending) = pending.as_ref() {
+            return Ok(Some(pending.as_outgoing_request()));
+        }
+
+        let backup_key = self.backup_key.read().await;
+
+        let (key, version) = if let Some(k) = backup_key.as_ref() {
+            if let Some(v) = k.version() {
+                (k, v.to_owned())
+            } else {
+                return Ok(None);
+            }
+        } else {
+            return Ok(None);
+        };
+
+        let sessions = self
+            .store
+            .inbound_group_sessions_for_backup(BACKUP_BATCH_SIZE)
+            .await?;
+
+        if sessions.is_empty() {
+            return Ok(None);
+        }
+
+        let (rooms, session_record) = Self::backup_keys(key, sessions).await;
+
+        let key_count: usize = session_record
+            .values()
+            .flat_map(|s| s.values())
+            .map(|s| s.len())
+            .sum();
+
+        info!(
+            "Backing up {} room keys to backup version {}",
+            key_count, version
+        );
+
+        let backup = PendingBackup {
+            request_id: Uuid::new_v4(),
+            request: KeysBackupRequest { version, rooms },
+            sessions: session_record,
+        };
+
+        let request = backup.as_outgoing_request();
+        *pending = Some(backup);
+
+        Ok(Some(request))
+    }
+
+    async fn backup_keys(
+        key: &MegolmV1BackupKey,
+        sessions: Vec<InboundGroupSession>,
+    ) -> (
+        BTreeMap<RoomId, RoomKeyBackup>,
+        BTreeMap<RoomId, BTreeMap<String, BTreeSet<String>>>,
+    ) {
+        let mut backup: BTreeMap<RoomId, RoomKeyBackup> = BTreeMap::new();
+        let mut session_record: BTreeMap<RoomId, BTreeMap<String, BTreeSet<String>>> =
+            BTreeMap::new();
+
+        for session in sessions {
+            let room_id = session.room_id().to_owned();
+            let session_id = session.session_id().to_owned();
+            let sender_key = session.sender_key.to_string();
+            let data = key.encrypt(&session).await;
+
+            session_record
+                .entry(room_id.clone())
+                .or_default()
+                .entry(sender_key)
+                .or_default()
+                .insert(session_id.clone());
+
+            backup
+                .entry(room_id)
+                .or_insert_with(|| RoomKeyBackup {
+                    sessions: BTreeMap::new(),
+                })
+                .sessions
+                .insert(session_id, data);
+        }
+
+        (backup, session_record)
+    }
+
+    /// Mark the room keys that were part of the backup request with the given
+    /// request id as backed up.
+    pub async fn mark_request_as_sent(&self, request_id: &Uuid) -> StoreResult<()> {
+        let mut pending = self.pending_backup.write().await;
+
+        let backup = if let Some(b) = pending.as_ref() {
+            if &b.request_id == request_id {
+                pending
+                    .take()
+                    .expect("The pending backup request disappeared")
+            } else {
+                warn!(
+                    "Got a response for an unknown backup request {}",
+                    request_id
+                );
+                return Ok(());
+            }
+        } else {
+            return Ok(());
+        };
+
+        let mut changed_sessions = Vec::new();
+
+        for (room_id, sender_keys) in &backup.sessions {
+            for (sender_key, session_ids) in sender_keys {
+                for session_id in session_ids {
+                    if let Some(session) = self
+                        .store
+                        .get_inbound_group_session(room_id, sender_key, session_id)
+                        .await?
+                    {
+                        session.mark_as_backed_up();
+                        changed_sessions.push(session);
+                    }
+                }
+            }
+        }
+
+        debug!("Marked {} room keys as backed up", changed_sessions.len());
+
+        let changes = Changes {
+            inbound_group_sessions: changed_sessions,
+            ..Default::default()
+        };
+
+        self.store.save_changes(changes).await
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use matrix_sdk_common::{events::AnySyncRoomEvent, identifiers::UserId, Raw};
+
+use crate::ReadOnlyDevice;
+
+/// The verification state of the device that sent an encrypted room event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerificationState {
+    /// The event was sent by a device that we trust, either because we
+    /// verified it locally or because it's signed by a verified cross signing
+    /// identity.
+    Verified,
+    /// The event was sent by a known device that we don't trust.
+    Unverified,
+    /// The event was sent by a device we don't know about, or the keys of the
+    /// known device don't match the keys of the room key.
+    UnknownDevice,
+    /// The room key that was used to decrypt the event was forwarded to us or
+    /// imported, we can't be sure that the claimed sender created it.
+    Forwarded,
+}
+
+/// Information about the encryption of a decrypted room event.
+#[derive(Clone, Debug)]
+pub struct EncryptionInfo {
+    /// The user that sent the event.
+    pub sender: UserId,
+    /// The curve25519 key of the device that created the room key.
+    pub sender_curve25519_key: String,
+    /// The ed25519 key that the creator of the room key claims to own.
+    pub sender_claimed_ed25519_key: Option<String>,
+    /// The device that sent the event, if we know about it and its keys match
+    /// the keys of the room key.
+    pub sender_device: Option<ReadOnlyDevice>,
+    /// The unique id of the room key that was used to decrypt the event.
+    pub session_id: String,
+    /// The chain of curve25519 keys through which the room key was forwarded
+    /// to us, empty if we received the room key directly from its creator.
+    pub forwarding_curve25519_key_chain: Vec<String>,
+    /// The verification state of the sender.
+    pub verification_state: VerificationState,
+}
+
+impl EncryptionInfo {
+    /// Was the event sent by a device we trust.
+    pub fn is_verified(&self) -> bool {
+        self.verification_state == VerificationState::Verified
+    }
+}
+
+/// A successfully decrypted room event.
+#[derive(Clone, Debug)]
+pub struct DecryptedEvent {
+    /// The decrypted event.
+    pub event: Raw<AnySyncRoomEvent>,
+    /// Information about the encryption of the event.
+    pub encryption_info: EncryptionInfo,
+}
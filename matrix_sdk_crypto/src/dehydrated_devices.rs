@@ -0,0 +1,452 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dehydrated devices, as described in MSC2697.
+//!
+//! A dehydrated device is a device whose Olm account is stored, encrypted
+//! with a user supplied key, on the server. Other users share room keys with
+//! it like with any other device, so the room keys that were sent while none
+//! of our devices was online can be recovered once we log in again.
+//!
+//! The endpoints that are used to upload a dehydrated device and to fetch the
+//! to-device events that were sent to it are unstable and not part of the
+//! client-server API yet, the machine only creates the request bodies and
+//! consumes the responses.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use matrix_sdk_common::{
+    api::r0::keys::OneTimeKey,
+    encryption::DeviceKeys,
+    events::AnyToDeviceEvent,
+    identifiers::{DeviceId, DeviceIdBox, DeviceKeyId},
+    uuid::Uuid,
+    Raw,
+};
+use olm_rs::{errors::OlmAccountError, PicklingMode};
+
+use crate::{
+    error::SignatureError,
+    olm::{
+        AccountPickle, PickledAccount, PrivateCrossSigningIdentity, ReadOnlyAccount, PICKLE_VERSION,
+    },
+    store::{Changes, CryptoStore, CryptoStoreError, MemoryStore},
+    OlmMachine,
+};
+
+/// The algorithm that is used to store the account of a dehydrated device.
+pub const DEHYDRATION_ALGORITHM: &str = "org.matrix.msc2697.v1.olm.libolm_pickle";
+
+/// Error type for the creation and rehydration of dehydrated devices.
+#[derive(Debug, Error)]
+pub enum DehydrationError {
+    /// The dehydrated device was stored using an algorithm we don't support.
+    #[error("the dehydrated device uses an unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+
+    /// The account of the dehydrated device couldn't be decrypted, most
+    /// likely the wrong key was used.
+    #[error("the dehydrated device couldn't be unpickled: {0}")]
+    Pickle(#[from] OlmAccountError),
+
+    /// The dehydrated device couldn't be signed, most likely because we don't
+    /// have our private self signing key.
+    #[error("the dehydrated device couldn't be signed: {0}")]
+    Signature(#[from] SignatureError),
+
+    /// The storage layer returned an error.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// The encrypted account of a dehydrated device, as stored on the server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DehydratedDeviceData {
+    /// The algorithm that was used to store the account.
+    pub algorithm: String,
+    /// The encrypted pickle of the Olm account of the device.
+    pub account: String,
+}
+
+/// The request body that uploads a newly created dehydrated device.
+///
+/// The request contains the encrypted account of the device as well as its
+/// public device keys and a set of one-time and fallback keys, so other
+/// devices can establish Olm sessions with it.
+#[derive(Clone, Debug, Serialize)]
+pub struct DehydratedDeviceRequest {
+    /// The unique id of the dehydrated device.
+    pub device_id: DeviceIdBox,
+    /// The display name that the dehydrated device should have.
+    pub initial_device_display_name: String,
+    /// The encrypted account of the device.
+    pub device_data: DehydratedDeviceData,
+    /// The signed device keys of the device.
+    pub device_keys: DeviceKeys,
+    /// Signed one-time keys of the device.
+    pub one_time_keys: BTreeMap<DeviceKeyId, OneTimeKey>,
+    /// Signed fallback keys of the device.
+    pub fallback_keys: BTreeMap<DeviceKeyId, OneTimeKey>,
+}
+
+/// The response of the server when our dehydrated device is fetched.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DehydratedDeviceResponse {
+    /// The unique id of the dehydrated device.
+    pub device_id: DeviceIdBox,
+    /// The encrypted account of the device.
+    pub device_data: DehydratedDeviceData,
+}
+
+/// A batch of to-device events that were sent to a dehydrated device.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DehydratedDeviceEvents {
+    /// The to-device events of this batch.
+    pub events: Vec<Raw<AnyToDeviceEvent>>,
+    /// The token that should be used to fetch the next batch of events.
+    pub next_batch: Option<String>,
+}
+
+/// A dehydrated device that was fetched from the server and decrypted.
+///
+/// The to-device events that were sent to the dehydrated device should be
+/// passed to [`receive_events`], they are handled by the `OlmMachine` that
+/// rehydrated the device as if they were sent to it.
+///
+/// Once all events are processed the dehydrated device should be replaced
+/// by a fresh one, created with `OlmMachine::create_dehydrated_device()`.
+///
+/// [`receive_events`]: #method.receive_events
+#[derive(Clone, Debug)]
+pub struct RehydratedDevice {
+    rehydrated: OlmMachine,
+    original: OlmMachine,
+}
+
+impl RehydratedDevice {
+    /// Decrypt the account of a dehydrated device.
+    ///
+    /// The room keys the device received will be imported into the store of
+    /// the given machine.
+    pub(crate) async fn new(
+        machine: OlmMachine,
+        pickle_key: &[u8],
+        device_id: &DeviceId,
+        device_data: DehydratedDeviceData,
+    ) -> Result<Self, DehydrationError> {
+        if device_data.algorithm != DEHYDRATION_ALGORITHM {
+            return Err(DehydrationError::UnsupportedAlgorithm(
+                device_data.algorithm,
+            ));
+        }
+
+        let pickle = PickledAccount {
+            user_id: machine.user_id().to_owned(),
+            device_id: device_id.into(),
+            pickle: AccountPickle::from(device_data.account),
+            shared: true,
+            uploaded_signed_key_count: 0,
+            fallback_key_pending: false,
+            version: PICKLE_VERSION,
+        };
+
+        let account = ReadOnlyAccount::from_pickle(
+            pickle,
+            PicklingMode::Encrypted {
+                key: pickle_key.to_vec(),
+            },
+        )?;
+
+        let store = MemoryStore::new();
+        store.save_account(account.clone()).await?;
+
+        let rehydrated = OlmMachine::new_helper(
+            machine.user_id(),
+            device_id.into(),
+            Box::new(store),
+            account,
+            PrivateCrossSigningIdentity::empty(machine.user_id().to_owned()),
+        );
+
+        debug!("Rehydrated the dehydrated device {}", device_id);
+
+        Ok(Self {
+            rehydrated,
+            original: machine,
+        })
+    }
+
+    /// Get the device id of the rehydrated device.
+    pub fn device_id(&self) -> &DeviceId {
+        self.rehydrated.device_id()
+    }
+
+    /// Decrypt to-device events that were sent to the dehydrated device.
+    ///
+    /// The decrypted events are handled by the machine that rehydrated the
+    /// device, room keys, including forwarded ones, are imported into its
+    /// store and the key requests for them are cancelled. Every encrypted
+    /// event is replaced with its decrypted version, sensitive data is removed
+    /// from it the same way `OlmMachine::receive_sync_response()` does it.
+    ///
+    /// Events that aren't encrypted or that fail to decrypt are left as they
+    /// are.
+    ///
+    /// Returns the number of room keys that were imported.
+    pub async fn receive_events(
+        &self,
+        events: &mut [Raw<AnyToDeviceEvent>],
+    ) -> Result<usize, DehydrationError> {
+        let mut sessions = Vec::new();
+
+        for raw_event in events.iter_mut() {
+            let event = if let Ok(AnyToDeviceEvent::RoomEncrypted(e)) = raw_event.deserialize() {
+                e
+            } else {
+                continue;
+            };
+
+            let decrypted = match self
+                .original
+                .decrypt_rehydrated_to_device_event(&self.rehydrated, &event)
+                .await
+            {
+                Ok(d) => d,
+                Err(e) => {
+                    warn!(
+                        "Failed to decrypt a to-device event from {} that was sent to \
+                         the dehydrated device {:?}",
+                        event.sender, e
+                    );
+                    continue;
+                }
+            };
+
+            // Later events might be encrypted using the same Olm session, so
+            // the session needs to be stored.
+            let changes = Changes {
+                account: Some(self.rehydrated.account().clone()),
+                sessions: vec![decrypted.session.session()],
+                message_hashes: vec![decrypted.message_hash],
+                ..Default::default()
+            };
+            self.rehydrated.store().save_changes(changes).await?;
+
+            if let Some(session) = decrypted.inbound_group_session {
+                let existing = self
+                    .original
+                    .store()
+                    .get_inbound_group_session(
+                        &session.room_id,
+                        &session.sender_key,
+                        session.session_id(),
+                    )
+                    .await?;
+
+                if existing.map_or(true, |e| {
+                    e.first_known_index() > session.first_known_index()
+                }) {
+                    sessions.push(session);
+                }
+            }
+
+            *raw_event = decrypted.event;
+        }
+
+        let count = sessions.len();
+
+        let changes = Changes {
+            inbound_group_sessions: sessions.clone(),
+            ..Default::default()
+        };
+        self.original.store().save_changes(changes).await?;
+        self.original.mark_keys_as_received(&sessions).await;
+
+        info!(
+            "Imported {} room keys from the dehydrated device {}",
+            count,
+            self.device_id()
+        );
+
+        Ok(count)
+    }
+}
+
+/// Create a new dehydrated device for the owner of the given identity.
+///
+/// The device keys are signed with the self signing key of the identity, the
+/// account of the device is encrypted using the given pickle key.
+pub(crate) async fn create_dehydrated_device(
+    identity: &PrivateCrossSigningIdentity,
+    display_name: &str,
+    pickle_key: &[u8],
+) -> Result<DehydratedDeviceRequest, DehydrationError> {
+    let device_id: DeviceIdBox = Uuid::new_v4().to_simple().to_string().to_uppercase().into();
+
+    let account = ReadOnlyAccount::new(identity.user_id(), &device_id);
+
+    let mut device_keys = account.device_keys().await;
+    identity.sign_device_keys(&mut device_keys).await?;
+
+    account
+        .generate_one_time_keys_helper(account.max_one_time_keys().await / 2)
+        .await;
+    account.generate_fallback_key().await;
+
+    let one_time_keys = account
+        .signed_one_time_keys_helper()
+        .await
+        .unwrap_or_default();
+    let fallback_keys = account.signed_fallback_keys().await;
+
+    account.mark_keys_as_published().await;
+
+    let pickle = account
+        .pickle(PicklingMode::Encrypted {
+            key: pickle_key.to_vec(),
+        })
+        .await;
+
+    debug!("Created the dehydrated device {}", device_id);
+
+    Ok(DehydratedDeviceRequest {
+        device_id,
+        initial_device_display_name: display_name.to_owned(),
+        device_data: DehydratedDeviceData {
+            algorithm: DEHYDRATION_ALGORITHM.to_owned(),
+            account: pickle.pickle.as_str().to_owned(),
+        },
+        device_keys,
+        one_time_keys,
+        fallback_keys,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::{
+        api::r0::keys::claim_keys,
+        events::{room::encrypted::EncryptedEventContent, AnyToDeviceEvent, EventType},
+        identifiers::{room_id, user_id, DeviceIdBox},
+        Raw,
+    };
+    use serde_json::json;
+    use std::{collections::BTreeMap, convert::TryFrom};
+
+    use super::DehydrationError;
+    use crate::{EncryptionSettings, OlmMachine, ReadOnlyDevice};
+
+    #[tokio::test]
+    async fn dehydrated_device_receives_room_keys() {
+        let alice = OlmMachine::new(&user_id!("@alice:example.org"), "ALICEDEVICE".into());
+        let bob = OlmMachine::new(&user_id!("@bob:example.org"), "BOBDEVICE".into());
+        let pickle_key = [0u8; 32];
+
+        // The dehydrated device can't be signed without our self signing key.
+        assert!(matches!(
+            alice
+                .create_dehydrated_device("Dehydrated device", &pickle_key)
+                .await,
+            Err(DehydrationError::Signature(_))
+        ));
+
+        alice.bootstrap_cross_signing(false).await.unwrap();
+
+        let request = alice
+            .create_dehydrated_device("Dehydrated device", &pickle_key)
+            .await
+            .unwrap();
+        assert!(!request.one_time_keys.is_empty());
+        assert_eq!(request.fallback_keys.len(), 1);
+
+        let device = ReadOnlyDevice::try_from(&request.device_keys).unwrap();
+        let identity = alice.get_identity(alice.user_id()).await.unwrap().unwrap();
+        identity.self_signing_key().verify_device(&device).unwrap();
+
+        // Bob learns about the dehydrated device and claims one of its keys.
+        bob.store().save_devices(&[device]).await.unwrap();
+
+        let mut keys = BTreeMap::new();
+        let (key_id, key) = request.one_time_keys.iter().next().unwrap();
+        keys.insert(key_id.clone(), key.clone());
+
+        let mut device_keys = BTreeMap::new();
+        device_keys.insert(request.device_id.clone(), keys);
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(alice.user_id().clone(), device_keys);
+
+        bob.receive_keys_claim_response(&claim_keys::Response::new(one_time_keys))
+            .await
+            .unwrap();
+
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = bob
+            .share_group_session(
+                &room_id,
+                [alice.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let content = to_device_requests[0]
+            .messages
+            .values()
+            .next()
+            .unwrap()
+            .values()
+            .next()
+            .unwrap();
+        let content: EncryptedEventContent = serde_json::from_str(content.get()).unwrap();
+
+        let mut events: Vec<Raw<AnyToDeviceEvent>> = vec![serde_json::from_value(json!({
+            "sender": bob.user_id(),
+            "type": EventType::RoomEncrypted,
+            "content": content,
+        }))
+        .unwrap()];
+
+        // A wrong pickle key doesn't unlock the device.
+        assert!(matches!(
+            alice
+                .rehydrate_device(&[1u8; 32], &request.device_id, request.device_data.clone())
+                .await,
+            Err(DehydrationError::Pickle(_))
+        ));
+
+        let rehydrated = alice
+            .rehydrate_device(&pickle_key, &request.device_id, request.device_data)
+            .await
+            .unwrap();
+        let device_id: DeviceIdBox = rehydrated.device_id().into();
+        assert_eq!(device_id, request.device_id);
+
+        assert_eq!(rehydrated.receive_events(&mut events).await.unwrap(), 1);
+        assert!(matches!(
+            events[0].deserialize(),
+            Ok(AnyToDeviceEvent::RoomKey(_))
+        ));
+
+        let sessions = alice.store().get_inbound_group_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(&*sessions[0].room_id, &room_id);
+        assert_eq!(&*sessions[0].sender_key, bob.identity_keys().curve25519());
+    }
+}
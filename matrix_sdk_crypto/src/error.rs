@@ -12,12 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_common::identifiers::{DeviceId, Error as IdentifierError, UserId};
+use std::collections::BTreeMap;
+
+use matrix_sdk_common::identifiers::{
+    DeviceId, DeviceIdBox, Error as IdentifierError, EventId, UserId,
+};
 use olm_rs::errors::{OlmGroupSessionError, OlmSessionError};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
-use super::store::CryptoStoreError;
+use super::{store::CryptoStoreError, withheld::WithheldCode};
 
 pub type OlmResult<T> = Result<T, OlmError>;
 pub type MegolmResult<T> = Result<T, MegolmError>;
@@ -64,6 +68,11 @@ pub enum OlmError {
             have a valid Olm session with us"
     )]
     MissingSession,
+
+    /// A room key wasn't shared because some of the devices that should
+    /// receive it aren't trusted and the share strategy forbids that.
+    #[error("the room key wasn't shared because of unverified devices: {0:?}")]
+    UnverifiedDevices(BTreeMap<UserId, Vec<DeviceIdBox>>),
 }
 
 /// Error representing a failure during a group encryption operation.
@@ -82,10 +91,23 @@ pub enum MegolmError {
     #[error("decryption failed because the session to decrypt the message is missing")]
     MissingSession,
 
+    /// Decryption failed because the sender intentionally didn't share the
+    /// session needed to decrypt the event with us.
+    #[error("decryption failed because the room key was withheld: {0}")]
+    Withheld(WithheldCode),
+
     /// The underlying group session operation returned an error.
     #[error("can't finish Olm group session operation {0}")]
     OlmGroupSession(#[from] OlmGroupSessionError),
 
+    /// A Megolm message index was already used by a different event, the
+    /// message was most likely replayed.
+    #[error(
+        "decryption failed because the message index {1} of the Megolm session {0} was \
+         already used by the event {2}"
+    )]
+    ReplayedMessage(String, u32, EventId),
+
     /// The storage layer returned an error.
     #[error(transparent)]
     Store(#[from] CryptoStoreError),
@@ -126,11 +148,26 @@ pub enum SessionUnpicklingError {
     /// The underlying Olm session operation returned an error.
     #[error("can't finish Olm Session operation {0}")]
     OlmSession(#[from] OlmSessionError),
+    /// The underlying Olm group session operation returned an error.
+    #[error("can't finish Olm group session operation {0}")]
+    OlmGroupSession(#[from] OlmGroupSessionError),
     /// The Session timestamp was invalid.
     #[error("can't load session timestamps")]
     SessionTimestampError,
 }
 
+/// Error representing a failure to manually verify a user identity.
+#[derive(Error, Debug)]
+pub enum IdentityVerificationError {
+    /// The identity couldn't be signed with our user-signing key.
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+
+    /// The crypto store failed to save the verified identity.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
 #[derive(Error, Debug)]
 pub enum SignatureError {
     #[error("the signature used a unsupported algorithm")]
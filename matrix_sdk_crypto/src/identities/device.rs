@@ -106,11 +106,26 @@ impl Device {
     }
 
     /// Get the trust state of the device.
+    ///
+    /// Returns true if the device was verified, either locally or using cross
+    /// signing. See [`trust_level`] for a more detailed answer.
+    ///
+    /// A device that was locally marked as blacklisted is never trusted, even
+    /// if it's signed by a user identity we verified. Such devices won't
+    /// receive room keys from our key sharing either.
+    ///
+    /// [`trust_level`]: #method.trust_level
     pub fn trust_state(&self) -> bool {
         self.inner
             .trust_state(&self.own_identity, &self.device_owner_identity)
     }
 
+    /// Get the trust level of the device.
+    pub fn trust_level(&self) -> TrustLevel {
+        self.inner
+            .trust_level(&self.own_identity, &self.device_owner_identity)
+    }
+
     /// Set the local trust state of the device to the given state.
     ///
     /// This won't affect any cross signing trust state, this only sets a flag
@@ -213,6 +228,17 @@ impl UserDevices {
             device_owner_identity: self.device_owner_identity.clone(),
         })
     }
+
+    /// Iterator over the device ids of the user devices and their trust
+    /// levels.
+    pub fn trust_levels(&self) -> impl Iterator<Item = (&DeviceIdBox, TrustLevel)> + '_ {
+        self.inner.iter().map(move |(device_id, d)| {
+            (
+                device_id,
+                d.trust_level(&self.own_identity, &self.device_owner_identity),
+            )
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -228,6 +254,37 @@ pub enum LocalTrust {
     Unset = 3,
 }
 
+/// The trust level of a device or of a user identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustLevel {
+    /// The device or identity was locally marked as verified, e.g. after an
+    /// interactive verification.
+    LocallyVerified,
+    /// The device is signed by a user identity that we verified.
+    CrossSignedVerified,
+    /// The device is signed by the user identity of its owner, but we didn't
+    /// verify the identity.
+    CrossSignedUnverified,
+    /// The device or identity isn't verified.
+    Unverified,
+    /// The device was locally marked as blacklisted.
+    Blacklisted,
+    /// The device was locally marked as ignored, its trust state doesn't
+    /// matter.
+    Ignored,
+}
+
+impl TrustLevel {
+    /// Is the device or identity verified, either locally or using cross
+    /// signing.
+    pub fn is_trusted(self) -> bool {
+        matches!(
+            self,
+            TrustLevel::LocallyVerified | TrustLevel::CrossSignedVerified
+        )
+    }
+}
+
 impl From<i64> for LocalTrust {
     fn from(state: i64) -> Self {
         match state {
@@ -329,17 +386,60 @@ impl ReadOnlyDevice {
         self.deleted.load(Ordering::Relaxed)
     }
 
+    /// Is the device trusted, see `trust_level()`.
+    ///
+    /// The local blacklist takes precedence over cross signing, a blacklisted
+    /// device isn't trusted even if a verified identity signed it.
     pub(crate) fn trust_state(
         &self,
         own_identity: &Option<OwnUserIdentity>,
         device_owner: &Option<UserIdentities>,
     ) -> bool {
-        // TODO we want to return an enum mentioning if the trust is local, if
-        // only the identity is trusted, if the identity and the device are
-        // trusted.
-        if self.is_trusted() {
+        self.trust_level(own_identity, device_owner).is_trusted()
+    }
+
+    pub(crate) fn trust_level(
+        &self,
+        own_identity: &Option<OwnUserIdentity>,
+        device_owner: &Option<UserIdentities>,
+    ) -> TrustLevel {
+        let local_trust = self.local_trust_state();
+
+        match local_trust {
+            LocalTrust::BlackListed => TrustLevel::Blacklisted,
             // If the device is localy marked as verified just return so, no
             // need to check signatures.
+            LocalTrust::Verified => TrustLevel::LocallyVerified,
+            _ if self.is_cross_signing_trusted(own_identity, device_owner) => {
+                TrustLevel::CrossSignedVerified
+            }
+            _ if device_owner.as_ref().map_or(false, |i| match i {
+                UserIdentities::Own(i) => i.is_device_signed(self).is_ok(),
+                UserIdentities::Other(i) => i.is_device_signed(self).is_ok(),
+            }) =>
+            {
+                TrustLevel::CrossSignedUnverified
+            }
+            LocalTrust::Ignored => TrustLevel::Ignored,
+            LocalTrust::Unset => TrustLevel::Unverified,
+        }
+    }
+
+    /// Is the device signed by a user identity that we trust.
+    fn is_cross_signing_trusted(
+        &self,
+        own_identity: &Option<OwnUserIdentity>,
+        device_owner: &Option<UserIdentities>,
+    ) -> bool {
+        if device_owner
+            .as_ref()
+            .and_then(|i| i.other())
+            .map_or(false, |i| {
+                i.is_verified() && i.is_device_signed(&self).is_ok()
+            })
+        {
+            // The owner of the device was interactively verified and the
+            // device is signed by the owner.
             true
         } else {
             own_identity.as_ref().map_or(false, |own_identity| {
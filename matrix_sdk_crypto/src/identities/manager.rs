@@ -15,7 +15,8 @@
 use std::{
     collections::{BTreeMap, HashSet},
     convert::TryFrom,
-    sync::Arc,
+    mem,
+    sync::{Arc, Mutex},
 };
 use tracing::{info, trace, warn};
 
@@ -36,15 +37,46 @@ use crate::{
     store::{Changes, DeviceChanges, IdentityChanges, Result as StoreResult, Store},
 };
 
+/// A change of a device or of a user identity that we learned about in a
+/// keys query response.
+#[derive(Clone, Debug)]
+pub enum IdentityChange {
+    /// We discovered a new device.
+    NewDevice(ReadOnlyDevice),
+    /// A device got deleted.
+    DeviceDeleted(ReadOnlyDevice),
+    /// The identity keys of a known device changed.
+    DeviceKeysChanged(ReadOnlyDevice),
+    /// We discovered a new user identity, either the user just set up cross
+    /// signing or this is the first time we see the identity.
+    IdentityCreated(UserIdentities),
+    /// The master key of another user changed.
+    ///
+    /// This is expected if the user reset their cross signing keys, but it
+    /// might also mean that someone is trying to impersonate the user, the
+    /// user should be warned about this and the identity should be verified
+    /// again.
+    MasterKeyChanged(UserIdentities),
+    /// The master key of our own user identity changed.
+    OwnIdentityChanged(UserIdentities),
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct IdentityManager {
     user_id: Arc<UserId>,
     device_id: Arc<DeviceIdBox>,
     group_manager: GroupSessionManager,
     store: Store,
+    /// Changes we learned about in keys query responses that weren't
+    /// collected yet.
+    changes: Arc<Mutex<Vec<IdentityChange>>>,
 }
 
 impl IdentityManager {
+    /// How many uncollected changes are kept, the oldest changes are dropped
+    /// once there are more.
+    const MAX_UNCOLLECTED_CHANGES: usize = 1000;
+
     pub fn new(
         user_id: Arc<UserId>,
         device_id: Arc<DeviceIdBox>,
@@ -56,6 +88,32 @@ impl IdentityManager {
             device_id,
             store,
             group_manager,
+            changes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Get and clear the list of identity and device changes we learned about
+    /// in keys query responses.
+    ///
+    /// The list needs to be drained regularly, only the latest
+    /// `MAX_UNCOLLECTED_CHANGES` changes are kept.
+    pub fn collect_changes(&self) -> Vec<IdentityChange> {
+        mem::take(&mut *self.changes.lock().unwrap())
+    }
+
+    /// Remember the given changes until they get collected, dropping the
+    /// oldest changes if nobody collects them.
+    fn record_changes(&self, feed: Vec<IdentityChange>) {
+        let mut changes = self.changes.lock().unwrap();
+        changes.extend(feed);
+
+        if changes.len() > Self::MAX_UNCOLLECTED_CHANGES {
+            let excess = changes.len() - Self::MAX_UNCOLLECTED_CHANGES;
+            warn!(
+                "Dropping {} identity changes that weren't collected",
+                excess
+            );
+            changes.drain(..excess);
         }
     }
 
@@ -80,19 +138,15 @@ impl IdentityManager {
         &self,
         response: &KeysQueryResponse,
     ) -> OlmResult<(DeviceChanges, IdentityChanges)> {
-        // TODO create a enum that tells us how the device/identity changed,
-        // e.g. new/deleted/display name change.
-        //
-        // TODO create a struct that will hold the device/identity and the
-        // change enum and return the struct.
-        //
         // TODO once outbound group sessions hold on to the set of users that
         // received the session, invalidate the session if a user device
         // got added/deleted.
+        let mut feed = Vec::new();
+
         let changed_devices = self
-            .handle_devices_from_key_query(&response.device_keys)
+            .handle_devices_from_key_query(&response.device_keys, &mut feed)
             .await?;
-        let changed_identities = self.handle_cross_singing_keys(response).await?;
+        let changed_identities = self.handle_cross_singing_keys(response, &mut feed).await?;
 
         let changes = Changes {
             identities: changed_identities.clone(),
@@ -101,6 +155,7 @@ impl IdentityManager {
         };
 
         self.store.save_changes(changes).await?;
+        self.record_changes(feed);
 
         Ok((changed_devices, changed_identities))
     }
@@ -112,11 +167,14 @@ impl IdentityManager {
     /// * `device_keys_map` - A map holding the device keys of the users for
     /// which the key query was done.
     ///
+    /// * `feed` - The list where the changes should be recorded.
+    ///
     /// Returns a list of devices that changed. Changed here means either
     /// they are new, one of their properties has changed or they got deleted.
     async fn handle_devices_from_key_query(
         &self,
         device_keys_map: &BTreeMap<UserId, BTreeMap<DeviceIdBox, DeviceKeys>>,
+        feed: &mut Vec<IdentityChange>,
     ) -> StoreResult<DeviceChanges> {
         let mut users_with_new_or_deleted_devices = HashSet::new();
 
@@ -145,6 +203,8 @@ impl IdentityManager {
                 let device = self.store.get_readonly_device(&user_id, device_id).await?;
 
                 if let Some(mut device) = device {
+                    let old_keys = device.keys().clone();
+
                     if let Err(e) = device.update_device(device_keys) {
                         warn!(
                             "Failed to update the device keys for {} {}: {:?}",
@@ -152,6 +212,11 @@ impl IdentityManager {
                         );
                         continue;
                     }
+
+                    if &old_keys != device.keys() {
+                        feed.push(IdentityChange::DeviceKeysChanged(device.clone()));
+                    }
+
                     changes.changed.push(device);
                 } else {
                     let device = match ReadOnlyDevice::try_from(device_keys) {
@@ -166,6 +231,7 @@ impl IdentityManager {
                     };
                     info!("Adding a new device to the device store {:?}", device);
                     users_with_new_or_deleted_devices.insert(user_id);
+                    feed.push(IdentityChange::NewDevice(device.clone()));
                     changes.new.push(device);
                 }
             }
@@ -180,13 +246,15 @@ impl IdentityManager {
                 users_with_new_or_deleted_devices.insert(user_id);
                 if let Some(device) = stored_devices.get(*device_id) {
                     device.mark_as_deleted();
+                    feed.push(IdentityChange::DeviceDeleted(device.clone()));
                     changes.deleted.push(device.clone());
                 }
             }
         }
 
         self.group_manager
-            .invalidate_sessions_new_devices(&users_with_new_or_deleted_devices);
+            .invalidate_sessions_new_devices(&users_with_new_or_deleted_devices)
+            .await?;
 
         Ok(changes)
     }
@@ -197,11 +265,14 @@ impl IdentityManager {
     ///
     /// * `response` - The keys query response.
     ///
+    /// * `feed` - The list where the changes should be recorded.
+    ///
     /// Returns a list of identities that changed. Changed here means either
     /// they are new, one of their properties has changed or they got deleted.
     async fn handle_cross_singing_keys(
         &self,
         response: &KeysQueryResponse,
+        feed: &mut Vec<IdentityChange>,
     ) -> StoreResult<IdentityChanges> {
         let mut changes = IdentityChanges::default();
 
@@ -218,7 +289,10 @@ impl IdentityManager {
                 continue;
             };
 
-            let result = if let Some(mut i) = self.store.get_user_identity(user_id).await? {
+            let identity = self.store.get_user_identity(user_id).await?;
+            let old_master_key = identity.as_ref().map(|i| i.master_key().clone());
+
+            let result = if let Some(mut i) = identity {
                 match &mut i {
                     UserIdentities::Own(ref mut identity) => {
                         let user_signing = if let Some(s) = response.user_signing_keys.get(user_id)
@@ -285,8 +359,19 @@ impl IdentityManager {
                         i
                     );
                     if new {
+                        feed.push(IdentityChange::IdentityCreated(i.clone()));
                         changes.new.push(i);
                     } else {
+                        if old_master_key.as_ref() != Some(i.master_key()) {
+                            let change = match &i {
+                                UserIdentities::Own(_) => IdentityChange::OwnIdentityChanged,
+                                UserIdentities::Other(_) => IdentityChange::MasterKeyChanged,
+                            };
+
+                            warn!("The master key of the user {} changed", user_id);
+                            feed.push(change(i.clone()));
+                        }
+
                         changes.changed.push(i);
                     }
                 }
@@ -386,7 +471,7 @@ pub(crate) mod test {
     use serde_json::json;
 
     use crate::{
-        identities::IdentityManager,
+        identities::{IdentityChange, IdentityManager},
         machine::test::response_from_file,
         olm::{Account, PrivateCrossSigningIdentity, ReadOnlyAccount},
         session_manager::GroupSessionManager,
@@ -658,6 +743,60 @@ pub(crate) mod test {
         assert!(identity.is_device_signed(&device).is_ok())
     }
 
+    #[async_test]
+    async fn test_identity_changes() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        assert!(manager.collect_changes().is_empty());
+
+        manager
+            .receive_keys_query_response(&other_key_query())
+            .await
+            .unwrap();
+
+        let changes = manager.collect_changes();
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(
+            &changes[0],
+            IdentityChange::NewDevice(d) if d.user_id() == &other_user
+        ));
+        assert!(matches!(
+            &changes[1],
+            IdentityChange::IdentityCreated(i) if i.user_id() == &other_user
+        ));
+        assert!(manager.collect_changes().is_empty());
+
+        // Nothing changed, so there's nothing to report.
+        manager
+            .receive_keys_query_response(&other_key_query())
+            .await
+            .unwrap();
+        assert!(manager.collect_changes().is_empty());
+
+        let mut response = other_key_query();
+        response.device_keys.get_mut(&other_user).unwrap().clear();
+        response.master_keys.clear();
+        response.self_signing_keys.clear();
+
+        manager
+            .receive_keys_query_response(&response)
+            .await
+            .unwrap();
+
+        let changes = manager.collect_changes();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(
+            &changes[0],
+            IdentityChange::DeviceDeleted(d) if d.device_id().as_str() == "SKISMLNIMH"
+        ));
+
+        // Changes that nobody collects don't pile up forever.
+        let max = IdentityManager::MAX_UNCOLLECTED_CHANGES;
+        manager.record_changes(vec![changes[0].clone(); max + 10]);
+        assert_eq!(manager.collect_changes().len(), max);
+    }
+
     #[async_test]
     async fn test_session_invalidation() {
         let manager = manager();
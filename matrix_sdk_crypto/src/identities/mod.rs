@@ -44,9 +44,10 @@ pub(crate) mod device;
 mod manager;
 pub(crate) mod user;
 
-pub use device::{Device, LocalTrust, ReadOnlyDevice, UserDevices};
+pub use device::{Device, LocalTrust, ReadOnlyDevice, TrustLevel, UserDevices};
+pub use manager::IdentityChange;
 pub(crate) use manager::IdentityManager;
 pub use user::{
     MasterPubkey, OwnUserIdentity, SelfSigningPubkey, UserIdentities, UserIdentity,
-    UserSigningPubkey,
+    UserSigningPubkey, VerifiableUserIdentity,
 };
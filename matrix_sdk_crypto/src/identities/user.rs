@@ -15,6 +15,7 @@
 use std::{
     collections::{btree_map::Iter, BTreeMap},
     convert::TryFrom,
+    ops::Deref,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -31,7 +32,13 @@ use matrix_sdk_common::{
 
 #[cfg(test)]
 use crate::olm::PrivateCrossSigningIdentity;
-use crate::{error::SignatureError, olm::Utility, ReadOnlyDevice};
+use crate::{
+    error::{IdentityVerificationError, SignatureError},
+    identities::TrustLevel,
+    olm::Utility,
+    verification::VerificationMachine,
+    ReadOnlyDevice,
+};
 
 /// Wrapper for a cross signing key marking it as the master key.
 ///
@@ -210,6 +217,14 @@ impl MasterPubkey {
         self.0.keys.get(key_id.as_str()).map(|k| k.as_str())
     }
 
+    /// Get the first available master key.
+    ///
+    /// There's usually only a single master key so this will usually fetch the
+    /// only key.
+    pub fn get_first_key(&self) -> Option<&str> {
+        self.0.keys.values().map(|k| k.as_str()).next()
+    }
+
     /// Check if the given cross signing sub-key is signed by the master key.
     ///
     /// # Arguments
@@ -415,6 +430,31 @@ impl UserIdentities {
         }
     }
 
+    /// Get the trust level of the identity.
+    ///
+    /// Identities of other users are trusted if they were verified locally or
+    /// if our own verified identity signed them.
+    ///
+    /// # Arguments
+    ///
+    /// * `own_identity` - Our own user identity, if we have one.
+    pub fn trust_level(&self, own_identity: Option<&OwnUserIdentity>) -> TrustLevel {
+        match self {
+            UserIdentities::Own(i) if i.is_verified() => TrustLevel::LocallyVerified,
+            UserIdentities::Own(_) => TrustLevel::Unverified,
+            UserIdentities::Other(i) if i.is_verified() => TrustLevel::LocallyVerified,
+            UserIdentities::Other(i) => {
+                if own_identity.map_or(false, |o| {
+                    o.is_verified() && o.is_identity_signed(i).is_ok()
+                }) {
+                    TrustLevel::CrossSignedVerified
+                } else {
+                    TrustLevel::Unverified
+                }
+            }
+        }
+    }
+
     /// Destructure the enum into an `OwnUserIdentity` if it's of the correct
     /// type.
     pub fn own(&self) -> Option<&OwnUserIdentity> {
@@ -450,6 +490,8 @@ pub struct UserIdentity {
     user_id: Arc<UserId>,
     pub(crate) master_key: MasterPubkey,
     self_signing_key: SelfSigningPubkey,
+    #[serde(skip)]
+    verified: Arc<AtomicBool>,
 }
 
 impl UserIdentity {
@@ -473,6 +515,7 @@ impl UserIdentity {
             user_id: Arc::new(master_key.0.user_id.clone()),
             master_key,
             self_signing_key,
+            verified: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -499,6 +542,7 @@ impl UserIdentity {
             user_id: Arc::new(identity.user_id().clone()),
             master_key,
             self_signing_key,
+            verified: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -517,8 +561,23 @@ impl UserIdentity {
         &self.self_signing_key
     }
 
+    /// Mark the identity as verified.
+    ///
+    /// This is done after an interactive verification with the owner of the
+    /// identity succeeded.
+    pub fn mark_as_verified(&self) {
+        self.verified.store(true, Ordering::SeqCst)
+    }
+
+    /// Check if the identity has been verified by us.
+    pub fn is_verified(&self) -> bool {
+        self.verified.load(Ordering::SeqCst)
+    }
+
     /// Update the identity with a new master key and self signing key.
     ///
+    /// Note: This will reset the verification state if the master keys differ.
+    ///
     /// # Arguments
     ///
     /// * `master_key` - The new master key of the user identity.
@@ -533,6 +592,10 @@ impl UserIdentity {
     ) -> Result<(), SignatureError> {
         master_key.verify_subkey(&self_signing_key)?;
 
+        if self.master_key != master_key {
+            self.verified.store(false, Ordering::SeqCst)
+        }
+
         self.master_key = master_key;
         self.self_signing_key = self_signing_key;
 
@@ -560,6 +623,41 @@ impl UserIdentity {
     }
 }
 
+/// A user identity of another user that can be verified manually.
+///
+/// Like a `Device` wraps a `ReadOnlyDevice`, this wraps a `UserIdentity` and
+/// adds the methods that need access to our verification machine. The wrapper
+/// isn't stored, only the `UserIdentity` is, to avoid reference cycles between
+/// the store and the verification machine.
+#[derive(Debug, Clone)]
+pub struct VerifiableUserIdentity {
+    pub(crate) inner: UserIdentity,
+    pub(crate) verification_machine: VerificationMachine,
+}
+
+impl Deref for VerifiableUserIdentity {
+    type Target = UserIdentity;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl VerifiableUserIdentity {
+    /// Manually verify this identity.
+    ///
+    /// This signs the master key of the identity with our user-signing key and
+    /// marks the identity as verified. The signature upload request will be
+    /// returned by `OlmMachine::outgoing_requests()`, once it's uploaded our
+    /// other devices will trust the identity as well.
+    ///
+    /// The identity isn't marked as verified if it can't be signed, e.g.
+    /// because we don't have a private user-signing key.
+    pub async fn verify(&self) -> Result<(), IdentityVerificationError> {
+        self.verification_machine.verify_identity(&self.inner).await
+    }
+}
+
 /// Struct representing a cross signing identity of our own user.
 ///
 /// This is the user identity of our own user. This user identity will contain a
@@ -713,7 +811,7 @@ pub(crate) mod test {
     use crate::{
         identities::{
             manager::test::{other_key_query, own_key_query},
-            Device, ReadOnlyDevice,
+            Device, LocalTrust, ReadOnlyDevice, TrustLevel,
         },
         olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
         store::MemoryStore,
@@ -815,9 +913,38 @@ pub(crate) mod test {
         assert!(!first.trust_state());
         assert!(!first.is_trusted());
 
+        assert_eq!(second.trust_level(), TrustLevel::CrossSignedUnverified);
+        assert_eq!(first.trust_level(), TrustLevel::Unverified);
+        assert_eq!(
+            UserIdentities::Own(identity.clone()).trust_level(None),
+            TrustLevel::Unverified
+        );
+
         identity.mark_as_verified();
         assert!(second.trust_state());
         assert!(!first.trust_state());
+
+        assert_eq!(second.trust_level(), TrustLevel::CrossSignedVerified);
+        assert_eq!(first.trust_level(), TrustLevel::Unverified);
+        assert_eq!(
+            UserIdentities::Own(identity.clone()).trust_level(None),
+            TrustLevel::LocallyVerified
+        );
+
+        // Blacklisting a device overrides the trust we got from cross signing.
+        second.set_trust_state(LocalTrust::BlackListed);
+        assert_eq!(second.trust_level(), TrustLevel::Blacklisted);
+        assert!(!second.trust_state());
+        second.set_trust_state(LocalTrust::Unset);
+        assert!(second.trust_state());
+
+        first.set_trust_state(LocalTrust::BlackListed);
+        assert_eq!(first.trust_level(), TrustLevel::Blacklisted);
+        first.set_trust_state(LocalTrust::Ignored);
+        assert_eq!(first.trust_level(), TrustLevel::Ignored);
+        first.set_trust_state(LocalTrust::Verified);
+        assert_eq!(first.trust_level(), TrustLevel::LocallyVerified);
+        assert!(first.trust_state());
     }
 
     #[async_test]
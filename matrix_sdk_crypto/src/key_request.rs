@@ -16,14 +16,16 @@
 //
 // handle the case where we can't create a session with a device. clearing our
 // stale key share requests that we'll never be able to handle.
-//
-// If we don't trust the device store an object that remembers the request and
-// let the users introspect that object.
 
 use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use serde::{Deserialize, Serialize};
 use serde_json::value::to_raw_value;
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    sync::{Arc, Mutex as SyncMutex, RwLock},
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
 use tracing::{error, info, trace, warn};
 
@@ -35,6 +37,8 @@ use matrix_sdk_common::{
         AnyToDeviceEvent, EventType, ToDeviceEvent,
     },
     identifiers::{DeviceId, DeviceIdBox, EventEncryptionAlgorithm, RoomId, UserId},
+    instant::Instant,
+    locks::Mutex,
     uuid::Uuid,
     Raw,
 };
@@ -63,6 +67,77 @@ pub enum KeyshareDecision {
     UntrustedDevice,
 }
 
+/// What should be done with an incoming room key request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyShareAction {
+    /// Share the room key with the requesting device.
+    Share,
+    /// Don't share the room key with the requesting device.
+    Refuse,
+    /// Let the user decide if the room key should be shared.
+    ///
+    /// The request can be listed using
+    /// `OlmMachine::deferred_key_requests()` and answered later on. Deferred
+    /// requests are only kept in memory, they don't survive a restart.
+    Defer,
+}
+
+/// A room key request that we received from another device.
+#[derive(Clone, Debug)]
+pub struct IncomingKeyRequest {
+    /// The user that requested the room key.
+    pub user_id: UserId,
+    /// The device that requested the room key.
+    pub device_id: DeviceIdBox,
+    /// The unique id of the request.
+    pub request_id: String,
+    /// Info about the room key that was requested.
+    pub info: RequestedKeyInfo,
+}
+
+/// A policy that decides if a room key should be shared with a device that
+/// requested it.
+///
+/// By default room keys are shared with our own trusted devices and with
+/// devices of other users that received the room key when it was created.
+pub trait KeySharePolicy: Debug + Send + Sync {
+    /// Decide what should be done with the given room key request.
+    ///
+    /// The default implementation follows the decision of the built-in policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The room key request.
+    ///
+    /// * `device` - The device that requested the room key.
+    ///
+    /// * `session` - The room key that was requested.
+    ///
+    /// * `default` - The decision of the built-in policy, the reason why the
+    /// room key shouldn't be shared if it refused the request.
+    fn decide(
+        &self,
+        request: &IncomingKeyRequest,
+        device: &Device,
+        session: &InboundGroupSession,
+        default: Result<(), KeyshareDecision>,
+    ) -> KeyShareAction {
+        let _ = (request, device, session);
+
+        if default.is_ok() {
+            KeyShareAction::Share
+        } else {
+            KeyShareAction::Refuse
+        }
+    }
+}
+
+/// The built-in key share policy.
+#[derive(Debug)]
+struct DefaultKeySharePolicy;
+
+impl KeySharePolicy for DefaultKeySharePolicy {}
+
 /// A queue where we store room key requests that we want to serve but the
 /// device that requested the key doesn't share an Olm session with us.
 #[derive(Debug, Clone)]
@@ -136,13 +211,65 @@ pub(crate) struct KeyRequestMachine {
     >,
     wait_queue: WaitQueue,
     users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
+    key_share_policy: Arc<RwLock<Arc<dyn KeySharePolicy>>>,
+    /// Key requests that the key share policy deferred to the user.
+    deferred_key_requests: Arc<DashMap<(UserId, DeviceIdBox, String), DeferredKeyRequest>>,
+    /// Key requests that the user accepted, these skip the key share policy.
+    accepted_key_requests: Arc<DashSet<(UserId, DeviceIdBox, String)>>,
+    /// The ids of our outgoing key requests, loaded lazily from the store.
+    ///
+    /// The lock serializes the read-modify-write cycles of the stored index.
+    outgoing_key_request_ids: Arc<Mutex<Option<BTreeSet<Uuid>>>>,
+    /// The last time our outgoing key requests were garbage collected.
+    last_garbage_collection: Arc<SyncMutex<Option<Instant>>>,
 }
 
+/// A key request that the key share policy deferred to the user.
+#[derive(Debug, Clone)]
+struct DeferredKeyRequest {
+    event: ToDeviceEvent<RoomKeyRequestToDeviceEventContent>,
+    /// The time the request was deferred.
+    deferred_at: SystemTime,
+}
+
+impl DeferredKeyRequest {
+    fn is_expired(&self) -> bool {
+        SystemTime::now()
+            .duration_since(self.deferred_at)
+            .unwrap_or_default()
+            > KeyRequestMachine::KEY_REQUEST_EXPIRATION
+    }
+}
+
+/// The key under which the ids of our outgoing key requests are stored.
+const OUTGOING_KEY_REQUESTS: &str = "outgoing_key_requests";
+
 #[derive(Debug, Serialize, Deserialize)]
 struct OugoingKeyInfo {
     request_id: Uuid,
     info: RequestedKeyInfo,
     sent_out: bool,
+    /// The time this request was created.
+    #[serde(default = "SystemTime::now")]
+    creation_time: SystemTime,
+    /// The time the key was first requested, re-sent requests keep the time
+    /// of the original request.
+    #[serde(default = "SystemTime::now")]
+    first_request_time: SystemTime,
+}
+
+/// A room key request that we sent out, or are going to send out, that didn't
+/// get answered yet.
+#[derive(Clone, Debug)]
+pub struct OutgoingKeyRequest {
+    /// The unique id of the request.
+    pub request_id: Uuid,
+    /// Info about the room key that was requested.
+    pub info: RequestedKeyInfo,
+    /// Was the request already sent out.
+    pub sent_out: bool,
+    /// The time the room key was first requested.
+    pub first_request_time: SystemTime,
 }
 
 trait Encode {
@@ -167,6 +294,15 @@ impl Encode for ForwardedRoomKeyToDeviceEventContent {
     }
 }
 
+fn requested_key_info(room_id: &RoomId, sender_key: &str, session_id: &str) -> RequestedKeyInfo {
+    RequestedKeyInfo {
+        algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
+        room_id: room_id.to_owned(),
+        sender_key: sender_key.to_owned(),
+        session_id: session_id.to_owned(),
+    }
+}
+
 fn wrap_key_request_content(
     recipient: UserId,
     id: Uuid,
@@ -193,6 +329,17 @@ fn wrap_key_request_content(
 }
 
 impl KeyRequestMachine {
+    /// Key requests that were sent out and didn't get answered for this long
+    /// are sent out again.
+    const KEY_REQUEST_RESEND_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    /// Key requests that didn't get answered for this long are canceled.
+    const KEY_REQUEST_EXPIRATION: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+    /// How often our outgoing key requests are garbage collected.
+    const GARBAGE_COLLECTION_INTERVAL: Duration = Duration::from_secs(60 * 5);
+    /// How many deferred key requests of a single device are kept, older
+    /// requests are dropped once a device goes over this limit.
+    const MAX_DEFERRED_KEY_REQUESTS_PER_DEVICE: usize = 50;
+
     pub fn new(
         user_id: Arc<UserId>,
         device_id: Arc<DeviceIdBox>,
@@ -209,9 +356,119 @@ impl KeyRequestMachine {
             incoming_key_requests: Arc::new(DashMap::new()),
             wait_queue: WaitQueue::new(),
             users_for_key_claim,
+            key_share_policy: Arc::new(RwLock::new(Arc::new(DefaultKeySharePolicy))),
+            deferred_key_requests: Arc::new(DashMap::new()),
+            accepted_key_requests: Arc::new(DashSet::new()),
+            outgoing_key_request_ids: Arc::new(Mutex::new(None)),
+            last_garbage_collection: Arc::new(SyncMutex::new(None)),
         }
     }
 
+    /// Set the policy that decides if a requested room key should be shared.
+    pub fn set_key_share_policy(&self, policy: Arc<dyn KeySharePolicy>) {
+        *self.key_share_policy.write().unwrap() = policy;
+    }
+
+    /// Get the key requests that the key share policy deferred to the user.
+    ///
+    /// Deferred requests are only kept in memory and expire after
+    /// `KEY_REQUEST_EXPIRATION`, every device can have at most
+    /// `MAX_DEFERRED_KEY_REQUESTS_PER_DEVICE` of them.
+    pub fn deferred_key_requests(&self) -> Vec<IncomingKeyRequest> {
+        self.deferred_key_requests
+            .iter()
+            .filter(|item| !item.value().is_expired())
+            .filter_map(|item| {
+                let (user_id, device_id, request_id) = item.key().clone();
+
+                item.value()
+                    .event
+                    .content
+                    .body
+                    .clone()
+                    .map(|info| IncomingKeyRequest {
+                        user_id,
+                        device_id,
+                        request_id,
+                        info,
+                    })
+            })
+            .collect()
+    }
+
+    /// Accept a deferred key request.
+    ///
+    /// The request is queued up again and will be served, bypassing the key
+    /// share policy, the next time incoming key requests are collected.
+    ///
+    /// Returns false if no such deferred request exists.
+    pub fn accept_key_request(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request_id: &str,
+    ) -> bool {
+        let key = (user_id.to_owned(), device_id.into(), request_id.to_owned());
+
+        match self.deferred_key_requests.remove(&key) {
+            Some((key, request)) if !request.is_expired() => {
+                self.accepted_key_requests.insert(key.clone());
+                self.incoming_key_requests.insert(key, request.event);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Refuse a deferred key request.
+    ///
+    /// Returns false if no such deferred request exists.
+    pub fn refuse_key_request(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request_id: &str,
+    ) -> bool {
+        let key = (user_id.to_owned(), device_id.into(), request_id.to_owned());
+        self.deferred_key_requests
+            .remove(&key)
+            .map_or(false, |(_, r)| !r.is_expired())
+    }
+
+    /// Remember a key request that the key share policy deferred to the user.
+    ///
+    /// If the requesting device already has too many deferred requests, its
+    /// oldest ones are dropped.
+    fn defer_key_request(
+        &self,
+        key: (UserId, DeviceIdBox, String),
+        event: ToDeviceEvent<RoomKeyRequestToDeviceEventContent>,
+    ) {
+        let mut from_device: Vec<(SystemTime, (UserId, DeviceIdBox, String))> = self
+            .deferred_key_requests
+            .iter()
+            .filter(|r| r.key().0 == key.0 && r.key().1 == key.1 && r.key().2 != key.2)
+            .map(|r| (r.value().deferred_at, r.key().clone()))
+            .collect();
+
+        if from_device.len() >= Self::MAX_DEFERRED_KEY_REQUESTS_PER_DEVICE {
+            from_device.sort_by_key(|(deferred_at, _)| *deferred_at);
+            let excess = from_device.len() + 1 - Self::MAX_DEFERRED_KEY_REQUESTS_PER_DEVICE;
+
+            for (_, old_key) in from_device.into_iter().take(excess) {
+                self.deferred_key_requests.remove(&old_key);
+            }
+        }
+
+        self.deferred_key_requests.insert(
+            key,
+            DeferredKeyRequest {
+                event,
+                deferred_at: SystemTime::now(),
+            },
+        );
+    }
+
     /// Our own user id.
     pub fn user_id(&self) -> &UserId {
         &self.user_id
@@ -315,8 +572,19 @@ impl KeyRequestMachine {
                     return Ok(None);
                 }
             }
-            // We ignore cancellations here since there's nothing to serve.
-            Action::CancelRequest => return Ok(None),
+            // There's nothing to serve for cancellations, but the request
+            // might be waiting for the user to decide on it.
+            Action::CancelRequest => {
+                let key = (
+                    event.sender.clone(),
+                    event.content.requesting_device_id.clone(),
+                    event.content.request_id.clone(),
+                );
+                self.deferred_key_requests.remove(&key);
+                self.accepted_key_requests.remove(&key);
+
+                return Ok(None);
+            }
             action => {
                 warn!("Unknown room key request action: {:?}", action);
                 return Ok(None);
@@ -348,42 +616,79 @@ impl KeyRequestMachine {
             .await?;
 
         if let Some(device) = device {
-            if let Err(e) = self.should_share_session(
-                &device,
-                self.outbound_group_sessions
-                    .get(&key_info.room_id)
-                    .as_deref(),
-            ) {
-                info!(
-                    "Received a key request from {} {} that we won't serve: {}",
-                    device.user_id(),
-                    device.device_id(),
-                    e
-                );
+            let key = (
+                event.sender.clone(),
+                event.content.requesting_device_id.clone(),
+                event.content.request_id.clone(),
+            );
 
-                Ok(None)
+            let action = if self.accepted_key_requests.contains(&key) {
+                KeyShareAction::Share
             } else {
-                info!(
-                    "Serving a key request for {} from {} {}.",
-                    key_info.session_id,
-                    device.user_id(),
-                    device.device_id()
+                let default = self.should_share_session(
+                    &device,
+                    self.outbound_group_sessions
+                        .get(&key_info.room_id)
+                        .as_deref(),
                 );
 
-                match self.share_session(&session, &device).await {
-                    Ok(s) => Ok(Some(s)),
-                    Err(OlmError::MissingSession) => {
-                        info!(
-                            "Key request from {} {} is missing an Olm session, \
-                             putting the request in the wait queue",
-                            device.user_id(),
-                            device.device_id()
-                        );
-                        self.handle_key_share_without_session(device, event);
-
-                        Ok(None)
+                let request = IncomingKeyRequest {
+                    user_id: key.0.clone(),
+                    device_id: key.1.clone(),
+                    request_id: key.2.clone(),
+                    info: key_info.clone(),
+                };
+
+                let policy = self.key_share_policy.read().unwrap().clone();
+                policy.decide(&request, &device, &session, default)
+            };
+
+            match action {
+                KeyShareAction::Refuse => {
+                    info!(
+                        "Received a key request from {} {} that we won't serve",
+                        device.user_id(),
+                        device.device_id(),
+                    );
+
+                    Ok(None)
+                }
+                KeyShareAction::Defer => {
+                    info!(
+                        "Received a key request from {} {}, deferring the decision to the user",
+                        device.user_id(),
+                        device.device_id(),
+                    );
+                    self.defer_key_request(key, event.clone());
+
+                    Ok(None)
+                }
+                KeyShareAction::Share => {
+                    info!(
+                        "Serving a key request for {} from {} {}.",
+                        key_info.session_id,
+                        device.user_id(),
+                        device.device_id()
+                    );
+
+                    match self.share_session(&session, &device).await {
+                        Ok(s) => {
+                            self.accepted_key_requests.remove(&key);
+                            Ok(Some(s))
+                        }
+                        Err(OlmError::MissingSession) => {
+                            info!(
+                                "Key request from {} {} is missing an Olm session, \
+                                 putting the request in the wait queue",
+                                device.user_id(),
+                                device.device_id()
+                            );
+                            self.handle_key_share_without_session(device, event);
+
+                            Ok(None)
+                        }
+                        Err(e) => Err(e),
                     }
-                    Err(e) => Err(e),
                 }
             }
         } else {
@@ -492,12 +797,7 @@ impl KeyRequestMachine {
         sender_key: &str,
         session_id: &str,
     ) -> Result<(), CryptoStoreError> {
-        let key_info = RequestedKeyInfo {
-            algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
-            room_id: room_id.to_owned(),
-            sender_key: sender_key.to_owned(),
-            session_id: session_id.to_owned(),
-        };
+        let key_info = requested_key_info(room_id, sender_key, session_id);
 
         let id: Option<String> = self.store.get_object(&key_info.encode()).await?;
 
@@ -506,6 +806,19 @@ impl KeyRequestMachine {
             return Ok(());
         }
 
+        self.create_outgoing_key_request_helper(key_info, SystemTime::now())
+            .await
+    }
+
+    /// Queue up a new key request for the given key info.
+    ///
+    /// The `first_request_time` is the time the key was first requested, it
+    /// stays the same if a request is re-sent.
+    async fn create_outgoing_key_request_helper(
+        &self,
+        key_info: RequestedKeyInfo,
+        first_request_time: SystemTime,
+    ) -> Result<(), CryptoStoreError> {
         info!("Creating new outgoing room key request {:#?}", key_info);
 
         let id = Uuid::new_v4();
@@ -523,6 +836,8 @@ impl KeyRequestMachine {
             request_id: id,
             info: content.body.unwrap(),
             sent_out: false,
+            creation_time: SystemTime::now(),
+            first_request_time,
         };
 
         self.save_outgoing_key_info(id, info).await?;
@@ -531,6 +846,240 @@ impl KeyRequestMachine {
         Ok(())
     }
 
+    /// Create a new outgoing key request for the key with the given session id,
+    /// even if we already requested it before.
+    ///
+    /// If the previous request was already sent out it gets canceled and a
+    /// new one is queued up, otherwise the previous request is left as it is
+    /// since it didn't reach any device yet.
+    ///
+    /// # Arguments
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `sender_key` - The curve25519 key of the sender that owns the key.
+    ///
+    /// * `session_id` - The id that uniquely identifies the session.
+    pub async fn resend_outgoing_key_request(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<(), CryptoStoreError> {
+        let key_info = requested_key_info(room_id, sender_key, session_id);
+
+        let id: Option<Uuid> = self.store.get_object(&key_info.encode()).await?;
+        let info: Option<OugoingKeyInfo> = if let Some(id) = id {
+            self.store.get_object(&id.to_string()).await?
+        } else {
+            None
+        };
+
+        if let Some(info) = info {
+            if info.sent_out {
+                self.resend_request(info).await
+            } else {
+                Ok(())
+            }
+        } else {
+            self.create_outgoing_key_request_helper(key_info, SystemTime::now())
+                .await
+        }
+    }
+
+    /// Cancel the given request and queue up a new one for the same key.
+    async fn resend_request(&self, info: OugoingKeyInfo) -> Result<(), CryptoStoreError> {
+        info!("Re-sending outgoing room key request {:#?}", info.info);
+
+        let key_info = info.info.clone();
+        let first_request_time = info.first_request_time;
+
+        self.cancel_request(info).await?;
+        self.create_outgoing_key_request_helper(key_info, first_request_time)
+            .await
+    }
+
+    /// Mark the room key with the given session id as received.
+    ///
+    /// If we requested the key, the request is canceled so our other devices
+    /// stop trying to answer it.
+    pub async fn mark_key_as_received(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<(), CryptoStoreError> {
+        let key_info = requested_key_info(room_id, sender_key, session_id);
+        let id: Option<Uuid> = self.store.get_object(&key_info.encode()).await?;
+
+        if let Some(id) = id {
+            let info: Option<OugoingKeyInfo> = self.store.get_object(&id.to_string()).await?;
+
+            if let Some(info) = info {
+                self.mark_as_done(info).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the room key requests for the given room that weren't answered yet.
+    pub async fn outgoing_key_requests(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<OutgoingKeyRequest>, CryptoStoreError> {
+        Ok(self
+            .load_outgoing_key_infos()
+            .await?
+            .into_iter()
+            .filter(|i| i.info.room_id == *room_id)
+            .map(|i| OutgoingKeyRequest {
+                request_id: i.request_id,
+                info: i.info,
+                sent_out: i.sent_out,
+                first_request_time: i.first_request_time,
+            })
+            .collect())
+    }
+
+    /// Expire, re-send and re-queue our outgoing key requests.
+    ///
+    /// Requests that didn't get answered for `KEY_REQUEST_EXPIRATION` are
+    /// canceled and forgotten, the same goes for incoming requests that were
+    /// deferred to the user that long ago. Requests that were sent out more than
+    /// `KEY_REQUEST_RESEND_INTERVAL` ago are canceled and sent out again.
+    /// Requests that weren't sent out yet but aren't queued up, e.g. because
+    /// the client restarted, are queued up again.
+    ///
+    /// This is called on every sync, the requests are only checked once every
+    /// `GARBAGE_COLLECTION_INTERVAL`.
+    pub async fn garbage_collect(&self) -> Result<(), CryptoStoreError> {
+        {
+            let mut last_garbage_collection = self.last_garbage_collection.lock().unwrap();
+
+            if last_garbage_collection
+                .map_or(false, |t| t.elapsed() < Self::GARBAGE_COLLECTION_INTERVAL)
+            {
+                return Ok(());
+            }
+
+            *last_garbage_collection = Some(Instant::now());
+        }
+
+        self.garbage_collect_helper().await
+    }
+
+    async fn garbage_collect_helper(&self) -> Result<(), CryptoStoreError> {
+        let now = SystemTime::now();
+        let age = |t: SystemTime| now.duration_since(t).unwrap_or_default();
+
+        self.deferred_key_requests.retain(|_, r| !r.is_expired());
+
+        for info in self.load_outgoing_key_infos().await? {
+            if age(info.first_request_time) > Self::KEY_REQUEST_EXPIRATION {
+                info!("Outgoing room key request expired {:#?}", info.info);
+                self.cancel_request(info).await?;
+            } else if info.sent_out {
+                if age(info.creation_time) > Self::KEY_REQUEST_RESEND_INTERVAL {
+                    self.resend_request(info).await?;
+                }
+            } else if !self
+                .outgoing_to_device_requests
+                .contains_key(&info.request_id)
+            {
+                let content = RoomKeyRequestToDeviceEventContent {
+                    action: Action::Request,
+                    request_id: info.request_id.to_string(),
+                    requesting_device_id: (&*self.device_id).clone(),
+                    body: Some(info.info),
+                };
+
+                let request =
+                    wrap_key_request_content(self.user_id().clone(), info.request_id, &content)?;
+
+                self.outgoing_to_device_requests
+                    .insert(info.request_id, request);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Update the stored index of our outgoing key requests.
+    ///
+    /// The index is only written back if `update` returns true.
+    async fn update_outgoing_key_request_ids(
+        &self,
+        update: impl FnOnce(&mut BTreeSet<Uuid>) -> bool,
+    ) -> Result<(), CryptoStoreError> {
+        let mut guard = self.outgoing_key_request_ids.lock().await;
+
+        if guard.is_none() {
+            *guard = Some(self.load_outgoing_key_request_ids().await?);
+        }
+
+        let ids = guard.as_mut().expect("The request ids were just loaded");
+
+        if update(ids) {
+            self.store.save_object(OUTGOING_KEY_REQUESTS, &*ids).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the ids of all our outgoing key requests.
+    async fn outgoing_key_request_ids(&self) -> Result<BTreeSet<Uuid>, CryptoStoreError> {
+        let mut ids = BTreeSet::new();
+        self.update_outgoing_key_request_ids(|i| {
+            ids = i.clone();
+            false
+        })
+        .await?;
+
+        Ok(ids)
+    }
+
+    /// Load the index of our outgoing key requests from the store.
+    ///
+    /// Requests that were stored before the index existed are found by
+    /// looking at all the stored values, the index is stored afterwards so
+    /// this only happens once.
+    async fn load_outgoing_key_request_ids(&self) -> Result<BTreeSet<Uuid>, CryptoStoreError> {
+        if let Some(ids) = self.store.get_object(OUTGOING_KEY_REQUESTS).await? {
+            return Ok(ids);
+        }
+
+        let ids: BTreeSet<Uuid> = self
+            .store
+            .get_values()
+            .await?
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let id = Uuid::parse_str(&key).ok()?;
+                serde_json::from_str::<OugoingKeyInfo>(&value).ok()?;
+                Some(id)
+            })
+            .collect();
+
+        self.store.save_object(OUTGOING_KEY_REQUESTS, &ids).await?;
+
+        Ok(ids)
+    }
+
+    /// Load all the outgoing key infos we have in the store.
+    async fn load_outgoing_key_infos(&self) -> Result<Vec<OugoingKeyInfo>, CryptoStoreError> {
+        let ids = self.outgoing_key_request_ids().await?;
+
+        let mut infos = Vec::new();
+
+        for id in ids {
+            if let Some(info) = self.store.get_object(&id.to_string()).await? {
+                infos.push(info);
+            }
+        }
+
+        Ok(infos)
+    }
+
     /// Save an outgoing key info.
     async fn save_outgoing_key_info(
         &self,
@@ -545,7 +1094,8 @@ impl KeyRequestMachine {
         self.store.save_object(&id_string, &info).await?;
         self.store.save_object(&info.info.encode(), &id).await?;
 
-        Ok(())
+        self.update_outgoing_key_request_ids(|ids| ids.insert(id))
+            .await
     }
 
     /// Get an outgoing key info that matches the forwarded room key content.
@@ -569,7 +1119,8 @@ impl KeyRequestMachine {
             .await?;
         self.store.delete_object(&info.info.encode()).await?;
 
-        Ok(())
+        self.update_outgoing_key_request_ids(|ids| ids.remove(&info.request_id))
+            .await
     }
 
     /// Mark the outgoing request as sent.
@@ -596,6 +1147,11 @@ impl KeyRequestMachine {
             key_info
         );
 
+        self.cancel_request(key_info).await
+    }
+
+    /// Forget the given outgoing key info and queue up a request cancelation.
+    async fn cancel_request(&self, key_info: OugoingKeyInfo) -> Result<(), CryptoStoreError> {
         self.outgoing_to_device_requests
             .remove(&key_info.request_id);
         // TODO return the key info instead of deleting it so the sync handler
@@ -674,27 +1230,34 @@ impl KeyRequestMachine {
 #[cfg(test)]
 mod test {
     use dashmap::DashMap;
+    use futures::future::join_all;
     use matrix_sdk_common::{
         api::r0::to_device::DeviceIdOrAllDevices,
         events::{
             forwarded_room_key::ForwardedRoomKeyToDeviceEventContent,
             room::encrypted::EncryptedEventContent,
-            room_key_request::RoomKeyRequestToDeviceEventContent, AnyToDeviceEvent, ToDeviceEvent,
+            room_key_request::{Action, RoomKeyRequestToDeviceEventContent},
+            AnyToDeviceEvent, ToDeviceEvent,
         },
         identifiers::{room_id, user_id, DeviceIdBox, RoomId, UserId},
         locks::Mutex,
+        uuid::Uuid,
     };
     use matrix_sdk_test::async_test;
-    use std::{convert::TryInto, sync::Arc};
+    use std::{collections::BTreeSet, convert::TryInto, sync::Arc, time::Duration};
 
     use crate::{
         identities::{LocalTrust, ReadOnlyDevice},
-        olm::{Account, PrivateCrossSigningIdentity, ReadOnlyAccount},
+        olm::{Account, InboundGroupSession, PrivateCrossSigningIdentity, ReadOnlyAccount},
         store::{CryptoStore, MemoryStore, Store},
         verification::VerificationMachine,
+        Device,
     };
 
-    use super::{KeyRequestMachine, KeyshareDecision};
+    use super::{
+        requested_key_info, IncomingKeyRequest, KeyRequestMachine, KeyShareAction, KeySharePolicy,
+        KeyshareDecision, OugoingKeyInfo, OUTGOING_KEY_REQUESTS,
+    };
 
     fn alice_id() -> UserId {
         user_id!("@alice:example.org")
@@ -808,6 +1371,181 @@ mod test {
         assert!(machine.outgoing_to_device_requests.is_empty());
     }
 
+    #[async_test]
+    async fn key_request_cancellation_and_expiry() {
+        let machine = get_machine().await;
+        let account = account();
+
+        let (_, session) = account
+            .create_group_session_pair_with_defaults(&room_id())
+            .await
+            .unwrap();
+
+        let machine = &machine;
+        let session = &session;
+
+        let create_request = || async move {
+            machine
+                .create_outgoing_key_request(
+                    session.room_id(),
+                    &session.sender_key,
+                    session.session_id(),
+                )
+                .await
+                .unwrap();
+            let id = machine
+                .outgoing_to_device_requests
+                .iter()
+                .next()
+                .unwrap()
+                .request_id;
+            machine.mark_outgoing_request_as_sent(&id).await.unwrap();
+            id
+        };
+
+        let id = create_request().await;
+
+        let requests = machine.outgoing_key_requests(&room_id()).await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].request_id, id);
+        assert!(requests[0].sent_out);
+        assert!(machine
+            .outgoing_key_requests(&room_id!("!other:example.org"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Receiving the key cancels the request.
+        machine
+            .mark_key_as_received(session.room_id(), &session.sender_key, session.session_id())
+            .await
+            .unwrap();
+        assert!(machine
+            .outgoing_key_requests(&room_id())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(machine.outgoing_to_device_requests.len(), 1);
+        machine.outgoing_to_device_requests.clear();
+
+        // Stale requests get sent out again.
+        let id = create_request().await;
+        let mut info: OugoingKeyInfo = machine
+            .store
+            .get_object(&id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let first_request_time = info.first_request_time;
+        info.creation_time -= Duration::from_secs(60 * 60 * 2);
+        machine
+            .store
+            .save_object(&id.to_string(), &info)
+            .await
+            .unwrap();
+
+        machine.garbage_collect_helper().await.unwrap();
+        // A cancellation and a new request are queued up.
+        assert_eq!(machine.outgoing_to_device_requests.len(), 2);
+
+        let requests = machine.outgoing_key_requests(&room_id()).await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_ne!(requests[0].request_id, id);
+        assert!(!requests[0].sent_out);
+        assert_eq!(requests[0].first_request_time, first_request_time);
+
+        // Requests that weren't sent out are queued up again, e.g. after a
+        // restart.
+        machine.outgoing_to_device_requests.clear();
+        machine.garbage_collect_helper().await.unwrap();
+        assert_eq!(machine.outgoing_to_device_requests.len(), 1);
+        assert!(machine
+            .outgoing_to_device_requests
+            .contains_key(&requests[0].request_id));
+
+        // Old requests expire.
+        let id = requests[0].request_id;
+        let mut info: OugoingKeyInfo = machine
+            .store
+            .get_object(&id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        info.first_request_time -= Duration::from_secs(60 * 60 * 24 * 8);
+        machine
+            .store
+            .save_object(&id.to_string(), &info)
+            .await
+            .unwrap();
+
+        machine.garbage_collect_helper().await.unwrap();
+        assert!(machine
+            .outgoing_key_requests(&room_id())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[async_test]
+    async fn outgoing_key_request_index() {
+        let machine = get_machine().await;
+        let account = account();
+
+        let mut sessions = Vec::new();
+
+        for _ in 0..5 {
+            let (_, session) = account
+                .create_group_session_pair_with_defaults(&room_id())
+                .await
+                .unwrap();
+            sessions.push(session);
+        }
+
+        join_all(sessions.iter().map(|s| {
+            machine.create_outgoing_key_request(s.room_id(), &s.sender_key, s.session_id())
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(
+            machine
+                .outgoing_key_requests(&room_id())
+                .await
+                .unwrap()
+                .len(),
+            5
+        );
+
+        // Requests that were stored before the index existed are found again.
+        let store = machine.store.clone();
+        store.delete_object(OUTGOING_KEY_REQUESTS).await.unwrap();
+
+        let machine = KeyRequestMachine::new(
+            Arc::new(alice_id()),
+            Arc::new(alice_device_id()),
+            store,
+            Arc::new(DashMap::new()),
+            Arc::new(DashMap::new()),
+        );
+
+        assert_eq!(
+            machine
+                .outgoing_key_requests(&room_id())
+                .await
+                .unwrap()
+                .len(),
+            5
+        );
+        assert!(machine
+            .store
+            .get_object::<BTreeSet<Uuid>>(OUTGOING_KEY_REQUESTS)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
     #[async_test]
     async fn receive_forwarded_key() {
         let machine = get_machine().await;
@@ -1174,6 +1912,182 @@ mod test {
         assert_eq!(session.session_id(), group_session.session_id())
     }
 
+    #[async_test]
+    async fn key_share_policy() {
+        #[derive(Debug)]
+        struct AskForAlice;
+
+        impl KeySharePolicy for AskForAlice {
+            fn decide(
+                &self,
+                request: &IncomingKeyRequest,
+                _: &Device,
+                _: &InboundGroupSession,
+                default: Result<(), KeyshareDecision>,
+            ) -> KeyShareAction {
+                assert_eq!(default, Err(KeyshareDecision::MissingOutboundSession));
+
+                if request.user_id == alice_id() {
+                    KeyShareAction::Defer
+                } else {
+                    KeyShareAction::Refuse
+                }
+            }
+        }
+
+        let alice_machine = get_machine().await;
+        let alice_account = Account {
+            inner: account(),
+            store: alice_machine.store.clone(),
+        };
+
+        let bob_machine = bob_machine();
+        let bob_account = bob_account();
+        bob_machine.set_key_share_policy(Arc::new(AskForAlice));
+
+        let (alice_session, bob_session) = alice_account.create_session_for(&bob_account).await;
+        let alice_device = ReadOnlyDevice::from_account(&alice_account).await;
+
+        alice_machine
+            .store
+            .save_sessions(&[alice_session])
+            .await
+            .unwrap();
+        bob_machine
+            .store
+            .save_sessions(&[bob_session])
+            .await
+            .unwrap();
+        bob_machine
+            .store
+            .save_devices(&[alice_device])
+            .await
+            .unwrap();
+
+        // Bob doesn't have the outbound session anymore, the built-in policy
+        // would refuse the request.
+        let (group_session, inbound_group_session) = bob_account
+            .create_group_session_pair_with_defaults(&room_id())
+            .await
+            .unwrap();
+
+        bob_machine
+            .store
+            .save_inbound_group_sessions(&[inbound_group_session])
+            .await
+            .unwrap();
+
+        alice_machine
+            .create_outgoing_key_request(
+                &room_id(),
+                bob_account.identity_keys.curve25519(),
+                group_session.session_id(),
+            )
+            .await
+            .unwrap();
+
+        let request = alice_machine
+            .outgoing_to_device_requests
+            .iter()
+            .next()
+            .unwrap();
+        let content = request
+            .request
+            .to_device()
+            .unwrap()
+            .messages
+            .get(&alice_id())
+            .unwrap()
+            .get(&DeviceIdOrAllDevices::AllDevices)
+            .unwrap();
+        let content: RoomKeyRequestToDeviceEventContent =
+            serde_json::from_str(content.get()).unwrap();
+        drop(request);
+
+        let event = ToDeviceEvent {
+            sender: alice_id(),
+            content,
+        };
+
+        bob_machine.receive_incoming_key_request(&event);
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+
+        // The decision is deferred to the user.
+        assert!(bob_machine.outgoing_to_device_requests.is_empty());
+        let deferred = bob_machine.deferred_key_requests();
+        assert_eq!(deferred.len(), 1);
+        assert_eq!(deferred[0].user_id, alice_id());
+        assert_eq!(deferred[0].request_id, event.content.request_id);
+        assert_eq!(deferred[0].info.session_id, group_session.session_id());
+
+        // Accepting the request serves it.
+        assert!(bob_machine.accept_key_request(
+            &deferred[0].user_id,
+            &deferred[0].device_id,
+            &deferred[0].request_id
+        ));
+        assert!(bob_machine.deferred_key_requests().is_empty());
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+        assert!(!bob_machine.outgoing_to_device_requests.is_empty());
+
+        // Unknown requests can't be answered.
+        assert!(!bob_machine.refuse_key_request(
+            &deferred[0].user_id,
+            &deferred[0].device_id,
+            &deferred[0].request_id
+        ));
+    }
+
+    #[test]
+    fn deferred_key_requests_are_bounded() {
+        let machine = bob_machine();
+        let max = KeyRequestMachine::MAX_DEFERRED_KEY_REQUESTS_PER_DEVICE;
+
+        let defer = |request_id: String| {
+            let event = ToDeviceEvent {
+                sender: alice_id(),
+                content: RoomKeyRequestToDeviceEventContent {
+                    action: Action::Request,
+                    body: Some(requested_key_info(&room_id(), "sender_key", "session_id")),
+                    request_id: request_id.clone(),
+                    requesting_device_id: alice_device_id(),
+                },
+            };
+
+            machine.defer_key_request((alice_id(), alice_device_id(), request_id), event);
+        };
+
+        for i in 0..max + 10 {
+            defer(i.to_string());
+        }
+
+        // A single device can't grow the deferred requests without a limit.
+        assert_eq!(machine.deferred_key_requests().len(), max);
+
+        // Requests from a different device aren't affected by the limit.
+        let event = ToDeviceEvent {
+            sender: alice_id(),
+            content: RoomKeyRequestToDeviceEventContent {
+                action: Action::Request,
+                body: Some(requested_key_info(&room_id(), "sender_key", "session_id")),
+                request_id: "other".to_owned(),
+                requesting_device_id: "OTHERDEVICE".into(),
+            },
+        };
+        let key = (alice_id(), "OTHERDEVICE".into(), "other".to_owned());
+        machine.defer_key_request(key.clone(), event);
+        assert_eq!(machine.deferred_key_requests().len(), max + 1);
+
+        // Deferred requests expire like our outgoing requests do.
+        machine
+            .deferred_key_requests
+            .get_mut(&key)
+            .unwrap()
+            .deferred_at -= KeyRequestMachine::KEY_REQUEST_EXPIRATION + Duration::from_secs(1);
+        assert_eq!(machine.deferred_key_requests().len(), max);
+        assert!(!machine.accept_key_request(&key.0, &key.1, &key.2));
+    }
+
     #[async_test]
     async fn key_share_cycle_without_session() {
         let alice_machine = get_machine().await;
@@ -27,6 +27,9 @@
 )]
 #![cfg_attr(feature = "docs", feature(doc_cfg))]
 
+mod backups;
+mod decryption;
+mod dehydrated_devices;
 mod error;
 mod file_encryption;
 mod identities;
@@ -34,23 +37,47 @@ mod key_request;
 mod machine;
 pub mod olm;
 mod requests;
+mod secret_sharing;
+mod secret_storage;
 mod session_manager;
 pub mod store;
 mod utilities;
 mod verification;
+mod withheld;
 
-pub use error::{MegolmError, OlmError};
+pub use backups::{BackupError, MegolmV1BackupKey, RecoveryKey};
+pub use decryption::{DecryptedEvent, EncryptionInfo, VerificationState};
+pub use dehydrated_devices::{
+    DehydratedDeviceData, DehydratedDeviceEvents, DehydratedDeviceRequest,
+    DehydratedDeviceResponse, DehydrationError, RehydratedDevice, DEHYDRATION_ALGORITHM,
+};
+pub use error::{IdentityVerificationError, MegolmError, OlmError};
 pub use file_encryption::{
     decrypt_key_export, encrypt_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError,
+    DecryptorError, KeyExportError,
 };
 pub use identities::{
-    Device, LocalTrust, OwnUserIdentity, ReadOnlyDevice, UserDevices, UserIdentities, UserIdentity,
+    Device, IdentityChange, LocalTrust, OwnUserIdentity, ReadOnlyDevice, TrustLevel, UserDevices,
+    UserIdentities, UserIdentity, VerifiableUserIdentity,
+};
+pub use key_request::{
+    IncomingKeyRequest, KeyShareAction, KeySharePolicy, KeyshareDecision, OutgoingKeyRequest,
 };
 pub use machine::OlmMachine;
 pub use olm::EncryptionSettings;
 pub(crate) use olm::ReadOnlyAccount;
 pub use requests::{
-    IncomingResponse, KeysQueryRequest, OutgoingRequest, OutgoingRequests, ToDeviceRequest,
+    IncomingResponse, KeysBackupRequest, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
+    OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest,
+};
+pub use secret_storage::{
+    AesHmacSha2EncryptedData, DefaultKeyContent, EncryptedSecretContent, PassphraseInfo,
+    SecretName, SecretStorageError, SecretStorageKey, SecretStorageKeyDescription,
+    DEFAULT_KEY_EVENT_TYPE, SECRET_STORAGE_V1,
+};
+pub use session_manager::ShareStrategy;
+pub use verification::{
+    QrDecodingError, QrVerification, QrVerificationData, QrVerificationMode, Sas,
+    VerificationRequest,
 };
-pub use verification::Sas;
+pub use withheld::WithheldCode;
@@ -12,15 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "sqlite_cryptostore")]
+#[cfg(any(feature = "sqlite_cryptostore", feature = "sled_cryptostore"))]
 use std::path::Path;
 use std::{collections::BTreeMap, mem, sync::Arc};
 
 use dashmap::DashMap;
+use serde_json::{value::RawValue as RawJsonValue, Value};
 use tracing::{debug, error, info, trace, warn};
+use zeroize::Zeroizing;
 
 use matrix_sdk_common::{
     api::r0::{
+        backup::{BackupAlgorithm, RoomKeyBackup},
         keys::{
             claim_keys::{Request as KeysClaimRequest, Response as KeysClaimResponse},
             get_keys::Response as KeysQueryResponse,
@@ -31,8 +34,9 @@ use matrix_sdk_common::{
     },
     assign,
     events::{
-        room::encrypted::EncryptedEventContent, room_key::RoomKeyEventContent,
-        AnyMessageEventContent, AnySyncRoomEvent, AnyToDeviceEvent, SyncMessageEvent,
+        room::{encrypted::EncryptedEventContent, message::MessageEventContent},
+        room_key::RoomKeyEventContent,
+        AnyMessageEventContent, AnySyncMessageEvent, AnyToDeviceEvent, SyncMessageEvent,
         ToDeviceEvent,
     },
     identifiers::{
@@ -43,24 +47,46 @@ use matrix_sdk_common::{
     Raw, UInt,
 };
 
+#[cfg(feature = "sled_cryptostore")]
+use crate::store::sled::SledStore;
 #[cfg(feature = "sqlite_cryptostore")]
 use crate::store::sqlite::SqliteStore;
 use crate::{
+    backups::{BackupMachine, MegolmV1BackupKey, RecoveryKey},
+    decryption::{DecryptedEvent, EncryptionInfo, VerificationState},
+    dehydrated_devices::{
+        create_dehydrated_device, DehydratedDeviceData, DehydratedDeviceRequest, DehydrationError,
+        RehydratedDevice,
+    },
     error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult},
-    identities::{Device, IdentityManager, UserDevices},
-    key_request::KeyRequestMachine,
+    identities::{
+        Device, IdentityChange, IdentityManager, UserDevices, UserIdentities,
+        VerifiableUserIdentity,
+    },
+    key_request::{IncomingKeyRequest, KeyRequestMachine, KeySharePolicy, OutgoingKeyRequest},
     olm::{
         Account, EncryptionSettings, ExportedRoomKey, GroupSessionKey, IdentityKeys,
-        InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity, ReadOnlyAccount,
-        SessionType,
+        InboundGroupSession, MegolmMessageIndex, OlmDecryptionInfo, PrivateCrossSigningIdentity,
+        ReadOnlyAccount, SessionType,
+    },
+    requests::{
+        IncomingResponse, OutgoingRequest, OutgoingVerificationRequest, UploadSigningKeysRequest,
     },
-    requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
-    session_manager::{GroupSessionManager, SessionManager},
+    secret_sharing::{
+        raw_event_type, SecretSharingMachine, SECRET_REQUEST_EVENT_TYPE, SECRET_SEND_EVENT_TYPE,
+    },
+    secret_storage::{EncryptedSecretContent, SecretName, SecretStorageError, SecretStorageKey},
+    session_manager::{GroupSessionManager, SessionManager, ShareStrategy},
     store::{
-        Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore, Result as StoreResult,
-        Store,
+        export_store, Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore,
+        Result as StoreResult, Store, StoreExportError,
+    },
+    verification::{
+        QrVerification, QrVerificationData, Sas, VerificationMachine, VerificationRequest,
+    },
+    withheld::{
+        parse_withheld_event, withheld_key, RoomKeyWithheldContent, ROOM_KEY_WITHHELD_EVENT_TYPE,
     },
-    verification::{Sas, VerificationMachine},
     ToDeviceRequest,
 };
 
@@ -93,9 +119,15 @@ pub struct OlmMachine {
     /// The state machine that is responsible to handle outgoing and incoming
     /// key requests.
     key_request_machine: KeyRequestMachine,
+    /// State machine handling secret requests and secrets that were sent to
+    /// us.
+    secret_sharing_machine: SecretSharingMachine,
     /// State machine handling public user identities and devices, keeping track
     /// of when a key query needs to be done and handling one.
     identity_manager: IdentityManager,
+    /// State machine that uploads our room keys to the server-side key
+    /// backup.
+    backup_machine: BackupMachine,
     cross_signing_request: Arc<Mutex<Option<UploadSignaturesRequest>>>,
 }
 
@@ -134,7 +166,7 @@ impl OlmMachine {
         )
     }
 
-    fn new_helper(
+    pub(crate) fn new_helper(
         user_id: &UserId,
         device_id: DeviceIdBox,
         store: Box<dyn CryptoStore>,
@@ -165,6 +197,15 @@ impl OlmMachine {
             users_for_key_claim.clone(),
         );
 
+        let secret_sharing_machine = SecretSharingMachine::new(
+            user_id.clone(),
+            device_id.clone(),
+            store.clone(),
+            user_identity.clone(),
+        );
+
+        let backup_machine = BackupMachine::new(account.clone(), store.clone());
+
         let account = Account {
             inner: account,
             store: store.clone(),
@@ -194,7 +235,9 @@ impl OlmMachine {
             group_session_manager,
             verification_machine,
             key_request_machine,
+            secret_sharing_machine,
             identity_manager,
+            backup_machine,
             cross_signing_request: Arc::new(Mutex::new(None)),
         }
     }
@@ -247,9 +290,14 @@ impl OlmMachine {
             }
         };
 
-        Ok(OlmMachine::new_helper(
-            &user_id, device_id, store, account, identity,
-        ))
+        let machine = OlmMachine::new_helper(&user_id, device_id, store, account, identity);
+        machine.backup_machine.load_backup_key().await?;
+        machine
+            .group_session_manager
+            .load_outbound_group_sessions()
+            .await?;
+
+        Ok(machine)
     }
 
     /// Create a new machine with the default crypto store.
@@ -275,6 +323,32 @@ impl OlmMachine {
         OlmMachine::new_with_store(user_id.to_owned(), device_id.into(), Box::new(store)).await
     }
 
+    /// Create a new machine that uses a sled database to store the encryption
+    /// keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that owns this machine.
+    ///
+    /// * `device_id` - The unique id of the device that owns this machine.
+    ///
+    /// * `path` - The path where the database should reside in.
+    ///
+    /// * `passphrase` - The passphrase that should be used to securely store
+    /// the encryption keys.
+    #[cfg(feature = "sled_cryptostore")]
+    #[cfg_attr(feature = "docs", doc(cfg(r#sled_cryptostore)))]
+    pub async fn new_with_sled_store(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        path: impl AsRef<Path>,
+        passphrase: &str,
+    ) -> StoreResult<Self> {
+        let store = SledStore::open_with_passphrase(&user_id, device_id, path, passphrase).await?;
+
+        OlmMachine::new_with_store(user_id.to_owned(), device_id.into(), Box::new(store)).await
+    }
+
     /// The unique user id that owns this `OlmMachine` instance.
     pub fn user_id(&self) -> &UserId {
         &self.user_id
@@ -320,7 +394,15 @@ impl OlmMachine {
         }
 
         requests.append(&mut self.outgoing_to_device_requests());
+        requests.append(&mut self.verification_machine.outgoing_room_message_requests());
         requests.append(&mut self.key_request_machine.outgoing_to_device_requests());
+        requests.append(&mut self.secret_sharing_machine.outgoing_to_device_requests());
+
+        match self.backup_machine.backup().await {
+            Ok(Some(r)) => requests.push(r),
+            Ok(None) => (),
+            Err(e) => warn!("Error while creating a room key backup request {:?}", e),
+        }
 
         requests
     }
@@ -358,6 +440,12 @@ impl OlmMachine {
             IncomingResponse::SignatureUpload(_) => {
                 self.verification_machine.mark_request_as_sent(request_id);
             }
+            IncomingResponse::KeysBackup(_) => {
+                self.backup_machine.mark_request_as_sent(request_id).await?;
+            }
+            IncomingResponse::RoomMessage(_) => {
+                self.verification_machine.mark_request_as_sent(request_id);
+            }
         };
 
         Ok(())
@@ -453,6 +541,11 @@ impl OlmMachine {
         self.account.should_upload_keys().await
     }
 
+    /// Get the store of the machine.
+    pub(crate) fn store(&self) -> &Store {
+        &self.store
+    }
+
     /// Get the underlying Olm account of the machine.
     #[cfg(test)]
     pub(crate) fn account(&self) -> &ReadOnlyAccount {
@@ -536,6 +629,22 @@ impl OlmMachine {
             .await
     }
 
+    /// Get the device and user identity changes we learned about since this
+    /// method was last called.
+    ///
+    /// The changes are discovered when keys query responses are passed to
+    /// [`mark_request_as_sent`]. Clients should warn their users if the master
+    /// key of a user they verified changes.
+    ///
+    /// The changes are buffered until this is called, so it needs to be called
+    /// regularly, e.g. after every sync. Only the latest 1000 changes are kept,
+    /// older ones are dropped if nobody collects them.
+    ///
+    /// [`mark_request_as_sent`]: #method.mark_request_as_sent
+    pub fn identity_changes(&self) -> Vec<IdentityChange> {
+        self.identity_manager.collect_changes()
+    }
+
     /// Get a request to upload E2EE keys to the server.
     ///
     /// Returns None if no keys need to be uploaded.
@@ -546,8 +655,8 @@ impl OlmMachine {
     /// [`receive_keys_upload_response`]: #method.receive_keys_upload_response
     /// [`OlmMachine`]: struct.OlmMachine.html
     async fn keys_for_upload(&self) -> Option<upload_keys::Request> {
-        let (device_keys, one_time_keys) = self.account.keys_for_upload().await?;
-        Some(assign!(upload_keys::Request::new(), { device_keys, one_time_keys }))
+        let (device_keys, one_time_keys, fallback_keys) = self.account.keys_for_upload().await?;
+        Some(assign!(upload_keys::Request::new(), { device_keys, one_time_keys, fallback_keys }))
     }
 
     /// Decrypt a to-device event.
@@ -558,11 +667,40 @@ impl OlmMachine {
     /// # Arguments
     ///
     /// * `event` - The to-device event that should be decrypted.
-    async fn decrypt_to_device_event(
+    pub(crate) async fn decrypt_to_device_event(
+        &self,
+        event: &ToDeviceEvent<EncryptedEventContent>,
+    ) -> OlmResult<OlmDecryptionInfo> {
+        self.decrypt_to_device_event_helper(&self.account, event)
+            .await
+    }
+
+    /// Decrypt a to-device event that was sent to a dehydrated device.
+    ///
+    /// The event is decrypted using the account of the rehydrated device, the
+    /// decrypted event is handled as if it was sent to us, e.g. room keys are
+    /// checked and returned the same way.
+    ///
+    /// # Arguments
+    ///
+    /// * `rehydrated` - The machine of the rehydrated device.
+    ///
+    /// * `event` - The to-device event that should be decrypted.
+    pub(crate) async fn decrypt_rehydrated_to_device_event(
+        &self,
+        rehydrated: &OlmMachine,
+        event: &ToDeviceEvent<EncryptedEventContent>,
+    ) -> OlmResult<OlmDecryptionInfo> {
+        self.decrypt_to_device_event_helper(&rehydrated.account, event)
+            .await
+    }
+
+    async fn decrypt_to_device_event_helper(
         &self,
+        account: &Account,
         event: &ToDeviceEvent<EncryptedEventContent>,
     ) -> OlmResult<OlmDecryptionInfo> {
-        let mut decrypted = self.account.decrypt_to_device_event(event).await?;
+        let mut decrypted = account.decrypt_to_device_event(event).await?;
         // Handle the decrypted event, e.g. fetch out Megolm sessions out of
         // the event.
         if let (Some(event), group_session) =
@@ -675,8 +813,109 @@ impl OlmMachine {
     ///
     /// Returns true if a session was invalidated, false if there was no session
     /// to invalidate.
-    pub fn invalidate_group_session(&self, room_id: &RoomId) -> bool {
-        self.group_session_manager.invalidate_group_session(room_id)
+    pub async fn invalidate_group_session(&self, room_id: &RoomId) -> StoreResult<bool> {
+        self.group_session_manager
+            .invalidate_group_session(room_id)
+            .await
+    }
+
+    /// Get the strategy deciding which devices receive our room keys.
+    pub fn share_strategy(&self) -> ShareStrategy {
+        self.group_session_manager.share_strategy()
+    }
+
+    /// Set the strategy deciding which devices receive our room keys.
+    ///
+    /// By default room keys are shared with all devices that aren't
+    /// blacklisted.
+    pub fn set_share_strategy(&self, strategy: ShareStrategy) {
+        self.group_session_manager.set_share_strategy(strategy)
+    }
+
+    /// Set the policy that decides if a room key should be shared with a
+    /// device that requested it.
+    ///
+    /// By default room keys are shared with our own trusted devices and with
+    /// devices of other users that received the room key when it was created.
+    pub fn set_key_share_policy(&self, policy: Arc<dyn KeySharePolicy>) {
+        self.key_request_machine.set_key_share_policy(policy)
+    }
+
+    /// Get the incoming room key requests that the key share policy deferred
+    /// to the user.
+    ///
+    /// The requests can be answered using the [`accept_key_request`] and
+    /// [`refuse_key_request`] methods.
+    ///
+    /// Deferred requests are only kept in memory, they are lost on restart
+    /// and expire after a week. At most 50 requests per device are kept, the
+    /// oldest ones are dropped first.
+    ///
+    /// [`accept_key_request`]: #method.accept_key_request
+    /// [`refuse_key_request`]: #method.refuse_key_request
+    pub fn deferred_key_requests(&self) -> Vec<IncomingKeyRequest> {
+        self.key_request_machine.deferred_key_requests()
+    }
+
+    /// Accept a deferred room key request and share the room key with the
+    /// device that requested it.
+    ///
+    /// The to-device request that shares the room key will be available in
+    /// the [`outgoing_requests`] once this returns. If we don't share an Olm
+    /// session with the device, one will be established first.
+    ///
+    /// Returns false if no such deferred request exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that requested the room key.
+    ///
+    /// * `device_id` - The device that requested the room key.
+    ///
+    /// * `request_id` - The unique id of the request.
+    ///
+    /// [`outgoing_requests`]: #method.outgoing_requests
+    pub async fn accept_key_request(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request_id: &str,
+    ) -> OlmResult<bool> {
+        if !self
+            .key_request_machine
+            .accept_key_request(user_id, device_id, request_id)
+        {
+            return Ok(false);
+        }
+
+        let changed_sessions = self
+            .key_request_machine
+            .collect_incoming_key_requests()
+            .await?;
+        self.store.save_sessions(&changed_sessions).await?;
+
+        Ok(true)
+    }
+
+    /// Refuse a deferred room key request.
+    ///
+    /// Returns false if no such deferred request exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that requested the room key.
+    ///
+    /// * `device_id` - The device that requested the room key.
+    ///
+    /// * `request_id` - The unique id of the request.
+    pub fn refuse_key_request(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request_id: &str,
+    ) -> bool {
+        self.key_request_machine
+            .refuse_key_request(user_id, device_id, request_id)
     }
 
     /// Get to-device requests to share a group session with users in a room.
@@ -687,6 +926,10 @@ impl OlmMachine {
     /// used.
     ///
     /// `users` - The list of users that should receive the group session.
+    ///
+    /// Which devices of the users receive the group session depends on the
+    /// current `ShareStrategy`, returns an `OlmError::UnverifiedDevices` error
+    /// if the strategy forbids sharing the session with some of the devices.
     pub async fn share_group_session(
         &self,
         room_id: &RoomId,
@@ -711,6 +954,10 @@ impl OlmMachine {
         &self,
         decrypted: &OlmDecryptionInfo,
     ) -> OlmResult<(Option<Raw<AnyToDeviceEvent>>, Option<InboundGroupSession>)> {
+        if raw_event_type(decrypted.event.json()).as_deref() == Some(SECRET_SEND_EVENT_TYPE) {
+            return self.receive_secret(decrypted).await;
+        }
+
         let event = match decrypted.event.deserialize() {
             Ok(e) => e,
             Err(e) => {
@@ -737,6 +984,33 @@ impl OlmMachine {
         }
     }
 
+    /// Receive a secret that one of our other devices sent to us.
+    ///
+    /// The secret is removed from the event before it's returned to the user.
+    /// If the secret was accepted, the name of the secret is added to the
+    /// content of the event as the `name` field.
+    async fn receive_secret(
+        &self,
+        decrypted: &OlmDecryptionInfo,
+    ) -> OlmResult<(Option<Raw<AnyToDeviceEvent>>, Option<InboundGroupSession>)> {
+        let secret_name = self
+            .secret_sharing_machine
+            .receive_secret(&decrypted.sender_key, decrypted.event.json())
+            .await?;
+
+        let mut event: Value = serde_json::from_str(decrypted.event.json().get())?;
+
+        if let Some(content) = event.get_mut("content").and_then(|c| c.as_object_mut()) {
+            content.insert("secret".to_owned(), Value::String(String::new()));
+
+            if let Some(secret_name) = secret_name {
+                content.insert("name".to_owned(), Value::String(secret_name.to_string()));
+            }
+        }
+
+        Ok((Some(serde_json::from_value(event)?), None))
+    }
+
     async fn handle_verification_event(&self, mut event: &mut AnyToDeviceEvent) {
         if let Err(e) = self.verification_machine.receive_event(&mut event).await {
             error!("Error handling a verification event: {:?}", e);
@@ -754,7 +1028,12 @@ impl OlmMachine {
         self.key_request_machine
             .mark_outgoing_request_as_sent(request_id)
             .await?;
-        self.group_session_manager.mark_request_as_sent(request_id);
+        self.secret_sharing_machine
+            .mark_outgoing_request_as_sent(request_id)
+            .await?;
+        self.group_session_manager
+            .mark_request_as_sent(request_id)
+            .await?;
         self.session_manager
             .mark_outgoing_request_as_sent(request_id);
 
@@ -766,6 +1045,118 @@ impl OlmMachine {
         self.verification_machine.get_sas(flow_id)
     }
 
+    /// Get the content of a `m.key.verification.request` room message.
+    ///
+    /// Sending out the content in a room, usually a direct message room with
+    /// the given user, will start an in-room verification with the user. Once
+    /// the other side accepts the request a verification can be started using
+    /// the event id of the request as the flow id.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should be verified.
+    pub fn verification_request_content(&self, user_id: &UserId) -> MessageEventContent {
+        self.verification_machine
+            .verification_request_content(user_id)
+    }
+
+    /// Request an interactive verification with the given user.
+    ///
+    /// The request will be sent to all the devices of the user. Once one of
+    /// them accepts the request a verification with that device can be
+    /// started using the flow id of the request. If the device only supports
+    /// `Sas` verification, it will be started automatically.
+    ///
+    /// Returns the new `VerificationRequest` and a to-device request that
+    /// needs to be sent out.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should be verified.
+    pub fn request_verification(&self, user_id: &UserId) -> (VerificationRequest, ToDeviceRequest) {
+        self.verification_machine.request_verification(user_id)
+    }
+
+    /// Get a verification request object with the given flow id.
+    ///
+    /// The flow id is the transaction id of to-device requests or the event id
+    /// of the `m.key.verification.request` room message for in-room requests.
+    pub fn get_verification_request(&self, flow_id: &str) -> Option<VerificationRequest> {
+        self.verification_machine.get_request(flow_id)
+    }
+
+    /// Start a `Sas` verification for the ready verification request with the
+    /// given flow id.
+    ///
+    /// Returns the new `Sas` object and the request that needs to be sent out,
+    /// `None` if there's no such request or if the request isn't ready.
+    pub async fn start_sas_for_request(
+        &self,
+        flow_id: &str,
+    ) -> StoreResult<Option<(Sas, OutgoingVerificationRequest)>> {
+        self.verification_machine
+            .start_sas_for_request(flow_id)
+            .await
+    }
+
+    /// Create a QR code for the ready verification request with the given
+    /// flow id.
+    ///
+    /// The binary payload of the QR code can be fetched using
+    /// [`QrVerification::to_bytes()`], rendering it is left to the caller.
+    /// Returns `None` if there's no such request, if the other side can't
+    /// scan QR codes or if one of the users lacks a cross signing identity.
+    ///
+    /// [`QrVerification::to_bytes()`]: struct.QrVerification.html#method.to_bytes
+    pub async fn generate_qr_code(&self, flow_id: &str) -> StoreResult<Option<QrVerification>> {
+        self.verification_machine.generate_qr_code(flow_id).await
+    }
+
+    /// Start a QR code verification using a QR code the other side is showing.
+    ///
+    /// Returns the new `QrVerification` and a request that needs to be sent
+    /// out, `None` if there's no ready verification request for the QR code.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data of the scanned QR code, use
+    /// [`QrVerificationData::from_bytes()`] to parse the binary payload.
+    ///
+    /// [`QrVerificationData::from_bytes()`]: struct.QrVerificationData.html#method.from_bytes
+    pub async fn scan_qr_code(
+        &self,
+        data: QrVerificationData,
+    ) -> StoreResult<Option<(QrVerification, OutgoingVerificationRequest)>> {
+        self.verification_machine.scan_qr_code(data).await
+    }
+
+    /// Get a QR code verification object with the given flow id.
+    pub fn get_qr_verification(&self, flow_id: &str) -> Option<QrVerification> {
+        self.verification_machine.get_qr_verification(flow_id)
+    }
+
+    /// Receive a room event that might be part of an in-room verification.
+    ///
+    /// `m.key.verification.request` room messages as well as the
+    /// `m.key.verification.*` events that relate to them will be handled, all
+    /// other events are ignored. Encrypted events need to be decrypted before
+    /// they are passed to this method.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent in.
+    ///
+    /// * `event` - The room event.
+    pub async fn receive_verification_event(
+        &self,
+        room_id: &RoomId,
+        event: &AnySyncMessageEvent,
+    ) -> StoreResult<()> {
+        self.verification_machine
+            .receive_room_event(room_id, event)
+            .await
+    }
+
     async fn update_one_time_key_count(&self, key_count: &BTreeMap<DeviceKeyAlgorithm, UInt>) {
         self.account.update_uploaded_key_count(key_count).await;
     }
@@ -795,6 +1186,9 @@ impl OlmMachine {
 
         self.update_one_time_key_count(&response.device_one_time_keys_count)
             .await;
+        self.account
+            .update_unused_fallback_key_types(response.device_unused_fallback_key_types.as_deref())
+            .await;
 
         for user_id in &response.device_lists.changed {
             if let Err(e) = self.identity_manager.mark_user_as_changed(&user_id).await {
@@ -864,13 +1258,26 @@ impl OlmMachine {
                 }
                 AnyToDeviceEvent::KeyVerificationAccept(..)
                 | AnyToDeviceEvent::KeyVerificationCancel(..)
+                | AnyToDeviceEvent::KeyVerificationDone(..)
                 | AnyToDeviceEvent::KeyVerificationKey(..)
                 | AnyToDeviceEvent::KeyVerificationMac(..)
+                | AnyToDeviceEvent::KeyVerificationReady(..)
                 | AnyToDeviceEvent::KeyVerificationRequest(..)
                 | AnyToDeviceEvent::KeyVerificationStart(..) => {
                     self.handle_verification_event(&mut event).await;
                 }
-                _ => continue,
+                _ => {
+                    if raw_event_type(event_result.json()).as_deref()
+                        == Some(SECRET_REQUEST_EVENT_TYPE)
+                    {
+                        self.secret_sharing_machine
+                            .receive_incoming_secret_request(event_result.json());
+                    } else if raw_event_type(event_result.json()).as_deref()
+                        == Some(ROOM_KEY_WITHHELD_EVENT_TYPE)
+                    {
+                        self.receive_room_key_withheld(event_result.json()).await;
+                    }
+                }
             }
         }
 
@@ -881,7 +1288,50 @@ impl OlmMachine {
 
         changes.sessions.extend(changed_sessions);
 
-        Ok(self.store.save_changes(changes).await?)
+        let changed_sessions = self
+            .secret_sharing_machine
+            .collect_incoming_secret_requests()
+            .await?;
+
+        changes.sessions.extend(changed_sessions);
+
+        let received_sessions = changes.inbound_group_sessions.clone();
+
+        self.store.save_changes(changes).await?;
+
+        // The Olm sessions and room keys are safely stored now, failing to
+        // update our key requests isn't worth throwing them away.
+        self.mark_keys_as_received(&received_sessions).await;
+
+        if let Err(e) = self.key_request_machine.garbage_collect().await {
+            warn!(
+                "Failed to garbage collect our outgoing key requests {:?}",
+                e
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Cancel our key requests for the room keys of the given sessions.
+    ///
+    /// Our other devices don't need to answer key requests for room keys we
+    /// already received. Errors are only logged, this should be called after
+    /// the sessions are stored.
+    pub(crate) async fn mark_keys_as_received(&self, sessions: &[InboundGroupSession]) {
+        for session in sessions {
+            if let Err(e) = self
+                .key_request_machine
+                .mark_key_as_received(&session.room_id, &session.sender_key, session.session_id())
+                .await
+            {
+                warn!(
+                    "Failed to mark the room key {} as received {:?}",
+                    session.session_id(),
+                    e
+                );
+            }
+        }
     }
 
     /// Decrypt an event from a room timeline.
@@ -891,11 +1341,14 @@ impl OlmMachine {
     /// * `event` - The event that should be decrypted.
     ///
     /// * `room_id` - The ID of the room where the event was sent to.
+    ///
+    /// Returns the decrypted event together with information about its
+    /// encryption, e.g. if it was sent by a verified device.
     pub async fn decrypt_room_event(
         &self,
         event: &SyncMessageEvent<EncryptedEventContent>,
         room_id: &RoomId,
-    ) -> MegolmResult<Raw<AnySyncRoomEvent>> {
+    ) -> MegolmResult<DecryptedEvent> {
         let content = match &event.content {
             EncryptedEventContent::MegolmV1AesSha2(c) => c,
             _ => return Err(EventError::UnsupportedAlgorithm.into()),
@@ -905,50 +1358,242 @@ impl OlmMachine {
             .store
             .get_inbound_group_session(room_id, &content.sender_key, &content.session_id)
             .await?;
-        // TODO check if the Olm session is wedged and re-request the key.
         let session = if let Some(s) = session {
             s
         } else {
             self.key_request_machine
                 .create_outgoing_key_request(room_id, &content.sender_key, &content.session_id)
                 .await?;
-            return Err(MegolmError::MissingSession);
+
+            // The room key might have never reached us because our Olm
+            // session with the sender is wedged, if so the session manager
+            // will create a new Olm session and request the key again.
+            if self
+                .session_manager
+                .unwedge_for_room_key(
+                    &event.sender,
+                    room_id,
+                    &content.sender_key,
+                    &content.session_id,
+                )
+                .await?
+            {
+                debug!(
+                    "The room key for session {} is missing and the Olm session with its \
+                     sender {} is wedged",
+                    content.session_id, event.sender
+                );
+            }
+
+            let withheld: Option<RoomKeyWithheldContent> = self
+                .store
+                .get_object(&withheld_key(
+                    room_id,
+                    &event.sender,
+                    &content.sender_key,
+                    &content.session_id,
+                ))
+                .await?;
+
+            return Err(withheld.map_or(MegolmError::MissingSession, |w| {
+                MegolmError::Withheld(w.code)
+            }));
         };
 
-        // TODO check the message index.
-        // TODO check if this is from a verified device.
-        let (decrypted_event, _) = session.decrypt(event).await?;
+        let (decrypted_event, message_index) = session.decrypt(event).await?;
+        self.check_message_index(&session, message_index, event)
+            .await?;
 
         trace!("Successfully decrypted Megolm event {:?}", decrypted_event);
-        // TODO set the encryption info on the event (is it verified, was it
-        // decrypted, sender key...)
 
-        Ok(decrypted_event)
+        let encryption_info = self.encryption_info(&event.sender, &session).await?;
+
+        Ok(DecryptedEvent {
+            event: decrypted_event,
+            encryption_info,
+        })
     }
 
-    /// Update the tracked users.
-    ///
-    /// # Arguments
-    ///
-    /// * `users` - An iterator over user ids that should be marked for
-    /// tracking.
-    ///
-    /// This will mark users that weren't seen before for a key query and
-    /// tracking.
-    ///
-    /// If the user is already known to the Olm machine it will not be
-    /// considered for a key query.
-    pub async fn update_tracked_users(&self, users: impl IntoIterator<Item = &UserId>) {
-        self.identity_manager.update_tracked_users(users).await
+    /// Remember that the sender of a `m.room_key.withheld` event didn't share
+    /// a room key with us, so we can tell why decryption failed.
+    async fn receive_room_key_withheld(&self, event: &RawJsonValue) {
+        let (sender, content) = if let Some(e) = parse_withheld_event(event) {
+            e
+        } else {
+            warn!("Received an invalid room key withheld event {:?}", event);
+            return;
+        };
+
+        if let (Some(room_id), Some(session_id)) = (&content.room_id, &content.session_id) {
+            // Only the owner of the session can withhold it, otherwise anyone
+            // could make the decryption of someone else's messages fail with
+            // a withheld error.
+            match self
+                .store
+                .get_device_from_curve_key(&sender, &content.sender_key)
+                .await
+            {
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    warn!(
+                        "Received a room key withheld event from {} for the sender key {} \
+                         which doesn't belong to any of their devices",
+                        sender, content.sender_key
+                    );
+                    return;
+                }
+                Err(e) => {
+                    error!(
+                        "Error fetching the device of a room key withheld event {:?}",
+                        e
+                    );
+                    return;
+                }
+            }
+
+            info!(
+                "{} withheld the room key of session {} in room {}: {}",
+                sender, session_id, room_id, content.code
+            );
+
+            let key = withheld_key(room_id, &sender, &content.sender_key, session_id);
+
+            if let Err(e) = self.store.save_object(&key, &content).await {
+                error!("Error storing room key withheld info {:?}", e);
+            }
+        } else {
+            info!(
+                "{} withheld room keys from us with the code {}",
+                sender, content.code
+            );
+        }
     }
 
-    /// Get a specific device of a user.
-    ///
-    /// # Arguments
-    ///
-    /// * `user_id` - The unique id of the user that the device belongs to.
+    /// Make sure that the given message index of the session wasn't already
+    /// used by a different event and remember the event that used it.
     ///
-    /// * `device_id` - The unique id of the device.
+    /// A reused message index means that a malicious server replayed the
+    /// ciphertext of an older event as a new event.
+    async fn check_message_index(
+        &self,
+        session: &InboundGroupSession,
+        message_index: u32,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+    ) -> MegolmResult<()> {
+        if let Some(known) = self
+            .store
+            .get_megolm_message_index(
+                session.room_id(),
+                session.sender_key(),
+                session.session_id(),
+                message_index,
+            )
+            .await?
+        {
+            if known.event_id != event.event_id || known.origin_server_ts != event.origin_server_ts
+            {
+                warn!(
+                    "Message index {} of the Megolm session {} was reused by {}, \
+                     already used by {}",
+                    message_index,
+                    session.session_id(),
+                    event.event_id,
+                    known.event_id
+                );
+
+                return Err(MegolmError::ReplayedMessage(
+                    session.session_id().to_owned(),
+                    message_index,
+                    known.event_id,
+                ));
+            }
+        } else {
+            let changes = Changes {
+                megolm_message_indices: vec![MegolmMessageIndex {
+                    room_id: session.room_id().to_owned(),
+                    sender_key: session.sender_key().to_owned(),
+                    session_id: session.session_id().to_owned(),
+                    message_index,
+                    event_id: event.event_id.clone(),
+                    origin_server_ts: event.origin_server_ts,
+                }],
+                ..Default::default()
+            };
+
+            self.store.save_changes(changes).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Collect the encryption info of an event that was decrypted using the
+    /// given inbound group session.
+    async fn encryption_info(
+        &self,
+        sender: &UserId,
+        session: &InboundGroupSession,
+    ) -> StoreResult<EncryptionInfo> {
+        let claimed_key = session.sender_claimed_ed25519_key();
+
+        // The device only counts as the sender if both of its keys match the
+        // keys of the room key.
+        let device = self
+            .store
+            .get_device_from_curve_key(sender, session.sender_key())
+            .await?
+            .filter(|d| {
+                claimed_key.map_or(false, |k| {
+                    d.get_key(DeviceKeyAlgorithm::Ed25519)
+                        .map_or(false, |key| key == k)
+                })
+            });
+
+        let verification_state = if session.is_imported() {
+            VerificationState::Forwarded
+        } else if let Some(device) = &device {
+            if device.trust_state() {
+                VerificationState::Verified
+            } else {
+                VerificationState::Unverified
+            }
+        } else {
+            VerificationState::UnknownDevice
+        };
+
+        Ok(EncryptionInfo {
+            sender: sender.clone(),
+            sender_curve25519_key: session.sender_key().to_owned(),
+            sender_claimed_ed25519_key: claimed_key.map(|k| k.to_owned()),
+            sender_device: device.map(|d| d.inner),
+            session_id: session.session_id().to_owned(),
+            forwarding_curve25519_key_chain: session.forwarding_key_chain().await,
+            verification_state,
+        })
+    }
+
+    /// Update the tracked users.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - An iterator over user ids that should be marked for
+    /// tracking.
+    ///
+    /// This will mark users that weren't seen before for a key query and
+    /// tracking.
+    ///
+    /// If the user is already known to the Olm machine it will not be
+    /// considered for a key query.
+    pub async fn update_tracked_users(&self, users: impl IntoIterator<Item = &UserId>) {
+        self.identity_manager.update_tracked_users(users).await
+    }
+
+    /// Get a specific device of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
     ///
     /// Returns a `Device` if one is found and the crypto store didn't throw an
     /// error.
@@ -1003,6 +1648,58 @@ impl OlmMachine {
         self.store.get_user_devices(user_id).await
     }
 
+    /// Get the cross signing identity of an user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the identity belongs to.
+    ///
+    /// Returns a `UserIdentities` enum if one is found and the crypto store
+    /// didn't throw an error. Use [`get_verifiable_identity`] to get an
+    /// identity of another user that can be verified manually.
+    ///
+    /// [`get_verifiable_identity`]: #method.get_verifiable_identity
+    pub async fn get_identity(&self, user_id: &UserId) -> StoreResult<Option<UserIdentities>> {
+        self.store.get_user_identity(user_id).await
+    }
+
+    /// Get the cross signing identity of another user in a form that can be
+    /// verified manually.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the identity belongs to.
+    ///
+    /// Returns `None` if we don't know the identity of the user or if the
+    /// identity is our own.
+    pub async fn get_verifiable_identity(
+        &self,
+        user_id: &UserId,
+    ) -> StoreResult<Option<VerifiableUserIdentity>> {
+        self.store.get_verifiable_identity(user_id).await
+    }
+
+    /// Get the room key requests for the given room that didn't get answered
+    /// yet.
+    ///
+    /// Room keys are requested automatically from our other devices when an
+    /// event can't be decrypted, the requests are canceled once the room key
+    /// arrives. Requests that don't get answered are re-sent periodically and
+    /// expire after a week.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the key requests should be
+    /// returned.
+    pub async fn outgoing_key_requests(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<Vec<OutgoingKeyRequest>> {
+        self.key_request_machine
+            .outgoing_key_requests(room_id)
+            .await
+    }
+
     /// Import the given room keys into our store.
     ///
     /// # Arguments
@@ -1033,14 +1730,32 @@ impl OlmMachine {
         &self,
         exported_keys: Vec<ExportedRoomKey>,
     ) -> StoreResult<(usize, usize)> {
+        self.import_keys_helper(exported_keys, false).await
+    }
+
+    async fn import_keys_helper(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        from_backup: bool,
+    ) -> StoreResult<(usize, usize)> {
+        /// The first known index of the sessions we already have, keyed by
+        /// the room id, sender key and session id of the session.
         struct ShallowSessions {
-            inner: BTreeMap<Arc<RoomId>, u32>,
+            inner: BTreeMap<(Arc<RoomId>, Arc<str>, String), u32>,
         }
 
         impl ShallowSessions {
+            fn key(session: &InboundGroupSession) -> (Arc<RoomId>, Arc<str>, String) {
+                (
+                    session.room_id.clone(),
+                    session.sender_key.clone(),
+                    session.session_id().to_owned(),
+                )
+            }
+
             fn has_better_session(&self, session: &InboundGroupSession) -> bool {
                 self.inner
-                    .get(&session.room_id)
+                    .get(&Self::key(session))
                     .map(|existing| existing <= &session.first_known_index())
                     .unwrap_or(false)
             }
@@ -1054,10 +1769,7 @@ impl OlmMachine {
                 .get_inbound_group_sessions()
                 .await?
                 .into_iter()
-                .map(|s| {
-                    let index = s.first_known_index();
-                    (s.room_id, index)
-                })
+                .map(|s| (ShallowSessions::key(&s), s.first_known_index()))
                 .collect(),
         };
 
@@ -1066,6 +1778,12 @@ impl OlmMachine {
         for key in exported_keys.into_iter() {
             let session = InboundGroupSession::from_export(key)?;
 
+            // Sessions that we got from the server-side key backup don't need
+            // to be uploaded again.
+            if from_backup {
+                session.mark_as_backed_up();
+            }
+
             // Only import the session if we didn't have this session or if it's
             // a better version of the same session, that is the first known
             // index is lower.
@@ -1077,11 +1795,12 @@ impl OlmMachine {
         let num_sessions = sessions.len();
 
         let changes = Changes {
-            inbound_group_sessions: sessions,
+            inbound_group_sessions: sessions.clone(),
             ..Default::default()
         };
 
         self.store.save_changes(changes).await?;
+        self.mark_keys_as_received(&sessions).await;
 
         info!(
             "Successfully imported {} inbound group sessions",
@@ -1091,6 +1810,46 @@ impl OlmMachine {
         Ok((num_sessions, total_sessions))
     }
 
+    /// Export everything this machine knows into a passphrase encrypted
+    /// archive.
+    ///
+    /// Unlike [`export_keys`], the export contains our Olm account, Olm
+    /// sessions, private cross signing keys, the devices and identities we
+    /// know about, and their trust state. It can be imported into any
+    /// `CryptoStore` using `store::import_store()` to restore this device on a
+    /// different host.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the export.
+    ///
+    /// * `rounds` - The number of rounds that should be used for the key
+    /// derivation of the passphrase.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the export securely.
+    ///
+    /// [`export_keys`]: #method.export_keys
+    pub async fn export_store(
+        &self,
+        passphrase: &str,
+        rounds: u32,
+    ) -> Result<String, StoreExportError> {
+        // Make sure the store holds the latest state of our account and
+        // identity, they might only live in memory so far.
+        let changes = Changes {
+            account: Some(self.account.inner.clone()),
+            private_identity: Some(self.user_identity.lock().await.clone()),
+            ..Default::default()
+        };
+
+        self.store.save_changes(changes).await?;
+
+        export_store(&*self.store, passphrase, rounds).await
+    }
+
     /// Export the keys that match the given predicate.
     ///
     /// # Arguments
@@ -1140,6 +1899,260 @@ impl OlmMachine {
 
         Ok(exported)
     }
+
+    /// Create a new dehydrated device.
+    ///
+    /// Returns the request body that uploads the device, the account of the
+    /// device is encrypted using the given pickle key. The same key needs to
+    /// be used to rehydrate the device.
+    ///
+    /// A dehydrated device receives room keys while none of our devices is
+    /// online, it should be created when logging out and replaced with a
+    /// fresh one after it was rehydrated.
+    ///
+    /// The device keys of the dehydrated device are signed with our self
+    /// signing key, so other users trust it the same way they trust our
+    /// other devices. This fails if we don't have the private self signing
+    /// key.
+    ///
+    /// # Arguments
+    ///
+    /// * `display_name` - The display name the dehydrated device should have.
+    ///
+    /// * `pickle_key` - The key that should be used to encrypt the account of
+    /// the device.
+    pub async fn create_dehydrated_device(
+        &self,
+        display_name: &str,
+        pickle_key: &[u8],
+    ) -> Result<DehydratedDeviceRequest, DehydrationError> {
+        let identity = self.user_identity.lock().await;
+        create_dehydrated_device(&identity, display_name, pickle_key).await
+    }
+
+    /// Decrypt a dehydrated device that was fetched from the server.
+    ///
+    /// The to-device events of the device should be passed to the returned
+    /// `RehydratedDevice`, the room keys they contain are imported into our
+    /// store.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that was used to encrypt the account of the
+    /// device.
+    ///
+    /// * `device_id` - The device id of the dehydrated device.
+    ///
+    /// * `device_data` - The encrypted account of the dehydrated device.
+    pub async fn rehydrate_device(
+        &self,
+        pickle_key: &[u8],
+        device_id: &DeviceId,
+        device_data: DehydratedDeviceData,
+    ) -> Result<RehydratedDevice, DehydrationError> {
+        RehydratedDevice::new(self.clone(), pickle_key, device_id, device_data).await
+    }
+
+    /// Enable the server-side backup of room keys.
+    ///
+    /// After this call room keys will be uploaded to the backup in batches, the
+    /// requests to do so are returned by [`outgoing_requests`].
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The public backup key that will be used to encrypt the room
+    /// keys.
+    ///
+    /// * `version` - The backup version, as returned by the server when the
+    /// backup was created, the room keys will be uploaded to.
+    ///
+    /// [`outgoing_requests`]: #method.outgoing_requests
+    pub async fn enable_backup(&self, key: MegolmV1BackupKey, version: String) -> StoreResult<()> {
+        self.backup_machine.enable_backup(key, version).await
+    }
+
+    /// Disable the server-side backup of room keys.
+    ///
+    /// This won't delete the backup version on the server.
+    pub async fn disable_backup(&self) -> StoreResult<()> {
+        self.backup_machine.disable_backup().await
+    }
+
+    /// Is the server-side backup of room keys enabled.
+    pub async fn backup_enabled(&self) -> bool {
+        self.backup_machine.enabled().await
+    }
+
+    /// Get the backup key that is currently used to back up room keys.
+    pub async fn backup_key(&self) -> Option<MegolmV1BackupKey> {
+        self.backup_machine.backup_key().await
+    }
+
+    /// Get the signed backup algorithm description that is needed to create a
+    /// new backup version on the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The public backup key that will be used for the new backup
+    /// version.
+    pub async fn backup_algorithm(&self, key: &MegolmV1BackupKey) -> BackupAlgorithm {
+        self.backup_machine.backup_algorithm(key).await
+    }
+
+    /// Decrypt and import room keys that were downloaded from the server-side
+    /// key backup.
+    ///
+    /// The restored room keys are marked as backed up. If the keys should be
+    /// uploaded to the same backup version, [`enable_backup`] needs to be
+    /// called before the restore, enabling a new backup version marks all room
+    /// keys as not backed up.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were successfully
+    /// decrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The private key of the backup.
+    ///
+    /// * `rooms` - The backed up room keys, as returned by the server.
+    ///
+    /// [`enable_backup`]: #method.enable_backup
+    pub async fn restore_backup(
+        &self,
+        recovery_key: &RecoveryKey,
+        rooms: &BTreeMap<RoomId, RoomKeyBackup>,
+    ) -> StoreResult<(usize, usize)> {
+        let keys = recovery_key.decrypt_backup(rooms);
+        self.import_keys_helper(keys, true).await
+    }
+
+    /// Encrypt our private cross signing keys with the given secret storage
+    /// key.
+    ///
+    /// Returns a list of secret names and encrypted secrets, each of them
+    /// should be uploaded as a global account data event with the secret name
+    /// as the event type.
+    ///
+    /// Keys that we don't have the private part of are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The secret storage key that should be used to encrypt the
+    /// cross signing keys.
+    pub async fn export_cross_signing_secrets(
+        &self,
+        key: &SecretStorageKey,
+    ) -> Vec<(SecretName, EncryptedSecretContent)> {
+        let identity = self.user_identity.lock().await;
+        let mut secrets = Vec::new();
+
+        for name in &[
+            SecretName::CrossSigningMasterKey,
+            SecretName::CrossSigningSelfSigningKey,
+            SecretName::CrossSigningUserSigningKey,
+        ] {
+            if let Some(secret) = identity.export_secret(name).await {
+                secrets.push((name.clone(), key.encrypt(name, &secret)));
+            }
+        }
+
+        secrets
+    }
+
+    /// Import our private cross signing keys, e.g. after they were decrypted
+    /// from secret storage.
+    ///
+    /// The keys are merged into our existing private cross signing identity,
+    /// keys that aren't given are left untouched. Every key is checked against
+    /// our public cross signing identity, or against our private identity if
+    /// we don't know the public one yet, and the import is refused if any of
+    /// them doesn't match.
+    ///
+    /// # Arguments
+    ///
+    /// * `master_key` - The unpadded base64 encoded private master key.
+    ///
+    /// * `self_signing_key` - The unpadded base64 encoded private self signing
+    /// key.
+    ///
+    /// * `user_signing_key` - The unpadded base64 encoded private user signing
+    /// key.
+    pub async fn import_cross_signing_keys(
+        &self,
+        master_key: Option<&str>,
+        self_signing_key: Option<&str>,
+        user_signing_key: Option<&str>,
+    ) -> Result<(), SecretStorageError> {
+        let public_identity = match self.store.get_user_identity(self.user_id()).await? {
+            Some(UserIdentities::Own(identity)) => Some(identity),
+            _ => None,
+        };
+
+        let identity = self.user_identity.lock().await;
+
+        identity
+            .import_secrets(
+                public_identity.as_ref(),
+                master_key,
+                self_signing_key,
+                user_signing_key,
+            )
+            .await?;
+
+        info!("Imported our private cross signing keys");
+
+        let changes = Changes {
+            private_identity: Some(identity.clone()),
+            ..Default::default()
+        };
+
+        Ok(self.store.save_changes(changes).await?)
+    }
+
+    /// Request a secret from our other devices.
+    ///
+    /// The request will be sent out with the next batch of
+    /// [`outgoing_requests`]. Once a trusted device answers the request, the
+    /// secret is stored: private cross signing keys are imported into our
+    /// private cross signing identity, other secrets can be retrieved with
+    /// [`get_secrets_from_inbox`].
+    ///
+    /// The `m.secret.send` event that carried the secret will be part of the
+    /// to-device events of the sync response, with the secret removed. Once
+    /// the secret is accepted, the `name` field of the event content contains
+    /// the name of the secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret that should be requested.
+    ///
+    /// [`outgoing_requests`]: #method.outgoing_requests
+    /// [`get_secrets_from_inbox`]: #method.get_secrets_from_inbox
+    pub async fn request_secret(&self, secret_name: SecretName) -> StoreResult<()> {
+        self.secret_sharing_machine
+            .request_secret(secret_name)
+            .await
+    }
+
+    /// Get the secrets with the given name that were sent to us by our other
+    /// devices.
+    pub async fn get_secrets_from_inbox(
+        &self,
+        secret_name: &SecretName,
+    ) -> StoreResult<Vec<Zeroizing<String>>> {
+        self.secret_sharing_machine
+            .get_secrets_from_inbox(secret_name)
+            .await
+    }
+
+    /// Delete the secrets with the given name that were sent to us by our
+    /// other devices.
+    pub async fn delete_secrets_from_inbox(&self, secret_name: &SecretName) -> StoreResult<()> {
+        self.secret_sharing_machine
+            .delete_secrets_from_inbox(secret_name)
+            .await
+    }
 }
 
 #[cfg(test)]
@@ -1154,7 +2167,7 @@ pub(crate) mod test {
     };
 
     use http::Response;
-    use serde_json::json;
+    use serde_json::{json, value::to_raw_value};
     #[cfg(feature = "sqlite_cryptostore")]
     use tempfile::tempdir;
 
@@ -1162,7 +2175,9 @@ pub(crate) mod test {
         machine::OlmMachine,
         olm::Utility,
         verification::test::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, ReadOnlyDevice, ToDeviceRequest,
+        EncryptionSettings, LocalTrust, MegolmError, OlmError, OutgoingRequests, ReadOnlyDevice,
+        RecoveryKey, SecretName, SecretStorageError, SecretStorageKey, ShareStrategy,
+        ToDeviceRequest, VerificationState, WithheldCode,
     };
 
     use matrix_sdk_common::{
@@ -1176,7 +2191,8 @@ pub(crate) mod test {
             EventType, SyncMessageEvent, ToDeviceEvent, Unsigned,
         },
         identifiers::{
-            event_id, room_id, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, UserId,
+            event_id, room_id, user_id, DeviceId, DeviceIdBox, DeviceKeyAlgorithm, DeviceKeyId,
+            UserId,
         },
         Raw,
     };
@@ -1426,7 +2442,7 @@ pub(crate) mod test {
             .get_outbound_group_session(&room_id)
             .is_some());
 
-        machine.invalidate_group_session(&room_id);
+        assert!(machine.invalidate_group_session(&room_id).await.unwrap());
 
         assert!(machine
             .group_session_manager
@@ -1519,6 +2535,57 @@ pub(crate) mod test {
         assert!(ret.is_none());
     }
 
+    #[tokio::test]
+    async fn test_fallback_key_rotation() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        machine.account.inner.update_uploaded_key_count(0);
+
+        let request = machine
+            .keys_for_upload()
+            .await
+            .expect("Can't prepare initial key upload");
+        assert_eq!(request.fallback_keys.len(), 1);
+
+        let mut response = keys_upload_response();
+        response.one_time_key_counts.insert(
+            DeviceKeyAlgorithm::SignedCurve25519,
+            (request.one_time_keys.unwrap().len() as u64)
+                .try_into()
+                .unwrap(),
+        );
+
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+
+        machine
+            .account
+            .update_unused_fallback_key_types(Some(&[DeviceKeyAlgorithm::SignedCurve25519][..]))
+            .await;
+        assert!(machine.keys_for_upload().await.is_none());
+
+        // The server handed out our fallback key, a new one needs to be
+        // uploaded.
+        machine
+            .account
+            .update_unused_fallback_key_types(Some(&[][..]))
+            .await;
+
+        let new_request = machine
+            .keys_for_upload()
+            .await
+            .expect("Can't prepare the fallback key upload");
+
+        assert!(new_request.device_keys.is_none());
+        assert!(new_request.one_time_keys.is_none());
+        assert_eq!(new_request.fallback_keys.len(), 1);
+        assert_ne!(
+            new_request.fallback_keys.keys().next(),
+            request.fallback_keys.keys().next()
+        );
+    }
+
     #[tokio::test]
     async fn test_keys_query() {
         let (machine, _) = get_prepared_machine().await;
@@ -1732,12 +2799,29 @@ pub(crate) mod test {
             unsigned: Unsigned::default(),
         };
 
-        let decrypted_event = bob
-            .decrypt_room_event(&event, &room_id)
-            .await
-            .unwrap()
-            .deserialize()
-            .unwrap();
+        let decrypted = bob.decrypt_room_event(&event, &room_id).await.unwrap();
+
+        let encryption_info = decrypted.encryption_info;
+        assert_eq!(&encryption_info.sender, alice.user_id());
+        assert_eq!(
+            encryption_info.sender_curve25519_key,
+            alice.identity_keys().curve25519()
+        );
+        assert_eq!(
+            encryption_info.sender_claimed_ed25519_key.as_deref(),
+            Some(alice.identity_keys().ed25519())
+        );
+        assert!(encryption_info.forwarding_curve25519_key_chain.is_empty());
+        assert_eq!(
+            encryption_info.sender_device.unwrap().device_id(),
+            alice.device_id()
+        );
+        assert_eq!(
+            encryption_info.verification_state,
+            VerificationState::Unverified
+        );
+
+        let decrypted_event = decrypted.event.deserialize().unwrap();
 
         match decrypted_event {
             AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
@@ -1754,6 +2838,176 @@ pub(crate) mod test {
             }
             _ => panic!("Decrypted room event has the wrong type"),
         }
+
+        // Decrypting the same event again is fine.
+        bob.decrypt_room_event(&event, &room_id).await.unwrap();
+
+        // Replaying the ciphertext as a new event isn't.
+        let replayed_event = SyncMessageEvent {
+            event_id: event_id!("$yyyyy:example.org"),
+            ..event
+        };
+
+        assert!(matches!(
+            bob.decrypt_room_event(&replayed_event, &room_id).await,
+            Err(MegolmError::ReplayedMessage(_, _, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_share_strategy() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        assert_eq!(alice.share_strategy(), ShareStrategy::AllDevices);
+        alice.set_share_strategy(ShareStrategy::ErrorOnUnverified);
+
+        let error = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap_err();
+
+        if let OlmError::UnverifiedDevices(devices) = error {
+            assert_eq!(devices.len(), 1);
+            let bob_device: DeviceIdBox = bob.device_id().into();
+            assert_eq!(devices[bob.user_id()], vec![bob_device]);
+        } else {
+            panic!(
+                "Invalid error when sharing with unverified devices {:?}",
+                error
+            );
+        }
+
+        assert!(alice
+            .group_session_manager
+            .get_outbound_group_session(&room_id)
+            .is_none());
+
+        alice.set_share_strategy(ShareStrategy::TrustedDevicesOnly);
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(to_device_requests.len(), 1);
+        assert_eq!(
+            to_device_requests[0].event_type,
+            EventType::Custom("m.room_key.withheld".to_owned())
+        );
+
+        alice
+            .get_device(bob.user_id(), bob.device_id())
+            .await
+            .unwrap()
+            .unwrap()
+            .set_local_trust(LocalTrust::Verified)
+            .await
+            .unwrap();
+        alice.invalidate_group_session(&room_id).await.unwrap();
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(to_device_requests.len(), 1);
+        assert_eq!(to_device_requests[0].event_type, EventType::RoomEncrypted);
+    }
+
+    #[tokio::test]
+    async fn test_room_key_withheld() {
+        let (alice, bob, _) = get_machine_pair().await;
+        let room_id = room_id!("!test:example.org");
+
+        // Alice doesn't have an Olm session with Bob, so she can't share the
+        // room key and tells him why. Her own device doesn't get notified.
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [alice.user_id().clone(), bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(to_device_requests.len(), 1);
+        let request = &to_device_requests[0];
+        assert_eq!(
+            request.event_type,
+            EventType::Custom("m.room_key.withheld".to_owned())
+        );
+        assert!(!request.messages.contains_key(alice.user_id()));
+
+        let content: serde_json::Value = serde_json::from_str(
+            request
+                .messages
+                .values()
+                .next()
+                .unwrap()
+                .values()
+                .next()
+                .unwrap()
+                .get(),
+        )
+        .unwrap();
+
+        let plaintext = "It is a secret to everybody";
+        let message = MessageEventContent::Text(TextMessageEventContent::plain(plaintext));
+
+        let encrypted_content = alice
+            .encrypt(&room_id, AnyMessageEventContent::RoomMessage(message))
+            .await
+            .unwrap();
+
+        let room_event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: SystemTime::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        // Someone else can't withhold Alice's session.
+        let event = json!({
+            "sender": "@mallory:example.org",
+            "type": "m.room_key.withheld",
+            "content": content,
+        });
+
+        bob.receive_room_key_withheld(&to_raw_value(&event).unwrap())
+            .await;
+
+        assert!(matches!(
+            bob.decrypt_room_event(&room_event, &room_id).await,
+            Err(MegolmError::MissingSession)
+        ));
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": "m.room_key.withheld",
+            "content": content,
+        });
+
+        bob.receive_room_key_withheld(&to_raw_value(&event).unwrap())
+            .await;
+
+        assert!(matches!(
+            bob.decrypt_room_event(&room_event, &room_id).await,
+            Err(MegolmError::Withheld(WithheldCode::NoOlm))
+        ));
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -1809,7 +3063,7 @@ pub(crate) mod test {
 
         let (alice_sas, request) = bob_device.start_verification().await.unwrap();
 
-        let mut event = request_to_event(alice.user_id(), &request);
+        let mut event = request_to_event(alice.user_id(), &request.into());
         bob.handle_verification_event(&mut event).await;
 
         let bob_sas = bob.get_verification(alice_sas.flow_id()).unwrap();
@@ -1878,4 +3132,238 @@ pub(crate) mod test {
         assert!(bob_sas.is_done());
         assert!(alice_device.is_trusted());
     }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine
+            .create_outbound_group_session_with_defaults(&room_id)
+            .await
+            .unwrap();
+
+        // No backup is enabled, nothing should be uploaded.
+        assert!(machine
+            .outgoing_requests()
+            .await
+            .iter()
+            .all(|r| !matches!(r.request(), OutgoingRequests::KeysBackup(_))));
+
+        let recovery_key = RecoveryKey::new();
+        machine
+            .enable_backup(recovery_key.public_key(), "1".to_owned())
+            .await
+            .unwrap();
+
+        let request = machine
+            .outgoing_requests()
+            .await
+            .into_iter()
+            .find(|r| matches!(r.request(), OutgoingRequests::KeysBackup(_)))
+            .expect("A backup request should have been created");
+
+        let rooms = if let OutgoingRequests::KeysBackup(r) = request.request() {
+            assert_eq!(r.version, "1");
+            r.rooms.clone()
+        } else {
+            unreachable!()
+        };
+
+        assert_eq!(rooms.get(&room_id).unwrap().sessions.len(), 1);
+
+        machine
+            .backup_machine
+            .mark_request_as_sent(request.request_id())
+            .await
+            .unwrap();
+
+        assert!(machine
+            .store
+            .inbound_group_sessions_for_backup(10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let keys = recovery_key.decrypt_backup(&rooms);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(&keys[0].room_id, &room_id);
+
+        let (machine, _) = get_prepared_machine().await;
+        let (imported, total) = machine.restore_backup(&recovery_key, &rooms).await.unwrap();
+
+        assert_eq!((imported, total), (1, 1));
+        assert!(machine
+            .store
+            .inbound_group_sessions_for_backup(10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn restore_backup_with_existing_room_keys() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        // Two different sessions for the same room.
+        for _ in 0..2 {
+            machine
+                .create_outbound_group_session_with_defaults(&room_id)
+                .await
+                .unwrap();
+        }
+
+        let recovery_key = RecoveryKey::new();
+        machine
+            .enable_backup(recovery_key.public_key(), "1".to_owned())
+            .await
+            .unwrap();
+
+        let request = machine.backup_machine.backup().await.unwrap().unwrap();
+        let rooms = if let OutgoingRequests::KeysBackup(r) = request.request() {
+            r.rooms.clone()
+        } else {
+            unreachable!()
+        };
+
+        let keys = recovery_key.decrypt_backup(&rooms);
+        assert_eq!(keys.len(), 2);
+
+        // The new device already received one of the sessions, the other one
+        // still needs to be restored even if it's for the same room.
+        let (machine, _) = get_prepared_machine().await;
+        let (imported, _) = machine.import_keys(vec![keys[0].clone()]).await.unwrap();
+        assert_eq!(imported, 1);
+
+        let (imported, total) = machine.restore_backup(&recovery_key, &rooms).await.unwrap();
+        assert_eq!((imported, total), (1, 2));
+        assert_eq!(
+            machine
+                .store
+                .get_inbound_group_sessions()
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn restored_keys_are_not_uploaded_again() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine
+            .create_outbound_group_session_with_defaults(&room_id)
+            .await
+            .unwrap();
+
+        let recovery_key = RecoveryKey::new();
+        machine
+            .enable_backup(recovery_key.public_key(), "1".to_owned())
+            .await
+            .unwrap();
+
+        let request = machine.backup_machine.backup().await.unwrap().unwrap();
+        let rooms = if let OutgoingRequests::KeysBackup(r) = request.request() {
+            r.rooms.clone()
+        } else {
+            unreachable!()
+        };
+
+        // A fresh device enables the backup version it restores from before
+        // importing the keys, the restored keys are already in that version.
+        let (machine, _) = get_prepared_machine().await;
+        machine
+            .enable_backup(recovery_key.public_key(), "1".to_owned())
+            .await
+            .unwrap();
+        let (imported, _) = machine.restore_backup(&recovery_key, &rooms).await.unwrap();
+
+        assert_eq!(imported, 1);
+        assert!(machine.backup_machine.backup().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn cross_signing_secret_storage() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        machine.bootstrap_cross_signing(false).await.unwrap();
+
+        let key = SecretStorageKey::new();
+        let secrets = machine.export_cross_signing_secrets(&key).await;
+        assert_eq!(secrets.len(), 3);
+
+        let decrypted: Vec<_> = secrets
+            .iter()
+            .map(|(name, content)| key.decrypt(name, content).unwrap())
+            .collect();
+
+        let device_id: Box<DeviceId> = "OTHERDEVICE".into();
+        let second = OlmMachine::new(&user_id(), &device_id);
+        assert!(second.user_identity.lock().await.is_empty().await);
+
+        second
+            .import_cross_signing_keys(Some(&decrypted[0]), None, None)
+            .await
+            .unwrap();
+        second
+            .import_cross_signing_keys(None, Some(&decrypted[1]), Some(&decrypted[2]))
+            .await
+            .unwrap();
+
+        // The partial imports were merged into a single identity.
+        let identity = second.user_identity.lock().await;
+        assert!(!identity.is_empty().await);
+        assert_eq!(
+            &*identity.master_key.lock().await,
+            &*machine.user_identity.lock().await.master_key.lock().await
+        );
+        drop(identity);
+
+        let other = OlmMachine::new(&alice_id(), &alice_device_id());
+        other.bootstrap_cross_signing(false).await.unwrap();
+        let other_secrets = other.export_cross_signing_secrets(&key).await;
+        let other_master = key
+            .decrypt(&other_secrets[0].0, &other_secrets[0].1)
+            .unwrap();
+
+        assert!(matches!(
+            machine
+                .import_cross_signing_keys(Some(&other_master), None, None)
+                .await,
+            Err(SecretStorageError::IdentityMismatch(_))
+        ));
+
+        // A mismatching subkey is refused even if the other keys are fine, and
+        // nothing gets imported.
+        let other_self_signing = key
+            .decrypt(&other_secrets[1].0, &other_secrets[1].1)
+            .unwrap();
+
+        assert!(matches!(
+            second
+                .import_cross_signing_keys(
+                    Some(&decrypted[0]),
+                    Some(&other_self_signing),
+                    Some(&decrypted[2])
+                )
+                .await,
+            Err(SecretStorageError::IdentityMismatch(
+                SecretName::CrossSigningSelfSigningKey
+            ))
+        ));
+
+        let identity = second.user_identity.lock().await;
+        assert_eq!(
+            &*identity.self_signing_key.lock().await,
+            &*machine
+                .user_identity
+                .lock()
+                .await
+                .self_signing_key
+                .lock()
+                .await
+        );
+    }
 }
@@ -62,7 +62,7 @@ use crate::{
 
 use super::{
     EncryptionSettings, InboundGroupSession, OutboundGroupSession, PrivateCrossSigningIdentity,
-    Session,
+    Session, PICKLE_VERSION,
 };
 
 #[derive(Debug, Clone)]
@@ -100,7 +100,7 @@ pub struct OlmDecryptionInfo {
 /// A hash of a succesfully decrypted Olm message.
 ///
 /// Can be used to check if a message has been replayed to us.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OlmMessageHash {
     /// The curve25519 key of the sender that sent us the Olm message.
     pub sender_key: String,
@@ -195,6 +195,22 @@ impl Account {
         self.inner.update_uploaded_key_count(count);
     }
 
+    /// Update the list of key types for which the server still has an unused
+    /// fallback key of ours.
+    ///
+    /// A new fallback key is generated if our fallback key was used up.
+    /// `None` means that the server doesn't support fallback keys.
+    pub async fn update_unused_fallback_key_types(&self, key_types: Option<&[DeviceKeyAlgorithm]>) {
+        if let Some(key_types) = key_types {
+            if !key_types.contains(&DeviceKeyAlgorithm::SignedCurve25519)
+                && !self.inner.fallback_key_pending()
+            {
+                debug!("Our fallback key was used up, generating a new one");
+                self.inner.generate_fallback_key().await;
+            }
+        }
+    }
+
     pub async fn receive_keys_upload_response(
         &self,
         response: &upload_keys::Response,
@@ -449,6 +465,9 @@ pub struct ReadOnlyAccount {
     /// needs to set this for us, depending on the count we will suggest the
     /// client to upload new keys.
     uploaded_signed_key_count: Arc<AtomicI64>,
+    /// Has a fallback key been generated that still needs to be uploaded to
+    /// the server.
+    fallback_key_pending: Arc<AtomicBool>,
 }
 
 /// A typed representation of a base64 encoded string containing the account
@@ -485,6 +504,12 @@ pub struct PickledAccount {
     pub shared: bool,
     /// The number of uploaded one-time keys we have on the server.
     pub uploaded_signed_key_count: i64,
+    /// Was a fallback key generated that still needs to be uploaded.
+    #[serde(default)]
+    pub fallback_key_pending: bool,
+    /// The version of the pickle format, see `PICKLE_VERSION`.
+    #[serde(default = "crate::olm::default_pickle_version")]
+    pub version: u32,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -516,6 +541,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::new(false)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(0)),
+            fallback_key_pending: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -575,6 +601,25 @@ impl ReadOnlyAccount {
         self.inner.lock().await.generate_one_time_keys(count);
     }
 
+    /// Generate a new fallback key, replacing the current one.
+    ///
+    /// The previous fallback key is kept around by libolm so sessions that
+    /// were created using it can still be established.
+    pub(crate) async fn generate_fallback_key(&self) {
+        self.inner.lock().await.generate_fallback_key();
+        self.fallback_key_pending.store(true, Ordering::Relaxed);
+    }
+
+    /// Does a freshly generated fallback key need to be uploaded.
+    pub(crate) fn fallback_key_pending(&self) -> bool {
+        self.fallback_key_pending.load(Ordering::Relaxed)
+    }
+
+    /// Get the current fallback key of the account, if one was generated.
+    pub(crate) async fn fallback_key(&self) -> Option<(String, String)> {
+        self.inner.lock().await.parsed_fallback_key()
+    }
+
     /// Get the maximum number of one-time keys the account can hold.
     pub(crate) async fn max_one_time_keys(&self) -> usize {
         self.inner.lock().await.max_number_of_one_time_keys()
@@ -601,7 +646,7 @@ impl ReadOnlyAccount {
 
     /// Should account or one-time keys be uploaded to the server.
     pub(crate) async fn should_upload_keys(&self) -> bool {
-        if !self.shared() {
+        if !self.shared() || self.fallback_key_pending() {
             return true;
         }
 
@@ -620,7 +665,11 @@ impl ReadOnlyAccount {
         key_count > 0
     }
 
-    /// Get a tuple of device and one-time keys that need to be uploaded.
+    /// Get a tuple of device, one-time and fallback keys that need to be
+    /// uploaded.
+    ///
+    /// A fallback key is generated together with the initial upload of our
+    /// device keys.
     ///
     /// Returns None if no keys need to be uploaded.
     pub(crate) async fn keys_for_upload(
@@ -628,12 +677,17 @@ impl ReadOnlyAccount {
     ) -> Option<(
         Option<DeviceKeys>,
         Option<BTreeMap<DeviceKeyId, OneTimeKey>>,
+        BTreeMap<DeviceKeyId, OneTimeKey>,
     )> {
         if !self.should_upload_keys().await {
             return None;
         }
 
         let device_keys = if !self.shared() {
+            if !self.fallback_key_pending() {
+                self.generate_fallback_key().await;
+            }
+
             Some(self.device_keys().await)
         } else {
             None
@@ -641,12 +695,19 @@ impl ReadOnlyAccount {
 
         let one_time_keys = self.signed_one_time_keys().await.ok();
 
-        Some((device_keys, one_time_keys))
+        let fallback_keys = if self.fallback_key_pending() {
+            self.signed_fallback_keys().await
+        } else {
+            BTreeMap::new()
+        };
+
+        Some((device_keys, one_time_keys, fallback_keys))
     }
 
-    /// Mark the current set of one-time keys as being published.
+    /// Mark the current set of one-time and fallback keys as being published.
     pub(crate) async fn mark_keys_as_published(&self) {
         self.inner.lock().await.mark_keys_as_published();
+        self.fallback_key_pending.store(false, Ordering::Relaxed);
     }
 
     /// Sign the given string using the accounts signing key.
@@ -671,6 +732,8 @@ impl ReadOnlyAccount {
             pickle,
             shared: self.shared(),
             uploaded_signed_key_count: self.uploaded_key_count(),
+            fallback_key_pending: self.fallback_key_pending(),
+            version: PICKLE_VERSION,
         }
     }
 
@@ -696,6 +759,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::from(pickle.shared)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(pickle.uploaded_signed_key_count)),
+            fallback_key_pending: Arc::new(AtomicBool::new(pickle.fallback_key_pending)),
         })
     }
 
@@ -786,24 +850,35 @@ impl ReadOnlyAccount {
                 "key": key,
             });
 
-            let signature = self.sign_json(key_json).await;
-
-            let mut signature_map = BTreeMap::new();
+            let signatures = self.key_signatures(key_json).await;
+            let signed_key = SignedKey::new(key.to_owned(), signatures);
 
-            signature_map.insert(
-                DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, &self.device_id),
-                signature,
+            one_time_key_map.insert(
+                DeviceKeyId::from_parts(
+                    DeviceKeyAlgorithm::SignedCurve25519,
+                    key_id.as_str().into(),
+                ),
+                OneTimeKey::SignedKey(signed_key),
             );
+        }
+
+        Ok(one_time_key_map)
+    }
 
-            let mut signatures = BTreeMap::new();
-            signatures.insert((*self.user_id).clone(), signature_map);
+    /// Sign and prepare the current fallback key to be uploaded.
+    pub(crate) async fn signed_fallback_keys(&self) -> BTreeMap<DeviceKeyId, OneTimeKey> {
+        let mut fallback_key_map = BTreeMap::new();
 
-            let signed_key = SignedKey {
-                key: key.to_owned(),
-                signatures,
-            };
+        if let Some((key_id, key)) = self.fallback_key().await {
+            let key_json = json!({
+                "key": key,
+                "fallback": true,
+            });
 
-            one_time_key_map.insert(
+            let signatures = self.key_signatures(key_json).await;
+            let signed_key = SignedKey::new_fallback(key, signatures);
+
+            fallback_key_map.insert(
                 DeviceKeyId::from_parts(
                     DeviceKeyAlgorithm::SignedCurve25519,
                     key_id.as_str().into(),
@@ -812,7 +887,27 @@ impl ReadOnlyAccount {
             );
         }
 
-        Ok(one_time_key_map)
+        fallback_key_map
+    }
+
+    /// Sign the given key JSON and put the signature into a signature map.
+    async fn key_signatures(
+        &self,
+        key_json: Value,
+    ) -> BTreeMap<UserId, BTreeMap<DeviceKeyId, String>> {
+        let signature = self.sign_json(key_json).await;
+
+        let mut signature_map = BTreeMap::new();
+
+        signature_map.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, &self.device_id),
+            signature,
+        );
+
+        let mut signatures = BTreeMap::new();
+        signatures.insert((*self.user_id).clone(), signature_map);
+
+        signatures
     }
 
     /// Generate, sign and prepare one-time keys to be uploaded.
@@ -894,6 +989,14 @@ impl ReadOnlyAccount {
             }
         };
 
+        if one_time_key.fallback {
+            debug!(
+                "Creating an Olm session for {} {} using a fallback key",
+                device.user_id(),
+                device.device_id()
+            );
+        }
+
         device.verify_one_time_key(&one_time_key).map_err(|e| {
             SessionCreationError::InvalidSignature(
                 device.user_id().to_owned(),
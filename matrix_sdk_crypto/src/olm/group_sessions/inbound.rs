@@ -16,7 +16,11 @@ use std::{
     collections::BTreeMap,
     convert::{TryFrom, TryInto},
     fmt, mem,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
 };
 
 use olm_rs::{
@@ -37,13 +41,16 @@ use matrix_sdk_common::{
         forwarded_room_key::ForwardedRoomKeyToDeviceEventContent,
         room::encrypted::EncryptedEventContent, AnySyncRoomEvent, SyncMessageEvent,
     },
-    identifiers::{DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId},
+    identifiers::{DeviceKeyAlgorithm, EventEncryptionAlgorithm, EventId, RoomId},
     locks::Mutex,
     Raw,
 };
 
 use super::{ExportedGroupSessionKey, ExportedRoomKey, GroupSessionKey};
-use crate::error::{EventError, MegolmResult};
+use crate::{
+    error::{EventError, MegolmResult},
+    olm::PICKLE_VERSION,
+};
 
 // TODO add creation times to the inbound grop sessions so we can export
 // sessions that were created between some time period, this should only be set
@@ -63,6 +70,7 @@ pub struct InboundGroupSession {
     pub(crate) room_id: Arc<RoomId>,
     forwarding_chains: Arc<Mutex<Option<Vec<String>>>>,
     imported: Arc<bool>,
+    backed_up: Arc<AtomicBool>,
 }
 
 impl InboundGroupSession {
@@ -104,6 +112,7 @@ impl InboundGroupSession {
             room_id: Arc::new(room_id.clone()),
             forwarding_chains: Arc::new(Mutex::new(None)),
             imported: Arc::new(false),
+            backed_up: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -156,6 +165,7 @@ impl InboundGroupSession {
             room_id: Arc::new(content.room_id.clone()),
             forwarding_chains: Arc::new(Mutex::new(Some(forwarding_chains))),
             imported: Arc::new(true),
+            backed_up: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -175,6 +185,8 @@ impl InboundGroupSession {
             room_id: (&*self.room_id).clone(),
             forwarding_chains: self.forwarding_chains.lock().await.clone(),
             imported: *self.imported,
+            backed_up: self.backed_up(),
+            version: PICKLE_VERSION,
         }
     }
 
@@ -238,6 +250,7 @@ impl InboundGroupSession {
             room_id: Arc::new(pickle.room_id),
             forwarding_chains: Arc::new(Mutex::new(pickle.forwarding_chains)),
             imported: Arc::new(pickle.imported),
+            backed_up: Arc::new(AtomicBool::new(pickle.backed_up)),
         })
     }
 
@@ -251,11 +264,58 @@ impl InboundGroupSession {
         &self.session_id
     }
 
+    /// The public curve25519 key of the device that created this session.
+    pub fn sender_key(&self) -> &str {
+        &self.sender_key
+    }
+
+    /// The public ed25519 key that the creator of this session claims to own.
+    pub fn sender_claimed_ed25519_key(&self) -> Option<&str> {
+        self.signing_key
+            .get(&DeviceKeyAlgorithm::Ed25519)
+            .map(|k| k.as_str())
+    }
+
+    /// The chain of curve25519 keys through which this session was forwarded
+    /// to us.
+    ///
+    /// The chain is empty if we received the session directly from its
+    /// creator.
+    pub async fn forwarding_key_chain(&self) -> Vec<String> {
+        self.forwarding_chains
+            .lock()
+            .await
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// Was the session forwarded to us or imported from a key export or
+    /// backup, instead of being received directly from its creator.
+    pub fn is_imported(&self) -> bool {
+        *self.imported
+    }
+
     /// Get the first message index we know how to decrypt.
     pub fn first_known_index(&self) -> u32 {
         self.first_known_index
     }
 
+    /// Has the session been uploaded to the server-side key backup.
+    pub fn backed_up(&self) -> bool {
+        self.backed_up.load(Ordering::SeqCst)
+    }
+
+    /// Mark the session as backed up.
+    pub fn mark_as_backed_up(&self) {
+        self.backed_up.store(true, Ordering::SeqCst)
+    }
+
+    /// Reset the backup state of the session, this is needed if a new backup
+    /// version gets created and the session needs to be uploaded again.
+    pub fn reset_backup_state(&self) {
+        self.backed_up.store(false, Ordering::SeqCst)
+    }
+
     /// Decrypt the given ciphertext.
     ///
     /// Returns the decrypted plaintext or an `OlmGroupSessionError` if
@@ -346,6 +406,25 @@ impl PartialEq for InboundGroupSession {
     }
 }
 
+/// The event that used a specific message index of a Megolm session.
+///
+/// Can be used to check if a Megolm message has been replayed to us.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MegolmMessageIndex {
+    /// The room the Megolm session belongs to.
+    pub room_id: RoomId,
+    /// The curve25519 key of the device that created the Megolm session.
+    pub sender_key: String,
+    /// The unique id of the Megolm session.
+    pub session_id: String,
+    /// The message index that was used.
+    pub message_index: u32,
+    /// The id of the event that used the message index.
+    pub event_id: EventId,
+    /// The timestamp of the event that used the message index.
+    pub origin_server_ts: SystemTime,
+}
+
 /// A pickled version of an `InboundGroupSession`.
 ///
 /// Holds all the information that needs to be stored in a database to restore
@@ -366,6 +445,13 @@ pub struct PickledInboundGroupSession {
     /// Flag remembering if the session was dirrectly sent to us by the sender
     /// or if it was imported.
     pub imported: bool,
+    /// Flag remembering if the session has been uploaded to the server-side
+    /// key backup.
+    #[serde(default)]
+    pub backed_up: bool,
+    /// The version of the pickle format, see `PICKLE_VERSION`.
+    #[serde(default = "crate::olm::default_pickle_version")]
+    pub version: u32,
 }
 
 /// The typed representation of a base64 encoded string of the GroupSession pickle.
@@ -407,6 +493,7 @@ impl TryFrom<ExportedRoomKey> for InboundGroupSession {
             room_id: Arc::new(key.room_id),
             forwarding_chains: Arc::new(Mutex::new(forwarding_chains)),
             imported: Arc::new(true),
+            backed_up: Arc::new(AtomicBool::new(false)),
         })
     }
 }
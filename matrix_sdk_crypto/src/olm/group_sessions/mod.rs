@@ -23,8 +23,13 @@ use zeroize::Zeroize;
 mod inbound;
 mod outbound;
 
-pub use inbound::{InboundGroupSession, InboundGroupSessionPickle, PickledInboundGroupSession};
-pub use outbound::{EncryptionSettings, OutboundGroupSession};
+pub use inbound::{
+    InboundGroupSession, InboundGroupSessionPickle, MegolmMessageIndex, PickledInboundGroupSession,
+};
+pub use outbound::{
+    EncryptionSettings, OutboundGroupSession, OutboundGroupSessionPickle,
+    PickledOutboundGroupSession, PickledShareRequest,
+};
 
 /// The private session key of a group session.
 /// Can be used to create a new inbound group session.
@@ -123,7 +128,7 @@ impl From<ForwardedRoomKeyToDeviceEventContent> for ExportedRoomKey {
 mod test {
     use std::{
         sync::Arc,
-        time::{Duration, Instant},
+        time::{Duration, Instant, SystemTime},
     };
 
     use matrix_sdk_common::{
@@ -131,10 +136,12 @@ mod test {
             room::message::{MessageEventContent, TextMessageEventContent},
             AnyMessageEventContent,
         },
-        identifiers::{room_id, user_id},
+        identifiers::{room_id, user_id, DeviceIdBox},
     };
 
-    use super::EncryptionSettings;
+    use olm_rs::PicklingMode;
+
+    use super::{EncryptionSettings, OutboundGroupSession};
     use crate::ReadOnlyAccount;
 
     #[tokio::test]
@@ -173,4 +180,85 @@ mod test {
         session.creation_time = Arc::new(Instant::now() - Duration::from_secs(60 * 60));
         assert!(session.expired());
     }
+
+    #[tokio::test]
+    async fn outbound_session_pickling() {
+        let settings = EncryptionSettings {
+            rotation_period_msgs: 2,
+            ..Default::default()
+        };
+
+        let account = ReadOnlyAccount::new(&user_id!("@alice:example.org"), "DEVICEID".into());
+        let (session, _) = account
+            .create_group_session_pair(&room_id!("!test_room:example.org"), settings)
+            .await
+            .unwrap();
+
+        let _ = session
+            .encrypt(AnyMessageEventContent::RoomMessage(
+                MessageEventContent::Text(TextMessageEventContent::plain("Test message")),
+            ))
+            .await;
+        let bob_device: DeviceIdBox = "BOBDEVICE".into();
+        session.mark_shared_with(&user_id!("@bob:example.org"), &bob_device);
+        session.mark_as_shared();
+
+        let pickle = session.pickle(PicklingMode::Unencrypted).await;
+
+        let restored = OutboundGroupSession::from_pickle(
+            account.device_id.clone(),
+            account.identity_keys.clone(),
+            pickle,
+            PicklingMode::Unencrypted,
+        )
+        .unwrap();
+
+        assert_eq!(session.session_id(), restored.session_id());
+        assert_eq!(session.room_id(), restored.room_id());
+        assert_eq!(
+            session.message_index().await,
+            restored.message_index().await
+        );
+        assert!(restored.shared());
+        assert!(!restored.invalidated());
+        assert!(restored.is_shared_with(&user_id!("@bob:example.org"), &bob_device));
+
+        // The message count was restored as well, one more message and the
+        // session needs to be rotated.
+        assert!(!restored.expired());
+        let _ = restored
+            .encrypt(AnyMessageEventContent::RoomMessage(
+                MessageEventContent::Text(TextMessageEventContent::plain("Test message")),
+            ))
+            .await;
+        assert!(restored.expired());
+    }
+
+    #[tokio::test]
+    async fn outbound_session_older_than_uptime() {
+        let account = ReadOnlyAccount::new(&user_id!("@alice:example.org"), "DEVICEID".into());
+        let (session, _) = account
+            .create_group_session_pair(
+                &room_id!("!test_room:example.org"),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let mut pickle = session.pickle(PicklingMode::Unencrypted).await;
+        // The system surely wasn't running since the epoch, this can't be
+        // represented as an `Instant`.
+        pickle.creation_time = SystemTime::UNIX_EPOCH;
+
+        let restored = OutboundGroupSession::from_pickle(
+            account.device_id.clone(),
+            account.identity_keys.clone(),
+            pickle,
+            PicklingMode::Unencrypted,
+        )
+        .unwrap();
+
+        assert_eq!(session.session_id(), restored.session_id());
+        assert!(restored.invalidated());
+    }
 }
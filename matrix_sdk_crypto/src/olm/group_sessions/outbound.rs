@@ -13,37 +13,43 @@
 // limitations under the License.
 
 use dashmap::{DashMap, DashSet};
-use matrix_sdk_common::{api::r0::to_device::DeviceIdOrAllDevices, uuid::Uuid};
+use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
+    uuid::{self, Uuid},
+};
 use std::{
     cmp::min,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
     fmt,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 use tracing::debug;
 
 use matrix_sdk_common::{
     events::{
         room::{encrypted::EncryptedEventContent, encryption::EncryptionEventContent},
-        AnyMessageEventContent, EventContent,
+        AnyMessageEventContent, EventContent, EventType,
     },
     identifiers::{DeviceId, DeviceIdBox, EventEncryptionAlgorithm, RoomId, UserId},
     instant::Instant,
     locks::Mutex,
 };
-use serde_json::{json, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::RawValue as RawJsonValue, Value};
 
-use olm_rs::outbound_group_session::OlmOutboundGroupSession;
 pub use olm_rs::{
     account::IdentityKeys,
     session::{OlmMessage, PreKeyMessage},
     utility::OlmUtility,
 };
+use olm_rs::{outbound_group_session::OlmOutboundGroupSession, PicklingMode};
 
-use crate::ToDeviceRequest;
+use crate::{error::SessionUnpicklingError, olm::PICKLE_VERSION, ToDeviceRequest};
 
 use super::GroupSessionKey;
 
@@ -53,7 +59,7 @@ const ROTATION_MESSAGES: u64 = 100;
 /// Settings for an encrypted room.
 ///
 /// This determines the algorithm and rotation periods of a group session.
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EncryptionSettings {
     /// The encryption algorithm that should be used in the room.
     pub algorithm: EventEncryptionAlgorithm,
@@ -151,17 +157,17 @@ impl OutboundGroupSession {
         }
     }
 
-    pub fn add_request(&self, request_id: Uuid, request: Arc<ToDeviceRequest>) {
+    pub(crate) fn add_request(&self, request_id: Uuid, request: Arc<ToDeviceRequest>) {
         self.to_share_with_set.insert(request_id, request);
     }
 
-    pub fn add_recipient(&self, user_id: &UserId) {
+    pub(crate) fn add_recipient(&self, user_id: &UserId) {
         self.shared_with_set
             .entry(user_id.to_owned())
             .or_insert_with(DashSet::new);
     }
 
-    pub fn contains_recipient(&self, user_id: &UserId) -> bool {
+    pub(crate) fn contains_recipient(&self, user_id: &UserId) -> bool {
         self.shared_with_set.contains_key(user_id)
     }
 
@@ -371,6 +377,120 @@ impl OutboundGroupSession {
         shared_with || should_be_shared_with
     }
 
+    /// Get the ids of the to-device requests sharing this session that weren't
+    /// yet marked as sent.
+    pub(crate) fn pending_request_ids(&self) -> Vec<Uuid> {
+        self.to_share_with_set.iter().map(|i| *i.key()).collect()
+    }
+
+    /// Store the group session as a base64 encoded string and the state of
+    /// the session alongside it.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_mode` - The mode that was used to pickle the group session,
+    /// either an unencrypted mode or an encrypted using passphrase.
+    pub async fn pickle(&self, pickle_mode: PicklingMode) -> PickledOutboundGroupSession {
+        let pickle = self.inner.lock().await.pickle(pickle_mode);
+
+        let shared_with_set = self
+            .shared_with_set
+            .iter()
+            .map(|u| {
+                (
+                    u.key().clone(),
+                    u.value().iter().map(|d| d.key().clone()).collect(),
+                )
+            })
+            .collect();
+
+        let requests = self
+            .to_share_with_set
+            .iter()
+            .map(|r| PickledShareRequest::from(r.value().as_ref()))
+            .collect();
+
+        PickledOutboundGroupSession {
+            pickle: OutboundGroupSessionPickle::from(pickle),
+            room_id: (&*self.room_id).clone(),
+            settings: (&*self.settings).clone(),
+            creation_time: SystemTime::now() - self.creation_time.elapsed(),
+            message_count: self.message_count.load(Ordering::SeqCst),
+            shared: self.shared(),
+            invalidated: self.invalidated(),
+            shared_with_set,
+            requests,
+            version: PICKLE_VERSION,
+        }
+    }
+
+    /// Restore an outbound group session from a previously pickled one.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The id of the device that created this session.
+    ///
+    /// * `identity_keys` - The identity keys of the account that created this
+    /// session.
+    ///
+    /// * `pickle` - The pickled version of the `OutboundGroupSession`.
+    ///
+    /// * `pickle_mode` - The mode that was used to pickle the session, either
+    /// an unencrypted mode or an encrypted using passphrase.
+    pub fn from_pickle(
+        device_id: Arc<DeviceIdBox>,
+        identity_keys: Arc<IdentityKeys>,
+        pickle: PickledOutboundGroupSession,
+        pickle_mode: PicklingMode,
+    ) -> Result<Self, SessionUnpicklingError> {
+        let session = OlmOutboundGroupSession::unpickle(pickle.pickle.0, pickle_mode)?;
+        let session_id = session.session_id();
+
+        let age = SystemTime::now()
+            .duration_since(pickle.creation_time)
+            .unwrap_or_default();
+
+        // An `Instant` can't go further back than the start of the system, so
+        // sessions that were created before a reboot might not be
+        // representable. Those are way past any sensible rotation period,
+        // mark them as invalidated so they get replaced instead of refusing
+        // to load them.
+        let (creation_time, invalidated) = match Instant::now().checked_sub(age) {
+            Some(creation_time) => (creation_time, pickle.invalidated),
+            None => (Instant::now(), true),
+        };
+
+        let shared_with_set: DashMap<UserId, DashSet<DeviceIdBox>> = pickle
+            .shared_with_set
+            .into_iter()
+            .map(|(u, d)| (u, d.into_iter().collect()))
+            .collect();
+
+        let to_share_with_set: DashMap<Uuid, Arc<ToDeviceRequest>> = pickle
+            .requests
+            .into_iter()
+            .filter_map(|r| {
+                let request = ToDeviceRequest::try_from(r).ok()?;
+                Some((request.txn_id, Arc::new(request)))
+            })
+            .collect();
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(session)),
+            device_id,
+            account_identity_keys: identity_keys,
+            session_id: session_id.into(),
+            room_id: Arc::new(pickle.room_id),
+            creation_time: Arc::new(creation_time),
+            message_count: Arc::new(AtomicU64::new(pickle.message_count)),
+            shared: Arc::new(AtomicBool::new(pickle.shared)),
+            invalidated: Arc::new(AtomicBool::new(invalidated)),
+            settings: Arc::new(pickle.settings),
+            shared_with_set: Arc::new(shared_with_set),
+            to_share_with_set: Arc::new(to_share_with_set),
+        })
+    }
+
     /// Mark that the session was shared with the given user/device pair.
     #[cfg(test)]
     pub fn mark_shared_with(&self, user_id: &UserId, device_id: &DeviceId) {
@@ -381,6 +501,119 @@ impl OutboundGroupSession {
     }
 }
 
+/// A pickled version of an `OutboundGroupSession`.
+///
+/// Holds all the information that needs to be stored in a database to restore
+/// an OutboundGroupSession.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PickledOutboundGroupSession {
+    /// The pickle string holding the OutboundGroupSession.
+    pub pickle: OutboundGroupSessionPickle,
+    /// The id of the room that the session is used in.
+    pub room_id: RoomId,
+    /// The settings that determine when the session needs to be rotated.
+    pub settings: EncryptionSettings,
+    /// The point in time when the session was created.
+    pub creation_time: SystemTime,
+    /// The number of messages that were encrypted using the session.
+    pub message_count: u64,
+    /// Has the session been shared with all the recipients.
+    pub shared: bool,
+    /// Has the session been invalidated.
+    pub invalidated: bool,
+    /// The set of devices that received the session.
+    pub shared_with_set: BTreeMap<UserId, BTreeSet<DeviceIdBox>>,
+    /// The to-device requests sharing the session that weren't yet marked as
+    /// sent.
+    pub requests: Vec<PickledShareRequest>,
+    /// The version of the pickle format, see `PICKLE_VERSION`.
+    #[serde(default = "crate::olm::default_pickle_version")]
+    pub version: u32,
+}
+
+/// The typed representation of a base64 encoded string of the GroupSession pickle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundGroupSessionPickle(String);
+
+impl From<String> for OutboundGroupSessionPickle {
+    fn from(pickle_string: String) -> Self {
+        OutboundGroupSessionPickle(pickle_string)
+    }
+}
+
+impl OutboundGroupSessionPickle {
+    /// Get the string representation of the pickle.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A pickled version of a to-device request that shares an outbound group
+/// session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PickledShareRequest {
+    /// The unique id of the request.
+    pub txn_id: String,
+    /// The type of the to-device event.
+    pub event_type: EventType,
+    /// The encrypted room key for each recipient device.
+    pub messages: BTreeMap<UserId, BTreeMap<DeviceIdBox, Box<RawJsonValue>>>,
+}
+
+impl From<&ToDeviceRequest> for PickledShareRequest {
+    fn from(request: &ToDeviceRequest) -> Self {
+        let messages = request
+            .messages
+            .iter()
+            .map(|(u, d)| {
+                let devices = d
+                    .iter()
+                    .filter_map(|(d, c)| {
+                        if let DeviceIdOrAllDevices::DeviceId(d) = d {
+                            Some((d.clone(), c.clone()))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+
+                (u.clone(), devices)
+            })
+            .collect();
+
+        Self {
+            txn_id: request.txn_id_string(),
+            event_type: request.event_type.clone(),
+            messages,
+        }
+    }
+}
+
+impl TryFrom<PickledShareRequest> for ToDeviceRequest {
+    type Error = uuid::Error;
+
+    fn try_from(request: PickledShareRequest) -> Result<Self, Self::Error> {
+        let messages = request
+            .messages
+            .into_iter()
+            .map(|(u, d)| {
+                (
+                    u,
+                    d.into_iter()
+                        .map(|(d, c)| (DeviceIdOrAllDevices::DeviceId(d), c))
+                        .collect(),
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            event_type: request.event_type,
+            txn_id: Uuid::parse_str(&request.txn_id)?,
+            messages,
+        })
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 impl std::fmt::Debug for OutboundGroupSession {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -25,19 +25,35 @@ mod utility;
 
 pub(crate) use account::{Account, OlmDecryptionInfo, SessionType};
 pub use account::{AccountPickle, OlmMessageHash, PickledAccount, ReadOnlyAccount};
+pub(crate) use group_sessions::GroupSessionKey;
 pub use group_sessions::{
-    EncryptionSettings, ExportedRoomKey, InboundGroupSession, InboundGroupSessionPickle,
-    PickledInboundGroupSession,
+    EncryptionSettings, ExportedGroupSessionKey, ExportedRoomKey, InboundGroupSession,
+    InboundGroupSessionPickle, MegolmMessageIndex, OutboundGroupSession,
+    OutboundGroupSessionPickle, PickledInboundGroupSession, PickledOutboundGroupSession,
+    PickledShareRequest,
 };
-pub(crate) use group_sessions::{GroupSessionKey, OutboundGroupSession};
 pub use olm_rs::{account::IdentityKeys, PicklingMode};
 pub use session::{PickledSession, Session, SessionPickle};
 pub use signing::{PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
 pub(crate) use utility::Utility;
 
+/// The version of the format of the pickled Olm objects, `PickledAccount`,
+/// `PickledSession`, `PickledInboundGroupSession` and
+/// `PickledOutboundGroupSession`, that this version of the library creates.
+///
+/// Pickles that were created before the format was versioned don't contain a
+/// version and are read as version 1.
+pub const PICKLE_VERSION: u32 = 1;
+
+pub(crate) fn default_pickle_version() -> u32 {
+    PICKLE_VERSION
+}
+
 #[cfg(test)]
 pub(crate) mod test {
-    use crate::olm::{InboundGroupSession, ReadOnlyAccount, Session};
+    use crate::olm::{
+        InboundGroupSession, PickledAccount, PicklingMode, ReadOnlyAccount, Session, PICKLE_VERSION,
+    };
     use matrix_sdk_common::{
         api::r0::keys::SignedKey,
         events::forwarded_room_key::ForwardedRoomKeyToDeviceEventContent,
@@ -76,10 +92,7 @@ pub(crate) mod test {
             .unwrap()
             .1
             .to_owned();
-        let one_time_key = SignedKey {
-            key: one_time_key,
-            signatures: BTreeMap::new(),
-        };
+        let one_time_key = SignedKey::new(one_time_key, BTreeMap::new());
         let sender_key = bob.identity_keys().curve25519().to_owned();
         let session = alice
             .create_outbound_session_helper(&sender_key, &one_time_key)
@@ -154,10 +167,7 @@ pub(crate) mod test {
             .1
             .to_owned();
 
-        let one_time_key = SignedKey {
-            key: one_time_key,
-            signatures: BTreeMap::new(),
-        };
+        let one_time_key = SignedKey::new(one_time_key, BTreeMap::new());
 
         let mut bob_session = bob
             .create_outbound_session_helper(alice_keys.curve25519(), &one_time_key)
@@ -243,4 +253,27 @@ pub(crate) mod test {
 
         assert_eq!(inbound.session_id(), imported.session_id());
     }
+
+    #[tokio::test]
+    async fn unversioned_pickle_loading() {
+        let account = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let pickle = account.pickle(PicklingMode::Unencrypted).await;
+        assert_eq!(pickle.version, PICKLE_VERSION);
+
+        // Pickles created before the format was versioned don't contain a
+        // version, those are read as the first version. They don't know about
+        // pending fallback keys either.
+        let mut pickle = serde_json::to_value(pickle).unwrap();
+        pickle.as_object_mut().unwrap().remove("version");
+        pickle
+            .as_object_mut()
+            .unwrap()
+            .remove("fallback_key_pending");
+        let pickle: PickledAccount = serde_json::from_value(pickle).unwrap();
+        assert_eq!(pickle.version, 1);
+        assert!(!pickle.fallback_key_pending);
+
+        let restored = ReadOnlyAccount::from_pickle(pickle, PicklingMode::Unencrypted).unwrap();
+        assert_eq!(account, restored);
+    }
 }
@@ -27,7 +27,7 @@ use olm_rs::{errors::OlmSessionError, session::OlmSession, PicklingMode};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use super::IdentityKeys;
+use super::{IdentityKeys, PICKLE_VERSION};
 use crate::{
     error::{EventError, OlmResult, SessionUnpicklingError},
     ReadOnlyDevice,
@@ -185,6 +185,7 @@ impl Session {
             // FIXME this should use the duration from the unix epoch.
             creation_time: self.creation_time.elapsed(),
             last_use_time: self.last_use_time.elapsed(),
+            version: PICKLE_VERSION,
         }
     }
 
@@ -258,6 +259,9 @@ pub struct PickledSession {
     pub creation_time: Duration,
     /// The relative time elapsed since the session was last used.
     pub last_use_time: Duration,
+    /// The version of the pickle format, see `PICKLE_VERSION`.
+    #[serde(default = "crate::olm::default_pickle_version")]
+    pub version: u32,
 }
 
 /// The typed representation of a base64 encoded string of the Olm Session pickle.
@@ -31,9 +31,13 @@ use matrix_sdk_common::{
     locks::Mutex,
 };
 
+use zeroize::Zeroizing;
+
 use crate::{
-    error::SignatureError, requests::UploadSigningKeysRequest, OwnUserIdentity, ReadOnlyAccount,
-    ReadOnlyDevice, UserIdentity,
+    error::SignatureError,
+    requests::UploadSigningKeysRequest,
+    secret_storage::{SecretName, SecretStorageError},
+    OwnUserIdentity, ReadOnlyAccount, ReadOnlyDevice, UserIdentity,
 };
 
 use pk_signing::{MasterSigning, PickledSignings, SelfSigning, Signing, SigningError, UserSigning};
@@ -276,7 +280,7 @@ impl PrivateCrossSigningIdentity {
 
     /// Create a new cross signing identity without signing the device that
     /// created it.
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testing"))]
     pub(crate) async fn new(user_id: UserId) -> Self {
         let master = Signing::new();
 
@@ -387,6 +391,214 @@ impl PrivateCrossSigningIdentity {
         })
     }
 
+    /// Export the private part of one of our cross signing keys.
+    ///
+    /// The key is exported as an unpadded base64 encoded string, as described
+    /// in the secret storage part of the spec.
+    ///
+    /// Returns `None` if we don't have the private key or if the secret name
+    /// doesn't belong to a cross signing key.
+    pub(crate) async fn export_secret(
+        &self,
+        secret_name: &SecretName,
+    ) -> Option<Zeroizing<String>> {
+        match secret_name {
+            SecretName::CrossSigningMasterKey => self
+                .master_key
+                .lock()
+                .await
+                .as_ref()
+                .map(|m| m.inner.export_seed()),
+            SecretName::CrossSigningSelfSigningKey => self
+                .self_signing_key
+                .lock()
+                .await
+                .as_ref()
+                .map(|s| s.inner.export_seed()),
+            SecretName::CrossSigningUserSigningKey => self
+                .user_signing_key
+                .lock()
+                .await
+                .as_ref()
+                .map(|u| u.inner.export_seed()),
+            _ => None,
+        }
+    }
+
+    /// Import private cross signing keys into this identity, e.g. after they
+    /// were decrypted from secret storage.
+    ///
+    /// Only the given keys are replaced, the rest of the identity is kept.
+    /// Every key is checked against the public key we already know for it,
+    /// from our public cross signing identity if we have one, otherwise from
+    /// this identity. Nothing is imported if any of the keys doesn't match.
+    ///
+    /// The public keys we already know are kept so existing signatures aren't
+    /// lost, new subkeys get signed by the master key if it's available.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_identity` - Our own public cross signing identity, if we
+    /// know it.
+    ///
+    /// * `master_key` - The unpadded base64 encoded private master key.
+    ///
+    /// * `self_signing_key` - The unpadded base64 encoded private self signing
+    /// key.
+    ///
+    /// * `user_signing_key` - The unpadded base64 encoded private user signing
+    /// key.
+    pub(crate) async fn import_secrets(
+        &self,
+        public_identity: Option<&OwnUserIdentity>,
+        master_key: Option<&str>,
+        self_signing_key: Option<&str>,
+        user_signing_key: Option<&str>,
+    ) -> Result<(), SecretStorageError> {
+        fn signing(seed: &str, secret_name: SecretName) -> Result<Signing, SecretStorageError> {
+            Signing::from_exported_seed(seed)
+                .map_err(|_| SecretStorageError::InvalidPrivateKey(secret_name))
+        }
+
+        let user_id = self.user_id().to_owned();
+
+        let mut master_lock = self.master_key.lock().await;
+        let mut self_signing_lock = self.self_signing_key.lock().await;
+        let mut user_signing_lock = self.user_signing_key.lock().await;
+
+        let master = if let Some(seed) = master_key {
+            let name = SecretName::CrossSigningMasterKey;
+            let mut master =
+                MasterSigning::from_signing(user_id.clone(), signing(seed, name.clone())?);
+
+            let known = public_identity
+                .map(|i| i.master_key().clone())
+                .or_else(|| master_lock.as_ref().map(|m| m.public_key.clone()));
+
+            if let Some(known) = known {
+                if master.public_key != known {
+                    return Err(SecretStorageError::IdentityMismatch(name));
+                }
+
+                master.public_key = known;
+            }
+
+            Some(master)
+        } else {
+            None
+        };
+
+        let signing_master = master.as_ref().or_else(|| master_lock.as_ref());
+
+        let self_signing = if let Some(seed) = self_signing_key {
+            let name = SecretName::CrossSigningSelfSigningKey;
+            let mut self_signing = SelfSigning::from_signing(
+                user_id.clone(),
+                signing(seed, name.clone())?,
+                signing_master,
+            )
+            .await;
+
+            let known = public_identity
+                .map(|i| i.self_signing_key().clone())
+                .or_else(|| self_signing_lock.as_ref().map(|s| s.public_key.clone()));
+
+            if let Some(known) = known {
+                if self_signing.public_key != known {
+                    return Err(SecretStorageError::IdentityMismatch(name));
+                }
+
+                self_signing.public_key = known;
+            }
+
+            Some(self_signing)
+        } else {
+            None
+        };
+
+        let user_signing = if let Some(seed) = user_signing_key {
+            let name = SecretName::CrossSigningUserSigningKey;
+            let mut user_signing =
+                UserSigning::from_signing(user_id, signing(seed, name.clone())?, signing_master)
+                    .await;
+
+            let known = public_identity
+                .map(|i| i.user_signing_key().clone())
+                .or_else(|| user_signing_lock.as_ref().map(|u| u.public_key.clone()));
+
+            if let Some(known) = known {
+                if user_signing.public_key != known {
+                    return Err(SecretStorageError::IdentityMismatch(name));
+                }
+
+                user_signing.public_key = known;
+            }
+
+            Some(user_signing)
+        } else {
+            None
+        };
+
+        let imported = master.is_some() || self_signing.is_some() || user_signing.is_some();
+
+        if let Some(master) = master {
+            *master_lock = Some(master);
+        }
+
+        if let Some(self_signing) = self_signing {
+            *self_signing_lock = Some(self_signing);
+        }
+
+        if let Some(user_signing) = user_signing {
+            *user_signing_lock = Some(user_signing);
+        }
+
+        if imported {
+            self.mark_as_shared();
+        }
+
+        Ok(())
+    }
+
+    /// Import a single private cross signing key, e.g. one that was sent to
+    /// us by one of our other devices.
+    ///
+    /// The private key is checked against our public cross signing identity,
+    /// the public key of the identity is kept so existing signatures aren't
+    /// lost.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_identity` - Our own public cross signing identity.
+    ///
+    /// * `secret_name` - The name of the secret that should be imported.
+    ///
+    /// * `seed` - The unpadded base64 encoded private key.
+    pub(crate) async fn import_secret(
+        &self,
+        public_identity: &OwnUserIdentity,
+        secret_name: &SecretName,
+        seed: &str,
+    ) -> Result<(), SecretStorageError> {
+        let public_identity = Some(public_identity);
+
+        match secret_name {
+            SecretName::CrossSigningMasterKey => {
+                self.import_secrets(public_identity, Some(seed), None, None)
+                    .await
+            }
+            SecretName::CrossSigningSelfSigningKey => {
+                self.import_secrets(public_identity, None, Some(seed), None)
+                    .await
+            }
+            SecretName::CrossSigningUserSigningKey => {
+                self.import_secrets(public_identity, None, None, Some(seed))
+                    .await
+            }
+            _ => Err(SecretStorageError::InvalidPrivateKey(secret_name.clone())),
+        }
+    }
+
     /// Get the upload request that is needed to share the public keys of this
     /// identity.
     pub(crate) async fn as_upload_request(&self) -> UploadSigningKeysRequest {
@@ -431,6 +643,7 @@ mod test {
     use std::{collections::BTreeMap, sync::Arc};
 
     use super::{PrivateCrossSigningIdentity, Signing};
+    use crate::secret_storage::SecretName;
 
     use matrix_sdk_common::{
         api::r0::keys::CrossSigningKey,
@@ -531,6 +744,54 @@ mod test {
         );
     }
 
+    #[async_test]
+    async fn identity_secret_export() {
+        let identity = PrivateCrossSigningIdentity::new(user_id()).await;
+
+        let master = identity
+            .export_secret(&SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap();
+        let self_signing = identity
+            .export_secret(&SecretName::CrossSigningSelfSigningKey)
+            .await
+            .unwrap();
+        let user_signing = identity
+            .export_secret(&SecretName::CrossSigningUserSigningKey)
+            .await
+            .unwrap();
+
+        assert!(identity
+            .export_secret(&SecretName::RecoveryKey)
+            .await
+            .is_none());
+
+        let restored = PrivateCrossSigningIdentity::empty(user_id());
+        restored
+            .import_secrets(
+                None,
+                Some(&master),
+                Some(&self_signing),
+                Some(&user_signing),
+            )
+            .await
+            .unwrap();
+
+        assert!(restored.shared());
+        assert_eq!(
+            &*identity.master_key.lock().await,
+            &*restored.master_key.lock().await
+        );
+        assert_eq!(
+            &*identity.user_signing_key.lock().await,
+            &*restored.user_signing_key.lock().await
+        );
+        assert_eq!(
+            &*identity.self_signing_key.lock().await,
+            &*restored.self_signing_key.lock().await
+        );
+    }
+
     #[async_test]
     async fn private_identity_signed_by_accound() {
         let account = ReadOnlyAccount::new(&user_id(), "DEVICEID".into());
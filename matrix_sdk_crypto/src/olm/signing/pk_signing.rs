@@ -42,11 +42,15 @@ use matrix_sdk_common::{
 use crate::{
     error::SignatureError,
     identities::{MasterPubkey, SelfSigningPubkey, UserSigningPubkey},
-    utilities::{decode_url_safe as decode, encode_url_safe as encode, DecodeError},
+    utilities::{
+        decode as decode_standard, decode_url_safe as decode, encode as encode_standard,
+        encode_url_safe as encode, DecodeError,
+    },
     UserIdentity,
 };
 
 const NONCE_SIZE: usize = 12;
+const SEED_SIZE: usize = 32;
 
 /// Error type reporting failures in the Signign operations.
 #[derive(Debug, Error)]
@@ -62,6 +66,10 @@ pub enum SigningError {
     /// Error deserializing the pickle data.
     #[error(transparent)]
     Json(#[from] JsonError),
+
+    /// The exported private key has an invalid length.
+    #[error("The exported private key has an invalid length")]
+    InvalidSeedLength,
 }
 
 #[derive(Clone)]
@@ -144,6 +152,11 @@ impl PublicSigningKey {
 }
 
 impl MasterSigning {
+    pub fn from_signing(user_id: UserId, inner: Signing) -> Self {
+        let public_key = inner.cross_signing_key(user_id, KeyUsage::Master).into();
+        Self { inner, public_key }
+    }
+
     pub async fn pickle(&self, pickle_key: &[u8]) -> PickledMasterSigning {
         let pickle = self.inner.pickle(pickle_key).await;
         let public_key = self.public_key.clone().into();
@@ -189,6 +202,23 @@ impl MasterSigning {
 }
 
 impl UserSigning {
+    pub async fn from_signing(
+        user_id: UserId,
+        inner: Signing,
+        master: Option<&MasterSigning>,
+    ) -> Self {
+        let mut public_key = inner.cross_signing_key(user_id, KeyUsage::UserSigning);
+
+        if let Some(master) = master {
+            master.sign_subkey(&mut public_key).await;
+        }
+
+        Self {
+            inner,
+            public_key: public_key.into(),
+        }
+    }
+
     pub async fn pickle(&self, pickle_key: &[u8]) -> PickledUserSigning {
         let pickle = self.inner.pickle(pickle_key).await;
         let public_key = self.public_key.clone().into();
@@ -236,6 +266,23 @@ impl UserSigning {
 }
 
 impl SelfSigning {
+    pub async fn from_signing(
+        user_id: UserId,
+        inner: Signing,
+        master: Option<&MasterSigning>,
+    ) -> Self {
+        let mut public_key = inner.cross_signing_key(user_id, KeyUsage::SelfSigning);
+
+        if let Some(master) = master {
+            master.sign_subkey(&mut public_key).await;
+        }
+
+        Self {
+            inner,
+            public_key: public_key.into(),
+        }
+    }
+
     pub async fn pickle(&self, pickle_key: &[u8]) -> PickledSelfSigning {
         let pickle = self.inner.pickle(pickle_key).await;
         let public_key = self.public_key.clone().into();
@@ -328,6 +375,20 @@ impl Signing {
         }
     }
 
+    pub fn from_exported_seed(seed: &str) -> Result<Self, SigningError> {
+        let seed = Zeroizing::new(decode_standard(seed)?);
+
+        if seed.len() != SEED_SIZE {
+            return Err(SigningError::InvalidSeedLength);
+        }
+
+        Ok(Self::from_seed(seed.to_vec()))
+    }
+
+    pub fn export_seed(&self) -> Zeroizing<String> {
+        Zeroizing::new(encode_standard(self.seed.as_slice()))
+    }
+
     pub fn from_pickle(pickle: PickledSigning, pickle_key: &[u8]) -> Result<Self, SigningError> {
         let pickled: InnerPickle = serde_json::from_str(pickle.as_str())?;
 
@@ -16,6 +16,7 @@ use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use matrix_sdk_common::{
     api::r0::{
+        backup::{add_backup_keys::Response as KeysBackupResponse, RoomKeyBackup},
         keys::{
             claim_keys::Response as KeysClaimResponse,
             get_keys::Response as KeysQueryResponse,
@@ -26,10 +27,11 @@ use matrix_sdk_common::{
             upload_signing_keys::Response as SigningKeysUploadResponse,
             CrossSigningKey,
         },
+        message::send_message_event::Response as RoomMessageResponse,
         to_device::{send_event_to_device::Response as ToDeviceResponse, DeviceIdOrAllDevices},
     },
-    events::EventType,
-    identifiers::{DeviceIdBox, UserId},
+    events::{AnyMessageEventContent, EventType},
+    identifiers::{DeviceIdBox, RoomId, UserId},
     uuid::Uuid,
 };
 
@@ -61,6 +63,67 @@ impl ToDeviceRequest {
     }
 }
 
+/// Request that will send a message event to a room.
+#[derive(Clone, Debug)]
+pub struct RoomMessageRequest {
+    /// The room to send the event to.
+    pub room_id: RoomId,
+
+    /// The transaction ID for this event.
+    ///
+    /// Clients should generate an ID unique across requests with the
+    /// same access token; it will be used by the server to ensure
+    /// idempotency of requests.
+    pub txn_id: Uuid,
+
+    /// The event content to send.
+    pub content: AnyMessageEventContent,
+}
+
+/// Enum over the different outgoing requests an interactive verification flow
+/// can produce.
+///
+/// Verifications between devices use to-device requests while verifications
+/// of other users can be done in a room, using room message requests.
+#[derive(Clone, Debug)]
+pub enum OutgoingVerificationRequest {
+    /// The to-device verification request variant.
+    ToDevice(ToDeviceRequest),
+    /// The in-room verification request variant.
+    InRoom(RoomMessageRequest),
+}
+
+impl OutgoingVerificationRequest {
+    /// Get the unique id of this request.
+    pub fn request_id(&self) -> Uuid {
+        match self {
+            OutgoingVerificationRequest::ToDevice(t) => t.txn_id,
+            OutgoingVerificationRequest::InRoom(r) => r.txn_id,
+        }
+    }
+}
+
+impl From<ToDeviceRequest> for OutgoingVerificationRequest {
+    fn from(r: ToDeviceRequest) -> Self {
+        OutgoingVerificationRequest::ToDevice(r)
+    }
+}
+
+impl From<RoomMessageRequest> for OutgoingVerificationRequest {
+    fn from(r: RoomMessageRequest) -> Self {
+        OutgoingVerificationRequest::InRoom(r)
+    }
+}
+
+impl From<OutgoingVerificationRequest> for OutgoingRequests {
+    fn from(request: OutgoingVerificationRequest) -> Self {
+        match request {
+            OutgoingVerificationRequest::ToDevice(r) => OutgoingRequests::ToDeviceRequest(r),
+            OutgoingVerificationRequest::InRoom(r) => OutgoingRequests::RoomMessage(r),
+        }
+    }
+}
+
 /// Request that will publish a cross signing identity.
 ///
 /// This uploads the public cross signing key triplet.
@@ -105,6 +168,17 @@ impl KeysQueryRequest {
     }
 }
 
+/// Customized version of `ruma_client_api::r0::backup::add_backup_keys::Request`, without any
+/// references.
+#[derive(Clone, Debug)]
+pub struct KeysBackupRequest {
+    /// The backup version that the room keys should be uploaded to.
+    pub version: String,
+
+    /// A map of room ids to the encrypted room keys that should be backed up.
+    pub rooms: BTreeMap<RoomId, RoomKeyBackup>,
+}
+
 /// Enum over the different outgoing requests we can have.
 #[derive(Debug)]
 pub enum OutgoingRequests {
@@ -120,6 +194,12 @@ pub enum OutgoingRequests {
     /// Signature upload request, this request is used after a successful device
     /// or user verification is done.
     SignatureUpload(SignatureUploadRequest),
+    /// A room keys backup request, uploading encrypted room keys to the
+    /// server-side key backup.
+    KeysBackup(KeysBackupRequest),
+    /// A room message request, this request is used to send the events of an
+    /// in-room interactive verification.
+    RoomMessage(RoomMessageRequest),
 }
 
 #[cfg(test)]
@@ -130,6 +210,13 @@ impl OutgoingRequests {
             _ => None,
         }
     }
+
+    pub fn room_message(&self) -> Option<&RoomMessageRequest> {
+        match self {
+            OutgoingRequests::RoomMessage(r) => Some(r),
+            _ => None,
+        }
+    }
 }
 
 impl From<KeysQueryRequest> for OutgoingRequests {
@@ -156,6 +243,18 @@ impl From<SignatureUploadRequest> for OutgoingRequests {
     }
 }
 
+impl From<KeysBackupRequest> for OutgoingRequests {
+    fn from(request: KeysBackupRequest) -> Self {
+        OutgoingRequests::KeysBackup(request)
+    }
+}
+
+impl From<RoomMessageRequest> for OutgoingRequests {
+    fn from(request: RoomMessageRequest) -> Self {
+        OutgoingRequests::RoomMessage(request)
+    }
+}
+
 /// Enum over all the incoming responses we need to receive.
 #[derive(Debug)]
 pub enum IncomingResponse<'a> {
@@ -176,6 +275,12 @@ pub enum IncomingResponse<'a> {
     /// The cross signing keys upload response, marking our private cross
     /// signing identity as shared.
     SignatureUpload(&'a SignatureUploadResponse),
+    /// The room keys backup response, marking the uploaded room keys as backed
+    /// up.
+    KeysBackup(&'a KeysBackupResponse),
+    /// The room message response, an in-room verification event was sent
+    /// out.
+    RoomMessage(&'a RoomMessageResponse),
 }
 
 impl<'a> From<&'a KeysUploadResponse> for IncomingResponse<'a> {
@@ -208,6 +313,18 @@ impl<'a> From<&'a SignatureUploadResponse> for IncomingResponse<'a> {
     }
 }
 
+impl<'a> From<&'a KeysBackupResponse> for IncomingResponse<'a> {
+    fn from(response: &'a KeysBackupResponse) -> Self {
+        IncomingResponse::KeysBackup(response)
+    }
+}
+
+impl<'a> From<&'a RoomMessageResponse> for IncomingResponse<'a> {
+    fn from(response: &'a RoomMessageResponse) -> Self {
+        IncomingResponse::RoomMessage(response)
+    }
+}
+
 /// Outgoing request type, holds the unique ID of the request and the actual
 /// request.
 #[derive(Debug, Clone)]
@@ -0,0 +1,773 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sharing of secrets, e.g. our private cross signing keys, between our own
+//! devices using the `m.secret.request` and `m.secret.send` events.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value, value::RawValue as RawJsonValue};
+use std::{collections::BTreeMap, sync::Arc};
+use thiserror::Error;
+use tracing::{info, trace, warn};
+use zeroize::Zeroizing;
+
+use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
+    events::EventType,
+    identifiers::{DeviceIdBox, UserId},
+    locks::Mutex,
+    uuid::Uuid,
+};
+
+use crate::{
+    error::{OlmError, OlmResult},
+    olm::{PrivateCrossSigningIdentity, Session},
+    requests::{OutgoingRequest, ToDeviceRequest},
+    secret_storage::{SecretName, SecretStorageError},
+    store::{Changes, CryptoStoreError, Store},
+    Device,
+};
+
+/// The event type of secret requests.
+pub(crate) const SECRET_REQUEST_EVENT_TYPE: &str = "m.secret.request";
+
+/// The event type of the events that carry a requested secret.
+pub(crate) const SECRET_SEND_EVENT_TYPE: &str = "m.secret.send";
+
+/// An error describing why a secret request won't be honored.
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum SecretShareDecision {
+    /// The secret request is from a device that we don't own, secrets are
+    /// only shared between our own devices.
+    #[error("the requesting device doesn't belong to us")]
+    ForeignDevice,
+    /// The secret request is from a device we own, yet we don't trust it.
+    #[error("requesting device isn't trusted")]
+    UntrustedDevice,
+    /// We don't have the requested secret.
+    #[error("the requested secret isn't available")]
+    MissingSecret,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SecretRequestAction {
+    Request,
+    RequestCancellation,
+}
+
+/// The content of a `m.secret.request` event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SecretRequestContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    action: SecretRequestAction,
+    requesting_device_id: DeviceIdBox,
+    request_id: String,
+}
+
+/// The content of a `m.secret.send` event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SecretSendContent {
+    request_id: String,
+    secret: String,
+}
+
+/// The parts of a secret sharing to-device event that we care about.
+#[derive(Clone, Debug, Deserialize)]
+struct SecretEvent<C> {
+    sender: UserId,
+    content: C,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct EventTypeDeHelper {
+    #[serde(rename = "type")]
+    event_type: String,
+}
+
+/// Get the event type of a raw event.
+pub(crate) fn raw_event_type(event: &RawJsonValue) -> Option<String> {
+    serde_json::from_str::<EventTypeDeHelper>(event.get())
+        .ok()
+        .map(|e| e.event_type)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OutgoingSecretInfo {
+    request_id: Uuid,
+    secret_name: String,
+    sent_out: bool,
+}
+
+fn secret_request_key(request_id: &str) -> String {
+    format!("secret_request|{}", request_id)
+}
+
+fn secret_inbox_key(secret_name: &SecretName) -> String {
+    format!("secret_inbox|{}", secret_name)
+}
+
+fn wrap_secret_request_content(
+    recipient: UserId,
+    id: Uuid,
+    content: &SecretRequestContent,
+) -> Result<OutgoingRequest, serde_json::Error> {
+    let mut messages = BTreeMap::new();
+
+    messages
+        .entry(recipient)
+        .or_insert_with(BTreeMap::new)
+        .insert(DeviceIdOrAllDevices::AllDevices, to_raw_value(content)?);
+
+    Ok(OutgoingRequest {
+        request_id: id,
+        request: Arc::new(
+            ToDeviceRequest {
+                event_type: EventType::Custom(SECRET_REQUEST_EVENT_TYPE.to_owned()),
+                txn_id: id,
+                messages,
+            }
+            .into(),
+        ),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SecretSharingMachine {
+    user_id: Arc<UserId>,
+    device_id: Arc<DeviceIdBox>,
+    store: Store,
+    identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
+    outgoing_to_device_requests: Arc<DashMap<Uuid, OutgoingRequest>>,
+    incoming_secret_requests:
+        Arc<DashMap<(UserId, DeviceIdBox, String), SecretEvent<SecretRequestContent>>>,
+}
+
+impl SecretSharingMachine {
+    pub fn new(
+        user_id: Arc<UserId>,
+        device_id: Arc<DeviceIdBox>,
+        store: Store,
+        identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
+    ) -> Self {
+        Self {
+            user_id,
+            device_id,
+            store,
+            identity,
+            outgoing_to_device_requests: Arc::new(DashMap::new()),
+            incoming_secret_requests: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Our own user id.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    pub fn outgoing_to_device_requests(&self) -> Vec<OutgoingRequest> {
+        #[allow(clippy::map_clone)]
+        self.outgoing_to_device_requests
+            .iter()
+            .map(|r| (*r).clone())
+            .collect()
+    }
+
+    /// Receive a `m.secret.request` event.
+    ///
+    /// Cancellations remove the matching request from our queue if we didn't
+    /// handle it yet.
+    pub fn receive_incoming_secret_request(&self, event: &RawJsonValue) {
+        let event: SecretEvent<SecretRequestContent> = match serde_json::from_str(event.get()) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Received an invalid secret request {:?}", e);
+                return;
+            }
+        };
+
+        let key = (
+            event.sender.clone(),
+            event.content.requesting_device_id.clone(),
+            event.content.request_id.clone(),
+        );
+
+        match event.content.action {
+            SecretRequestAction::Request => {
+                self.incoming_secret_requests.insert(key, event);
+            }
+            SecretRequestAction::RequestCancellation => {
+                self.incoming_secret_requests.remove(&key);
+            }
+        }
+    }
+
+    /// Handle all the incoming secret requests that are queued up and empty
+    /// our secret request queue.
+    pub async fn collect_incoming_secret_requests(&self) -> OlmResult<Vec<Session>> {
+        let mut changed_sessions = Vec::new();
+
+        for item in self.incoming_secret_requests.iter() {
+            if let Some(s) = self.handle_secret_request(item.value()).await? {
+                changed_sessions.push(s);
+            }
+        }
+
+        self.incoming_secret_requests.clear();
+
+        Ok(changed_sessions)
+    }
+
+    /// Handle a single incoming secret request.
+    async fn handle_secret_request(
+        &self,
+        event: &SecretEvent<SecretRequestContent>,
+    ) -> OlmResult<Option<Session>> {
+        let secret_name: SecretName = if let Some(name) = &event.content.name {
+            name.as_str().into()
+        } else {
+            warn!(
+                "Received a secret request from {} {} without a secret name",
+                event.sender, event.content.requesting_device_id
+            );
+            return Ok(None);
+        };
+
+        if event.sender == *self.user_id && event.content.requesting_device_id == *self.device_id {
+            trace!("Ignoring a secret request that we sent out ourselves");
+            return Ok(None);
+        }
+
+        let device = if let Some(d) = self
+            .store
+            .get_device(&event.sender, &event.content.requesting_device_id)
+            .await?
+        {
+            d
+        } else {
+            warn!(
+                "Received a secret request from an unknown device {} {}.",
+                event.sender, event.content.requesting_device_id
+            );
+            self.store.update_tracked_user(&event.sender, true).await?;
+
+            return Ok(None);
+        };
+
+        if let Err(e) = self.should_share_secret(&device) {
+            info!(
+                "Received a secret request for {} from {} {} that we won't serve: {}",
+                secret_name,
+                device.user_id(),
+                device.device_id(),
+                e
+            );
+            return Ok(None);
+        }
+
+        let secret = if let Some(s) = self.get_secret(&secret_name).await? {
+            s
+        } else {
+            info!(
+                "Received a secret request for {} from {} {} that we won't serve: {}",
+                secret_name,
+                device.user_id(),
+                device.device_id(),
+                SecretShareDecision::MissingSecret
+            );
+            return Ok(None);
+        };
+
+        info!(
+            "Serving a secret request for {} from {} {}.",
+            secret_name,
+            device.user_id(),
+            device.device_id()
+        );
+
+        match self
+            .share_secret(&device, &event.content.request_id, &secret)
+            .await
+        {
+            Ok(s) => Ok(Some(s)),
+            Err(OlmError::MissingSession) => {
+                warn!(
+                    "Can't serve a secret request from {} {}, no Olm session found",
+                    device.user_id(),
+                    device.device_id()
+                );
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check if it's ok to share a secret with the given device.
+    ///
+    /// Secrets are only shared with our own devices and only if we trust
+    /// them.
+    fn should_share_secret(&self, device: &Device) -> Result<(), SecretShareDecision> {
+        if device.user_id() != self.user_id() {
+            Err(SecretShareDecision::ForeignDevice)
+        } else if !device.trust_state() {
+            Err(SecretShareDecision::UntrustedDevice)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get a secret that we can share, either a private cross signing key or
+    /// a secret that we previously received.
+    async fn get_secret(
+        &self,
+        secret_name: &SecretName,
+    ) -> Result<Option<Zeroizing<String>>, CryptoStoreError> {
+        if let Some(secret) = self.identity.lock().await.export_secret(secret_name).await {
+            Ok(Some(secret))
+        } else {
+            Ok(self.get_secrets_from_inbox(secret_name).await?.pop())
+        }
+    }
+
+    async fn share_secret(
+        &self,
+        device: &Device,
+        request_id: &str,
+        secret: &str,
+    ) -> OlmResult<Session> {
+        let content = json!({
+            "request_id": request_id,
+            "secret": secret,
+        });
+
+        let (used_session, content) = device
+            .encrypt(
+                EventType::Custom(SECRET_SEND_EVENT_TYPE.to_owned()),
+                content,
+            )
+            .await?;
+
+        let id = Uuid::new_v4();
+        let mut messages = BTreeMap::new();
+
+        messages
+            .entry(device.user_id().to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(
+                DeviceIdOrAllDevices::DeviceId(device.device_id().into()),
+                to_raw_value(&content)?,
+            );
+
+        let request = OutgoingRequest {
+            request_id: id,
+            request: Arc::new(
+                ToDeviceRequest {
+                    event_type: EventType::RoomEncrypted,
+                    txn_id: id,
+                    messages,
+                }
+                .into(),
+            ),
+        };
+
+        self.outgoing_to_device_requests.insert(id, request);
+
+        Ok(used_session)
+    }
+
+    /// Request a secret from all of our other devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret that should be requested.
+    pub async fn request_secret(&self, secret_name: SecretName) -> Result<(), CryptoStoreError> {
+        let id = Uuid::new_v4();
+
+        info!("Creating new outgoing secret request for {}", secret_name);
+
+        let content = SecretRequestContent {
+            name: Some(secret_name.to_string()),
+            action: SecretRequestAction::Request,
+            requesting_device_id: (&*self.device_id).clone(),
+            request_id: id.to_string(),
+        };
+
+        let request = wrap_secret_request_content(self.user_id().clone(), id, &content)?;
+
+        let info = OutgoingSecretInfo {
+            request_id: id,
+            secret_name: secret_name.to_string(),
+            sent_out: false,
+        };
+
+        self.store
+            .save_object(&secret_request_key(&id.to_string()), &info)
+            .await?;
+        self.outgoing_to_device_requests.insert(id, request);
+
+        Ok(())
+    }
+
+    /// Mark the outgoing request as sent.
+    pub async fn mark_outgoing_request_as_sent(&self, id: &Uuid) -> Result<(), CryptoStoreError> {
+        self.outgoing_to_device_requests.remove(id);
+        let key = secret_request_key(&id.to_string());
+        let info: Option<OutgoingSecretInfo> = self.store.get_object(&key).await?;
+
+        if let Some(mut info) = info {
+            trace!("Marking outgoing secret request as sent {:?}", info);
+            info.sent_out = true;
+            self.store.save_object(&key, &info).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark the given outgoing secret request as done.
+    ///
+    /// This will queue up a request cancellation for our other devices.
+    async fn mark_as_done(&self, info: OutgoingSecretInfo) -> Result<(), CryptoStoreError> {
+        self.outgoing_to_device_requests.remove(&info.request_id);
+        self.store
+            .delete_object(&secret_request_key(&info.request_id.to_string()))
+            .await?;
+
+        let content = SecretRequestContent {
+            name: None,
+            action: SecretRequestAction::RequestCancellation,
+            requesting_device_id: (&*self.device_id).clone(),
+            request_id: info.request_id.to_string(),
+        };
+
+        let id = Uuid::new_v4();
+        let request = wrap_secret_request_content(self.user_id().clone(), id, &content)?;
+        self.outgoing_to_device_requests.insert(id, request);
+
+        Ok(())
+    }
+
+    /// Receive a decrypted `m.secret.send` event.
+    ///
+    /// The secret is only accepted if we requested it and if it was sent by
+    /// one of our own trusted devices. Private cross signing keys are imported
+    /// into our private identity, other secrets are put into the secret inbox.
+    ///
+    /// Returns the name of the received secret if it was accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender_key` - The curve25519 key of the device that sent the secret.
+    ///
+    /// * `event` - The decrypted `m.secret.send` event.
+    pub async fn receive_secret(
+        &self,
+        sender_key: &str,
+        event: &RawJsonValue,
+    ) -> Result<Option<SecretName>, CryptoStoreError> {
+        let event: SecretEvent<SecretSendContent> = match serde_json::from_str(event.get()) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Received an invalid m.secret.send event {:?}", e);
+                return Ok(None);
+            }
+        };
+
+        let key = secret_request_key(&event.content.request_id);
+        let info: OutgoingSecretInfo = if let Some(i) = self.store.get_object(&key).await? {
+            i
+        } else {
+            info!(
+                "Received a secret from {}, but no matching secret request was found",
+                event.sender
+            );
+            return Ok(None);
+        };
+
+        if event.sender != *self.user_id {
+            warn!(
+                "Received a secret from {} which isn't our own user",
+                event.sender
+            );
+            return Ok(None);
+        }
+
+        let device = self
+            .store
+            .get_device_from_curve_key(&event.sender, sender_key)
+            .await?;
+
+        match device {
+            Some(d) if d.trust_state() => (),
+            _ => {
+                warn!(
+                    "Received a secret from {} with the sender key {}, but the \
+                     device isn't trusted",
+                    event.sender, sender_key
+                );
+                return Ok(None);
+            }
+        }
+
+        let secret_name: SecretName = info.secret_name.as_str().into();
+        let secret = Zeroizing::new(event.content.secret);
+
+        match secret_name {
+            SecretName::CrossSigningMasterKey
+            | SecretName::CrossSigningSelfSigningKey
+            | SecretName::CrossSigningUserSigningKey => {
+                if let Err(e) = self.import_cross_signing_key(&secret_name, &secret).await {
+                    warn!("Couldn't import the received secret {}: {}", secret_name, e);
+                    return Ok(None);
+                }
+            }
+            _ => self.add_to_inbox(&secret_name, &secret).await?,
+        }
+
+        info!("Received and stored the secret {}", secret_name);
+
+        self.mark_as_done(info).await?;
+
+        Ok(Some(secret_name))
+    }
+
+    async fn import_cross_signing_key(
+        &self,
+        secret_name: &SecretName,
+        secret: &str,
+    ) -> Result<(), SecretStorageError> {
+        let public_identity = self
+            .store
+            .get_user_identity(&self.user_id)
+            .await?
+            .and_then(|i| i.own().cloned())
+            .ok_or_else(|| SecretStorageError::IdentityMismatch(secret_name.clone()))?;
+
+        let identity = self.identity.lock().await;
+        identity
+            .import_secret(&public_identity, secret_name, secret)
+            .await?;
+
+        let changes = Changes {
+            private_identity: Some(identity.clone()),
+            ..Default::default()
+        };
+
+        Ok(self.store.save_changes(changes).await?)
+    }
+
+    async fn add_to_inbox(
+        &self,
+        secret_name: &SecretName,
+        secret: &str,
+    ) -> Result<(), CryptoStoreError> {
+        let key = secret_inbox_key(secret_name);
+        let mut secrets: Vec<String> = self.store.get_object(&key).await?.unwrap_or_default();
+
+        if !secrets.iter().any(|s| s == secret) {
+            secrets.push(secret.to_owned());
+        }
+
+        self.store.save_object(&key, &secrets).await
+    }
+
+    /// Get the secrets with the given name that we received from our other
+    /// devices.
+    pub async fn get_secrets_from_inbox(
+        &self,
+        secret_name: &SecretName,
+    ) -> Result<Vec<Zeroizing<String>>, CryptoStoreError> {
+        let secrets: Vec<String> = self
+            .store
+            .get_object(&secret_inbox_key(secret_name))
+            .await?
+            .unwrap_or_default();
+
+        Ok(secrets.into_iter().map(Zeroizing::new).collect())
+    }
+
+    /// Delete all the secrets with the given name from the secret inbox.
+    pub async fn delete_secrets_from_inbox(
+        &self,
+        secret_name: &SecretName,
+    ) -> Result<(), CryptoStoreError> {
+        self.store
+            .delete_object(&secret_inbox_key(secret_name))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::{
+        api::r0::to_device::DeviceIdOrAllDevices,
+        identifiers::{user_id, DeviceIdBox, UserId},
+        locks::Mutex,
+    };
+    use matrix_sdk_test::async_test;
+    use serde_json::{json, value::to_raw_value};
+    use std::sync::Arc;
+
+    use crate::{
+        identities::{LocalTrust, ReadOnlyDevice},
+        olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
+        secret_storage::SecretName,
+        store::{CryptoStore, MemoryStore, Store},
+        verification::VerificationMachine,
+        OutgoingRequests,
+    };
+
+    use super::{SecretShareDecision, SecretSharingMachine};
+
+    fn alice_id() -> UserId {
+        user_id!("@alice:example.org")
+    }
+
+    fn alice_device_id() -> DeviceIdBox {
+        "JLAFKJWSCS".into()
+    }
+
+    fn alice2_device_id() -> DeviceIdBox {
+        "ILMLKASTES".into()
+    }
+
+    async fn get_machine() -> SecretSharingMachine {
+        let user_id = Arc::new(alice_id());
+        let account = ReadOnlyAccount::new(&user_id, &alice_device_id());
+        let store: Arc<Box<dyn CryptoStore>> = Arc::new(Box::new(MemoryStore::new()));
+        let identity = Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(alice_id())));
+        let verification = VerificationMachine::new(account, identity.clone(), store.clone());
+        let store = Store::new(user_id.clone(), identity.clone(), store, verification);
+
+        SecretSharingMachine::new(user_id, Arc::new(alice_device_id()), store, identity)
+    }
+
+    #[async_test]
+    async fn create_secret_request() {
+        let machine = get_machine().await;
+
+        assert!(machine.outgoing_to_device_requests().is_empty());
+
+        machine
+            .request_secret(SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap();
+
+        let requests = machine.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+
+        let request = if let OutgoingRequests::ToDeviceRequest(r) = requests[0].request() {
+            r
+        } else {
+            panic!("Invalid outgoing request type");
+        };
+
+        let content = request
+            .messages
+            .get(&alice_id())
+            .unwrap()
+            .get(&DeviceIdOrAllDevices::AllDevices)
+            .unwrap();
+        let content: serde_json::Value = serde_json::from_str(content.get()).unwrap();
+
+        assert_eq!(content["name"], "m.cross_signing.master");
+        assert_eq!(content["action"], "request");
+
+        machine
+            .mark_outgoing_request_as_sent(requests[0].request_id())
+            .await
+            .unwrap();
+        assert!(machine.outgoing_to_device_requests().is_empty());
+    }
+
+    #[async_test]
+    async fn secret_share_decision() {
+        let machine = get_machine().await;
+
+        let own_account = ReadOnlyAccount::new(&alice_id(), &alice2_device_id());
+        let own_device = ReadOnlyDevice::from_account(&own_account).await;
+        machine.store.save_devices(&[own_device]).await.unwrap();
+
+        let bob_account = ReadOnlyAccount::new(&user_id!("@bob:example.org"), &alice2_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob_account).await;
+        machine.store.save_devices(&[bob_device]).await.unwrap();
+
+        let own_device = machine
+            .store
+            .get_device(&alice_id(), &alice2_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        let bob_device = machine
+            .store
+            .get_device(&user_id!("@bob:example.org"), &alice2_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            Err(SecretShareDecision::ForeignDevice),
+            machine.should_share_secret(&bob_device)
+        );
+        assert_eq!(
+            Err(SecretShareDecision::UntrustedDevice),
+            machine.should_share_secret(&own_device)
+        );
+
+        own_device
+            .set_local_trust(LocalTrust::Verified)
+            .await
+            .unwrap();
+        let own_device = machine
+            .store
+            .get_device(&alice_id(), &alice2_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(machine.should_share_secret(&own_device).is_ok());
+    }
+
+    #[async_test]
+    async fn incoming_request_cancellation() {
+        let machine = get_machine().await;
+
+        let request = json!({
+            "sender": alice_id(),
+            "type": "m.secret.request",
+            "content": {
+                "name": "m.cross_signing.master",
+                "action": "request",
+                "requesting_device_id": alice2_device_id(),
+                "request_id": "randomly_generated_id_9573"
+            }
+        });
+        let cancellation = json!({
+            "sender": alice_id(),
+            "type": "m.secret.request",
+            "content": {
+                "action": "request_cancellation",
+                "requesting_device_id": alice2_device_id(),
+                "request_id": "randomly_generated_id_9573"
+            }
+        });
+
+        machine.receive_incoming_secret_request(&to_raw_value(&request).unwrap());
+        assert_eq!(machine.incoming_secret_requests.len(), 1);
+
+        machine.receive_incoming_secret_request(&to_raw_value(&cancellation).unwrap());
+        assert!(machine.incoming_secret_requests.is_empty());
+    }
+}
@@ -0,0 +1,727 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Secret storage, encrypting secrets like our private cross signing keys so
+//! they can be stored in the global account data of the user.
+//!
+//! Only the `m.secret_storage.v1.aes-hmac-sha2` algorithm is supported.
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use aes_ctr::{
+    cipher::{NewStreamCipher, SyncStreamCipher},
+    Aes256Ctr,
+};
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use pbkdf2::pbkdf2;
+use serde::{Deserialize, Serialize};
+use serde_json::Error as JsonError;
+use sha2::{Sha256, Sha512};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    backups::RecoveryKey,
+    store::CryptoStoreError,
+    utilities::{decode, encode, DecodeError},
+};
+
+/// The name of the only secret storage algorithm we support.
+pub const SECRET_STORAGE_V1: &str = "m.secret_storage.v1.aes-hmac-sha2";
+
+/// The event type of the account data event pointing to the default secret
+/// storage key.
+pub const DEFAULT_KEY_EVENT_TYPE: &str = "m.secret_storage.default_key";
+
+const PBKDF2_ALGORITHM: &str = "m.pbkdf2";
+#[cfg(not(test))]
+const PBKDF2_ROUNDS: u32 = 500_000;
+// Deriving keys with the real number of rounds makes the tests crawl.
+#[cfg(test)]
+const PBKDF2_ROUNDS: u32 = 1_000;
+/// The largest number of PBKDF2 rounds we accept from a key description, the
+/// description comes from account data so it can't be trusted.
+const MAX_PBKDF2_ROUNDS: u32 = 5_000_000;
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const SALT_SIZE: usize = 32;
+const KEY_ID_SIZE: usize = 24;
+
+/// Error type for secret storage operations.
+#[derive(Error, Debug)]
+pub enum SecretStorageError {
+    /// The key uses an algorithm we don't support.
+    #[error("The secret storage key uses an unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+
+    /// The key wasn't created from a passphrase.
+    #[error("The secret storage key wasn't derived from a passphrase")]
+    MissingPassphraseInfo,
+
+    /// The key derivation parameters of the passphrase aren't supported.
+    #[error("The passphrase info of the secret storage key is invalid: {0}")]
+    InvalidPassphraseInfo(String),
+
+    /// The given passphrase or recovery key doesn't match the key description.
+    #[error("The passphrase or recovery key doesn't match the secret storage key")]
+    InvalidKey,
+
+    /// The secret isn't encrypted with the given key.
+    #[error("The secret isn't encrypted with the secret storage key {0}")]
+    MissingSecret(String),
+
+    /// The MAC of the encrypted secret is invalid.
+    #[error("The MAC of the encrypted secret is invalid")]
+    InvalidMac,
+
+    /// The decrypted secret isn't valid UTF-8.
+    #[error(transparent)]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+
+    /// A base64 encoded value couldn't be decoded.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// The account data didn't contain valid JSON.
+    #[error(transparent)]
+    Json(#[from] JsonError),
+
+    /// The imported private key doesn't match our public identity.
+    #[error("The private key {0} doesn't match our public cross signing identity")]
+    IdentityMismatch(SecretName),
+
+    /// The imported private key is malformed.
+    #[error("The private key {0} is malformed")]
+    InvalidPrivateKey(SecretName),
+
+    /// The imported keys couldn't be saved in the store.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// The names of the secrets that we know how to handle.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SecretName {
+    /// The private part of the cross signing master key.
+    CrossSigningMasterKey,
+    /// The private part of the cross signing self signing key.
+    CrossSigningSelfSigningKey,
+    /// The private part of the cross signing user signing key.
+    CrossSigningUserSigningKey,
+    /// The recovery key of the server-side room key backup.
+    RecoveryKey,
+    /// Any other secret.
+    Custom(String),
+}
+
+impl SecretName {
+    /// Get the string representation of the secret name, this is used as the
+    /// event type of the account data event holding the secret.
+    pub fn as_str(&self) -> &str {
+        match self {
+            SecretName::CrossSigningMasterKey => "m.cross_signing.master",
+            SecretName::CrossSigningSelfSigningKey => "m.cross_signing.self_signing",
+            SecretName::CrossSigningUserSigningKey => "m.cross_signing.user_signing",
+            SecretName::RecoveryKey => "m.megolm_backup.v1",
+            SecretName::Custom(s) => s,
+        }
+    }
+}
+
+impl From<&str> for SecretName {
+    fn from(name: &str) -> Self {
+        match name {
+            "m.cross_signing.master" => SecretName::CrossSigningMasterKey,
+            "m.cross_signing.self_signing" => SecretName::CrossSigningSelfSigningKey,
+            "m.cross_signing.user_signing" => SecretName::CrossSigningUserSigningKey,
+            "m.megolm_backup.v1" => SecretName::RecoveryKey,
+            name => SecretName::Custom(name.to_owned()),
+        }
+    }
+}
+
+impl From<String> for SecretName {
+    fn from(name: String) -> Self {
+        name.as_str().into()
+    }
+}
+
+impl fmt::Display for SecretName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Info about the passphrase a secret storage key was derived from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PassphraseInfo {
+    /// The key derivation algorithm, always `m.pbkdf2`.
+    pub algorithm: String,
+    /// The salt that was used for the key derivation.
+    pub salt: String,
+    /// The number of PBKDF2 iterations.
+    pub iterations: u32,
+    /// The length of the derived key in bits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u32>,
+}
+
+/// The content of the `m.secret_storage.key.<key_id>` account data event.
+///
+/// This is the public description of a secret storage key and can be used to
+/// check if a passphrase or recovery key is correct.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SecretStorageKeyDescription {
+    /// The human readable name of the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The algorithm the key is used with.
+    pub algorithm: String,
+    /// Info about the passphrase the key was derived from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<PassphraseInfo>,
+    /// The initialization vector that was used to create the key check MAC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iv: Option<String>,
+    /// The MAC of 32 zero bytes encrypted with the key, used to check the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+/// The content of the `m.secret_storage.default_key` account data event.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DefaultKeyContent {
+    /// The id of the default secret storage key.
+    pub key: String,
+}
+
+/// A secret encrypted with the `m.secret_storage.v1.aes-hmac-sha2` algorithm.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AesHmacSha2EncryptedData {
+    /// The base64 encoded initialization vector.
+    pub iv: String,
+    /// The base64 encoded ciphertext.
+    pub ciphertext: String,
+    /// The base64 encoded MAC of the ciphertext.
+    pub mac: String,
+}
+
+/// The content of an account data event holding an encrypted secret.
+///
+/// The event type of the account data event is the name of the secret.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedSecretContent {
+    /// Map from the secret storage key id to the secret encrypted with that
+    /// key.
+    pub encrypted: BTreeMap<String, AesHmacSha2EncryptedData>,
+}
+
+/// A secret storage key.
+///
+/// The key is used to encrypt secrets that are stored in the global account
+/// data of the user.
+#[derive(Clone)]
+pub struct SecretStorageKey {
+    key_id: Arc<str>,
+    key: Arc<Zeroizing<Vec<u8>>>,
+    description: SecretStorageKeyDescription,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SecretStorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretStorageKey")
+            .field("key_id", &self.key_id)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+impl SecretStorageKey {
+    /// Create a new random secret storage key.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// create the key.
+    pub fn new() -> Self {
+        let mut key = Zeroizing::new(vec![0u8; KEY_SIZE]);
+        getrandom(&mut key).expect("Can't generate randomness");
+
+        Self::from_parts(Self::random_key_id(), key, None)
+    }
+
+    /// Create a new secret storage key that is derived from the given
+    /// passphrase.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// create the key.
+    pub fn new_from_passphrase(passphrase: &str) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        getrandom(&mut salt).expect("Can't generate randomness");
+
+        let info = PassphraseInfo {
+            algorithm: PBKDF2_ALGORITHM.to_owned(),
+            salt: encode(salt),
+            iterations: PBKDF2_ROUNDS,
+            bits: Some((KEY_SIZE * 8) as u32),
+        };
+
+        let key = Self::derive_key(passphrase, &info);
+
+        Self::from_parts(Self::random_key_id(), key, Some(info))
+    }
+
+    /// Restore a secret storage key from a passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase the key was derived from.
+    ///
+    /// * `key_id` - The id of the key.
+    ///
+    /// * `description` - The public description of the key, as found in the
+    /// `m.secret_storage.key.<key_id>` account data event.
+    pub fn from_passphrase(
+        passphrase: &str,
+        key_id: &str,
+        description: SecretStorageKeyDescription,
+    ) -> Result<Self, SecretStorageError> {
+        Self::check_algorithm(&description)?;
+
+        let info = description
+            .passphrase
+            .as_ref()
+            .ok_or(SecretStorageError::MissingPassphraseInfo)?;
+
+        Self::check_passphrase_info(info)?;
+        let key = Self::derive_key(passphrase, info);
+
+        Self::restore(key_id, key, description)
+    }
+
+    /// Restore a secret storage key from a base58 encoded recovery key.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The base58 encoded version of the key.
+    ///
+    /// * `key_id` - The id of the key.
+    ///
+    /// * `description` - The public description of the key, as found in the
+    /// `m.secret_storage.key.<key_id>` account data event.
+    pub fn from_base58(
+        recovery_key: &str,
+        key_id: &str,
+        description: SecretStorageKeyDescription,
+    ) -> Result<Self, SecretStorageError> {
+        Self::check_algorithm(&description)?;
+
+        let key =
+            RecoveryKey::from_base58(recovery_key).map_err(|_| SecretStorageError::InvalidKey)?;
+
+        Self::restore(key_id, Zeroizing::new(key.as_bytes().to_vec()), description)
+    }
+
+    /// Export the key as a base58 encoded recovery key.
+    pub fn to_base58(&self) -> String {
+        // Keys are either random, decoded from a recovery key or derived with
+        // checked passphrase info, all of them are `KEY_SIZE` bytes long.
+        RecoveryKey::from_bytes(&self.key)
+            .expect("Secret storage keys always have a valid length")
+            .to_base58()
+    }
+
+    /// The unique id of the key.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The event type of the account data event holding the public
+    /// description of this key.
+    pub fn event_type(&self) -> String {
+        format!("m.secret_storage.key.{}", self.key_id)
+    }
+
+    /// The public description of the key, this should be uploaded as the
+    /// content of the account data event with the [`event_type()`].
+    ///
+    /// [`event_type()`]: #method.event_type
+    pub fn description(&self) -> &SecretStorageKeyDescription {
+        &self.description
+    }
+
+    /// The content of the `m.secret_storage.default_key` account data event
+    /// that marks this key as the default key.
+    pub fn default_key_content(&self) -> DefaultKeyContent {
+        DefaultKeyContent {
+            key: self.key_id.to_string(),
+        }
+    }
+
+    /// Encrypt a secret with this key.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, this is used as the event type
+    /// of the account data event holding the secret.
+    ///
+    /// * `secret` - The secret that should be encrypted.
+    pub fn encrypt(&self, secret_name: &SecretName, secret: &str) -> EncryptedSecretContent {
+        let mut encrypted = BTreeMap::new();
+        encrypted.insert(
+            self.key_id.to_string(),
+            encrypt_helper(&self.key, secret_name.as_str(), secret.as_bytes()),
+        );
+
+        EncryptedSecretContent { encrypted }
+    }
+
+    /// Decrypt a secret that was encrypted with this key.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret.
+    ///
+    /// * `content` - The content of the account data event holding the secret.
+    pub fn decrypt(
+        &self,
+        secret_name: &SecretName,
+        content: &EncryptedSecretContent,
+    ) -> Result<Zeroizing<String>, SecretStorageError> {
+        let data = content
+            .encrypted
+            .get(self.key_id())
+            .ok_or_else(|| SecretStorageError::MissingSecret(self.key_id.to_string()))?;
+
+        let plaintext = decrypt_helper(&self.key, secret_name.as_str(), data)?;
+
+        Ok(Zeroizing::new(String::from_utf8(plaintext.to_vec())?))
+    }
+
+    fn from_parts(
+        key_id: String,
+        key: Zeroizing<Vec<u8>>,
+        passphrase: Option<PassphraseInfo>,
+    ) -> Self {
+        let check = encrypt_helper(&key, "", &[0u8; KEY_SIZE]);
+
+        let description = SecretStorageKeyDescription {
+            name: None,
+            algorithm: SECRET_STORAGE_V1.to_owned(),
+            passphrase,
+            iv: Some(check.iv),
+            mac: Some(check.mac),
+        };
+
+        Self {
+            key_id: key_id.into(),
+            key: Arc::new(key),
+            description,
+        }
+    }
+
+    fn restore(
+        key_id: &str,
+        key: Zeroizing<Vec<u8>>,
+        description: SecretStorageKeyDescription,
+    ) -> Result<Self, SecretStorageError> {
+        // Old keys might not have a key check, we can't verify those.
+        if let (Some(iv), Some(mac)) = (&description.iv, &description.mac) {
+            let iv = decode_padded(iv)?;
+
+            if iv.len() != IV_SIZE {
+                return Err(SecretStorageError::InvalidKey);
+            }
+
+            // Check the MAC the same way we do when decrypting secrets, this
+            // makes sure the comparison happens in constant time.
+            let expected = encrypt_with_iv(&key, "", &[0u8; KEY_SIZE], &iv);
+            let check = AesHmacSha2EncryptedData {
+                mac: mac.clone(),
+                ..expected
+            };
+
+            decrypt_helper(&key, "", &check).map_err(|e| match e {
+                SecretStorageError::InvalidMac => SecretStorageError::InvalidKey,
+                e => e,
+            })?;
+        }
+
+        Ok(Self {
+            key_id: key_id.into(),
+            key: Arc::new(key),
+            description,
+        })
+    }
+
+    fn check_algorithm(
+        description: &SecretStorageKeyDescription,
+    ) -> Result<(), SecretStorageError> {
+        if description.algorithm != SECRET_STORAGE_V1 {
+            Err(SecretStorageError::UnsupportedAlgorithm(
+                description.algorithm.clone(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_passphrase_info(info: &PassphraseInfo) -> Result<(), SecretStorageError> {
+        if info.algorithm != PBKDF2_ALGORITHM {
+            Err(SecretStorageError::UnsupportedAlgorithm(
+                info.algorithm.clone(),
+            ))
+        } else if info.bits.map_or(false, |b| b as usize != KEY_SIZE * 8) {
+            Err(SecretStorageError::InvalidPassphraseInfo(format!(
+                "unsupported key length of {} bits",
+                info.bits.unwrap_or_default()
+            )))
+        } else if info.iterations == 0 || info.iterations > MAX_PBKDF2_ROUNDS {
+            Err(SecretStorageError::InvalidPassphraseInfo(format!(
+                "unsupported number of iterations {}",
+                info.iterations
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Derive a key from the passphrase, the passphrase info needs to be
+    /// checked with `check_passphrase_info()` first.
+    fn derive_key(passphrase: &str, info: &PassphraseInfo) -> Zeroizing<Vec<u8>> {
+        let mut key = Zeroizing::new(vec![0u8; KEY_SIZE]);
+
+        pbkdf2::<Hmac<Sha512>>(
+            passphrase.as_bytes(),
+            info.salt.as_bytes(),
+            info.iterations,
+            &mut key,
+        );
+
+        key
+    }
+
+    fn random_key_id() -> String {
+        let mut bytes = [0u8; KEY_ID_SIZE];
+        getrandom(&mut bytes).expect("Can't generate randomness");
+        encode(bytes)
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect()
+    }
+}
+
+impl Default for SecretStorageKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The spec uses padded base64 for secret storage, accept both the padded and
+/// unpadded variants.
+fn decode_padded(input: &str) -> Result<Vec<u8>, DecodeError> {
+    decode(input.trim_end_matches('='))
+}
+
+fn encode_padded(input: impl AsRef<[u8]>) -> String {
+    base64::encode(input)
+}
+
+/// Derive the AES and HMAC keys for the given secret name.
+fn derive_keys(key: &[u8], secret_name: &str) -> (Zeroizing<Vec<u8>>, Zeroizing<Vec<u8>>) {
+    let hkdf: Hkdf<Sha256> = Hkdf::new(Some(&[0u8; KEY_SIZE]), key);
+    let mut keys = Zeroizing::new(vec![0u8; KEY_SIZE * 2]);
+
+    hkdf.expand(secret_name.as_bytes(), &mut keys)
+        .expect("Can't expand the secret storage key");
+
+    let (aes_key, hmac_key) = keys.split_at(KEY_SIZE);
+
+    (
+        Zeroizing::new(aes_key.to_vec()),
+        Zeroizing::new(hmac_key.to_vec()),
+    )
+}
+
+fn encrypt_helper(key: &[u8], secret_name: &str, plaintext: &[u8]) -> AesHmacSha2EncryptedData {
+    let mut iv = [0u8; IV_SIZE];
+    getrandom(&mut iv).expect("Can't generate randomness");
+
+    // Clear bit 63 of the IV to avoid problems with some AES-CTR
+    // implementations overflowing the counter.
+    iv[8] &= 0x7f;
+
+    encrypt_with_iv(key, secret_name, plaintext, &iv)
+}
+
+fn encrypt_with_iv(
+    key: &[u8],
+    secret_name: &str,
+    plaintext: &[u8],
+    iv: &[u8],
+) -> AesHmacSha2EncryptedData {
+    let (aes_key, hmac_key) = derive_keys(key, secret_name);
+
+    let mut ciphertext = plaintext.to_vec();
+    let mut aes = Aes256Ctr::new_var(&aes_key, iv).expect("Can't create AES object");
+    aes.apply_keystream(&mut ciphertext);
+
+    let mut hmac = Hmac::<Sha256>::new_varkey(&hmac_key).expect("Can't create HMAC object");
+    hmac.update(&ciphertext);
+    let mac = hmac.finalize();
+
+    AesHmacSha2EncryptedData {
+        iv: encode_padded(iv),
+        ciphertext: encode_padded(ciphertext),
+        mac: encode_padded(mac.into_bytes()),
+    }
+}
+
+fn decrypt_helper(
+    key: &[u8],
+    secret_name: &str,
+    data: &AesHmacSha2EncryptedData,
+) -> Result<Zeroizing<Vec<u8>>, SecretStorageError> {
+    let (aes_key, hmac_key) = derive_keys(key, secret_name);
+
+    let iv = decode_padded(&data.iv)?;
+    let mac = decode_padded(&data.mac)?;
+    let mut plaintext = Zeroizing::new(decode_padded(&data.ciphertext)?);
+
+    if iv.len() != IV_SIZE {
+        return Err(SecretStorageError::InvalidMac);
+    }
+
+    let mut hmac = Hmac::<Sha256>::new_varkey(&hmac_key).expect("Can't create HMAC object");
+    hmac.update(&plaintext);
+    hmac.verify(&mac)
+        .map_err(|_| SecretStorageError::InvalidMac)?;
+
+    let mut aes = Aes256Ctr::new_var(&aes_key, &iv).expect("Can't create AES object");
+    aes.apply_keystream(&mut plaintext);
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SecretName, SecretStorageError, SecretStorageKey, MAX_PBKDF2_ROUNDS};
+    use crate::utilities::encode;
+
+    #[test]
+    fn secret_encryption_roundtrip() {
+        let key = SecretStorageKey::new();
+        let name = SecretName::CrossSigningMasterKey;
+
+        let content = key.encrypt(&name, "It's a secret to everybody");
+        let decrypted = key.decrypt(&name, &content).unwrap();
+
+        assert_eq!(decrypted.as_str(), "It's a secret to everybody");
+
+        let other_name = SecretName::CrossSigningUserSigningKey;
+        assert!(matches!(
+            key.decrypt(&other_name, &content),
+            Err(SecretStorageError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn key_restoration() {
+        let key = SecretStorageKey::new();
+        let recovery_key = key.to_base58();
+
+        let restored =
+            SecretStorageKey::from_base58(&recovery_key, key.key_id(), key.description().clone())
+                .unwrap();
+        assert_eq!(restored.key_id(), key.key_id());
+
+        let other = SecretStorageKey::new();
+        assert!(SecretStorageKey::from_base58(
+            &other.to_base58(),
+            key.key_id(),
+            key.description().clone()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn passphrase_key_restoration() {
+        let key = SecretStorageKey::new_from_passphrase("It's a secret to everybody");
+        let name = SecretName::CrossSigningSelfSigningKey;
+        let content = key.encrypt(&name, "secret");
+
+        let restored = SecretStorageKey::from_passphrase(
+            "It's a secret to everybody",
+            key.key_id(),
+            key.description().clone(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            restored.decrypt(&name, &content).unwrap().as_str(),
+            "secret"
+        );
+
+        assert!(matches!(
+            SecretStorageKey::from_passphrase("wrong", key.key_id(), key.description().clone()),
+            Err(SecretStorageError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn invalid_passphrase_info() {
+        let key = SecretStorageKey::new_from_passphrase("It's a secret to everybody");
+
+        let mut description = key.description().clone();
+        description.passphrase.as_mut().unwrap().bits = Some(512);
+
+        assert!(matches!(
+            SecretStorageKey::from_passphrase(
+                "It's a secret to everybody",
+                key.key_id(),
+                description
+            ),
+            Err(SecretStorageError::InvalidPassphraseInfo(_))
+        ));
+
+        let mut description = key.description().clone();
+        description.passphrase.as_mut().unwrap().iterations = MAX_PBKDF2_ROUNDS + 1;
+
+        assert!(matches!(
+            SecretStorageKey::from_passphrase(
+                "It's a secret to everybody",
+                key.key_id(),
+                description
+            ),
+            Err(SecretStorageError::InvalidPassphraseInfo(_))
+        ));
+
+        let mut description = key.description().clone();
+        description.iv = Some(encode([0u8; 4]));
+
+        assert!(matches!(
+            SecretStorageKey::from_passphrase(
+                "It's a secret to everybody",
+                key.key_id(),
+                description
+            ),
+            Err(SecretStorageError::InvalidKey)
+        ));
+    }
+}
@@ -14,14 +14,15 @@
 
 use std::{
     collections::{BTreeMap, HashSet},
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
 };
 
+use atomic::Atomic;
 use dashmap::DashMap;
 use matrix_sdk_common::{
     api::r0::to_device::DeviceIdOrAllDevices,
     events::{room::encrypted::EncryptedEventContent, AnyMessageEventContent, EventType},
-    identifiers::{RoomId, UserId},
+    identifiers::{DeviceIdBox, RoomId, UserId},
     uuid::Uuid,
 };
 use tracing::{debug, info};
@@ -29,10 +30,36 @@ use tracing::{debug, info};
 use crate::{
     error::{EventError, MegolmResult, OlmResult},
     olm::{Account, InboundGroupSession, OutboundGroupSession},
-    store::{Changes, Store},
+    store::{Changes, Result as StoreResult, Store},
+    withheld::{RoomKeyWithheldContent, WithheldCode, ROOM_KEY_WITHHELD_EVENT_TYPE},
     Device, EncryptionSettings, OlmError, ToDeviceRequest,
 };
 
+/// Strategy deciding which devices of the room members receive our room keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShareStrategy {
+    /// Share room keys with all devices that aren't blacklisted.
+    AllDevices,
+    /// Share room keys only with devices that we trust, either because we
+    /// verified them locally or because they are signed by a verified cross
+    /// signing identity.
+    ///
+    /// Untrusted devices are notified that the room key was withheld from
+    /// them.
+    TrustedDevicesOnly,
+    /// Refuse to share the room key if any of the devices isn't trusted.
+    ///
+    /// The untrusted devices are listed in the returned
+    /// `OlmError::UnverifiedDevices` error.
+    ErrorOnUnverified,
+}
+
+impl Default for ShareStrategy {
+    fn default() -> Self {
+        ShareStrategy::AllDevices
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GroupSessionManager {
     account: Account,
@@ -43,6 +70,8 @@ pub struct GroupSessionManager {
     /// The currently active outbound group sessions.
     outbound_group_sessions: Arc<DashMap<RoomId, OutboundGroupSession>>,
     outbound_sessions_being_shared: Arc<DashMap<Uuid, OutboundGroupSession>>,
+    /// The strategy deciding which devices receive our room keys.
+    share_strategy: Arc<Atomic<ShareStrategy>>,
 }
 
 impl GroupSessionManager {
@@ -54,20 +83,84 @@ impl GroupSessionManager {
             store,
             outbound_group_sessions: Arc::new(DashMap::new()),
             outbound_sessions_being_shared: Arc::new(DashMap::new()),
+            share_strategy: Arc::new(Atomic::new(ShareStrategy::default())),
         }
     }
 
-    pub fn invalidate_group_session(&self, room_id: &RoomId) -> bool {
-        self.outbound_group_sessions.remove(room_id).is_some()
+    /// Get the strategy deciding which devices receive our room keys.
+    pub fn share_strategy(&self) -> ShareStrategy {
+        self.share_strategy.load(Ordering::Relaxed)
+    }
+
+    /// Set the strategy deciding which devices receive our room keys.
+    pub fn set_share_strategy(&self, strategy: ShareStrategy) {
+        self.share_strategy.store(strategy, Ordering::Relaxed)
     }
 
-    pub fn mark_request_as_sent(&self, request_id: &Uuid) {
+    /// Load the outbound group sessions that were persisted in the store.
+    ///
+    /// Sessions that were invalidated are skipped, a new session will be
+    /// created for those rooms once a message needs to be sent.
+    pub async fn load_outbound_group_sessions(&self) -> StoreResult<()> {
+        for session in self.store.get_outbound_group_sessions().await? {
+            if session.invalidated() {
+                continue;
+            }
+
+            for request_id in session.pending_request_ids() {
+                self.outbound_sessions_being_shared
+                    .insert(request_id, session.clone());
+            }
+
+            debug!(
+                "Restored outbound group session {} for room {}",
+                session.session_id(),
+                session.room_id()
+            );
+
+            self.outbound_group_sessions
+                .insert(session.room_id().to_owned(), session);
+        }
+
+        Ok(())
+    }
+
+    /// Persist the current state of the given outbound group sessions.
+    async fn save_sessions(&self, sessions: Vec<OutboundGroupSession>) -> StoreResult<()> {
+        let changes = Changes {
+            outbound_group_sessions: sessions,
+            ..Default::default()
+        };
+
+        self.store.save_changes(changes).await
+    }
+
+    pub async fn invalidate_group_session(&self, room_id: &RoomId) -> StoreResult<bool> {
+        if let Some((_, session)) = self.outbound_group_sessions.remove(room_id) {
+            session.invalidate_session();
+            self.save_sessions(vec![session]).await?;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub async fn mark_request_as_sent(&self, request_id: &Uuid) -> StoreResult<()> {
         if let Some((_, s)) = self.outbound_sessions_being_shared.remove(request_id) {
             s.mark_request_as_sent(request_id);
+            self.save_sessions(vec![s]).await?;
         }
+
+        Ok(())
     }
 
-    pub fn invalidate_sessions_new_devices(&self, users: &HashSet<&UserId>) {
+    pub async fn invalidate_sessions_new_devices(
+        &self,
+        users: &HashSet<&UserId>,
+    ) -> StoreResult<()> {
+        let mut invalidated = Vec::new();
+
         for session in self.outbound_group_sessions.iter() {
             if users.iter().any(|u| session.contains_recipient(u)) {
                 info!(
@@ -82,8 +175,16 @@ impl GroupSessionManager {
                         self.outbound_sessions_being_shared.remove(&request_id);
                     }
                 }
+
+                invalidated.push(session.clone());
             }
         }
+
+        if !invalidated.is_empty() {
+            self.save_sessions(invalidated).await?;
+        }
+
+        Ok(())
     }
 
     /// Get an outbound group session for a room, if one exists.
@@ -112,7 +213,13 @@ impl GroupSessionManager {
             panic!("Session expired");
         }
 
-        Ok(session.encrypt(content).await)
+        let content = session.encrypt(content).await;
+
+        // The ratchet of the session moved forward, persist it so we don't
+        // reuse a message index after a restart.
+        self.save_sessions(vec![session]).await?;
+
+        Ok(content)
     }
 
     /// Should the client share a group session for the given room.
@@ -161,6 +268,9 @@ impl GroupSessionManager {
     /// used.
     ///
     /// `users` - The list of users that should receive the group session.
+    ///
+    /// Which devices of the users receive the group session depends on the
+    /// current `ShareStrategy`.
     pub async fn share_group_session(
         &self,
         room_id: &RoomId,
@@ -169,17 +279,51 @@ impl GroupSessionManager {
     ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
         let mut changes = Changes::default();
 
+        let users: Vec<&UserId> = users.collect();
+        let strategy = self.share_strategy();
+
+        let mut devices: Vec<Device> = Vec::new();
+        let mut withheld: Vec<(Device, WithheldCode)> = Vec::new();
+        let mut unverified: BTreeMap<UserId, Vec<DeviceIdBox>> = BTreeMap::new();
+
+        for user_id in &users {
+            let user_devices = self.store.get_user_devices(user_id).await?;
+
+            for device in user_devices.devices() {
+                // Our own device already has the room key, it doesn't need to
+                // receive it nor to be told that it was withheld.
+                if device.user_id() == self.account.user_id()
+                    && device.device_id() == self.account.device_id()
+                {
+                    continue;
+                }
+
+                if device.is_blacklisted() {
+                    withheld.push((device, WithheldCode::Blacklisted));
+                } else if strategy == ShareStrategy::AllDevices || device.trust_state() {
+                    devices.push(device);
+                } else if strategy == ShareStrategy::TrustedDevicesOnly {
+                    withheld.push((device, WithheldCode::Unverified));
+                } else {
+                    unverified
+                        .entry(device.user_id().clone())
+                        .or_insert_with(Vec::new)
+                        .push(device.device_id().into());
+                }
+            }
+        }
+
+        if !unverified.is_empty() {
+            return Err(OlmError::UnverifiedDevices(unverified));
+        }
+
         let (session, inbound_session) = self
             .create_outbound_group_session(room_id, encryption_settings.into())
             .await?;
         changes.inbound_group_sessions.push(inbound_session);
 
-        let mut devices: Vec<Device> = Vec::new();
-
         for user_id in users {
             session.add_recipient(user_id);
-            let user_devices = self.store.get_user_devices(&user_id).await?;
-            devices.extend(user_devices.devices().filter(|d| !d.is_blacklisted()));
         }
 
         let mut requests = Vec::new();
@@ -197,6 +341,7 @@ impl GroupSessionManager {
                     Ok(c) => c,
                     Err(OlmError::MissingSession)
                     | Err(OlmError::EventError(EventError::MissingSenderKey)) => {
+                        withheld.push((device.clone(), WithheldCode::NoOlm));
                         continue;
                     }
                     Err(e) => return Err(e),
@@ -236,8 +381,62 @@ impl GroupSessionManager {
             session.mark_as_shared();
         }
 
+        requests.extend(self.withheld_requests(&session, &withheld)?);
+
+        changes.outbound_group_sessions.push(session);
         self.store.save_changes(changes).await?;
 
         Ok(requests)
     }
+
+    /// Create `m.room_key.withheld` to-device requests that notify the given
+    /// devices that the room key of the session won't be shared with them.
+    ///
+    /// Those requests aren't tracked by the session, the devices never
+    /// received the room key.
+    fn withheld_requests(
+        &self,
+        session: &OutboundGroupSession,
+        withheld: &[(Device, WithheldCode)],
+    ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
+        let sender_key = self.account.identity_keys().curve25519();
+        let mut requests = Vec::new();
+
+        for chunk in withheld.chunks(Self::MAX_TO_DEVICE_MESSAGES) {
+            let mut messages = BTreeMap::new();
+
+            for (device, code) in chunk {
+                debug!(
+                    "Withholding the room key of session {} from {} {}: {}",
+                    session.session_id(),
+                    device.user_id(),
+                    device.device_id(),
+                    code
+                );
+
+                let content = RoomKeyWithheldContent::new(
+                    session.room_id(),
+                    session.session_id(),
+                    sender_key,
+                    *code,
+                );
+
+                messages
+                    .entry(device.user_id().clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(
+                        DeviceIdOrAllDevices::DeviceId(device.device_id().into()),
+                        serde_json::value::to_raw_value(&content)?,
+                    );
+            }
+
+            requests.push(Arc::new(ToDeviceRequest {
+                event_type: EventType::Custom(ROOM_KEY_WITHHELD_EVENT_TYPE.to_owned()),
+                txn_id: Uuid::new_v4(),
+                messages,
+            }));
+        }
+
+        Ok(requests)
+    }
 }
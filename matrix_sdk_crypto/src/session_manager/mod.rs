@@ -16,4 +16,5 @@ mod group_sessions;
 mod sessions;
 
 pub(crate) use group_sessions::GroupSessionManager;
+pub use group_sessions::ShareStrategy;
 pub(crate) use sessions::SessionManager;
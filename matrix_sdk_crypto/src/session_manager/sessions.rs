@@ -22,7 +22,8 @@ use matrix_sdk_common::{
     },
     assign,
     events::EventType,
-    identifiers::{DeviceId, DeviceIdBox, DeviceKeyAlgorithm, UserId},
+    identifiers::{DeviceId, DeviceIdBox, DeviceKeyAlgorithm, RoomId, UserId},
+    instant::Instant,
     uuid::Uuid,
 };
 use serde_json::{json, value::to_raw_value};
@@ -47,6 +48,14 @@ pub(crate) struct SessionManager {
     /// [`get_missing_sessions`](#method.get_missing_sessions) is called.
     users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
     wedged_devices: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
+    /// The last time we tried to unwedge a device because we're missing a
+    /// room key that it created.
+    unwedging_attempts: Arc<DashMap<(UserId, DeviceIdBox), Instant>>,
+    /// Room keys, identified by the room id, sender key and session id, that
+    /// will be requested again once the wedged device that created them gets
+    /// unwedged.
+    room_keys_for_wedged_devices:
+        Arc<DashMap<(UserId, DeviceIdBox), DashSet<(RoomId, String, String)>>>,
     key_request_machine: KeyRequestMachine,
     outgoing_to_device_requests: Arc<DashMap<Uuid, OutgoingRequest>>,
 }
@@ -54,6 +63,7 @@ pub(crate) struct SessionManager {
 impl SessionManager {
     const KEY_CLAIM_TIMEOUT: Duration = Duration::from_secs(10);
     const UNWEDGING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    const UNWEDGING_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 5);
 
     pub fn new(
         account: Account,
@@ -67,6 +77,8 @@ impl SessionManager {
             key_request_machine,
             users_for_key_claim,
             wedged_devices: Arc::new(DashMap::new()),
+            unwedging_attempts: Arc::new(DashMap::new()),
+            room_keys_for_wedged_devices: Arc::new(DashMap::new()),
             outgoing_to_device_requests: Arc::new(DashMap::new()),
         }
     }
@@ -108,7 +120,6 @@ impl SessionManager {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn is_device_wedged(&self, device: &ReadOnlyDevice) -> bool {
         self.wedged_devices
             .get(device.user_id())
@@ -116,9 +127,76 @@ impl SessionManager {
             .unwrap_or(false)
     }
 
+    /// Try to unwedge the device that created a room key we're missing.
+    ///
+    /// If the Olm session with the device that created the room key is known
+    /// to be wedged, a new Olm session will be created with it. Once the
+    /// session is established a dummy message is sent to the device and the
+    /// room key is requested again.
+    ///
+    /// Returns true if the device is wedged, false otherwise.
+    ///
+    /// A new Olm session is created at most once every
+    /// `UNWEDGING_RETRY_INTERVAL` per device.
+    pub async fn unwedge_for_room_key(
+        &self,
+        sender: &UserId,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> StoreResult<bool> {
+        let device = if let Some(d) = self
+            .store
+            .get_device_from_curve_key(sender, sender_key)
+            .await?
+        {
+            d
+        } else {
+            return Ok(false);
+        };
+
+        if !self.is_device_wedged(&device) {
+            return Ok(false);
+        }
+
+        let key = (device.user_id().to_owned(), device.device_id().into());
+
+        self.room_keys_for_wedged_devices
+            .entry(key.clone())
+            .or_insert_with(DashSet::new)
+            .insert((
+                room_id.to_owned(),
+                sender_key.to_owned(),
+                session_id.to_owned(),
+            ));
+
+        let should_retry = self
+            .unwedging_attempts
+            .get(&key)
+            .map(|t| t.elapsed() > Self::UNWEDGING_RETRY_INTERVAL)
+            .unwrap_or(true);
+
+        if should_retry {
+            info!(
+                "Missing a room key from the wedged device {} {}, creating a new Olm session",
+                device.user_id(),
+                device.device_id()
+            );
+
+            self.unwedging_attempts.insert(key.clone(), Instant::now());
+            self.users_for_key_claim
+                .entry(key.0)
+                .or_insert_with(DashSet::new)
+                .insert(key.1);
+        }
+
+        Ok(true)
+    }
+
     /// Check if the session was created to unwedge a Device.
     ///
-    /// If the device was wedged this will queue up a dummy to-device message.
+    /// If the device was wedged this will queue up a dummy to-device message
+    /// and request the room keys we missed from it again.
     async fn check_if_unwedged(&self, user_id: &UserId, device_id: &DeviceId) -> OlmResult<()> {
         if self
             .wedged_devices
@@ -154,6 +232,20 @@ impl SessionManager {
 
                 self.outgoing_to_device_requests.insert(id, request);
             }
+
+            if let Some((_, room_keys)) = self
+                .room_keys_for_wedged_devices
+                .remove(&(user_id.to_owned(), device_id.into()))
+            {
+                for (room_id, sender_key, session_id) in room_keys {
+                    self.key_request_machine
+                        .resend_outgoing_key_request(&room_id, &sender_key, &session_id)
+                        .await?;
+                }
+            }
+
+            self.unwedging_attempts
+                .remove(&(user_id.to_owned(), device_id.into()));
         }
 
         Ok(())
@@ -177,7 +269,9 @@ impl SessionManager {
     /// in flight, e.g. using a lock.
     ///
     /// The response of a successful key claiming requests needs to be passed to
-    /// the `OlmMachine` with the [`receive_keys_claim_response`].
+    /// the `OlmMachine` with the [`receive_keys_claim_response`]. The server
+    /// hands out a fallback key if a device ran out of one-time keys, those
+    /// are accepted as well.
     ///
     /// # Arguments
     ///
@@ -418,6 +512,40 @@ mod test {
             .is_none());
     }
 
+    #[async_test]
+    async fn session_creation_with_fallback_key() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        // Bob ran out of one-time keys, the server hands out his fallback key.
+        bob.generate_fallback_key().await;
+        let fallback_keys = bob.signed_fallback_keys().await;
+        bob.mark_keys_as_published().await;
+        assert_eq!(fallback_keys.len(), 1);
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys
+            .entry(bob.user_id().clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(bob.device_id().into(), fallback_keys);
+
+        let response = KeyClaimResponse::new(one_time_keys);
+
+        manager
+            .receive_keys_claim_response(&response)
+            .await
+            .unwrap();
+
+        assert!(manager
+            .get_missing_sessions(&mut [bob.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
     // This test doesn't run on macos because we're modifying the session
     // creation time so we can get around the UNWEDGING_INTERVAL.
     #[async_test]
@@ -494,4 +622,90 @@ mod test {
             .is_none());
         assert!(!manager.outgoing_to_device_requests.is_empty())
     }
+
+    #[async_test]
+    #[cfg(target_os = "linux")]
+    async fn session_unwedging_for_room_key() {
+        use matrix_sdk_common::{
+            identifiers::{room_id, DeviceKeyAlgorithm},
+            instant::{Duration, Instant},
+        };
+
+        let manager = session_manager().await;
+        let bob = bob_account();
+        let (_, mut session) = bob.create_session_for(&manager.account).await;
+
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        session.creation_time = Arc::new(Instant::now() - Duration::from_secs(3601));
+
+        manager
+            .store
+            .save_devices(&[bob_device.clone()])
+            .await
+            .unwrap();
+        manager.store.save_sessions(&[session]).await.unwrap();
+
+        let room_id = room_id!("!test:localhost");
+        let curve_key = bob_device.get_key(DeviceKeyAlgorithm::Curve25519).unwrap();
+
+        // Bob's device isn't wedged, nothing to do.
+        assert!(!manager
+            .unwedge_for_room_key(bob.user_id(), &room_id, curve_key, "session_id")
+            .await
+            .unwrap());
+
+        manager
+            .mark_device_as_wedged(bob_device.user_id(), &curve_key)
+            .await
+            .unwrap();
+        manager.users_for_key_claim.clear();
+
+        assert!(manager
+            .unwedge_for_room_key(bob.user_id(), &room_id, curve_key, "session_id")
+            .await
+            .unwrap());
+        assert!(manager.users_for_key_claim.contains_key(bob.user_id()));
+
+        // Unwedging is rate limited per device.
+        manager.users_for_key_claim.clear();
+        assert!(manager
+            .unwedge_for_room_key(bob.user_id(), &room_id, curve_key, "other_session_id")
+            .await
+            .unwrap());
+        assert!(!manager.users_for_key_claim.contains_key(bob.user_id()));
+
+        bob.generate_one_time_keys_helper(1).await;
+        let one_time = bob.signed_one_time_keys_helper().await.unwrap();
+        bob.mark_keys_as_published().await;
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys
+            .entry(bob.user_id().clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(bob.device_id().into(), one_time);
+
+        let response = KeyClaimResponse::new(one_time_keys);
+
+        assert!(manager
+            .key_request_machine
+            .outgoing_to_device_requests()
+            .is_empty());
+
+        manager
+            .receive_keys_claim_response(&response)
+            .await
+            .unwrap();
+
+        assert!(!manager.is_device_wedged(&bob_device));
+        assert!(!manager.outgoing_to_device_requests.is_empty());
+        // Both of the missing room keys are requested again.
+        assert_eq!(
+            manager
+                .key_request_machine
+                .outgoing_to_device_requests()
+                .len(),
+            2
+        );
+        assert!(manager.room_keys_for_wedged_devices.is_empty());
+    }
 }
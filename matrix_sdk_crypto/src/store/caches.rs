@@ -70,6 +70,20 @@ impl SessionStore {
         self.entries.get(sender_key).map(|s| s.clone())
     }
 
+    /// Get all the sessions the store knows about.
+    pub async fn get_all(&self) -> Vec<Session> {
+        let entries: Vec<Arc<Mutex<Vec<Session>>>> =
+            self.entries.iter().map(|e| e.value().clone()).collect();
+
+        let mut sessions = Vec::new();
+
+        for entry in entries {
+            sessions.extend(entry.lock().await.iter().cloned());
+        }
+
+        sessions
+    }
+
     /// Add a list of sessions belonging to the sender key.
     pub fn set_for_sender(&self, sender_key: &str, sessions: Vec<Session>) {
         self.entries
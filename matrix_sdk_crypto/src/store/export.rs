@@ -0,0 +1,587 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export and import of the whole content of a `CryptoStore`.
+//!
+//! Unlike a room key export, a store export contains the Olm account, so it
+//! can be used to move a device to a different host or to a different store
+//! implementation without losing its identity.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+use matrix_sdk_common::identifiers::{DeviceIdBox, DeviceKeyId, EventEncryptionAlgorithm, UserId};
+
+use super::{
+    check_pickle_version, Changes, CryptoStore, CryptoStoreError, DeviceChanges, IdentityChanges,
+    PickleKey,
+};
+use crate::{
+    error::SignatureError,
+    file_encryption::{decrypt_helper, encrypt_helper, KeyExportError},
+    identities::{
+        LocalTrust, MasterPubkey, OwnUserIdentity, ReadOnlyDevice, SelfSigningPubkey,
+        UserIdentities, UserIdentity, UserSigningPubkey,
+    },
+    olm::{
+        InboundGroupSession, MegolmMessageIndex, OlmMessageHash, OutboundGroupSession,
+        PickledAccount, PickledCrossSigningIdentity, PickledInboundGroupSession,
+        PickledOutboundGroupSession, PickledSession, PicklingMode, PrivateCrossSigningIdentity,
+        ReadOnlyAccount, Session,
+    },
+};
+
+/// The version of the store export format.
+pub const STORE_EXPORT_VERSION: u32 = 1;
+
+const HEADER: &str = "-----BEGIN MATRIX CRYPTO STORE EXPORT-----";
+const FOOTER: &str = "-----END MATRIX CRYPTO STORE EXPORT-----";
+
+/// Error type for the export and import of a whole crypto store.
+#[derive(Debug, Error)]
+pub enum StoreExportError {
+    /// The export couldn't be decrypted or decoded.
+    #[error(transparent)]
+    Encryption(#[from] KeyExportError),
+
+    /// The export uses a version of the format we don't support.
+    #[error("the store export uses an unsupported version {0}")]
+    UnsupportedVersion(u32),
+
+    /// The store that should be exported doesn't contain an account.
+    #[error("the store doesn't contain an account")]
+    MissingAccount,
+
+    /// The store we're importing into already contains a different account.
+    #[error("the store already contains a different account")]
+    AccountMismatch,
+
+    /// A device or user identity in the export has invalid signatures.
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+
+    /// The content of the store couldn't be serialized.
+    #[error(transparent)]
+    Serialization(#[from] SerdeError),
+
+    /// The storage layer returned an error.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// A serializable version of a `ReadOnlyDevice`, also used by stores that keep
+/// devices as JSON blobs.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SerializedDevice {
+    user_id: UserId,
+    device_id: DeviceIdBox,
+    display_name: Option<String>,
+    trust_state: LocalTrust,
+    algorithms: Vec<EventEncryptionAlgorithm>,
+    keys: BTreeMap<DeviceKeyId, String>,
+    signatures: BTreeMap<UserId, BTreeMap<DeviceKeyId, String>>,
+}
+
+impl From<&ReadOnlyDevice> for SerializedDevice {
+    fn from(device: &ReadOnlyDevice) -> Self {
+        Self {
+            user_id: device.user_id().to_owned(),
+            device_id: device.device_id().into(),
+            display_name: device.display_name().clone(),
+            trust_state: device.local_trust_state(),
+            algorithms: device.algorithms().to_vec(),
+            keys: device.keys().clone(),
+            signatures: device.signatures().clone(),
+        }
+    }
+}
+
+impl From<SerializedDevice> for ReadOnlyDevice {
+    fn from(device: SerializedDevice) -> Self {
+        ReadOnlyDevice::new(
+            device.user_id,
+            device.device_id,
+            device.display_name,
+            device.trust_state,
+            device.algorithms,
+            device.keys,
+            device.signatures,
+        )
+    }
+}
+
+/// A serializable version of the public part of a user identity.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum SerializedIdentity {
+    Own {
+        master_key: MasterPubkey,
+        self_signing_key: SelfSigningPubkey,
+        user_signing_key: UserSigningPubkey,
+        verified: bool,
+    },
+    Other {
+        master_key: MasterPubkey,
+        self_signing_key: SelfSigningPubkey,
+        verified: bool,
+    },
+}
+
+impl From<&UserIdentities> for SerializedIdentity {
+    fn from(identity: &UserIdentities) -> Self {
+        match identity {
+            UserIdentities::Own(i) => SerializedIdentity::Own {
+                master_key: i.master_key().clone(),
+                self_signing_key: i.self_signing_key().clone(),
+                user_signing_key: i.user_signing_key().clone(),
+                verified: i.is_verified(),
+            },
+            UserIdentities::Other(i) => SerializedIdentity::Other {
+                master_key: i.master_key().clone(),
+                self_signing_key: i.self_signing_key().clone(),
+                verified: i.is_verified(),
+            },
+        }
+    }
+}
+
+impl SerializedIdentity {
+    /// Restore the identity, this checks the signatures of the keys again.
+    pub(crate) fn restore(self) -> Result<UserIdentities, SignatureError> {
+        Ok(match self {
+            SerializedIdentity::Own {
+                master_key,
+                self_signing_key,
+                user_signing_key,
+                verified,
+            } => {
+                let identity =
+                    OwnUserIdentity::new(master_key, self_signing_key, user_signing_key)?;
+
+                if verified {
+                    identity.mark_as_verified();
+                }
+
+                identity.into()
+            }
+            SerializedIdentity::Other {
+                master_key,
+                self_signing_key,
+                verified,
+            } => {
+                let identity = UserIdentity::new(master_key, self_signing_key)?;
+
+                if verified {
+                    identity.mark_as_verified();
+                }
+
+                identity.into()
+            }
+        })
+    }
+}
+
+/// The plaintext content of a store export.
+///
+/// All the Olm objects are pickled using a random pickle key that is part of
+/// the export, the export as a whole is encrypted using the passphrase.
+#[derive(Serialize, Deserialize)]
+struct StoreExport {
+    version: u32,
+    pickle_key: Vec<u8>,
+    account: PickledAccount,
+    private_identity: Option<PickledCrossSigningIdentity>,
+    sessions: Vec<PickledSession>,
+    inbound_group_sessions: Vec<PickledInboundGroupSession>,
+    outbound_group_sessions: Vec<PickledOutboundGroupSession>,
+    devices: Vec<SerializedDevice>,
+    identities: Vec<SerializedIdentity>,
+    tracked_users: BTreeMap<UserId, bool>,
+    values: HashMap<String, String>,
+    #[serde(default)]
+    message_hashes: Vec<OlmMessageHash>,
+    #[serde(default)]
+    megolm_message_indices: Vec<MegolmMessageIndex>,
+}
+
+/// Export everything the given store holds into a passphrase encrypted
+/// archive.
+///
+/// # Arguments
+///
+/// * `store` - The store that should be exported.
+///
+/// * `passphrase` - The passphrase that will be used to encrypt the export.
+///
+/// * `rounds` - The number of rounds that should be used for the key
+/// derivation when the passphrase gets turned into an AES key, see
+/// `encrypt_key_export()` for the recommended values.
+///
+/// # Panics
+///
+/// This method will panic if it can't get enough randomness from the OS to
+/// encrypt the export securely.
+pub async fn export_store(
+    store: &dyn CryptoStore,
+    passphrase: &str,
+    rounds: u32,
+) -> Result<String, StoreExportError> {
+    let account = store
+        .load_account()
+        .await?
+        .ok_or(StoreExportError::MissingAccount)?;
+
+    let pickle_key = PickleKey::new();
+    let pickle_mode = pickle_key.pickle_mode();
+
+    let private_identity = if let Some(i) = store.load_identity().await? {
+        Some(i.pickle(pickle_key.key()).await?)
+    } else {
+        None
+    };
+
+    let mut sessions = Vec::new();
+
+    for session in store.get_all_sessions().await? {
+        sessions.push(session.pickle(pickle_mode.clone()).await);
+    }
+
+    let mut inbound_group_sessions = Vec::new();
+
+    for session in store.get_inbound_group_sessions().await? {
+        inbound_group_sessions.push(session.pickle(pickle_mode.clone()).await);
+    }
+
+    let mut outbound_group_sessions = Vec::new();
+
+    for session in store.get_outbound_group_sessions().await? {
+        outbound_group_sessions.push(session.pickle(pickle_mode.clone()).await);
+    }
+
+    let users_for_key_query = store.users_for_key_query();
+    let mut tracked_users = BTreeMap::new();
+    let mut devices = Vec::new();
+    let mut identities = Vec::new();
+
+    let mut users = store.tracked_users();
+    users.insert(account.user_id().to_owned());
+
+    for user_id in users {
+        devices.extend(
+            store
+                .get_user_devices(&user_id)
+                .await?
+                .values()
+                .map(SerializedDevice::from),
+        );
+
+        if let Some(identity) = store.get_user_identity(&user_id).await? {
+            identities.push(SerializedIdentity::from(&identity));
+        }
+
+        if store.is_user_tracked(&user_id) {
+            let dirty = users_for_key_query.contains(&user_id);
+            tracked_users.insert(user_id, dirty);
+        }
+    }
+
+    let mut export = StoreExport {
+        version: STORE_EXPORT_VERSION,
+        pickle_key: pickle_key.key().to_vec(),
+        account: account.pickle(pickle_mode).await,
+        private_identity,
+        sessions,
+        inbound_group_sessions,
+        outbound_group_sessions,
+        devices,
+        identities,
+        tracked_users,
+        values: store.get_values().await?,
+        message_hashes: store.get_message_hashes().await?,
+        megolm_message_indices: store.get_megolm_message_indices().await?,
+    };
+
+    let plaintext = serde_json::to_string(&export);
+    export.pickle_key.zeroize();
+
+    let mut plaintext = Zeroizing::new(plaintext?.into_bytes());
+    let ciphertext = encrypt_helper(&mut plaintext, passphrase, rounds);
+
+    Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
+}
+
+/// Import a store export that was created using [`export_store`] into the
+/// given store.
+///
+/// The store should be empty or contain the same account as the export. Once
+/// the import succeeds an `OlmMachine` can be created using
+/// `OlmMachine::new_with_store()`, it will have the same identity, sessions
+/// and trust state as the machine the export was created from.
+///
+/// # Arguments
+///
+/// * `store` - The store the export should be imported into.
+///
+/// * `export` - The armored and encrypted store export.
+///
+/// * `passphrase` - The passphrase that was used to encrypt the export.
+///
+/// [`export_store`]: fn.export_store.html
+pub async fn import_store(
+    store: &dyn CryptoStore,
+    export: &str,
+    passphrase: &str,
+) -> Result<(), StoreExportError> {
+    let export = export.trim();
+
+    if !(export.starts_with(HEADER) && export.ends_with(FOOTER)) {
+        return Err(KeyExportError::InvalidHeaders.into());
+    }
+
+    let payload: String = export
+        .lines()
+        .filter(|l| !(l.starts_with(HEADER) || l.starts_with(FOOTER)))
+        .collect();
+
+    let plaintext = Zeroizing::new(decrypt_helper(&payload, passphrase)?);
+    let export: StoreExport = serde_json::from_str(&plaintext)?;
+
+    if export.version != STORE_EXPORT_VERSION {
+        return Err(StoreExportError::UnsupportedVersion(export.version));
+    }
+
+    let pickle_key = Zeroizing::new(export.pickle_key);
+    let pickle_mode = PicklingMode::Encrypted {
+        key: pickle_key.to_vec(),
+    };
+
+    check_pickle_version(export.account.version)?;
+    let account = ReadOnlyAccount::from_pickle(export.account, pickle_mode.clone())
+        .map_err(CryptoStoreError::from)?;
+
+    if let Some(existing) = store.load_account().await? {
+        if existing.identity_keys().curve25519() != account.identity_keys().curve25519() {
+            return Err(StoreExportError::AccountMismatch);
+        }
+    }
+
+    let private_identity = if let Some(pickle) = export.private_identity {
+        Some(
+            PrivateCrossSigningIdentity::from_pickle(pickle, &pickle_key)
+                .await
+                .map_err(|_| CryptoStoreError::UnpicklingError)?,
+        )
+    } else {
+        None
+    };
+
+    let sessions = export
+        .sessions
+        .into_iter()
+        .map(|s| {
+            check_pickle_version(s.version)?;
+
+            Ok(Session::from_pickle(
+                account.user_id.clone(),
+                account.device_id.clone(),
+                account.identity_keys.clone(),
+                s,
+                pickle_mode.clone(),
+            )?)
+        })
+        .collect::<Result<Vec<_>, CryptoStoreError>>()?;
+
+    let inbound_group_sessions = export
+        .inbound_group_sessions
+        .into_iter()
+        .map(|s| {
+            check_pickle_version(s.version)?;
+            Ok(InboundGroupSession::from_pickle(s, pickle_mode.clone())?)
+        })
+        .collect::<Result<Vec<_>, CryptoStoreError>>()?;
+
+    let outbound_group_sessions = export
+        .outbound_group_sessions
+        .into_iter()
+        .map(|s| {
+            check_pickle_version(s.version)?;
+
+            Ok(OutboundGroupSession::from_pickle(
+                account.device_id.clone(),
+                account.identity_keys.clone(),
+                s,
+                pickle_mode.clone(),
+            )?)
+        })
+        .collect::<Result<Vec<_>, CryptoStoreError>>()?;
+
+    let identities = export
+        .identities
+        .into_iter()
+        .map(SerializedIdentity::restore)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Everything is written in a single transaction, a failed import must not
+    // leave a half restored store behind.
+    let changes = Changes {
+        account: Some(account),
+        private_identity,
+        sessions,
+        inbound_group_sessions,
+        outbound_group_sessions,
+        devices: DeviceChanges {
+            new: export
+                .devices
+                .into_iter()
+                .map(ReadOnlyDevice::from)
+                .collect(),
+            ..Default::default()
+        },
+        identities: IdentityChanges {
+            new: identities,
+            ..Default::default()
+        },
+        message_hashes: export.message_hashes,
+        megolm_message_indices: export.megolm_message_indices,
+        tracked_users: export.tracked_users,
+        values: export.values,
+    };
+
+    store.save_changes(changes).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use matrix_sdk_common::identifiers::{event_id, room_id, user_id};
+
+    use super::{export_store, import_store, StoreExportError};
+    use crate::{
+        olm::{test::get_account_and_session, MegolmMessageIndex, OlmMessageHash},
+        store::{Changes, CryptoStore, MemoryStore},
+        OlmMachine,
+    };
+
+    #[tokio::test]
+    async fn store_export_cycle() {
+        let (account, session) = get_account_and_session().await;
+        let store = MemoryStore::new();
+
+        let (_, inbound) = account
+            .create_group_session_pair_with_defaults(&room_id!("!test:localhost"))
+            .await
+            .unwrap();
+
+        let hash = OlmMessageHash {
+            sender_key: "sender_key".to_owned(),
+            hash: "hash".to_owned(),
+        };
+
+        let index = MegolmMessageIndex {
+            room_id: inbound.room_id().to_owned(),
+            sender_key: inbound.sender_key().to_owned(),
+            session_id: inbound.session_id().to_owned(),
+            message_index: 0,
+            event_id: event_id!("$event:localhost"),
+            origin_server_ts: UNIX_EPOCH + Duration::from_millis(1_000),
+        };
+
+        store.save_account(account.clone()).await.unwrap();
+        store
+            .save_changes(Changes {
+                sessions: vec![session.clone()],
+                inbound_group_sessions: vec![inbound.clone()],
+                message_hashes: vec![hash.clone()],
+                megolm_message_indices: vec![index.clone()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        store
+            .update_tracked_user(&user_id!("@bob:localhost"), true)
+            .await
+            .unwrap();
+        store
+            .save_value("key".to_owned(), "value".to_owned())
+            .await
+            .unwrap();
+
+        let export = export_store(&store, "secret", 1).await.unwrap();
+
+        let restored = MemoryStore::new();
+
+        assert!(matches!(
+            import_store(&restored, &export, "wrong").await,
+            Err(StoreExportError::Encryption(_))
+        ));
+
+        import_store(&restored, &export, "secret").await.unwrap();
+
+        let restored_account = restored.load_account().await.unwrap().unwrap();
+        assert_eq!(account.identity_keys(), restored_account.identity_keys());
+
+        let sessions = restored.get_all_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), session.session_id());
+
+        assert!(restored
+            .get_inbound_group_session(&inbound.room_id, &inbound.sender_key, inbound.session_id())
+            .await
+            .unwrap()
+            .is_some());
+
+        assert!(restored.is_user_tracked(&user_id!("@bob:localhost")));
+        assert!(restored
+            .users_for_key_query()
+            .contains(&user_id!("@bob:localhost")));
+        assert_eq!(
+            restored.get_value("key").await.unwrap().as_deref(),
+            Some("value")
+        );
+
+        assert!(restored.is_message_known(&hash).await.unwrap());
+        assert_eq!(
+            restored
+                .get_megolm_message_index(
+                    inbound.room_id(),
+                    inbound.sender_key(),
+                    inbound.session_id(),
+                    0
+                )
+                .await
+                .unwrap(),
+            Some(index)
+        );
+
+        let machine = OlmMachine::new_with_store(
+            account.user_id().to_owned(),
+            account.device_id().into(),
+            Box::new(restored),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            machine.identity_keys().curve25519(),
+            account.identity_keys().curve25519()
+        );
+    }
+}
@@ -0,0 +1,1160 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A conformance test suite for `CryptoStore` implementations.
+//!
+//! The suite is generated using the [`cryptostore_integration_tests`] macro,
+//! this module contains the helpers the generated tests use to create the
+//! objects that get stored.
+//!
+//! [`cryptostore_integration_tests`]: ../../macro.cryptostore_integration_tests.html
+
+use std::{collections::BTreeMap, convert::TryFrom, time::SystemTime};
+
+use matrix_sdk_common::{
+    api::r0::keys::SignedKey,
+    identifiers::{event_id, room_id, user_id, DeviceId, DeviceIdBox, RoomId, UserId},
+};
+use olm_rs::outbound_group_session::OlmOutboundGroupSession;
+
+use crate::{
+    identities::{OwnUserIdentity, ReadOnlyDevice, UserIdentity},
+    olm::{
+        GroupSessionKey, InboundGroupSession, MegolmMessageIndex, OlmMessageHash,
+        OutboundGroupSession, PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
+    },
+    EncryptionSettings,
+};
+
+/// Generate a conformance test suite for a `CryptoStore` implementation.
+///
+/// The macro takes the name of an async function that opens a store for the
+/// user returned by [`alice_id()`] and the device returned by
+/// [`alice_device_id()`]. The function gets a directory the store can keep its
+/// data in, and an optional passphrase the store should be protected with:
+///
+/// ```ignore
+/// async fn open_store(dir: &Path, passphrase: Option<&str>) -> impl CryptoStore
+/// ```
+///
+/// Every test gets a fresh temporary directory that is removed once the test
+/// is done. Stores that persist their data should be invoked with the
+/// `persistent` flag, this adds tests that reopen the store in the same
+/// directory and check that nothing was lost.
+///
+/// The generated tests live in a `cryptostore_integration_tests` module and
+/// use the `tokio::test` attribute, `tokio` with the `macros` and
+/// `rt-multi-thread` features, as well as `tempfile`, need to be available as
+/// dev-dependencies.
+///
+/// Outside of this crate, the `testing` feature needs to be enabled.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[cfg(test)]
+/// mod test {
+///     use std::path::Path;
+///
+///     use matrix_sdk_crypto::store::MemoryStore;
+///
+///     async fn open_store(_: &Path, passphrase: Option<&str>) -> MemoryStore {
+///         passphrase.map_or_else(MemoryStore::new, MemoryStore::with_passphrase)
+///     }
+///
+///     matrix_sdk_crypto::cryptostore_integration_tests!(open_store);
+/// }
+/// ```
+///
+/// [`alice_id()`]: store/integration_tests/fn.alice_id.html
+/// [`alice_device_id()`]: store/integration_tests/fn.alice_device_id.html
+#[macro_export]
+macro_rules! cryptostore_integration_tests {
+    (@suite $open_store:ident, { $($persistent_test:item)* }) => {
+        mod cryptostore_integration_tests {
+            use $crate::{
+                olm::ReadOnlyAccount,
+                store::{
+                    integration_tests::{
+                        alice_id, bob_device_id, bob_id, get_account, get_device,
+                        get_inbound_group_session, get_megolm_message_index, get_message_hash,
+                        get_other_identity, get_outbound_group_session, get_own_identity,
+                        get_session, is_shared_with, mark_account_as_shared,
+                    },
+                    Changes, CryptoStore, DeviceChanges, IdentityChanges,
+                },
+            };
+
+            fn store_dir() -> tempfile::TempDir {
+                tempfile::tempdir().expect("Can't create a temporary directory")
+            }
+
+            async fn get_store(dir: &tempfile::TempDir) -> impl CryptoStore {
+                super::$open_store(dir.path(), None).await
+            }
+
+            async fn get_store_with_passphrase(
+                dir: &tempfile::TempDir,
+                passphrase: &str,
+            ) -> impl CryptoStore {
+                super::$open_store(dir.path(), Some(passphrase)).await
+            }
+
+            async fn get_loaded_store(
+                dir: &tempfile::TempDir,
+            ) -> (ReadOnlyAccount, impl CryptoStore) {
+                let store = get_store(dir).await;
+                let account = get_account();
+
+                store
+                    .save_account(account.clone())
+                    .await
+                    .expect("Can't save account");
+
+                (account, store)
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn account_saving() {
+                let dir = store_dir();
+                let store = get_store(&dir).await;
+                let account = get_account();
+
+                assert!(store.load_account().await.unwrap().is_none());
+
+                store.save_account(account.clone()).await.unwrap();
+                let loaded_account = store.load_account().await.unwrap().unwrap();
+                assert_eq!(account, loaded_account);
+
+                mark_account_as_shared(&account, 50);
+
+                let changes = Changes {
+                    account: Some(account.clone()),
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+                let loaded_account = store.load_account().await.unwrap().unwrap();
+                assert!(loaded_account.shared());
+                assert_eq!(
+                    account.uploaded_key_count(),
+                    loaded_account.uploaded_key_count()
+                );
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn private_identity_saving() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                assert!(store.load_identity().await.unwrap().is_none());
+
+                let (_, identity) = get_own_identity().await;
+                identity.mark_as_shared();
+
+                let changes = Changes {
+                    private_identity: Some(identity.clone()),
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let loaded_identity = store.load_identity().await.unwrap().unwrap();
+                assert_eq!(identity.user_id(), loaded_identity.user_id());
+                assert!(loaded_identity.shared());
+                assert!(!loaded_identity.is_empty().await);
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn session_saving() {
+                let dir = store_dir();
+                let (account, store) = get_loaded_store(&dir).await;
+                let session = get_session(&account).await;
+
+                assert!(store
+                    .get_sessions(&session.sender_key)
+                    .await
+                    .unwrap()
+                    .is_none());
+
+                let changes = Changes {
+                    sessions: vec![session.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let sessions = store
+                    .get_sessions(&session.sender_key)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let sessions = sessions.lock().await;
+
+                assert_eq!(sessions.len(), 1);
+                assert_eq!(sessions[0], session);
+
+                let all_sessions = store.get_all_sessions().await.unwrap();
+                assert_eq!(all_sessions.len(), 1);
+                assert_eq!(all_sessions[0].session_id(), session.session_id());
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn inbound_group_session_saving() {
+                let dir = store_dir();
+                let (account, store) = get_loaded_store(&dir).await;
+                let session = get_inbound_group_session(&account);
+
+                assert!(store
+                    .get_inbound_group_session(
+                        session.room_id(),
+                        session.sender_key(),
+                        session.session_id()
+                    )
+                    .await
+                    .unwrap()
+                    .is_none());
+
+                let changes = Changes {
+                    inbound_group_sessions: vec![session.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let loaded_session = store
+                    .get_inbound_group_session(
+                        session.room_id(),
+                        session.sender_key(),
+                        session.session_id(),
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(session, loaded_session);
+                assert_eq!(
+                    session.first_known_index(),
+                    loaded_session.first_known_index()
+                );
+                assert_eq!(
+                    store.get_inbound_group_sessions().await.unwrap(),
+                    vec![session]
+                );
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn inbound_group_session_backup_state() {
+                let dir = store_dir();
+                let (account, store) = get_loaded_store(&dir).await;
+                let session = get_inbound_group_session(&account);
+
+                let changes = Changes {
+                    inbound_group_sessions: vec![session.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                assert_eq!(
+                    store.inbound_group_sessions_for_backup(10).await.unwrap(),
+                    vec![session.clone()]
+                );
+                assert!(store
+                    .inbound_group_sessions_for_backup(0)
+                    .await
+                    .unwrap()
+                    .is_empty());
+
+                session.mark_as_backed_up();
+
+                let changes = Changes {
+                    inbound_group_sessions: vec![session.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                assert!(store
+                    .inbound_group_sessions_for_backup(10)
+                    .await
+                    .unwrap()
+                    .is_empty());
+
+                store.reset_backup_state().await.unwrap();
+
+                assert_eq!(
+                    store.inbound_group_sessions_for_backup(10).await.unwrap(),
+                    vec![session]
+                );
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn outbound_group_session_saving() {
+                let dir = store_dir();
+                let (account, store) = get_loaded_store(&dir).await;
+                let session = get_outbound_group_session(&account).await;
+                session.mark_shared_with(&bob_id(), &bob_device_id());
+
+                assert!(store
+                    .get_outbound_group_sessions()
+                    .await
+                    .unwrap()
+                    .is_empty());
+
+                let changes = Changes {
+                    outbound_group_sessions: vec![session.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let sessions = store.get_outbound_group_sessions().await.unwrap();
+                assert_eq!(sessions.len(), 1);
+
+                let loaded = &sessions[0];
+                assert_eq!(session.session_id(), loaded.session_id());
+                assert_eq!(session.room_id(), loaded.room_id());
+
+                // Only the latest session of a room is kept.
+                let new_session = get_outbound_group_session(&account).await;
+
+                let changes = Changes {
+                    outbound_group_sessions: vec![new_session.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let sessions = store.get_outbound_group_sessions().await.unwrap();
+                assert_eq!(sessions.len(), 1);
+                assert_eq!(new_session.session_id(), sessions[0].session_id());
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn tracked_users() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                let user_id = bob_id();
+
+                assert!(!store.is_user_tracked(&user_id));
+                assert!(!store.has_users_for_key_query());
+
+                assert!(store.update_tracked_user(&user_id, false).await.unwrap());
+                assert!(!store.update_tracked_user(&user_id, false).await.unwrap());
+
+                assert!(store.is_user_tracked(&user_id));
+                assert!(store.tracked_users().contains(&user_id));
+                assert!(!store.has_users_for_key_query());
+                assert!(!store.users_for_key_query().contains(&user_id));
+
+                assert!(!store.update_tracked_user(&user_id, true).await.unwrap());
+                assert!(store.has_users_for_key_query());
+                assert!(store.users_for_key_query().contains(&user_id));
+
+                assert!(!store.update_tracked_user(&user_id, false).await.unwrap());
+                assert!(store.is_user_tracked(&user_id));
+                assert!(!store.has_users_for_key_query());
+                assert!(!store.users_for_key_query().contains(&user_id));
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn device_saving() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                let device = get_device().await;
+
+                assert!(store
+                    .get_device(device.user_id(), device.device_id())
+                    .await
+                    .unwrap()
+                    .is_none());
+                assert!(store
+                    .get_user_devices(device.user_id())
+                    .await
+                    .unwrap()
+                    .is_empty());
+
+                let changes = Changes {
+                    devices: DeviceChanges {
+                        new: vec![device.clone()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let loaded_device = store
+                    .get_device(device.user_id(), device.device_id())
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(device, loaded_device);
+                assert_eq!(device.keys(), loaded_device.keys());
+                assert_eq!(device.algorithms(), loaded_device.algorithms());
+                assert_eq!(device.display_name(), loaded_device.display_name());
+
+                let user_devices = store.get_user_devices(device.user_id()).await.unwrap();
+                assert_eq!(user_devices.len(), 1);
+                assert_eq!(user_devices.get(device.device_id()), Some(&device));
+
+                let changes = Changes {
+                    devices: DeviceChanges {
+                        deleted: vec![device.clone()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                assert!(store
+                    .get_device(device.user_id(), device.device_id())
+                    .await
+                    .unwrap()
+                    .is_none());
+                assert!(store
+                    .get_user_devices(device.user_id())
+                    .await
+                    .unwrap()
+                    .is_empty());
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn user_identity_saving() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                let (own_identity, _) = get_own_identity().await;
+                let other_identity = get_other_identity().await;
+
+                assert!(store
+                    .get_user_identity(&alice_id())
+                    .await
+                    .unwrap()
+                    .is_none());
+
+                let changes = Changes {
+                    identities: IdentityChanges {
+                        new: vec![own_identity.clone().into()],
+                        changed: vec![other_identity.clone().into()],
+                    },
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let loaded = store
+                    .get_user_identity(own_identity.user_id())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(loaded, own_identity.clone().into());
+                assert_eq!(loaded.master_key(), own_identity.master_key());
+                assert!(!loaded.own().unwrap().is_verified());
+
+                let loaded = store
+                    .get_user_identity(other_identity.user_id())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(loaded, other_identity.clone().into());
+                assert_eq!(loaded.self_signing_key(), other_identity.self_signing_key());
+                assert!(!loaded.other().unwrap().is_verified());
+
+                own_identity.mark_as_verified();
+                other_identity.mark_as_verified();
+
+                let changes = Changes {
+                    identities: IdentityChanges {
+                        changed: vec![own_identity.clone().into(), other_identity.clone().into()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                let loaded = store
+                    .get_user_identity(own_identity.user_id())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(loaded.own().unwrap().is_verified());
+
+                let loaded = store
+                    .get_user_identity(other_identity.user_id())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(loaded.other().unwrap().is_verified());
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn key_value_saving() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                let key = "test_key".to_owned();
+                let value = "secret value".to_owned();
+
+                assert!(store.get_value(&key).await.unwrap().is_none());
+
+                store.save_value(key.clone(), value.clone()).await.unwrap();
+                assert_eq!(store.get_value(&key).await.unwrap(), Some(value.clone()));
+                assert_eq!(store.get_values().await.unwrap().get(&key), Some(&value));
+
+                let new_value = "new secret value".to_owned();
+                store
+                    .save_value(key.clone(), new_value.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(store.get_value(&key).await.unwrap(), Some(new_value));
+
+                store.remove_value(&key).await.unwrap();
+                assert!(store.get_value(&key).await.unwrap().is_none());
+                assert!(!store.get_values().await.unwrap().contains_key(&key));
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn olm_hash_saving() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                let hash = get_message_hash();
+
+                assert!(!store.is_message_known(&hash).await.unwrap());
+
+                let changes = Changes {
+                    message_hashes: vec![hash.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+                assert!(store.is_message_known(&hash).await.unwrap());
+
+                let mut other_hash = hash;
+                other_hash.hash = "other_hash".to_owned();
+                assert!(!store.is_message_known(&other_hash).await.unwrap());
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn megolm_message_index_saving() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                let index = get_megolm_message_index();
+
+                assert!(store
+                    .get_megolm_message_index(
+                        &index.room_id,
+                        &index.sender_key,
+                        &index.session_id,
+                        index.message_index,
+                    )
+                    .await
+                    .unwrap()
+                    .is_none());
+
+                let changes = Changes {
+                    megolm_message_indices: vec![index.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                assert_eq!(
+                    store
+                        .get_megolm_message_index(
+                            &index.room_id,
+                            &index.sender_key,
+                            &index.session_id,
+                            index.message_index,
+                        )
+                        .await
+                        .unwrap(),
+                    Some(index.clone())
+                );
+                assert!(store
+                    .get_megolm_message_index(
+                        &index.room_id,
+                        &index.sender_key,
+                        &index.session_id,
+                        index.message_index + 1,
+                    )
+                    .await
+                    .unwrap()
+                    .is_none());
+
+                // Session ids are chosen by the sender, a session with the
+                // same id from another sender has its own message indices.
+                assert!(store
+                    .get_megolm_message_index(
+                        &index.room_id,
+                        "other_sender",
+                        &index.session_id,
+                        index.message_index,
+                    )
+                    .await
+                    .unwrap()
+                    .is_none());
+            }
+
+            /// All the parts of a single `Changes` object need to be stored by
+            /// one `save_changes()` call.
+            #[tokio::test(flavor = "multi_thread")]
+            async fn save_changes_stores_everything() {
+                let dir = store_dir();
+                let store = get_store(&dir).await;
+                let account = get_account();
+
+                let session = get_session(&account).await;
+                let inbound = get_inbound_group_session(&account);
+                let outbound = get_outbound_group_session(&account).await;
+                let device = get_device().await;
+                let (identity, private_identity) = get_own_identity().await;
+                let hash = get_message_hash();
+                let index = get_megolm_message_index();
+
+                let changes = Changes {
+                    account: Some(account.clone()),
+                    private_identity: Some(private_identity),
+                    sessions: vec![session.clone()],
+                    message_hashes: vec![hash.clone()],
+                    megolm_message_indices: vec![index.clone()],
+                    inbound_group_sessions: vec![inbound.clone()],
+                    outbound_group_sessions: vec![outbound.clone()],
+                    identities: IdentityChanges {
+                        new: vec![identity.clone().into()],
+                        ..Default::default()
+                    },
+                    devices: DeviceChanges {
+                        new: vec![device.clone()],
+                        ..Default::default()
+                    },
+                    tracked_users: vec![(bob_id(), true)].into_iter().collect(),
+                    values: vec![("key".to_owned(), "value".to_owned())]
+                        .into_iter()
+                        .collect(),
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                assert_eq!(store.load_account().await.unwrap(), Some(account));
+                assert!(store.load_identity().await.unwrap().is_some());
+                assert!(store.is_user_tracked(&bob_id()));
+                assert!(store.users_for_key_query().contains(&bob_id()));
+                assert_eq!(store.get_value("key").await.unwrap().as_deref(), Some("value"));
+                assert!(store
+                    .get_sessions(&session.sender_key)
+                    .await
+                    .unwrap()
+                    .is_some());
+                assert!(store.is_message_known(&hash).await.unwrap());
+                assert_eq!(
+                    store
+                        .get_megolm_message_index(
+                            &index.room_id,
+                            &index.sender_key,
+                            &index.session_id,
+                            index.message_index,
+                        )
+                        .await
+                        .unwrap(),
+                    Some(index)
+                );
+                assert_eq!(
+                    store
+                        .get_inbound_group_session(
+                            inbound.room_id(),
+                            inbound.sender_key(),
+                            inbound.session_id()
+                        )
+                        .await
+                        .unwrap(),
+                    Some(inbound)
+                );
+                assert_eq!(
+                    store.get_outbound_group_sessions().await.unwrap()[0].session_id(),
+                    outbound.session_id()
+                );
+                assert_eq!(
+                    store.get_user_identity(identity.user_id()).await.unwrap(),
+                    Some(identity.into())
+                );
+                assert_eq!(
+                    store
+                        .get_device(device.user_id(), device.device_id())
+                        .await
+                        .unwrap(),
+                    Some(device)
+                );
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn passphrase_changing() {
+                let dir = store_dir();
+                let store = get_store_with_passphrase(&dir, "old_passphrase").await;
+                let account = get_account();
+                let session = get_session(&account).await;
+                let inbound = get_inbound_group_session(&account);
+                let (_, identity) = get_own_identity().await;
+
+                let changes = Changes {
+                    account: Some(account.clone()),
+                    private_identity: Some(identity),
+                    sessions: vec![session.clone()],
+                    inbound_group_sessions: vec![inbound.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                assert!(store
+                    .change_passphrase("wrong_passphrase", "new_passphrase", true)
+                    .await
+                    .is_err());
+
+                store
+                    .change_passphrase("old_passphrase", "new_passphrase", false)
+                    .await
+                    .unwrap();
+                store
+                    .change_passphrase("new_passphrase", "newer_passphrase", true)
+                    .await
+                    .unwrap();
+
+                assert!(store
+                    .change_passphrase("old_passphrase", "new_passphrase", false)
+                    .await
+                    .is_err());
+
+                assert_eq!(store.load_account().await.unwrap(), Some(account));
+                assert!(store.load_identity().await.unwrap().is_some());
+                assert_eq!(
+                    store.get_all_sessions().await.unwrap()[0].session_id(),
+                    session.session_id()
+                );
+                assert_eq!(
+                    store
+                        .get_inbound_group_session(
+                            inbound.room_id(),
+                            inbound.sender_key(),
+                            inbound.session_id()
+                        )
+                        .await
+                        .unwrap(),
+                    Some(inbound)
+                );
+            }
+
+            $($persistent_test)*
+        }
+    };
+    ($open_store:ident) => {
+        $crate::cryptostore_integration_tests!(@suite $open_store, {});
+    };
+    ($open_store:ident, persistent) => {
+        $crate::cryptostore_integration_tests!(@suite $open_store, {
+            use $crate::olm::InboundGroupSession;
+
+            async fn reopen_store(dir: &tempfile::TempDir) -> impl CryptoStore {
+                let store = get_store(dir).await;
+                store.load_account().await.unwrap();
+
+                store
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn everything_survives_reopening() {
+                let dir = store_dir();
+                let store = get_store(&dir).await;
+                let account = get_account();
+
+                let session = get_session(&account).await;
+                let outbound = get_outbound_group_session(&account).await;
+                outbound.mark_shared_with(&bob_id(), &bob_device_id());
+
+                // A forwarded session, the forwarding chain needs to survive
+                // as well.
+                let mut export = get_inbound_group_session(&account).export().await;
+                export.forwarding_curve25519_key_chain = vec!["some_chain".to_owned()];
+                let inbound = InboundGroupSession::from_export(export).unwrap();
+
+                let device = get_device().await;
+                let (identity, private_identity) = get_own_identity().await;
+                let other_identity = get_other_identity().await;
+                other_identity.mark_as_verified();
+                let hash = get_message_hash();
+                let index = get_megolm_message_index();
+
+                mark_account_as_shared(&account, 50);
+                inbound.mark_as_backed_up();
+
+                let changes = Changes {
+                    account: Some(account.clone()),
+                    private_identity: Some(private_identity),
+                    sessions: vec![session.clone()],
+                    message_hashes: vec![hash.clone()],
+                    megolm_message_indices: vec![index.clone()],
+                    inbound_group_sessions: vec![inbound.clone()],
+                    outbound_group_sessions: vec![outbound.clone()],
+                    identities: IdentityChanges {
+                        new: vec![identity.clone().into(), other_identity.clone().into()],
+                        ..Default::default()
+                    },
+                    devices: DeviceChanges {
+                        new: vec![device.clone()],
+                        ..Default::default()
+                    },
+                    tracked_users: vec![(bob_id(), true)].into_iter().collect(),
+                    values: vec![("key".to_owned(), "value".to_owned())]
+                        .into_iter()
+                        .collect(),
+                };
+
+                store.save_changes(changes).await.unwrap();
+                drop(store);
+
+                let store = get_store(&dir).await;
+                let loaded_account = store.load_account().await.unwrap().unwrap();
+                assert_eq!(account, loaded_account);
+                assert!(loaded_account.shared());
+                assert_eq!(
+                    account.uploaded_key_count(),
+                    loaded_account.uploaded_key_count()
+                );
+
+                assert!(store.load_identity().await.unwrap().is_some());
+                assert!(store.is_user_tracked(&bob_id()));
+                assert!(store.users_for_key_query().contains(&bob_id()));
+                assert_eq!(store.get_value("key").await.unwrap().as_deref(), Some("value"));
+
+                let sessions = store
+                    .get_sessions(&session.sender_key)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(sessions.lock().await[0].session_id(), session.session_id());
+
+                assert!(store.is_message_known(&hash).await.unwrap());
+                assert_eq!(
+                    store
+                        .get_megolm_message_index(
+                            &index.room_id,
+                            &index.sender_key,
+                            &index.session_id,
+                            index.message_index,
+                        )
+                        .await
+                        .unwrap(),
+                    Some(index)
+                );
+
+                let loaded_inbound = store
+                    .get_inbound_group_session(
+                        inbound.room_id(),
+                        inbound.sender_key(),
+                        inbound.session_id(),
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(inbound, loaded_inbound);
+                assert!(loaded_inbound.backed_up());
+                assert_eq!(
+                    loaded_inbound.forwarding_key_chain().await,
+                    vec!["some_chain".to_owned()]
+                );
+
+                let loaded_outbound = store.get_outbound_group_sessions().await.unwrap();
+                assert_eq!(loaded_outbound[0].session_id(), outbound.session_id());
+                assert!(is_shared_with(
+                    &loaded_outbound[0],
+                    &bob_id(),
+                    &bob_device_id()
+                ));
+
+                assert_eq!(
+                    store.get_user_identity(identity.user_id()).await.unwrap(),
+                    Some(identity.into())
+                );
+                let loaded_identity = store
+                    .get_user_identity(other_identity.user_id())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(loaded_identity.other().unwrap().is_verified());
+
+                assert_eq!(
+                    store
+                        .get_device(device.user_id(), device.device_id())
+                        .await
+                        .unwrap(),
+                    Some(device.clone())
+                );
+
+                let changes = Changes {
+                    devices: DeviceChanges {
+                        deleted: vec![device.clone()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+                drop(store);
+
+                let store = reopen_store(&dir).await;
+                assert!(store
+                    .get_device(device.user_id(), device.device_id())
+                    .await
+                    .unwrap()
+                    .is_none());
+            }
+
+            /// A `save_changes()` call that fails must not leave any of its
+            /// changes behind.
+            #[tokio::test(flavor = "multi_thread")]
+            async fn save_changes_is_atomic() {
+                let dir = store_dir();
+                let store = get_store(&dir).await;
+                let account = get_account();
+                let session = get_session(&account).await;
+                let device = get_device().await;
+                let hash = get_message_hash();
+
+                // Sessions can't be stored before an account is, so the whole
+                // call needs to fail.
+                let changes = Changes {
+                    sessions: vec![session.clone()],
+                    message_hashes: vec![hash.clone()],
+                    devices: DeviceChanges {
+                        new: vec![device.clone()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+
+                assert!(store.save_changes(changes).await.is_err());
+                drop(store);
+
+                let store = get_store(&dir).await;
+                store.save_account(account).await.unwrap();
+
+                assert!(store
+                    .get_sessions(&session.sender_key)
+                    .await
+                    .unwrap()
+                    .is_none());
+                assert!(!store.is_message_known(&hash).await.unwrap());
+                assert!(store
+                    .get_device(device.user_id(), device.device_id())
+                    .await
+                    .unwrap()
+                    .is_none());
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn tracked_users_survive_reopening() {
+                let dir = store_dir();
+                let (_, store) = get_loaded_store(&dir).await;
+                let user_id = bob_id();
+
+                store.update_tracked_user(&user_id, true).await.unwrap();
+                drop(store);
+
+                let store = reopen_store(&dir).await;
+                assert!(store.is_user_tracked(&user_id));
+                assert!(store.users_for_key_query().contains(&user_id));
+
+                store.update_tracked_user(&user_id, false).await.unwrap();
+                drop(store);
+
+                let store = reopen_store(&dir).await;
+                assert!(store.is_user_tracked(&user_id));
+                assert!(!store.has_users_for_key_query());
+            }
+
+            #[tokio::test(flavor = "multi_thread")]
+            async fn passphrase_survives_reopening() {
+                let dir = store_dir();
+                let store = get_store_with_passphrase(&dir, "old_passphrase").await;
+                let account = get_account();
+                let session = get_session(&account).await;
+
+                let changes = Changes {
+                    account: Some(account.clone()),
+                    sessions: vec![session.clone()],
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+                store
+                    .change_passphrase("old_passphrase", "new_passphrase", true)
+                    .await
+                    .unwrap();
+                drop(store);
+
+                let store = get_store_with_passphrase(&dir, "new_passphrase").await;
+                assert_eq!(store.load_account().await.unwrap(), Some(account));
+                assert_eq!(
+                    store.get_all_sessions().await.unwrap()[0].session_id(),
+                    session.session_id()
+                );
+            }
+        });
+    };
+}
+
+/// The user id of the owner of the stores that are tested.
+pub fn alice_id() -> UserId {
+    user_id!("@alice:example.org")
+}
+
+/// The device id of the owner of the stores that are tested.
+pub fn alice_device_id() -> DeviceIdBox {
+    "ALICEDEVICE".into()
+}
+
+/// The user id of a user we communicate with.
+pub fn bob_id() -> UserId {
+    user_id!("@bob:example.org")
+}
+
+/// The device id of a device we communicate with.
+pub fn bob_device_id() -> DeviceIdBox {
+    "BOBDEVICE".into()
+}
+
+fn room_id() -> RoomId {
+    room_id!("!test:example.org")
+}
+
+/// Create a new account for the owner of the stores.
+pub fn get_account() -> ReadOnlyAccount {
+    ReadOnlyAccount::new(&alice_id(), &alice_device_id())
+}
+
+/// Mark the account as shared and set the number of one-time keys the server
+/// has for it.
+pub fn mark_account_as_shared(account: &ReadOnlyAccount, uploaded_key_count: u64) {
+    account.mark_as_shared();
+    account.update_uploaded_key_count(uploaded_key_count);
+}
+
+/// Check if the outbound group session was shared with the given device.
+pub fn is_shared_with(
+    session: &OutboundGroupSession,
+    user_id: &UserId,
+    device_id: &DeviceId,
+) -> bool {
+    session.is_shared_with(user_id, device_id)
+}
+
+/// Create a new Olm session between the given account and a device of Bob.
+pub async fn get_session(account: &ReadOnlyAccount) -> Session {
+    let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+    bob.generate_one_time_keys_helper(1).await;
+
+    let one_time_key = bob
+        .one_time_keys()
+        .await
+        .curve25519()
+        .values()
+        .next()
+        .cloned()
+        .expect("Can't generate a one-time key");
+    let one_time_key = SignedKey::new(one_time_key, BTreeMap::new());
+    let sender_key = bob.identity_keys().curve25519().to_owned();
+
+    account
+        .create_outbound_session_helper(&sender_key, &one_time_key)
+        .await
+        .expect("Can't create an Olm session")
+}
+
+/// Create a new inbound group session that was sent by the given account.
+pub fn get_inbound_group_session(account: &ReadOnlyAccount) -> InboundGroupSession {
+    let identity_keys = account.identity_keys();
+    let outbound = OlmOutboundGroupSession::new();
+
+    InboundGroupSession::new(
+        identity_keys.curve25519(),
+        identity_keys.ed25519(),
+        &room_id(),
+        GroupSessionKey(outbound.session_key()),
+    )
+    .expect("Can't create an inbound group session")
+}
+
+/// Create a new outbound group session for the given account.
+pub async fn get_outbound_group_session(account: &ReadOnlyAccount) -> OutboundGroupSession {
+    account
+        .create_group_session_pair(&room_id(), EncryptionSettings::default())
+        .await
+        .expect("Can't create an outbound group session")
+        .0
+}
+
+/// Get the device of Bob.
+pub async fn get_device() -> ReadOnlyDevice {
+    let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+    ReadOnlyDevice::try_from(&bob.device_keys().await).expect("Can't create a device")
+}
+
+/// Create a new cross signing identity for the owner of the stores.
+///
+/// Returns the public and the private part of the identity.
+pub async fn get_own_identity() -> (OwnUserIdentity, PrivateCrossSigningIdentity) {
+    let private_identity = PrivateCrossSigningIdentity::new(alice_id()).await;
+    let identity = private_identity
+        .as_public_identity()
+        .await
+        .expect("Can't create a public identity");
+
+    (identity, private_identity)
+}
+
+/// Create a new cross signing identity for Bob.
+pub async fn get_other_identity() -> UserIdentity {
+    let identity = PrivateCrossSigningIdentity::new(bob_id())
+        .await
+        .as_public_identity()
+        .await
+        .expect("Can't create a public identity");
+
+    UserIdentity::new(
+        identity.master_key().clone(),
+        identity.self_signing_key().clone(),
+    )
+    .expect("Can't create a user identity")
+}
+
+/// Get a hash of an Olm message.
+pub fn get_message_hash() -> OlmMessageHash {
+    OlmMessageHash {
+        sender_key: "test_sender".to_owned(),
+        hash: "test_hash".to_owned(),
+    }
+}
+
+/// Get a message index of a Megolm session.
+pub fn get_megolm_message_index() -> MegolmMessageIndex {
+    MegolmMessageIndex {
+        room_id: room_id!("!test:localhost"),
+        sender_key: "test_sender".to_owned(),
+        session_id: "test_session".to_owned(),
+        message_index: 5,
+        event_id: event_id!("$test_event:example.org"),
+        origin_server_ts: SystemTime::UNIX_EPOCH,
+    }
+}
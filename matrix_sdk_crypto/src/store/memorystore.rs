@@ -14,7 +14,7 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    sync::{Arc, RwLock as SyncRwLock},
 };
 
 use dashmap::{DashMap, DashSet};
@@ -23,40 +23,51 @@ use matrix_sdk_common::{
     identifiers::{DeviceId, DeviceIdBox, RoomId, UserId},
     locks::Mutex,
 };
+use zeroize::Zeroizing;
 
 use super::{
     caches::{DeviceStore, GroupSessionStore, SessionStore},
-    Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, Session,
+    Changes, CryptoStore, CryptoStoreError, InboundGroupSession, ReadOnlyAccount, Result, Session,
 };
 use crate::{
     identities::{ReadOnlyDevice, UserIdentities},
-    olm::PrivateCrossSigningIdentity,
+    olm::{MegolmMessageIndex, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity},
 };
 
 /// An in-memory only store that will forget all the E2EE key once it's dropped.
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
+    account: Arc<SyncRwLock<Option<ReadOnlyAccount>>>,
+    private_identity: Arc<SyncRwLock<Option<PrivateCrossSigningIdentity>>>,
     sessions: SessionStore,
     inbound_group_sessions: GroupSessionStore,
+    outbound_group_sessions: Arc<DashMap<RoomId, OutboundGroupSession>>,
     tracked_users: Arc<DashSet<UserId>>,
     users_for_key_query: Arc<DashSet<UserId>>,
     olm_hashes: Arc<DashMap<String, DashSet<String>>>,
+    megolm_message_indices: Arc<DashMap<(RoomId, String, String, u32), MegolmMessageIndex>>,
     devices: DeviceStore,
     identities: Arc<DashMap<UserId, UserIdentities>>,
     values: Arc<DashMap<String, String>>,
+    passphrase: Arc<SyncRwLock<Option<Zeroizing<String>>>>,
 }
 
 impl Default for MemoryStore {
     fn default() -> Self {
         MemoryStore {
+            account: Arc::new(SyncRwLock::new(None)),
+            private_identity: Arc::new(SyncRwLock::new(None)),
             sessions: SessionStore::new(),
             inbound_group_sessions: GroupSessionStore::new(),
+            outbound_group_sessions: Arc::new(DashMap::new()),
             tracked_users: Arc::new(DashSet::new()),
             users_for_key_query: Arc::new(DashSet::new()),
             olm_hashes: Arc::new(DashMap::new()),
+            megolm_message_indices: Arc::new(DashMap::new()),
             devices: DeviceStore::new(),
             identities: Arc::new(DashMap::new()),
             values: Arc::new(DashMap::new()),
+            passphrase: Arc::new(SyncRwLock::new(None)),
         }
     }
 }
@@ -67,6 +78,17 @@ impl MemoryStore {
         Self::default()
     }
 
+    /// Create a new empty `MemoryStore` that is protected by a passphrase.
+    ///
+    /// Nothing is encrypted in a memory store, the passphrase is only checked
+    /// when it gets changed, like it would be for a persistent store.
+    pub fn with_passphrase(passphrase: &str) -> Self {
+        let store = Self::default();
+        *store.passphrase.write().unwrap() = Some(Zeroizing::new(passphrase.to_owned()));
+
+        store
+    }
+
     pub(crate) async fn save_devices(&self, mut devices: Vec<ReadOnlyDevice>) {
         for device in devices.drain(..) {
             let _ = self.devices.add(device);
@@ -96,18 +118,32 @@ impl MemoryStore {
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 impl CryptoStore for MemoryStore {
     async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
-        Ok(None)
+        Ok(self.account.read().unwrap().clone())
     }
 
-    async fn save_account(&self, _: ReadOnlyAccount) -> Result<()> {
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        *self.account.write().unwrap() = Some(account);
         Ok(())
     }
 
     async fn save_changes(&self, mut changes: Changes) -> Result<()> {
+        if let Some(account) = changes.account {
+            *self.account.write().unwrap() = Some(account);
+        }
+
+        if let Some(identity) = changes.private_identity {
+            *self.private_identity.write().unwrap() = Some(identity);
+        }
+
         self.save_sessions(changes.sessions).await;
         self.save_inbound_group_sessions(changes.inbound_group_sessions)
             .await;
 
+        for session in changes.outbound_group_sessions {
+            self.outbound_group_sessions
+                .insert(session.room_id().to_owned(), session);
+        }
+
         self.save_devices(changes.devices.new).await;
         self.save_devices(changes.devices.changed).await;
         self.delete_devices(changes.devices.deleted).await;
@@ -130,6 +166,26 @@ impl CryptoStore for MemoryStore {
                 .insert(hash.hash.clone());
         }
 
+        for index in changes.megolm_message_indices {
+            self.megolm_message_indices.insert(
+                (
+                    index.room_id.clone(),
+                    index.sender_key.clone(),
+                    index.session_id.clone(),
+                    index.message_index,
+                ),
+                index,
+            );
+        }
+
+        for (user_id, dirty) in changes.tracked_users {
+            self.update_tracked_user(&user_id, dirty).await?;
+        }
+
+        for (key, value) in changes.values {
+            self.values.insert(key, value);
+        }
+
         Ok(())
     }
 
@@ -137,6 +193,10 @@ impl CryptoStore for MemoryStore {
         Ok(self.sessions.get(sender_key))
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        Ok(self.sessions.get_all().await)
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -152,6 +212,40 @@ impl CryptoStore for MemoryStore {
         Ok(self.inbound_group_sessions.get_all())
     }
 
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        Ok(self
+            .outbound_group_sessions
+            .iter()
+            .map(|s| s.value().clone())
+            .collect())
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        Ok(self
+            .inbound_group_sessions
+            .get_all()
+            .into_iter()
+            .filter(|s| !s.backed_up())
+            .take(limit)
+            .collect())
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        for session in self.inbound_group_sessions.get_all() {
+            session.reset_backup_state();
+        }
+
+        Ok(())
+    }
+
+    fn tracked_users(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.tracked_users.iter().map(|u| u.clone()).collect()
+    }
+
     fn users_for_key_query(&self) -> HashSet<UserId> {
         #[allow(clippy::map_clone)]
         self.users_for_key_query.iter().map(|u| u.clone()).collect()
@@ -221,143 +315,111 @@ impl CryptoStore for MemoryStore {
         Ok(self.values.get(key).map(|v| v.to_owned()))
     }
 
+    async fn get_values(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .values
+            .iter()
+            .map(|v| (v.key().to_owned(), v.value().to_owned()))
+            .collect())
+    }
+
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
-        Ok(None)
+        Ok(self.private_identity.read().unwrap().clone())
     }
 
-    async fn is_message_known(&self, message_hash: &crate::olm::OlmMessageHash) -> Result<bool> {
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
         Ok(self
             .olm_hashes
             .entry(message_hash.sender_key.to_owned())
             .or_insert_with(DashSet::new)
             .contains(&message_hash.hash))
     }
-}
-
-#[cfg(test)]
-mod test {
-    use crate::{
-        identities::device::test::get_device,
-        olm::{test::get_account_and_session, InboundGroupSession, OlmMessageHash},
-        store::{memorystore::MemoryStore, Changes, CryptoStore},
-    };
-    use matrix_sdk_common::identifiers::room_id;
-
-    #[tokio::test]
-    async fn test_session_store() {
-        let (account, session) = get_account_and_session().await;
-        let store = MemoryStore::new();
-
-        assert!(store.load_account().await.unwrap().is_none());
-        store.save_account(account).await.unwrap();
 
-        store.save_sessions(vec![session.clone()]).await;
-
-        let sessions = store
-            .get_sessions(&session.sender_key)
-            .await
-            .unwrap()
-            .unwrap();
-        let sessions = sessions.lock().await;
-
-        let loaded_session = &sessions[0];
-
-        assert_eq!(&session, loaded_session);
+    async fn get_message_hashes(&self) -> Result<Vec<OlmMessageHash>> {
+        Ok(self
+            .olm_hashes
+            .iter()
+            .flat_map(|entry| {
+                let sender_key = entry.key().to_owned();
+
+                entry
+                    .value()
+                    .iter()
+                    .map(|hash| OlmMessageHash {
+                        sender_key: sender_key.clone(),
+                        hash: hash.to_owned(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect())
     }
 
-    #[tokio::test]
-    async fn test_group_session_store() {
-        let (account, _) = get_account_and_session().await;
-        let room_id = room_id!("!test:localhost");
-
-        let (outbound, _) = account
-            .create_group_session_pair_with_defaults(&room_id)
-            .await
-            .unwrap();
-        let inbound = InboundGroupSession::new(
-            "test_key",
-            "test_key",
-            &room_id,
-            outbound.session_key().await,
-        )
-        .unwrap();
-
-        let store = MemoryStore::new();
-        let _ = store
-            .save_inbound_group_sessions(vec![inbound.clone()])
-            .await;
-
-        let loaded_session = store
-            .get_inbound_group_session(&room_id, "test_key", outbound.session_id())
-            .await
-            .unwrap()
-            .unwrap();
-        assert_eq!(inbound, loaded_session);
+    async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        _repickle: bool,
+    ) -> Result<()> {
+        let mut passphrase = self.passphrase.write().unwrap();
+
+        // Like a persistent store that was opened without a passphrase, a
+        // memory store without one has nothing the old passphrase can unlock.
+        match passphrase.as_ref() {
+            Some(p) if p.as_str() == old_passphrase => {
+                // Nothing is pickled in the memory store, so there's nothing
+                // to repickle either.
+                *passphrase = Some(Zeroizing::new(new_passphrase.to_owned()));
+                Ok(())
+            }
+            _ => Err(CryptoStoreError::UnpicklingError),
+        }
     }
 
-    #[tokio::test]
-    async fn test_device_store() {
-        let device = get_device();
-        let store = MemoryStore::new();
-
-        store.save_devices(vec![device.clone()]).await;
-
-        let loaded_device = store
-            .get_device(device.user_id(), device.device_id())
-            .await
-            .unwrap()
-            .unwrap();
-
-        assert_eq!(device, loaded_device);
-
-        let user_devices = store.get_user_devices(device.user_id()).await.unwrap();
-
-        assert_eq!(&**user_devices.keys().next().unwrap(), device.device_id());
-        assert_eq!(user_devices.values().next().unwrap(), &device);
-
-        let loaded_device = user_devices.get(device.device_id()).unwrap();
-
-        assert_eq!(&device, loaded_device);
+    async fn get_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<MegolmMessageIndex>> {
+        Ok(self
+            .megolm_message_indices
+            .get(&(
+                room_id.to_owned(),
+                sender_key.to_owned(),
+                session_id.to_owned(),
+                message_index,
+            ))
+            .map(|i| i.clone()))
+    }
 
-        store.delete_devices(vec![device.clone()]).await;
-        assert!(store
-            .get_device(device.user_id(), device.device_id())
-            .await
-            .unwrap()
-            .is_none());
+    async fn get_megolm_message_indices(&self) -> Result<Vec<MegolmMessageIndex>> {
+        Ok(self
+            .megolm_message_indices
+            .iter()
+            .map(|i| i.value().clone())
+            .collect())
     }
+}
 
-    #[tokio::test]
-    async fn test_tracked_users() {
-        let device = get_device();
-        let store = MemoryStore::new();
+#[cfg(test)]
+mod test {
+    use std::path::Path;
 
-        assert!(store
-            .update_tracked_user(device.user_id(), false)
-            .await
-            .unwrap());
-        assert!(!store
-            .update_tracked_user(device.user_id(), false)
-            .await
-            .unwrap());
+    use crate::store::{memorystore::MemoryStore, CryptoStore};
 
-        assert!(store.is_user_tracked(device.user_id()));
+    async fn open_store(_: &Path, passphrase: Option<&str>) -> MemoryStore {
+        passphrase.map_or_else(MemoryStore::new, MemoryStore::with_passphrase)
     }
 
-    #[tokio::test]
-    async fn test_message_hash() {
-        let store = MemoryStore::new();
-
-        let hash = OlmMessageHash {
-            sender_key: "test_sender".to_owned(),
-            hash: "test_hash".to_owned(),
-        };
-
-        let mut changes = Changes::default();
-        changes.message_hashes.push(hash.clone());
+    crate::cryptostore_integration_tests!(open_store);
 
-        assert!(!store.is_message_known(&hash).await.unwrap());
-        store.save_changes(changes).await.unwrap();
-        assert!(store.is_message_known(&hash).await.unwrap());
+    #[tokio::test]
+    async fn change_passphrase_without_passphrase() {
+        // Stores without a passphrase have nothing to unlock.
+        assert!(MemoryStore::new()
+            .change_passphrase("", "new_passphrase", false)
+            .await
+            .is_err());
     }
 }
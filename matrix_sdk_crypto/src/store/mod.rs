@@ -17,9 +17,9 @@
 //! The storage layer for the [`OlmMachine`] can be customized using a trait.
 //! Implementing your own [`CryptoStore`]
 //!
-//! An in-memory only store is provided as well as a SQLite based one, depending
-//! on your needs and targets a custom store may be implemented, e.g. for
-//! `wasm-unknown-unknown` an indexeddb store would be needed
+//! An in-memory only store is provided as well as a SQLite and a sled based
+//! one, depending on your needs and targets a custom store may be implemented,
+//! e.g. for `wasm-unknown-unknown` an indexeddb store would be needed
 //!
 //! ```
 //! # use matrix_sdk_crypto::{
@@ -38,12 +38,23 @@
 //! [`CryptoStore`]: trait.Cryptostore.html
 
 pub mod caches;
+mod export;
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(feature = "docs", doc(cfg(r#testing)))]
+pub mod integration_tests;
 mod memorystore;
 mod pickle_key;
 #[cfg(not(target_arch = "wasm32"))]
+#[cfg(feature = "sled_cryptostore")]
+pub(crate) mod sled;
+#[cfg(not(target_arch = "wasm32"))]
 #[cfg(feature = "sqlite_cryptostore")]
 pub(crate) mod sqlite;
 
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(feature = "sled_cryptostore")]
+pub use self::sled::SledStore;
+pub use export::{export_store, import_store, StoreExportError, STORE_EXPORT_VERSION};
 pub use memorystore::MemoryStore;
 pub use pickle_key::{EncryptedPickleKey, PickleKey};
 #[cfg(not(target_arch = "wasm32"))]
@@ -51,7 +62,7 @@ pub use pickle_key::{EncryptedPickleKey, PickleKey};
 pub use sqlite::SqliteStore;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
     io::Error as IoError,
     ops::Deref,
@@ -68,6 +79,11 @@ use thiserror::Error;
 #[cfg(feature = "sqlite_cryptostore")]
 use sqlx::Error as SqlxError;
 
+#[cfg_attr(feature = "docs", doc(cfg(r#sled_cryptostore)))]
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(feature = "sled_cryptostore")]
+use ::sled::Error as SledError;
+
 use matrix_sdk_common::{
     async_trait,
     identifiers::{
@@ -79,10 +95,11 @@ use matrix_sdk_common::{
 };
 
 use crate::{
-    error::SessionUnpicklingError,
-    identities::{Device, ReadOnlyDevice, UserDevices, UserIdentities},
+    error::{SessionUnpicklingError, SignatureError},
+    identities::{Device, ReadOnlyDevice, UserDevices, UserIdentities, VerifiableUserIdentity},
     olm::{
-        InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
+        InboundGroupSession, MegolmMessageIndex, OlmMessageHash, OutboundGroupSession,
+        PrivateCrossSigningIdentity, ReadOnlyAccount, Session, PICKLE_VERSION,
     },
     verification::VerificationMachine,
 };
@@ -111,9 +128,15 @@ pub struct Changes {
     pub private_identity: Option<PrivateCrossSigningIdentity>,
     pub sessions: Vec<Session>,
     pub message_hashes: Vec<OlmMessageHash>,
+    pub megolm_message_indices: Vec<MegolmMessageIndex>,
     pub inbound_group_sessions: Vec<InboundGroupSession>,
+    pub outbound_group_sessions: Vec<OutboundGroupSession>,
     pub identities: IdentityChanges,
     pub devices: DeviceChanges,
+    /// Users that should be tracked, the value marks them for a key query.
+    pub tracked_users: BTreeMap<UserId, bool>,
+    /// Key-value pairs that should be stored, see `CryptoStore::save_value()`.
+    pub values: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -254,6 +277,21 @@ impl Store {
             }))
     }
 
+    pub async fn get_verifiable_identity(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<VerifiableUserIdentity>> {
+        Ok(self
+            .inner
+            .get_user_identity(user_id)
+            .await?
+            .and_then(|i| i.other().cloned())
+            .map(|i| VerifiableUserIdentity {
+                inner: i,
+                verification_machine: self.verification_machine.clone(),
+            }))
+    }
+
     pub async fn get_object<V: for<'b> Deserialize<'b>>(&self, key: &str) -> Result<Option<V>> {
         if let Some(value) = self.get_value(key).await? {
             Ok(Some(serde_json::from_str(&value)?))
@@ -296,6 +334,12 @@ pub enum CryptoStoreError {
     #[error(transparent)]
     DatabaseError(#[from] SqlxError),
 
+    /// An error occurred in the sled database.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg(feature = "sled_cryptostore")]
+    #[error(transparent)]
+    Sled(#[from] SledError),
+
     /// An IO error occurred.
     #[error(transparent)]
     Io(#[from] IoError),
@@ -320,6 +364,23 @@ pub enum CryptoStoreError {
     #[error("An object failed to be decrypted while unpickling")]
     UnpicklingError,
 
+    /// The database was written by a newer version of the library, its
+    /// schema can't be read by this version.
+    #[error("the database has schema version {0}, the newest supported version is {1}")]
+    UnsupportedDatabaseVersion(u32, u32),
+
+    /// A version number that was read from the database is invalid.
+    #[error("the database contains an invalid version number {0}")]
+    InvalidVersion(i64),
+
+    /// A pickled object was created by a newer version of the library.
+    #[error("the pickle has version {0}, the newest supported version is {1}")]
+    UnsupportedPickleVersion(u32, u32),
+
+    /// A stored user identity failed its signature check.
+    #[error(transparent)]
+    InvalidIdentity(#[from] SignatureError),
+
     /// A Matirx identifier failed to be validated.
     #[error(transparent)]
     IdentifierValidation(#[from] IdentifierValidationError),
@@ -329,6 +390,19 @@ pub enum CryptoStoreError {
     Serialization(#[from] SerdeError),
 }
 
+/// Check that a pickle with the given version can be read by this version of
+/// the library.
+pub(crate) fn check_pickle_version(version: u32) -> Result<()> {
+    if version > PICKLE_VERSION {
+        Err(CryptoStoreError::UnsupportedPickleVersion(
+            version,
+            PICKLE_VERSION,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 /// Trait abstracting a store that the `OlmMachine` uses to store cryptographic
 /// keys.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -376,9 +450,38 @@ pub trait CryptoStore: AsyncTraitDeps {
         session_id: &str,
     ) -> Result<Option<InboundGroupSession>>;
 
+    /// Get all the Olm sessions we have stored, regardless of the sender key
+    /// they belong to.
+    async fn get_all_sessions(&self) -> Result<Vec<Session>>;
+
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>>;
 
+    /// Get all the outbound group sessions we have stored.
+    ///
+    /// Only the latest outbound group session of each room is stored.
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>>;
+
+    /// Get a batch of inbound group sessions that haven't been uploaded to the
+    /// server-side key backup yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of sessions that should be returned.
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>>;
+
+    /// Mark all the inbound group sessions we have as not backed up.
+    ///
+    /// This is used when a new backup version gets enabled or when the
+    /// current one gets disabled.
+    async fn reset_backup_state(&self) -> Result<()>;
+
+    /// Get the set of users that we are tracking.
+    fn tracked_users(&self) -> HashSet<UserId>;
+
     /// Is the given user already tracked.
     fn is_user_tracked(&self, user_id: &UserId) -> bool;
 
@@ -439,6 +542,58 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Load a serializeable object from the store.
     async fn get_value(&self, key: &str) -> Result<Option<String>>;
 
+    /// Get all the key-value pairs that are stored.
+    async fn get_values(&self) -> Result<HashMap<String, String>>;
+
     /// Check if a hash for an Olm message stored in the database.
     async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool>;
+
+    /// Get the hashes of all the Olm messages we have stored.
+    async fn get_message_hashes(&self) -> Result<Vec<OlmMessageHash>>;
+
+    /// Change the passphrase that is used to encrypt the pickle key of the
+    /// store.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase the store is currently using.
+    ///
+    /// * `new_passphrase` - The passphrase that should be used from now on.
+    ///
+    /// * `repickle` - Should a fresh pickle key be generated and all the
+    /// accounts, sessions, group sessions and cross signing keys be pickled
+    /// again using it. Otherwise only the existing pickle key is encrypted
+    /// with the new passphrase.
+    ///
+    /// Returns an `UnpicklingError` if the old passphrase doesn't match.
+    async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        repickle: bool,
+    ) -> Result<()>;
+
+    /// Get the event that used the given message index of a Megolm session.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the Megolm session belongs to.
+    ///
+    /// * `sender_key` - The curve25519 key of the device that created the
+    /// Megolm session.
+    ///
+    /// * `session_id` - The unique id of the Megolm session.
+    ///
+    /// * `message_index` - The message index that was used.
+    async fn get_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<MegolmMessageIndex>>;
+
+    /// Get all the Megolm message indices we have stored, regardless of the
+    /// session they belong to.
+    async fn get_megolm_message_indices(&self) -> Result<Vec<MegolmMessageIndex>>;
 }
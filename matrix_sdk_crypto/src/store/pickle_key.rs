@@ -32,6 +32,14 @@ const NONCE_SIZE: usize = 12;
 const KDF_SALT_SIZE: usize = 32;
 const KDF_ROUNDS: u32 = 10000;
 
+/// The key that is used to pickle objects if a store was opened without a
+/// passphrase.
+///
+/// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
+/// panic once we try to pickle a Signing object.
+#[cfg(any(feature = "sled_cryptostore", feature = "sqlite_cryptostore"))]
+const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
+
 /// Version specific info for the key derivation method that is used.
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum KdfInfo {
@@ -98,6 +106,14 @@ impl TryFrom<Vec<u8>> for PickleKey {
 }
 
 impl PickleKey {
+    /// Get the pickle key that stores use if they weren't given a passphrase.
+    #[cfg(any(feature = "sled_cryptostore", feature = "sqlite_cryptostore"))]
+    pub(crate) fn unencrypted() -> Self {
+        Self {
+            aes256_key: DEFAULT_PICKLE.as_bytes().to_vec(),
+        }
+    }
+
     /// Generate a new random pickle key.
     pub fn new() -> Self {
         Default::default()
@@ -0,0 +1,1331 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    convert::TryFrom,
+    path::Path,
+    result::Result as StdResult,
+    sync::{Arc, RwLock as SyncRwLock},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use dashmap::DashSet;
+use matrix_sdk_common::{
+    async_trait,
+    identifiers::{DeviceId, DeviceIdBox, EventId, RoomId, UserId},
+    locks::Mutex,
+};
+use serde::de::DeserializeOwned;
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionError},
+    Config, Db, Iter, Transactional, Tree,
+};
+use zeroize::Zeroizing;
+
+use super::{
+    caches::SessionStore,
+    check_pickle_version,
+    export::{SerializedDevice, SerializedIdentity},
+    pickle_key::{EncryptedPickleKey, PickleKey},
+    Changes, CryptoStore, CryptoStoreError, Result,
+};
+use crate::{
+    identities::{ReadOnlyDevice, UserIdentities},
+    olm::{
+        IdentityKeys, InboundGroupSession, MegolmMessageIndex, OlmMessageHash,
+        OutboundGroupSession, PickledAccount, PickledCrossSigningIdentity,
+        PickledInboundGroupSession, PickledOutboundGroupSession, PickledSession, PicklingMode,
+        PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
+    },
+};
+
+static DATABASE_NAME: &str = "matrix-sdk-crypto.sled";
+
+/// The version of the database layout this version of the library uses.
+///
+/// Every change to the layout needs to bump this and add a matching step to
+/// `SledStore::migrate()`.
+const DATABASE_VERSION: u32 = 2;
+const VERSION_KEY: &str = "version";
+
+const ACCOUNT_KEY: &str = "account";
+const PRIVATE_IDENTITY_KEY: &str = "private_identity";
+
+/// Separator for the parts of our compound keys, this byte can't appear in
+/// valid UTF-8 so the parts can't be confused with each other.
+const KEY_SEPARATOR: u8 = 0xff;
+
+fn encode_key(parts: &[&str]) -> Vec<u8> {
+    let mut key = Vec::new();
+
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            key.push(KEY_SEPARATOR);
+        }
+
+        key.extend_from_slice(part.as_bytes());
+    }
+
+    key
+}
+
+/// Split a compound key into its parts.
+fn decode_key(key: &[u8]) -> Vec<String> {
+    key.split(|b| *b == KEY_SEPARATOR)
+        .map(|part| String::from_utf8_lossy(part).into_owned())
+        .collect()
+}
+
+/// Encode a key that can be used to scan for all the compound keys that
+/// start with the given parts.
+fn encode_prefix(parts: &[&str]) -> Vec<u8> {
+    let mut key = encode_key(parts);
+    key.push(KEY_SEPARATOR);
+
+    key
+}
+
+fn deserialize_values<T: DeserializeOwned>(iter: Iter) -> Result<Vec<T>> {
+    iter.map(|entry| Ok(serde_json::from_slice(&entry?.1)?))
+        .collect()
+}
+
+fn transaction_error(error: TransactionError<Infallible>) -> CryptoStoreError {
+    match error {
+        TransactionError::Abort(e) => match e {},
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+/// Sled based implementation of a `CryptoStore`.
+#[derive(Clone)]
+#[cfg_attr(feature = "docs", doc(cfg(r#sled_cryptostore)))]
+pub struct SledStore {
+    user_id: Arc<UserId>,
+    device_id: Arc<Box<DeviceId>>,
+    account_info: Arc<SyncRwLock<Option<AccountInfo>>>,
+    path: Arc<Path>,
+    pickle_key: Arc<SyncRwLock<PickleKey>>,
+    /// Held while changes are pickled and written, so the pickle key can't be
+    /// replaced underneath a write.
+    write_lock: Arc<Mutex<()>>,
+
+    session_cache: SessionStore,
+    tracked_users_cache: Arc<DashSet<UserId>>,
+    users_for_key_query_cache: Arc<DashSet<UserId>>,
+
+    inner: Db,
+    pickle_keys: Tree,
+    account: Tree,
+    private_identity: Tree,
+    sessions: Tree,
+    inbound_group_sessions: Tree,
+    outbound_group_sessions: Tree,
+    olm_hashes: Tree,
+    megolm_message_indices: Tree,
+    tracked_users: Tree,
+    devices: Tree,
+    identities: Tree,
+    values: Tree,
+}
+
+#[derive(Clone)]
+struct AccountInfo {
+    identity_keys: Arc<IdentityKeys>,
+}
+
+/// Changes that are pickled and serialized into key/value pairs, ready to be
+/// written to the database in a single transaction.
+#[derive(Default)]
+struct SerializedChanges {
+    pickle_key: Option<Vec<u8>>,
+    account: Option<Vec<u8>>,
+    private_identity: Option<Vec<u8>>,
+    sessions: Vec<(Vec<u8>, Vec<u8>)>,
+    inbound_group_sessions: Vec<(Vec<u8>, Vec<u8>)>,
+    outbound_group_sessions: Vec<(Vec<u8>, Vec<u8>)>,
+    olm_hashes: Vec<Vec<u8>>,
+    megolm_message_indices: Vec<(Vec<u8>, Vec<u8>)>,
+    devices: Vec<(Vec<u8>, Vec<u8>)>,
+    deleted_devices: Vec<Vec<u8>>,
+    identities: Vec<(Vec<u8>, Vec<u8>)>,
+    tracked_users: Vec<(Vec<u8>, Vec<u8>)>,
+    values: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl SledStore {
+    /// Open a new `SledStore`.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user for which the store should be
+    /// opened.
+    ///
+    /// * `device_id` - The unique id of the device for which the store should
+    /// be opened.
+    ///
+    /// * `path` - The path where the database should reside in.
+    pub async fn open<P: AsRef<Path>>(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        path: P,
+    ) -> Result<SledStore> {
+        SledStore::open_helper(user_id, device_id, path, None)
+    }
+
+    /// Open a new `SledStore`.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user for which the store should be
+    /// opened.
+    ///
+    /// * `device_id` - The unique id of the device for which the store should
+    /// be opened.
+    ///
+    /// * `path` - The path where the database should reside in.
+    ///
+    /// * `passphrase` - The passphrase that should be used to securely store
+    /// the encryption keys.
+    pub async fn open_with_passphrase<P: AsRef<Path>>(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        path: P,
+        passphrase: &str,
+    ) -> Result<SledStore> {
+        SledStore::open_helper(user_id, device_id, path, Some(passphrase))
+    }
+
+    fn open_helper<P: AsRef<Path>>(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        path: P,
+        passphrase: Option<&str>,
+    ) -> Result<SledStore> {
+        let path = path.as_ref().join(DATABASE_NAME);
+        let db = Config::new().path(&path).open()?;
+        Self::migrate(&db)?;
+
+        let pickle_keys = db.open_tree("pickle_keys")?;
+
+        let pickle_key = if let Some(passphrase) = passphrase {
+            Self::get_or_create_pickle_key(user_id, device_id, passphrase, &pickle_keys)?
+        } else {
+            PickleKey::unencrypted()
+        };
+
+        // Every account gets its own set of trees, so multiple accounts can
+        // share a database.
+        let open_tree =
+            |name: &str| db.open_tree(encode_key(&[user_id.as_str(), device_id.as_str(), name]));
+
+        Ok(SledStore {
+            user_id: Arc::new(user_id.to_owned()),
+            device_id: Arc::new(device_id.into()),
+            account_info: Arc::new(SyncRwLock::new(None)),
+            path: path.into(),
+            pickle_key: Arc::new(SyncRwLock::new(pickle_key)),
+            write_lock: Arc::new(Mutex::new(())),
+            session_cache: SessionStore::new(),
+            tracked_users_cache: Arc::new(DashSet::new()),
+            users_for_key_query_cache: Arc::new(DashSet::new()),
+            account: open_tree("account")?,
+            private_identity: open_tree("private_identity")?,
+            sessions: open_tree("sessions")?,
+            inbound_group_sessions: open_tree("inbound_group_sessions")?,
+            outbound_group_sessions: open_tree("outbound_group_sessions")?,
+            olm_hashes: open_tree("olm_hashes")?,
+            megolm_message_indices: open_tree("megolm_message_indices")?,
+            tracked_users: open_tree("tracked_users")?,
+            devices: open_tree("devices")?,
+            identities: open_tree("identities")?,
+            values: open_tree("values")?,
+            pickle_keys,
+            inner: db,
+        })
+    }
+
+    /// Bring the layout of the database up to date.
+    ///
+    /// Databases that were created before the layout was versioned report
+    /// version 0.
+    fn migrate(db: &Db) -> Result<()> {
+        let version: u32 = db
+            .get(VERSION_KEY)?
+            .map(|v| serde_json::from_slice(&v))
+            .transpose()?
+            .unwrap_or(0);
+
+        if version > DATABASE_VERSION {
+            return Err(CryptoStoreError::UnsupportedDatabaseVersion(
+                version,
+                DATABASE_VERSION,
+            ));
+        }
+
+        for step in version + 1..=DATABASE_VERSION {
+            match step {
+                // The first version only needs the trees, those are created
+                // when the store gets opened.
+                1 => {}
+                2 => Self::key_megolm_message_indices_by_sender(db)?,
+                _ => {
+                    return Err(CryptoStoreError::UnsupportedDatabaseVersion(
+                        step,
+                        DATABASE_VERSION,
+                    ))
+                }
+            }
+
+            db.insert(VERSION_KEY, serde_json::to_vec(&step)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Key the used Megolm message indices by the room and sender key of their
+    /// session as well, a session id alone doesn't identify a session.
+    ///
+    /// Existing indices take the room and sender key from the inbound group
+    /// sessions of the same account with the same session id, indices of
+    /// sessions we don't have anymore are dropped.
+    fn key_megolm_message_indices_by_sender(db: &Db) -> Result<()> {
+        for name in db.tree_names() {
+            let mut account = decode_key(&name);
+
+            if account.len() != 3 || account.pop().as_deref() != Some("megolm_message_indices") {
+                continue;
+            }
+
+            let indices = db.open_tree(&name)?;
+            let inbound_group_sessions = db.open_tree(encode_key(&[
+                &account[0],
+                &account[1],
+                "inbound_group_sessions",
+            ]))?;
+
+            let mut senders: HashMap<String, Vec<(String, String)>> = HashMap::new();
+
+            for key in inbound_group_sessions.iter().keys() {
+                if let [room_id, sender_key, session_id] = decode_key(&key?).as_slice() {
+                    senders
+                        .entry(session_id.to_owned())
+                        .or_default()
+                        .push((room_id.to_owned(), sender_key.to_owned()));
+                }
+            }
+
+            let old_indices = indices.iter().collect::<StdResult<Vec<_>, _>>()?;
+            indices.clear()?;
+
+            for (key, value) in old_indices {
+                if let [session_id, message_index] = decode_key(&key).as_slice() {
+                    for (room_id, sender_key) in senders.get(session_id).into_iter().flatten() {
+                        indices.insert(
+                            encode_key(&[room_id, sender_key, session_id, message_index]),
+                            value.clone(),
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_or_create_pickle_key(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        passphrase: &str,
+        pickle_keys: &Tree,
+    ) -> Result<PickleKey> {
+        let key = encode_key(&[user_id.as_str(), device_id.as_str()]);
+
+        Ok(if let Some(encrypted) = pickle_keys.get(&key)? {
+            let encrypted: EncryptedPickleKey = serde_json::from_slice(&encrypted)?;
+            PickleKey::from_encrypted(passphrase, encrypted)
+                .map_err(|_| CryptoStoreError::UnpicklingError)?
+        } else {
+            let pickle_key = PickleKey::new();
+            let encrypted = serde_json::to_vec(&pickle_key.encrypt(passphrase))?;
+            pickle_keys.insert(key, encrypted)?;
+
+            pickle_key
+        })
+    }
+
+    fn pickle_key_id(&self) -> Vec<u8> {
+        encode_key(&[self.user_id.as_str(), self.device_id.as_str()])
+    }
+
+    fn get_pickle_mode(&self) -> PicklingMode {
+        self.pickle_key.read().unwrap().pickle_mode()
+    }
+
+    fn get_pickle_key(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.pickle_key.read().unwrap().key().to_vec())
+    }
+
+    fn identity_keys(&self) -> Result<Arc<IdentityKeys>> {
+        self.account_info
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|i| i.identity_keys.clone())
+            .ok_or(CryptoStoreError::AccountUnset)
+    }
+
+    async fn change_passphrase_helper(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        repickle: bool,
+    ) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+
+        // Stores that were opened without a passphrase don't have an
+        // encrypted pickle key, there's nothing the old passphrase can unlock.
+        let encrypted = self
+            .pickle_keys
+            .get(self.pickle_key_id())?
+            .ok_or(CryptoStoreError::UnpicklingError)?;
+        let encrypted: EncryptedPickleKey = serde_json::from_slice(&encrypted)?;
+        let pickle_key = PickleKey::from_encrypted(old_passphrase, encrypted)
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        if !repickle {
+            let encrypted = serde_json::to_vec(&pickle_key.encrypt(new_passphrase))?;
+            self.pickle_keys.insert(self.pickle_key_id(), encrypted)?;
+            self.inner.flush_async().await?;
+
+            return Ok(());
+        }
+
+        // Load everything that is pickled with the current pickle key before
+        // we replace it.
+        let account = self.load_account().await?;
+
+        let changes = if let Some(account) = account {
+            Changes {
+                account: Some(account),
+                private_identity: self.load_identity().await?,
+                sessions: self.load_all_sessions()?,
+                inbound_group_sessions: self.load_inbound_group_sessions()?,
+                outbound_group_sessions: self.load_outbound_group_sessions()?,
+                ..Default::default()
+            }
+        } else {
+            Changes::default()
+        };
+
+        let new_pickle_key = PickleKey::new();
+        let encrypted = serde_json::to_vec(&new_pickle_key.encrypt(new_passphrase))?;
+
+        let old_pickle_key =
+            std::mem::replace(&mut *self.pickle_key.write().unwrap(), new_pickle_key);
+
+        let result = match self.serialize_changes(&changes).await {
+            Ok(mut serialized) => {
+                serialized.pickle_key = Some(encrypted);
+                self.apply_changes(&serialized)
+            }
+            Err(e) => Err(e),
+        };
+
+        if result.is_err() {
+            *self.pickle_key.write().unwrap() = old_pickle_key;
+        }
+
+        result?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn serialize_changes(&self, changes: &Changes) -> Result<SerializedChanges> {
+        let pickle_mode = self.get_pickle_mode();
+
+        let account = if let Some(account) = &changes.account {
+            Some(serde_json::to_vec(
+                &account.pickle(pickle_mode.clone()).await,
+            )?)
+        } else {
+            None
+        };
+
+        let private_identity = if let Some(identity) = &changes.private_identity {
+            Some(serde_json::to_vec(
+                &identity.pickle(&self.get_pickle_key()).await?,
+            )?)
+        } else {
+            None
+        };
+
+        let mut sessions = Vec::new();
+
+        for session in &changes.sessions {
+            let key = encode_key(&[&session.sender_key, session.session_id()]);
+            let pickle = session.pickle(pickle_mode.clone()).await;
+            sessions.push((key, serde_json::to_vec(&pickle)?));
+        }
+
+        let mut inbound_group_sessions = Vec::new();
+
+        for session in &changes.inbound_group_sessions {
+            let key = encode_key(&[
+                session.room_id().as_str(),
+                session.sender_key(),
+                session.session_id(),
+            ]);
+            let pickle = session.pickle(pickle_mode.clone()).await;
+            inbound_group_sessions.push((key, serde_json::to_vec(&pickle)?));
+        }
+
+        let mut outbound_group_sessions = Vec::new();
+
+        for session in &changes.outbound_group_sessions {
+            let key = session.room_id().as_str().as_bytes().to_vec();
+            let pickle = session.pickle(pickle_mode.clone()).await;
+            outbound_group_sessions.push((key, serde_json::to_vec(&pickle)?));
+        }
+
+        let olm_hashes = changes
+            .message_hashes
+            .iter()
+            .map(|h| encode_key(&[&h.sender_key, &h.hash]))
+            .collect();
+
+        let megolm_message_indices = changes
+            .megolm_message_indices
+            .iter()
+            .map(|i| {
+                let key = encode_key(&[
+                    i.room_id.as_str(),
+                    &i.sender_key,
+                    &i.session_id,
+                    &i.message_index.to_string(),
+                ]);
+                let timestamp = i
+                    .origin_server_ts
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+
+                Ok((key, serde_json::to_vec(&(&i.event_id, timestamp))?))
+            })
+            .collect::<Result<_>>()?;
+
+        let devices = changes
+            .devices
+            .new
+            .iter()
+            .chain(&changes.devices.changed)
+            .map(|d| {
+                let key = encode_key(&[d.user_id().as_str(), d.device_id().as_str()]);
+                Ok((key, serde_json::to_vec(&SerializedDevice::from(d))?))
+            })
+            .collect::<Result<_>>()?;
+
+        let deleted_devices = changes
+            .devices
+            .deleted
+            .iter()
+            .map(|d| encode_key(&[d.user_id().as_str(), d.device_id().as_str()]))
+            .collect();
+
+        let identities = changes
+            .identities
+            .new
+            .iter()
+            .chain(&changes.identities.changed)
+            .map(|i| {
+                let key = i.user_id().as_str().as_bytes().to_vec();
+                Ok((key, serde_json::to_vec(&SerializedIdentity::from(i))?))
+            })
+            .collect::<Result<_>>()?;
+
+        let tracked_users = changes
+            .tracked_users
+            .iter()
+            .map(|(user_id, dirty)| {
+                let key = user_id.as_str().as_bytes().to_vec();
+                Ok((key, serde_json::to_vec(&(user_id, dirty))?))
+            })
+            .collect::<Result<_>>()?;
+
+        let values = changes
+            .values
+            .iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect();
+
+        Ok(SerializedChanges {
+            pickle_key: None,
+            account,
+            private_identity,
+            sessions,
+            inbound_group_sessions,
+            outbound_group_sessions,
+            olm_hashes,
+            megolm_message_indices,
+            devices,
+            deleted_devices,
+            identities,
+            tracked_users,
+            values,
+        })
+    }
+
+    fn apply_changes(&self, changes: &SerializedChanges) -> Result<()> {
+        (
+            &self.pickle_keys,
+            &self.account,
+            &self.private_identity,
+            &self.sessions,
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.olm_hashes,
+            &self.megolm_message_indices,
+            &self.devices,
+            &self.identities,
+            &self.tracked_users,
+            &self.values,
+        )
+            .transaction(
+                |(
+                    pickle_keys,
+                    account,
+                    private_identity,
+                    sessions,
+                    inbound_group_sessions,
+                    outbound_group_sessions,
+                    olm_hashes,
+                    megolm_message_indices,
+                    devices,
+                    identities,
+                    tracked_users,
+                    values,
+                )|
+                 -> ConflictableTransactionResult<(), Infallible> {
+                    if let Some(key) = &changes.pickle_key {
+                        pickle_keys.insert(self.pickle_key_id(), key.as_slice())?;
+                    }
+
+                    if let Some(pickle) = &changes.account {
+                        account.insert(ACCOUNT_KEY, pickle.as_slice())?;
+                    }
+
+                    if let Some(pickle) = &changes.private_identity {
+                        private_identity.insert(PRIVATE_IDENTITY_KEY, pickle.as_slice())?;
+                    }
+
+                    for (key, value) in &changes.sessions {
+                        sessions.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    for (key, value) in &changes.inbound_group_sessions {
+                        inbound_group_sessions.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    for (key, value) in &changes.outbound_group_sessions {
+                        outbound_group_sessions.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    for key in &changes.olm_hashes {
+                        olm_hashes.insert(key.as_slice(), &[] as &[u8])?;
+                    }
+
+                    for (key, value) in &changes.megolm_message_indices {
+                        megolm_message_indices.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    for (key, value) in &changes.devices {
+                        devices.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    for key in &changes.deleted_devices {
+                        devices.remove(key.as_slice())?;
+                    }
+
+                    for (key, value) in &changes.identities {
+                        identities.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    for (key, value) in &changes.tracked_users {
+                        tracked_users.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    for (key, value) in &changes.values {
+                        values.insert(key.as_slice(), value.as_slice())?;
+                    }
+
+                    Ok(())
+                },
+            )
+            .map_err(transaction_error)
+    }
+
+    fn lazy_load_sessions(&self, sender_key: &str) -> Result<()> {
+        if self.session_cache.get(sender_key).is_none() {
+            let sessions = self.load_sessions_for(sender_key)?;
+
+            if !sessions.is_empty() {
+                self.session_cache.set_for_sender(sender_key, sessions);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deserialize_session(&self, pickle: PickledSession) -> Result<Session> {
+        check_pickle_version(pickle.version)?;
+
+        Ok(Session::from_pickle(
+            self.user_id.clone(),
+            self.device_id.clone(),
+            self.identity_keys()?,
+            pickle,
+            self.get_pickle_mode(),
+        )?)
+    }
+
+    fn load_sessions_for(&self, sender_key: &str) -> Result<Vec<Session>> {
+        deserialize_values(self.sessions.scan_prefix(encode_prefix(&[sender_key])))?
+            .into_iter()
+            .map(|p| self.deserialize_session(p))
+            .collect()
+    }
+
+    fn load_all_sessions(&self) -> Result<Vec<Session>> {
+        deserialize_values(self.sessions.iter())?
+            .into_iter()
+            .map(|p| self.deserialize_session(p))
+            .collect()
+    }
+
+    fn load_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        let pickles: Vec<PickledInboundGroupSession> =
+            deserialize_values(self.inbound_group_sessions.iter())?;
+
+        pickles
+            .into_iter()
+            .map(|p| {
+                check_pickle_version(p.version)?;
+                Ok(InboundGroupSession::from_pickle(p, self.get_pickle_mode())?)
+            })
+            .collect()
+    }
+
+    fn load_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        let identity_keys = self.identity_keys()?;
+        let pickles: Vec<PickledOutboundGroupSession> =
+            deserialize_values(self.outbound_group_sessions.iter())?;
+
+        pickles
+            .into_iter()
+            .map(|p| {
+                check_pickle_version(p.version)?;
+
+                Ok(OutboundGroupSession::from_pickle(
+                    self.device_id.clone(),
+                    identity_keys.clone(),
+                    p,
+                    self.get_pickle_mode(),
+                )?)
+            })
+            .collect()
+    }
+
+    /// Update the cached tracking state of a user.
+    ///
+    /// Returns true if the user wasn't tracked before.
+    fn update_tracked_users_cache(&self, user_id: UserId, dirty: bool) -> bool {
+        if dirty {
+            self.users_for_key_query_cache.insert(user_id.clone());
+        } else {
+            self.users_for_key_query_cache.remove(&user_id);
+        }
+
+        self.tracked_users_cache.insert(user_id)
+    }
+
+    fn load_tracked_users(&self) -> Result<()> {
+        let users: Vec<(UserId, bool)> = deserialize_values(self.tracked_users.iter())?;
+
+        for (user_id, dirty) in users {
+            self.tracked_users_cache.insert(user_id.clone());
+
+            if dirty {
+                self.users_for_key_query_cache.insert(user_id);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CryptoStore for SledStore {
+    async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        let pickle: PickledAccount = if let Some(pickle) = self.account.get(ACCOUNT_KEY)? {
+            serde_json::from_slice(&pickle)?
+        } else {
+            return Ok(None);
+        };
+
+        check_pickle_version(pickle.version)?;
+        let account = ReadOnlyAccount::from_pickle(pickle, self.get_pickle_mode())?;
+
+        *self.account_info.write().unwrap() = Some(AccountInfo {
+            identity_keys: account.identity_keys.clone(),
+        });
+
+        self.load_tracked_users()?;
+
+        Ok(Some(account))
+    }
+
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        let changes = Changes {
+            account: Some(account),
+            ..Default::default()
+        };
+
+        self.save_changes(changes).await
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        let pickle: PickledCrossSigningIdentity =
+            if let Some(pickle) = self.private_identity.get(PRIVATE_IDENTITY_KEY)? {
+                serde_json::from_slice(&pickle)?
+            } else {
+                return Ok(None);
+            };
+
+        let identity = PrivateCrossSigningIdentity::from_pickle(pickle, &self.get_pickle_key())
+            .await
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        Ok(Some(identity))
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        // Sessions can only be restored using the identity keys of the
+        // account, refuse to store them if we don't have one.
+        if changes.account.is_none() && !changes.sessions.is_empty() {
+            self.identity_keys()?;
+        }
+
+        // The pickle key must stay the same until the changes are written.
+        let _guard = self.write_lock.lock().await;
+        let serialized = self.serialize_changes(&changes).await?;
+
+        for session in &changes.sessions {
+            self.lazy_load_sessions(&session.sender_key)?;
+        }
+
+        self.apply_changes(&serialized)?;
+
+        if let Some(account) = &changes.account {
+            *self.account_info.write().unwrap() = Some(AccountInfo {
+                identity_keys: account.identity_keys.clone(),
+            });
+        }
+
+        for session in changes.sessions {
+            self.session_cache.add(session).await;
+        }
+
+        for (user_id, dirty) in changes.tracked_users {
+            self.update_tracked_users_cache(user_id, dirty);
+        }
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        self.lazy_load_sessions(sender_key)?;
+        Ok(self.session_cache.get(sender_key))
+    }
+
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        self.load_all_sessions()
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        let key = encode_key(&[room_id.as_str(), sender_key, session_id]);
+
+        if let Some(pickle) = self.inbound_group_sessions.get(key)? {
+            let pickle: PickledInboundGroupSession = serde_json::from_slice(&pickle)?;
+            check_pickle_version(pickle.version)?;
+
+            Ok(Some(InboundGroupSession::from_pickle(
+                pickle,
+                self.get_pickle_mode(),
+            )?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions()
+    }
+
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        self.load_outbound_group_sessions()
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        // Only unpickle the sessions we're going to return, the backup flag
+        // can be read from the serialized pickle.
+        let mut sessions = Vec::new();
+
+        for entry in self.inbound_group_sessions.iter() {
+            if sessions.len() >= limit {
+                break;
+            }
+
+            let pickle: PickledInboundGroupSession = serde_json::from_slice(&entry?.1)?;
+
+            if !pickle.backed_up {
+                check_pickle_version(pickle.version)?;
+                sessions.push(InboundGroupSession::from_pickle(
+                    pickle,
+                    self.get_pickle_mode(),
+                )?);
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        let sessions: Vec<InboundGroupSession> = self
+            .load_inbound_group_sessions()?
+            .into_iter()
+            .filter(|s| s.backed_up())
+            .collect();
+
+        for session in &sessions {
+            session.reset_backup_state();
+        }
+
+        let changes = Changes {
+            inbound_group_sessions: sessions,
+            ..Default::default()
+        };
+
+        self.save_changes(changes).await
+    }
+
+    fn is_user_tracked(&self, user_id: &UserId) -> bool {
+        self.tracked_users_cache.contains(user_id)
+    }
+
+    fn has_users_for_key_query(&self) -> bool {
+        !self.users_for_key_query_cache.is_empty()
+    }
+
+    fn tracked_users(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.tracked_users_cache.iter().map(|u| u.clone()).collect()
+    }
+
+    fn users_for_key_query(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.users_for_key_query_cache
+            .iter()
+            .map(|u| u.clone())
+            .collect()
+    }
+
+    async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
+        let already_added = self.update_tracked_users_cache(user.clone(), dirty);
+
+        self.tracked_users
+            .insert(user.as_str(), serde_json::to_vec(&(user, dirty))?)?;
+        self.inner.flush_async().await?;
+
+        Ok(already_added)
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        let key = encode_key(&[user_id.as_str(), device_id.as_str()]);
+
+        Ok(if let Some(device) = self.devices.get(key)? {
+            let device: SerializedDevice = serde_json::from_slice(&device)?;
+            Some(device.into())
+        } else {
+            None
+        })
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<DeviceIdBox, ReadOnlyDevice>> {
+        let devices: Vec<SerializedDevice> =
+            deserialize_values(self.devices.scan_prefix(encode_prefix(&[user_id.as_str()])))?;
+
+        Ok(devices
+            .into_iter()
+            .map(|d| {
+                let device = ReadOnlyDevice::from(d);
+                (device.device_id().into(), device)
+            })
+            .collect())
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentities>> {
+        let identity: SerializedIdentity =
+            if let Some(identity) = self.identities.get(user_id.as_str())? {
+                serde_json::from_slice(&identity)?
+            } else {
+                return Ok(None);
+            };
+
+        Ok(Some(identity.restore()?))
+    }
+
+    async fn save_value(&self, key: String, value: String) -> Result<()> {
+        self.values.insert(key.as_str(), value.as_str())?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn remove_value(&self, key: &str) -> Result<()> {
+        self.values.remove(key)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .values
+            .get(key)?
+            .map(|v| String::from_utf8_lossy(&v).into_owned()))
+    }
+
+    async fn get_values(&self) -> Result<HashMap<String, String>> {
+        self.values
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+
+                Ok((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                ))
+            })
+            .collect()
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        Ok(self
+            .olm_hashes
+            .contains_key(encode_key(&[&message_hash.sender_key, &message_hash.hash]))?)
+    }
+
+    async fn get_message_hashes(&self) -> Result<Vec<OlmMessageHash>> {
+        self.olm_hashes
+            .iter()
+            .map(|entry| {
+                let (key, _) = entry?;
+                let mut parts = decode_key(&key).into_iter();
+
+                Ok(OlmMessageHash {
+                    sender_key: parts.next().unwrap_or_default(),
+                    hash: parts.next().unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        repickle: bool,
+    ) -> Result<()> {
+        self.change_passphrase_helper(old_passphrase, new_passphrase, repickle)
+            .await
+    }
+
+    async fn get_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<MegolmMessageIndex>> {
+        let key = encode_key(&[
+            room_id.as_str(),
+            sender_key,
+            session_id,
+            &message_index.to_string(),
+        ]);
+
+        Ok(if let Some(value) = self.megolm_message_indices.get(key)? {
+            let (event_id, timestamp): (EventId, u64) = serde_json::from_slice(&value)?;
+
+            Some(MegolmMessageIndex {
+                room_id: room_id.to_owned(),
+                sender_key: sender_key.to_owned(),
+                session_id: session_id.to_owned(),
+                message_index,
+                event_id,
+                origin_server_ts: UNIX_EPOCH + Duration::from_millis(timestamp),
+            })
+        } else {
+            None
+        })
+    }
+
+    async fn get_megolm_message_indices(&self) -> Result<Vec<MegolmMessageIndex>> {
+        self.megolm_message_indices
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let mut parts = decode_key(&key).into_iter();
+                let room_id = RoomId::try_from(parts.next().unwrap_or_default())?;
+                let sender_key = parts.next().unwrap_or_default();
+                let session_id = parts.next().unwrap_or_default();
+                let message_index = serde_json::from_str(&parts.next().unwrap_or_default())?;
+                let (event_id, timestamp): (EventId, u64) = serde_json::from_slice(&value)?;
+
+                Ok(MegolmMessageIndex {
+                    room_id,
+                    sender_key,
+                    session_id,
+                    message_index,
+                    event_id,
+                    origin_server_ts: UNIX_EPOCH + Duration::from_millis(timestamp),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl std::fmt::Debug for SledStore {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> StdResult<(), std::fmt::Error> {
+        fmt.debug_struct("SledStore")
+            .field("user_id", &self.user_id)
+            .field("device_id", &self.device_id)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{
+        olm::{ReadOnlyAccount, PICKLE_VERSION},
+        store::{
+            integration_tests::{
+                alice_device_id, alice_id, get_account, get_inbound_group_session,
+            },
+            Changes,
+        },
+    };
+    use matrix_sdk_common::identifiers::event_id;
+    use serde_json::{json, Value};
+    use tempfile::tempdir;
+
+    use super::{
+        encode_key, CryptoStore, CryptoStoreError, SledStore, ACCOUNT_KEY, DATABASE_VERSION,
+        VERSION_KEY,
+    };
+
+    async fn open_store(dir: &Path, passphrase: Option<&str>) -> SledStore {
+        if let Some(passphrase) = passphrase {
+            SledStore::open_with_passphrase(&alice_id(), &alice_device_id(), dir, passphrase)
+                .await
+                .expect("Can't create a passphrase protected store")
+        } else {
+            SledStore::open(&alice_id(), &alice_device_id(), dir)
+                .await
+                .expect("Can't create store")
+        }
+    }
+
+    async fn get_store(passphrase: Option<&str>) -> (SledStore, tempfile::TempDir) {
+        let dir = tempdir().unwrap();
+        let store = open_store(dir.path(), passphrase).await;
+
+        (store, dir)
+    }
+
+    async fn reopen_store(dir: &tempfile::TempDir) -> SledStore {
+        let store = open_store(dir.path(), None).await;
+        store.load_account().await.unwrap();
+
+        store
+    }
+
+    async fn get_loaded_store() -> (ReadOnlyAccount, SledStore, tempfile::TempDir) {
+        let (store, dir) = get_store(None).await;
+        let account = get_account();
+        store
+            .save_account(account.clone())
+            .await
+            .expect("Can't save account");
+
+        (account, store, dir)
+    }
+
+    crate::cryptostore_integration_tests!(open_store, persistent);
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wrong_passphrase_is_rejected() {
+        let (store, dir) = get_store(Some("secret_passphrase")).await;
+        store
+            .change_passphrase("secret_passphrase", "new_passphrase", true)
+            .await
+            .unwrap();
+        drop(store);
+
+        assert!(SledStore::open_with_passphrase(
+            &alice_id(),
+            &alice_device_id(),
+            dir.path(),
+            "secret_passphrase"
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unversioned_database_is_migrated() {
+        let (account, store, dir) = get_loaded_store().await;
+
+        store.inner.remove(VERSION_KEY).unwrap();
+        drop(store);
+
+        let store = reopen_store(&dir).await;
+        assert_eq!(store.load_account().await.unwrap(), Some(account));
+
+        let version: u32 =
+            serde_json::from_slice(&store.inner.get(VERSION_KEY).unwrap().unwrap()).unwrap();
+        assert_eq!(version, DATABASE_VERSION);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn megolm_message_indices_are_migrated() {
+        let (account, store, dir) = get_loaded_store().await;
+        let inbound = get_inbound_group_session(&account);
+
+        let changes = Changes {
+            inbound_group_sessions: vec![inbound.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        // The first version of the layout keyed the indices by the session id
+        // and message index only.
+        let value = serde_json::to_vec(&(event_id!("$event:example.org"), 0u64)).unwrap();
+        store
+            .megolm_message_indices
+            .insert(encode_key(&[inbound.session_id(), "0"]), value.clone())
+            .unwrap();
+        store
+            .megolm_message_indices
+            .insert(encode_key(&["unknown_session", "0"]), value)
+            .unwrap();
+        store
+            .inner
+            .insert(VERSION_KEY, serde_json::to_vec(&1u32).unwrap())
+            .unwrap();
+        drop(store);
+
+        let store = reopen_store(&dir).await;
+
+        // Indices of sessions we don't have can't be attributed to a sender
+        // and are dropped.
+        let indices = store.get_megolm_message_indices().await.unwrap();
+        assert_eq!(indices.len(), 1);
+        assert!(store
+            .get_megolm_message_index(
+                inbound.room_id(),
+                inbound.sender_key(),
+                inbound.session_id(),
+                0
+            )
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn newer_database_is_rejected() {
+        let (store, dir) = get_store(None).await;
+
+        store
+            .inner
+            .insert(
+                VERSION_KEY,
+                serde_json::to_vec(&(DATABASE_VERSION + 1)).unwrap(),
+            )
+            .unwrap();
+        drop(store);
+
+        let result = SledStore::open(&alice_id(), &alice_device_id(), dir.path()).await;
+
+        assert!(matches!(
+            result,
+            Err(CryptoStoreError::UnsupportedDatabaseVersion(v, DATABASE_VERSION))
+                if v == DATABASE_VERSION + 1
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn newer_pickle_is_rejected() {
+        let (_, store, _dir) = get_loaded_store().await;
+
+        let mut pickle: Value =
+            serde_json::from_slice(&store.account.get(ACCOUNT_KEY).unwrap().unwrap()).unwrap();
+        pickle["version"] = json!(PICKLE_VERSION + 1);
+        store
+            .account
+            .insert(ACCOUNT_KEY, serde_json::to_vec(&pickle).unwrap())
+            .unwrap();
+
+        assert!(matches!(
+            store.load_account().await,
+            Err(CryptoStoreError::UnsupportedPickleVersion(v, PICKLE_VERSION))
+                if v == PICKLE_VERSION + 1
+        ));
+    }
+}
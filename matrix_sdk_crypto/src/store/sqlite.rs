@@ -17,7 +17,8 @@ use std::{
     convert::TryFrom,
     path::Path,
     result::Result as StdResult,
-    sync::{Arc, Mutex as SyncMutex},
+    sync::{Arc, Mutex as SyncMutex, RwLock as SyncRwLock},
+    time::UNIX_EPOCH,
 };
 
 use dashmap::DashSet;
@@ -25,16 +26,18 @@ use matrix_sdk_common::{
     api::r0::keys::{CrossSigningKey, KeyUsage},
     async_trait,
     identifiers::{
-        DeviceId, DeviceIdBox, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm, RoomId,
-        UserId,
+        DeviceId, DeviceIdBox, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm, EventId,
+        RoomId, UserId,
     },
     instant::Duration,
     locks::Mutex,
 };
 use sqlx::{query, query_as, sqlite::SqliteConnectOptions, Connection, Executor, SqliteConnection};
+use zeroize::Zeroizing;
 
 use super::{
     caches::SessionStore,
+    check_pickle_version,
     pickle_key::{EncryptedPickleKey, PickleKey},
     Changes, CryptoStore, CryptoStoreError, Result,
 };
@@ -42,16 +45,13 @@ use crate::{
     identities::{LocalTrust, OwnUserIdentity, ReadOnlyDevice, UserIdentities, UserIdentity},
     olm::{
         AccountPickle, IdentityKeys, InboundGroupSession, InboundGroupSessionPickle,
-        OlmMessageHash, PickledAccount, PickledCrossSigningIdentity, PickledInboundGroupSession,
+        MegolmMessageIndex, OlmMessageHash, OutboundGroupSession, PickledAccount,
+        PickledCrossSigningIdentity, PickledInboundGroupSession, PickledOutboundGroupSession,
         PickledSession, PicklingMode, PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
         SessionPickle,
     },
 };
 
-/// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
-/// panic once we try to pickle a Signing object.
-const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
-
 /// SQLite based implementation of a `CryptoStore`.
 #[derive(Clone)]
 #[cfg_attr(feature = "docs", doc(cfg(r#sqlite_cryptostore)))]
@@ -66,7 +66,7 @@ pub struct SqliteStore {
     users_for_key_query: Arc<DashSet<UserId>>,
 
     connection: Arc<Mutex<SqliteConnection>>,
-    pickle_key: Arc<PickleKey>,
+    pickle_key: Arc<SyncRwLock<PickleKey>>,
 }
 
 #[derive(Clone)]
@@ -83,8 +83,35 @@ enum CrosssigningKeyType {
     UserSigning = 2,
 }
 
+/// The id, pickle, sender key, room id, imported flag and pickle version of an
+/// inbound group session row.
+type InboundGroupSessionRow = (i64, String, String, String, bool, i64);
+
+/// The room id, sender key, session id, message index, event id and timestamp
+/// of a Megolm message index row.
+type MegolmMessageIndexRow = (String, String, String, i64, String, i64);
+
 static DATABASE_NAME: &str = "matrix-sdk-crypto.db";
 
+/// The version of the database schema this version of the library uses.
+///
+/// Every change to the schema needs to bump this and add a matching step to
+/// `SqliteStore::run_migration()`.
+const DATABASE_VERSION: u32 = 4;
+
+/// Convert a version number that was read from the database.
+fn stored_version(version: i64) -> Result<u32> {
+    u32::try_from(version).map_err(|_| CryptoStoreError::InvalidVersion(version))
+}
+
+/// Convert the pickle version of a row and check that we can read the pickle.
+fn stored_pickle_version(version: i64) -> Result<u32> {
+    let version = stored_version(version)?;
+    check_pickle_version(version)?;
+
+    Ok(version)
+}
+
 impl SqliteStore {
     /// Open a new `SqliteStore`.
     ///
@@ -142,13 +169,12 @@ impl SqliteStore {
             .filename(&path);
 
         let mut connection = SqliteConnection::connect_with(&options).await?;
-        Self::create_tables(&mut connection).await?;
+        Self::migrate(&mut connection).await?;
 
         let pickle_key = if let Some(passphrase) = passphrase {
             Self::get_or_create_pickle_key(user_id, device_id, &passphrase, &mut connection).await?
         } else {
-            PickleKey::try_from(DEFAULT_PICKLE.as_bytes().to_vec())
-                .expect("Can't create default pickle key")
+            PickleKey::unencrypted()
         };
 
         let store = SqliteStore {
@@ -160,7 +186,7 @@ impl SqliteStore {
             connection: Arc::new(Mutex::new(connection)),
             tracked_users: Arc::new(DashSet::new()),
             users_for_key_query: Arc::new(DashSet::new()),
-            pickle_key: Arc::new(pickle_key),
+            pickle_key: Arc::new(SyncRwLock::new(pickle_key)),
         };
 
         Ok(store)
@@ -174,6 +200,151 @@ impl SqliteStore {
             .map(|i| i.account_id)
     }
 
+    /// Bring the database schema up to date.
+    ///
+    /// Migration steps are run in order, each one in its own transaction
+    /// together with the bump of the stored schema version. Databases that
+    /// were created before the schema was versioned report version 0, the
+    /// first step only creates missing tables so it's safe to run on those.
+    async fn migrate(connection: &mut SqliteConnection) -> Result<()> {
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS schema_version (
+                "id" INTEGER NOT NULL PRIMARY KEY CHECK (id = 0),
+                "version" INTEGER NOT NULL
+            );
+        "#,
+            )
+            .await?;
+
+        let row: Option<(i64,)> = query_as("SELECT version FROM schema_version WHERE id = 0")
+            .fetch_optional(&mut *connection)
+            .await?;
+        let version = row.map_or(Ok(0), |r| stored_version(r.0))?;
+
+        if version > DATABASE_VERSION {
+            return Err(CryptoStoreError::UnsupportedDatabaseVersion(
+                version,
+                DATABASE_VERSION,
+            ));
+        }
+
+        for step in version + 1..=DATABASE_VERSION {
+            let mut transaction = connection.begin().await?;
+
+            Self::run_migration(&mut transaction, step).await?;
+
+            query(
+                "INSERT INTO schema_version (id, version) VALUES (0, ?1)
+                 ON CONFLICT(id) DO UPDATE SET version = excluded.version",
+            )
+            .bind(step as i64)
+            .execute(&mut *transaction)
+            .await?;
+
+            transaction.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the migration step that brings the schema to the given version.
+    async fn run_migration(connection: &mut SqliteConnection, version: u32) -> Result<()> {
+        match version {
+            1 => Self::create_tables(connection).await,
+            2 => Self::add_pickle_versions(connection).await,
+            3 => Self::add_fallback_key_pending(connection).await,
+            4 => Self::key_megolm_message_indices_by_sender(connection).await,
+            _ => Err(CryptoStoreError::UnsupportedDatabaseVersion(
+                version,
+                DATABASE_VERSION,
+            )),
+        }
+    }
+
+    /// Store the version of the pickles that are split into columns, the
+    /// columns don't carry the version of the pickle otherwise.
+    ///
+    /// Everything that was stored before this step uses the first version of
+    /// the pickle format.
+    async fn add_pickle_versions(connection: &mut SqliteConnection) -> Result<()> {
+        connection
+            .execute(
+                r#"
+            ALTER TABLE accounts ADD COLUMN "pickle_version" INTEGER NOT NULL DEFAULT 1;
+            ALTER TABLE sessions ADD COLUMN "pickle_version" INTEGER NOT NULL DEFAULT 1;
+            ALTER TABLE inbound_group_sessions
+                ADD COLUMN "pickle_version" INTEGER NOT NULL DEFAULT 1;
+        "#,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remember if the account has a fallback key that still needs to be
+    /// uploaded, accounts that were stored before this step don't have one.
+    async fn add_fallback_key_pending(connection: &mut SqliteConnection) -> Result<()> {
+        connection
+            .execute(
+                r#"
+            ALTER TABLE accounts
+                ADD COLUMN "fallback_key_pending" INTEGER NOT NULL DEFAULT 0;
+        "#,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Key the used Megolm message indices by the room and sender key of their
+    /// session as well, a session id alone doesn't identify a session.
+    ///
+    /// Existing indices take the room and sender key from the inbound group
+    /// sessions with the same session id, indices of sessions we don't have
+    /// anymore are dropped.
+    async fn key_megolm_message_indices_by_sender(connection: &mut SqliteConnection) -> Result<()> {
+        connection
+            .execute(
+                r#"
+            ALTER TABLE megolm_message_indices RENAME TO old_megolm_message_indices;
+
+            CREATE TABLE megolm_message_indices (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "room_id" TEXT NOT NULL,
+                "sender_key" TEXT NOT NULL,
+                "session_id" TEXT NOT NULL,
+                "message_index" INTEGER NOT NULL,
+                "event_id" TEXT NOT NULL,
+                "origin_server_ts" INTEGER NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id,room_id,sender_key,session_id,message_index)
+            );
+
+            INSERT INTO megolm_message_indices (
+                account_id, room_id, sender_key, session_id, message_index, event_id,
+                origin_server_ts
+            )
+            SELECT i.account_id, s.room_id, s.sender_key, i.session_id, i.message_index,
+                   i.event_id, i.origin_server_ts
+            FROM old_megolm_message_indices i
+            JOIN inbound_group_sessions s
+                ON s.account_id = i.account_id AND s.session_id = i.session_id;
+
+            DROP TABLE old_megolm_message_indices;
+
+            CREATE INDEX IF NOT EXISTS "megolm_message_indices_index"
+                ON "megolm_message_indices" ("account_id");
+        "#,
+            )
+            .await?;
+
+        Ok(())
+    }
+
     async fn create_tables(connection: &mut SqliteConnection) -> Result<()> {
         connection
             .execute(
@@ -280,6 +451,26 @@ impl SqliteStore {
             )
             .await?;
 
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS outbound_group_sessions (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "room_id" TEXT NOT NULL,
+                "session_id" TEXT NOT NULL,
+                "pickle" TEXT NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id,room_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS "outbound_group_sessions_account_id"
+                ON "outbound_group_sessions" ("account_id");
+        "#,
+            )
+            .await?;
+
         connection
             .execute(
                 r#"
@@ -315,6 +506,22 @@ impl SqliteStore {
             )
             .await?;
 
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS group_session_backups (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "session_id" INTEGER NOT NULL,
+                FOREIGN KEY ("session_id") REFERENCES "inbound_group_sessions" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(session_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS "group_session_backups_session_id" ON "group_session_backups" ("session_id");
+        "#,
+            )
+            .await?;
+
         connection
             .execute(
                 r#"
@@ -510,6 +717,27 @@ impl SqliteStore {
             )
             .await?;
 
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS megolm_message_indices (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "session_id" TEXT NOT NULL,
+                "message_index" INTEGER NOT NULL,
+                "event_id" TEXT NOT NULL,
+                "origin_server_ts" INTEGER NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id,session_id,message_index)
+            );
+
+            CREATE INDEX IF NOT EXISTS "megolm_message_indices_index"
+                ON "megolm_message_indices" ("account_id");
+        "#,
+            )
+            .await?;
+
         Ok(())
     }
 
@@ -564,6 +792,219 @@ impl SqliteStore {
         })
     }
 
+    async fn change_passphrase_helper(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        repickle: bool,
+    ) -> Result<()> {
+        // Every write pickles its objects while holding the connection lock,
+        // holding it for the whole load, repickle and key swap ensures no
+        // object gets stored using the old pickle key after it was replaced.
+        let mut connection = self.connection.lock().await;
+
+        let row: Option<(String,)> =
+            query_as("SELECT key FROM pickle_keys WHERE user_id = ? and device_id = ?")
+                .bind(self.user_id.as_str())
+                .bind(self.device_id.as_str())
+                .fetch_optional(&mut *connection)
+                .await?;
+
+        // Stores that were opened without a passphrase don't have an
+        // encrypted pickle key, there's nothing the old passphrase can unlock.
+        let row = row.ok_or(CryptoStoreError::UnpicklingError)?;
+        let encrypted: EncryptedPickleKey = serde_json::from_str(&row.0)?;
+        let pickle_key = PickleKey::from_encrypted(old_passphrase, encrypted)
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        if !repickle {
+            return Self::save_pickle_key(
+                &self.user_id,
+                &self.device_id,
+                pickle_key.encrypt(new_passphrase),
+                &mut connection,
+            )
+            .await;
+        }
+
+        // Load everything that is pickled with the current pickle key before
+        // we replace it.
+        let account = self.load_account_helper(&mut connection).await?;
+
+        let (identity, sessions, inbound_group_sessions, outbound_group_sessions) =
+            if account.is_some() {
+                (
+                    self.load_identity_helper(&mut connection).await?,
+                    self.load_all_sessions_helper(&mut connection).await?,
+                    self.load_inbound_group_sessions_helper(&mut connection)
+                        .await?,
+                    self.load_outbound_group_sessions_helper(&mut connection)
+                        .await?,
+                )
+            } else {
+                (None, Vec::new(), Vec::new(), Vec::new())
+            };
+
+        let new_pickle_key = PickleKey::new();
+        let encrypted = new_pickle_key.encrypt(new_passphrase);
+
+        let old_pickle_key =
+            std::mem::replace(&mut *self.pickle_key.write().unwrap(), new_pickle_key);
+
+        let result = self
+            .save_repickled(
+                &mut connection,
+                encrypted,
+                account,
+                identity,
+                &sessions,
+                &inbound_group_sessions,
+                &outbound_group_sessions,
+            )
+            .await;
+
+        if result.is_err() {
+            *self.pickle_key.write().unwrap() = old_pickle_key;
+        }
+
+        result
+    }
+
+    async fn load_account_helper(
+        &self,
+        connection: &mut SqliteConnection,
+    ) -> Result<Option<ReadOnlyAccount>> {
+        let row: Option<(i64, String, bool, i64, bool, i64)> = query_as(
+            "SELECT id, pickle, shared, uploaded_key_count, fallback_key_pending, pickle_version
+                      FROM accounts WHERE user_id = ? and device_id = ?",
+        )
+        .bind(self.user_id.as_str())
+        .bind(self.device_id.as_str())
+        .fetch_optional(&mut *connection)
+        .await?;
+
+        let (id, pickle, shared, uploaded_key_count, fallback_key_pending, version) =
+            if let Some(row) = row {
+                row
+            } else {
+                return Ok(None);
+            };
+
+        let pickle = PickledAccount {
+            user_id: (&*self.user_id).clone(),
+            device_id: (&*self.device_id).clone(),
+            pickle: AccountPickle::from(pickle),
+            shared,
+            uploaded_signed_key_count: uploaded_key_count,
+            fallback_key_pending,
+            version: stored_pickle_version(version)?,
+        };
+
+        let account = ReadOnlyAccount::from_pickle(pickle, self.get_pickle_mode())?;
+
+        *self.account_info.lock().unwrap() = Some(AccountInfo {
+            account_id: id,
+            identity_keys: account.identity_keys.clone(),
+        });
+
+        Ok(Some(account))
+    }
+
+    async fn load_identity_helper(
+        &self,
+        connection: &mut SqliteConnection,
+    ) -> Result<Option<PrivateCrossSigningIdentity>> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+
+        let row: Option<(String, bool)> = query_as(
+            "SELECT pickle, shared FROM private_identities
+                      WHERE account_id = ?",
+        )
+        .bind(account_id)
+        .fetch_optional(&mut *connection)
+        .await?;
+
+        if let Some(row) = row {
+            let pickle = PickledCrossSigningIdentity {
+                user_id: (&*self.user_id).clone(),
+                pickle: row.0,
+                shared: row.1,
+            };
+
+            // TODO remove this unwrap
+            let identity = PrivateCrossSigningIdentity::from_pickle(pickle, &self.get_pickle_key())
+                .await
+                .unwrap();
+
+            Ok(Some(identity))
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn save_repickled(
+        &self,
+        connection: &mut SqliteConnection,
+        pickle_key: EncryptedPickleKey,
+        account: Option<ReadOnlyAccount>,
+        identity: Option<PrivateCrossSigningIdentity>,
+        sessions: &[Session],
+        inbound_group_sessions: &[InboundGroupSession],
+        outbound_group_sessions: &[OutboundGroupSession],
+    ) -> Result<()> {
+        let mut transaction = connection.begin().await?;
+
+        if let Some(account) = account {
+            self.save_account_helper(&mut transaction, account).await?;
+        }
+
+        if let Some(identity) = identity {
+            self.save_identity(&mut transaction, identity).await?;
+        }
+
+        self.save_sessions_helper(&mut transaction, sessions)
+            .await?;
+        self.save_inbound_group_sessions(&mut transaction, inbound_group_sessions)
+            .await?;
+        self.save_outbound_group_sessions(&mut transaction, outbound_group_sessions)
+            .await?;
+        Self::save_pickle_key(&self.user_id, &self.device_id, pickle_key, &mut transaction).await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn load_all_sessions(&self) -> Result<Vec<Session>> {
+        let mut connection = self.connection.lock().await;
+        self.load_all_sessions_helper(&mut connection).await
+    }
+
+    async fn load_all_sessions_helper(
+        &self,
+        connection: &mut SqliteConnection,
+    ) -> Result<Vec<Session>> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+
+        let sender_keys: Vec<(String,)> =
+            query_as("SELECT DISTINCT sender_key FROM sessions WHERE account_id = ?")
+                .bind(account_id)
+                .fetch_all(&mut *connection)
+                .await?;
+
+        let mut sessions = Vec::new();
+
+        for (sender_key,) in sender_keys {
+            sessions.extend(
+                self.load_sessions_for_helper(connection, &sender_key)
+                    .await?,
+            );
+        }
+
+        Ok(sessions)
+    }
+
     async fn lazy_load_sessions(
         &self,
         connection: &mut SqliteConnection,
@@ -611,8 +1052,8 @@ impl SqliteStore {
             .unwrap()
             .clone()
             .ok_or(CryptoStoreError::AccountUnset)?;
-        let mut rows: Vec<(String, String, String, String)> = query_as(
-            "SELECT pickle, sender_key, creation_time, last_use_time
+        let mut rows: Vec<(String, String, String, String, i64)> = query_as(
+            "SELECT pickle, sender_key, creation_time, last_use_time, pickle_version
              FROM sessions WHERE account_id = ? and sender_key = ?",
         )
         .bind(account_info.account_id)
@@ -633,6 +1074,7 @@ impl SqliteStore {
                     last_use_time,
                     creation_time,
                     sender_key,
+                    version: stored_pickle_version(row.4)?,
                 };
 
                 Ok(Session::from_pickle(
@@ -646,6 +1088,7 @@ impl SqliteStore {
             .collect::<Result<Vec<Session>>>()?)
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn load_inbound_session_data(
         &self,
         connection: &mut SqliteConnection,
@@ -654,7 +1097,10 @@ impl SqliteStore {
         sender_key: String,
         room_id: RoomId,
         imported: bool,
+        version: i64,
     ) -> Result<InboundGroupSession> {
+        let version = stored_pickle_version(version)?;
+
         let key_rows: Vec<(String, String)> =
             query_as("SELECT algorithm, key FROM group_session_claimed_keys WHERE session_id = ?")
                 .bind(session_row_id)
@@ -685,6 +1131,12 @@ impl SqliteStore {
             Some(chains)
         };
 
+        let backup_row: Option<(i64,)> =
+            query_as("SELECT session_id FROM group_session_backups WHERE session_id = ?")
+                .bind(session_row_id)
+                .fetch_optional(&mut *connection)
+                .await?;
+
         let pickle = PickledInboundGroupSession {
             pickle: InboundGroupSessionPickle::from(pickle),
             sender_key,
@@ -692,6 +1144,8 @@ impl SqliteStore {
             room_id,
             forwarding_chains: chains,
             imported,
+            backed_up: backup_row.is_some(),
+            version,
         };
 
         Ok(InboundGroupSession::from_pickle(
@@ -709,8 +1163,8 @@ impl SqliteStore {
         let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
         let mut connection = self.connection.lock().await;
 
-        let row: Option<(i64, String, bool)> = query_as(
-            "SELECT id, pickle, imported
+        let row: Option<(i64, String, bool, i64)> = query_as(
+            "SELECT id, pickle, imported, pickle_version
              FROM inbound_group_sessions
              WHERE (
                  account_id = ? and
@@ -735,6 +1189,7 @@ impl SqliteStore {
         let session_row_id = row.0;
         let pickle = row.1;
         let imported = row.2;
+        let version = row.3;
 
         let session = self
             .load_inbound_session_data(
@@ -744,6 +1199,7 @@ impl SqliteStore {
                 sender_key.to_owned(),
                 room_id.to_owned(),
                 imported,
+                version,
             )
             .await?;
 
@@ -751,34 +1207,75 @@ impl SqliteStore {
     }
 
     async fn load_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
-        let mut sessions = Vec::new();
+        let mut connection = self.connection.lock().await;
+        self.load_inbound_group_sessions_helper(&mut connection)
+            .await
+    }
 
+    async fn load_inbound_group_sessions_helper(
+        &self,
+        connection: &mut SqliteConnection,
+    ) -> Result<Vec<InboundGroupSession>> {
         let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
-        let mut connection = self.connection.lock().await;
 
-        let mut rows: Vec<(i64, String, String, String, bool)> = query_as(
-            "SELECT id, pickle, sender_key, room_id, imported
+        let rows: Vec<InboundGroupSessionRow> = query_as(
+            "SELECT id, pickle, sender_key, room_id, imported, pickle_version
              FROM inbound_group_sessions WHERE account_id = ?",
         )
         .bind(account_id)
         .fetch_all(&mut *connection)
         .await?;
 
-        for row in rows.drain(..) {
+        self.load_inbound_group_session_rows(connection, rows).await
+    }
+
+    async fn load_inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let rows: Vec<InboundGroupSessionRow> = query_as(
+            "SELECT id, pickle, sender_key, room_id, imported, pickle_version
+             FROM inbound_group_sessions
+             WHERE account_id = ?
+             AND id NOT IN (SELECT session_id FROM group_session_backups)
+             LIMIT ?",
+        )
+        .bind(account_id)
+        .bind(limit as i64)
+        .fetch_all(&mut *connection)
+        .await?;
+
+        self.load_inbound_group_session_rows(&mut connection, rows)
+            .await
+    }
+
+    async fn load_inbound_group_session_rows(
+        &self,
+        connection: &mut SqliteConnection,
+        rows: Vec<InboundGroupSessionRow>,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let mut sessions = Vec::new();
+
+        for row in rows {
             let session_row_id = row.0;
             let pickle = row.1;
             let sender_key = row.2;
             let room_id = RoomId::try_from(row.3)?;
             let imported = row.4;
+            let version = row.5;
 
             let session = self
                 .load_inbound_session_data(
-                    &mut connection,
+                    connection,
                     session_row_id,
                     pickle,
                     sender_key,
                     room_id.to_owned(),
                     imported,
+                    version,
                 )
                 .await?;
 
@@ -788,11 +1285,89 @@ impl SqliteStore {
         Ok(sessions)
     }
 
-    async fn save_tracked_user(&self, user: &UserId, dirty: bool) -> Result<()> {
+    async fn save_outbound_group_sessions(
+        &self,
+        connection: &mut SqliteConnection,
+        sessions: &[OutboundGroupSession],
+    ) -> Result<()> {
         let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+
+        for session in sessions {
+            let pickle = session.pickle(self.get_pickle_mode()).await;
+
+            query(
+                "INSERT INTO outbound_group_sessions (
+                    account_id, room_id, session_id, pickle
+                 ) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(account_id, room_id) DO UPDATE SET
+                    session_id = excluded.session_id,
+                    pickle = excluded.pickle
+                 ",
+            )
+            .bind(account_id)
+            .bind(session.room_id().as_str())
+            .bind(session.session_id())
+            .bind(serde_json::to_string(&pickle)?)
+            .execute(&mut *connection)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn load_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        let mut connection = self.connection.lock().await;
+        self.load_outbound_group_sessions_helper(&mut connection)
+            .await
+    }
+
+    async fn load_outbound_group_sessions_helper(
+        &self,
+        connection: &mut SqliteConnection,
+    ) -> Result<Vec<OutboundGroupSession>> {
+        let account_info = self
+            .account_info
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(CryptoStoreError::AccountUnset)?;
+
+        let rows: Vec<(String,)> =
+            query_as("SELECT pickle FROM outbound_group_sessions WHERE account_id = ?")
+                .bind(account_info.account_id)
+                .fetch_all(&mut *connection)
+                .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let pickle: PickledOutboundGroupSession = serde_json::from_str(&row.0)?;
+                check_pickle_version(pickle.version)?;
+
+                Ok(OutboundGroupSession::from_pickle(
+                    self.device_id.clone(),
+                    account_info.identity_keys.clone(),
+                    pickle,
+                    self.get_pickle_mode(),
+                )?)
+            })
+            .collect()
+    }
+
+    async fn save_tracked_user(&self, user: &UserId, dirty: bool) -> Result<()> {
         let mut connection = self.connection.lock().await;
         // TODO see the todo in the memory store, we need to avoid a race
         // between a sync and key query.
+        self.save_tracked_user_helper(&mut connection, user, dirty)
+            .await
+    }
+
+    async fn save_tracked_user_helper(
+        &self,
+        connection: &mut SqliteConnection,
+        user: &UserId,
+        dirty: bool,
+    ) -> Result<()> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
 
         query(
             "INSERT INTO tracked_users (
@@ -811,6 +1386,39 @@ impl SqliteStore {
         Ok(())
     }
 
+    /// Update the in-memory tracking state of a user.
+    ///
+    /// Returns true if the user wasn't tracked before.
+    fn update_tracked_users_cache(&self, user: &UserId, dirty: bool) -> bool {
+        let already_added = self.tracked_users.insert(user.clone());
+
+        if dirty {
+            self.users_for_key_query.insert(user.clone());
+        } else {
+            self.users_for_key_query.remove(user);
+        }
+
+        already_added
+    }
+
+    async fn save_value_helper(
+        &self,
+        connection: &mut SqliteConnection,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+
+        query("REPLACE INTO key_value (account_id, key, value) VALUES (?1, ?2, ?3)")
+            .bind(account_id)
+            .bind(key)
+            .bind(value)
+            .execute(&mut *connection)
+            .await?;
+
+        Ok(())
+    }
+
     async fn load_tracked_users(&self) -> Result<()> {
         let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
         let mut connection = self.connection.lock().await;
@@ -1087,11 +1695,11 @@ impl SqliteStore {
     }
 
     fn get_pickle_mode(&self) -> PicklingMode {
-        self.pickle_key.pickle_mode()
+        self.pickle_key.read().unwrap().pickle_mode()
     }
 
-    fn get_pickle_key(&self) -> &[u8] {
-        self.pickle_key.key()
+    fn get_pickle_key(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.pickle_key.read().unwrap().key().to_vec())
     }
 
     async fn save_inbound_group_session_helper(
@@ -1106,8 +1714,8 @@ impl SqliteStore {
         query(
             "REPLACE INTO inbound_group_sessions (
                 session_id, account_id, sender_key,
-                room_id, pickle, imported
-             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                room_id, pickle, imported, pickle_version
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
              ",
         )
         .bind(session_id)
@@ -1116,6 +1724,7 @@ impl SqliteStore {
         .bind(pickle.room_id.as_str())
         .bind(pickle.pickle.as_str())
         .bind(pickle.imported)
+        .bind(pickle.version as i64)
         .execute(&mut *connection)
         .await?;
 
@@ -1160,6 +1769,18 @@ impl SqliteStore {
             }
         }
 
+        if pickle.backed_up {
+            query("INSERT OR IGNORE INTO group_session_backups (session_id) VALUES (?1)")
+                .bind(session_row_id)
+                .execute(&mut *connection)
+                .await?;
+        } else {
+            query("DELETE FROM group_session_backups WHERE session_id = ?1")
+                .bind(session_row_id)
+                .execute(&mut *connection)
+                .await?;
+        }
+
         Ok(())
     }
 
@@ -1258,6 +1879,14 @@ impl SqliteStore {
         )
         .await?;
 
+        let verified: Option<(bool,)> =
+            query_as("SELECT trusted FROM users_trust_state WHERE user_id = ?")
+                .bind(user_row_id)
+                .fetch_optional(&mut *connection)
+                .await?;
+
+        let verified = verified.map_or(false, |r| r.0);
+
         if user_id == &*self.user_id {
             let user_signing = SqliteStore::load_cross_signing_key(
                 &mut connection,
@@ -1267,17 +1896,8 @@ impl SqliteStore {
             )
             .await?;
 
-            let verified: Option<(bool,)> =
-                query_as("SELECT trusted FROM users_trust_state WHERE user_id = ?")
-                    .bind(user_row_id)
-                    .fetch_optional(&mut *connection)
-                    .await?;
-
-            let verified = verified.map_or(false, |r| r.0);
-
             let identity =
-                OwnUserIdentity::new(master.into(), self_singing.into(), user_signing.into())
-                    .expect("Signature check failed on stored identity");
+                OwnUserIdentity::new(master.into(), self_singing.into(), user_signing.into())?;
 
             if verified {
                 identity.mark_as_verified();
@@ -1285,10 +1905,13 @@ impl SqliteStore {
 
             Ok(Some(UserIdentities::Own(identity)))
         } else {
-            Ok(Some(UserIdentities::Other(
-                UserIdentity::new(master.into(), self_singing.into())
-                    .expect("Signature check failed on stored identity"),
-            )))
+            let identity = UserIdentity::new(master.into(), self_singing.into())?;
+
+            if verified {
+                identity.mark_as_verified();
+            }
+
+            Ok(Some(UserIdentities::Other(identity)))
         }
     }
 
@@ -1392,8 +2015,9 @@ impl SqliteStore {
 
             query(
                 "REPLACE INTO sessions (
-                    session_id, account_id, creation_time, last_use_time, sender_key, pickle
-                 ) VALUES (?, ?, ?, ?, ?, ?)",
+                    session_id, account_id, creation_time, last_use_time, sender_key, pickle,
+                    pickle_version
+                 ) VALUES (?, ?, ?, ?, ?, ?, ?)",
             )
             .bind(&session_id)
             .bind(&account_id)
@@ -1401,6 +2025,7 @@ impl SqliteStore {
             .bind(&*last_use_time)
             .bind(&pickle.sender_key)
             .bind(&pickle.pickle.as_str())
+            .bind(pickle.version as i64)
             .execute(&mut *connection)
             .await?;
         }
@@ -1504,13 +2129,47 @@ impl SqliteStore {
         Ok(())
     }
 
+    async fn save_megolm_message_indices(
+        &self,
+        connection: &mut SqliteConnection,
+        indices: &[MegolmMessageIndex],
+    ) -> Result<()> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+
+        for index in indices {
+            let timestamp = index
+                .origin_server_ts
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64;
+
+            query(
+                "REPLACE INTO megolm_message_indices (
+                    account_id, room_id, sender_key, session_id, message_index, event_id,
+                    origin_server_ts
+                 ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .bind(account_id)
+            .bind(index.room_id.as_str())
+            .bind(&index.sender_key)
+            .bind(&index.session_id)
+            .bind(index.message_index as i64)
+            .bind(index.event_id.as_str())
+            .bind(timestamp)
+            .execute(&mut *connection)
+            .await?;
+        }
+
+        Ok(())
+    }
+
     async fn save_identity(
         &self,
         connection: &mut SqliteConnection,
         identity: PrivateCrossSigningIdentity,
     ) -> Result<()> {
         let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
-        let pickle = identity.pickle(self.get_pickle_key()).await?;
+        let pickle = identity.pickle(&self.get_pickle_key()).await?;
 
         query(
             "INSERT INTO private_identities (
@@ -1540,12 +2199,15 @@ impl SqliteStore {
 
         query(
             "INSERT INTO accounts (
-                user_id, device_id, pickle, shared, uploaded_key_count
-             ) VALUES (?1, ?2, ?3, ?4, ?5)
+                user_id, device_id, pickle, shared, uploaded_key_count,
+                fallback_key_pending, pickle_version
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
              ON CONFLICT(user_id, device_id) DO UPDATE SET
                 pickle = excluded.pickle,
                 shared = excluded.shared,
-                uploaded_key_count = excluded.uploaded_key_count
+                uploaded_key_count = excluded.uploaded_key_count,
+                fallback_key_pending = excluded.fallback_key_pending,
+                pickle_version = excluded.pickle_version
              ",
         )
         .bind(pickle.user_id.as_str())
@@ -1553,6 +2215,8 @@ impl SqliteStore {
         .bind(pickle.pickle.as_str())
         .bind(pickle.shared)
         .bind(pickle.uploaded_signed_key_count)
+        .bind(pickle.fallback_key_pending)
+        .bind(pickle.version as i64)
         .execute(&mut *connection)
         .await?;
 
@@ -1610,21 +2274,26 @@ impl SqliteStore {
         )
         .await?;
 
-        if let UserIdentities::Own(own_identity) = user {
-            SqliteStore::save_cross_signing_key(
-                &mut connection,
-                user_row_id,
-                CrosssigningKeyType::UserSigning,
-                own_identity.user_signing_key(),
-            )
-            .await?;
-
-            query("REPLACE INTO users_trust_state (user_id, trusted) VALUES (?1, ?2)")
-                .bind(user_row_id)
-                .bind(own_identity.is_verified())
-                .execute(&mut *connection)
+        let verified = match user {
+            UserIdentities::Own(own_identity) => {
+                SqliteStore::save_cross_signing_key(
+                    &mut connection,
+                    user_row_id,
+                    CrosssigningKeyType::UserSigning,
+                    own_identity.user_signing_key(),
+                )
                 .await?;
-        }
+
+                own_identity.is_verified()
+            }
+            UserIdentities::Other(identity) => identity.is_verified(),
+        };
+
+        query("REPLACE INTO users_trust_state (user_id, trusted) VALUES (?1, ?2)")
+            .bind(user_row_id)
+            .bind(verified)
+            .execute(&mut *connection)
+            .await?;
 
         Ok(())
     }
@@ -1634,42 +2303,14 @@ impl SqliteStore {
 impl CryptoStore for SqliteStore {
     async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
         let mut connection = self.connection.lock().await;
-
-        let row: Option<(i64, String, bool, i64)> = query_as(
-            "SELECT id, pickle, shared, uploaded_key_count FROM accounts
-                      WHERE user_id = ? and device_id = ?",
-        )
-        .bind(self.user_id.as_str())
-        .bind(self.device_id.as_str())
-        .fetch_optional(&mut *connection)
-        .await?;
-
-        let result = if let Some((id, pickle, shared, uploaded_key_count)) = row {
-            let pickle = PickledAccount {
-                user_id: (&*self.user_id).clone(),
-                device_id: (&*self.device_id).clone(),
-                pickle: AccountPickle::from(pickle),
-                shared,
-                uploaded_signed_key_count: uploaded_key_count,
-            };
-
-            let account = ReadOnlyAccount::from_pickle(pickle, self.get_pickle_mode())?;
-
-            *self.account_info.lock().unwrap() = Some(AccountInfo {
-                account_id: id,
-                identity_keys: account.identity_keys.clone(),
-            });
-
-            Some(account)
-        } else {
-            return Ok(None);
-        };
-
+        let account = self.load_account_helper(&mut connection).await?;
         drop(connection);
 
-        self.load_tracked_users().await?;
+        if account.is_some() {
+            self.load_tracked_users().await?;
+        }
 
-        Ok(result)
+        Ok(account)
     }
 
     async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
@@ -1678,33 +2319,8 @@ impl CryptoStore for SqliteStore {
     }
 
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
-        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
         let mut connection = self.connection.lock().await;
-
-        let row: Option<(String, bool)> = query_as(
-            "SELECT pickle, shared FROM private_identities
-                      WHERE account_id = ?",
-        )
-        .bind(account_id)
-        .fetch_optional(&mut *connection)
-        .await?;
-
-        if let Some(row) = row {
-            let pickle = PickledCrossSigningIdentity {
-                user_id: (&*self.user_id).clone(),
-                pickle: row.0,
-                shared: row.1,
-            };
-
-            // TODO remove this unwrap
-            let identity = PrivateCrossSigningIdentity::from_pickle(pickle, self.get_pickle_key())
-                .await
-                .unwrap();
-
-            Ok(Some(identity))
-        } else {
-            Ok(None)
-        }
+        self.load_identity_helper(&mut connection).await
     }
 
     async fn save_changes(&self, changes: Changes) -> Result<()> {
@@ -1723,6 +2339,8 @@ impl CryptoStore for SqliteStore {
             .await?;
         self.save_inbound_group_sessions(&mut transaction, &changes.inbound_group_sessions)
             .await?;
+        self.save_outbound_group_sessions(&mut transaction, &changes.outbound_group_sessions)
+            .await?;
 
         self.save_devices(&mut transaction, &changes.devices.new)
             .await?;
@@ -1737,9 +2355,24 @@ impl CryptoStore for SqliteStore {
             .await?;
         self.save_olm_hashses(&mut transaction, &changes.message_hashes)
             .await?;
+        self.save_megolm_message_indices(&mut transaction, &changes.megolm_message_indices)
+            .await?;
+
+        for (user_id, dirty) in &changes.tracked_users {
+            self.save_tracked_user_helper(&mut transaction, user_id, *dirty)
+                .await?;
+        }
+
+        for (key, value) in &changes.values {
+            self.save_value_helper(&mut transaction, key, value).await?;
+        }
 
         transaction.commit().await?;
 
+        for (user_id, dirty) in &changes.tracked_users {
+            self.update_tracked_users_cache(user_id, *dirty);
+        }
+
         Ok(())
     }
 
@@ -1748,6 +2381,10 @@ impl CryptoStore for SqliteStore {
         Ok(self.get_sessions_for(&mut connection, sender_key).await?)
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        self.load_all_sessions().await
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -1763,6 +2400,33 @@ impl CryptoStore for SqliteStore {
         Ok(self.load_inbound_group_sessions().await?)
     }
 
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        self.load_outbound_group_sessions().await
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions_for_backup(limit).await
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        query(
+            "DELETE FROM group_session_backups WHERE session_id IN (
+                SELECT id FROM inbound_group_sessions WHERE account_id = ?
+             )",
+        )
+        .bind(account_id)
+        .execute(&mut *connection)
+        .await?;
+
+        Ok(())
+    }
+
     fn is_user_tracked(&self, user_id: &UserId) -> bool {
         self.tracked_users.contains(user_id)
     }
@@ -1771,20 +2435,18 @@ impl CryptoStore for SqliteStore {
         !self.users_for_key_query.is_empty()
     }
 
+    fn tracked_users(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.tracked_users.iter().map(|u| u.clone()).collect()
+    }
+
     fn users_for_key_query(&self) -> HashSet<UserId> {
         #[allow(clippy::map_clone)]
         self.users_for_key_query.iter().map(|u| u.clone()).collect()
     }
 
     async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
-        let already_added = self.tracked_users.insert(user.clone());
-
-        if dirty {
-            self.users_for_key_query.insert(user.clone());
-        } else {
-            self.users_for_key_query.remove(user);
-        }
-
+        let already_added = self.update_tracked_users_cache(user, dirty);
         self.save_tracked_user(user, dirty).await?;
 
         Ok(already_added)
@@ -1810,17 +2472,8 @@ impl CryptoStore for SqliteStore {
     }
 
     async fn save_value(&self, key: String, value: String) -> Result<()> {
-        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
         let mut connection = self.connection.lock().await;
-
-        query("REPLACE INTO key_value (account_id, key, value) VALUES (?1, ?2, ?3)")
-            .bind(account_id)
-            .bind(&key)
-            .bind(&value)
-            .execute(&mut *connection)
-            .await?;
-
-        Ok(())
+        self.save_value_helper(&mut connection, &key, &value).await
     }
 
     async fn remove_value(&self, key: &str) -> Result<()> {
@@ -1854,21 +2507,125 @@ impl CryptoStore for SqliteStore {
         Ok(row.map(|r| r.0))
     }
 
-    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+    async fn get_values(&self) -> Result<HashMap<String, String>> {
         let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
         let mut connection = self.connection.lock().await;
 
-        let row: Option<(String,)> = query_as(
-            "SELECT hash FROM olm_hashes WHERE account_id = ? and sender_key = ? and hash = ?",
-        )
-        .bind(account_id)
-        .bind(&message_hash.sender_key)
-        .bind(&message_hash.hash)
-        .fetch_optional(&mut *connection)
+        let rows: Vec<(String, String)> =
+            query_as("SELECT key, value FROM key_value WHERE account_id = ?")
+                .bind(account_id)
+                .fetch_all(&mut *connection)
+                .await?;
+
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let row: Option<(String,)> = query_as(
+            "SELECT hash FROM olm_hashes WHERE account_id = ? and sender_key = ? and hash = ?",
+        )
+        .bind(account_id)
+        .bind(&message_hash.sender_key)
+        .bind(&message_hash.hash)
+        .fetch_optional(&mut *connection)
         .await?;
 
         Ok(row.is_some())
     }
+
+    async fn get_message_hashes(&self) -> Result<Vec<OlmMessageHash>> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let rows: Vec<(String, String)> =
+            query_as("SELECT sender_key, hash FROM olm_hashes WHERE account_id = ?")
+                .bind(account_id)
+                .fetch_all(&mut *connection)
+                .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(sender_key, hash)| OlmMessageHash { sender_key, hash })
+            .collect())
+    }
+
+    async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        repickle: bool,
+    ) -> Result<()> {
+        self.change_passphrase_helper(old_passphrase, new_passphrase, repickle)
+            .await
+    }
+
+    async fn get_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<MegolmMessageIndex>> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let row: Option<(String, i64)> = query_as(
+            "SELECT event_id, origin_server_ts FROM megolm_message_indices
+             WHERE account_id = ? and room_id = ? and sender_key = ? and session_id = ?
+             and message_index = ?",
+        )
+        .bind(account_id)
+        .bind(room_id.as_str())
+        .bind(sender_key)
+        .bind(session_id)
+        .bind(message_index as i64)
+        .fetch_optional(&mut *connection)
+        .await?;
+
+        Ok(if let Some((event_id, timestamp)) = row {
+            Some(MegolmMessageIndex {
+                room_id: room_id.to_owned(),
+                sender_key: sender_key.to_owned(),
+                session_id: session_id.to_owned(),
+                message_index,
+                event_id: EventId::try_from(event_id)?,
+                origin_server_ts: UNIX_EPOCH + Duration::from_millis(timestamp as u64),
+            })
+        } else {
+            None
+        })
+    }
+
+    async fn get_megolm_message_indices(&self) -> Result<Vec<MegolmMessageIndex>> {
+        let account_id = self.account_id().ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let rows: Vec<MegolmMessageIndexRow> = query_as(
+            "SELECT room_id, sender_key, session_id, message_index, event_id,
+             origin_server_ts FROM megolm_message_indices WHERE account_id = ?",
+        )
+        .bind(account_id)
+        .fetch_all(&mut *connection)
+        .await?;
+
+        rows.into_iter()
+            .map(
+                |(room_id, sender_key, session_id, message_index, event_id, timestamp)| {
+                    Ok(MegolmMessageIndex {
+                        room_id: RoomId::try_from(room_id)?,
+                        sender_key,
+                        session_id,
+                        message_index: message_index as u32,
+                        event_id: EventId::try_from(event_id)?,
+                        origin_server_ts: UNIX_EPOCH + Duration::from_millis(timestamp as u64),
+                    })
+                },
+            )
+            .collect()
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -1884,63 +2641,41 @@ impl std::fmt::Debug for SqliteStore {
 
 #[cfg(test)]
 mod test {
+    use std::path::Path;
+
     use crate::{
-        identities::{
-            device::test::get_device,
-            user::test::{get_other_identity, get_own_identity},
-        },
-        olm::{
-            GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
-            ReadOnlyAccount, Session,
+        olm::{ReadOnlyAccount, PICKLE_VERSION},
+        store::{
+            integration_tests::{
+                alice_device_id, alice_id, get_account, get_inbound_group_session, get_session,
+            },
+            Changes,
         },
-        store::{Changes, DeviceChanges, IdentityChanges},
-    };
-    use matrix_sdk_common::{
-        api::r0::keys::SignedKey,
-        identifiers::{room_id, user_id, DeviceId, UserId},
     };
-    use olm_rs::outbound_group_session::OlmOutboundGroupSession;
-    use std::collections::BTreeMap;
+    use sqlx::{query, query_as, sqlite::SqliteConnectOptions, Connection, SqliteConnection};
     use tempfile::tempdir;
 
-    use super::{CryptoStore, SqliteStore};
-
-    fn alice_id() -> UserId {
-        user_id!("@alice:example.org")
-    }
-
-    fn alice_device_id() -> Box<DeviceId> {
-        "ALICEDEVICE".into()
-    }
-
-    fn bob_id() -> UserId {
-        user_id!("@bob:example.org")
-    }
-
-    fn bob_device_id() -> Box<DeviceId> {
-        "BOBDEVICE".into()
-    }
+    use super::{
+        CryptoStore, CryptoStoreError, PickleKey, SqliteStore, DATABASE_NAME, DATABASE_VERSION,
+    };
 
-    async fn get_store(passphrase: Option<&str>) -> (SqliteStore, tempfile::TempDir) {
-        let tmpdir = tempdir().unwrap();
-        let tmpdir_path = tmpdir.path().to_str().unwrap();
-
-        let store = if let Some(passphrase) = passphrase {
-            SqliteStore::open_with_passphrase(
-                &alice_id(),
-                &alice_device_id(),
-                tmpdir_path,
-                passphrase,
-            )
-            .await
-            .expect("Can't create a passphrase protected store")
+    async fn open_store(dir: &Path, passphrase: Option<&str>) -> SqliteStore {
+        if let Some(passphrase) = passphrase {
+            SqliteStore::open_with_passphrase(&alice_id(), &alice_device_id(), dir, passphrase)
+                .await
+                .expect("Can't create a passphrase protected store")
         } else {
-            SqliteStore::open(&alice_id(), &alice_device_id(), tmpdir_path)
+            SqliteStore::open(&alice_id(), &alice_device_id(), dir)
                 .await
                 .expect("Can't create store")
-        };
+        }
+    }
 
-        (store, tmpdir)
+    async fn get_store(passphrase: Option<&str>) -> (SqliteStore, tempfile::TempDir) {
+        let dir = tempdir().unwrap();
+        let store = open_store(dir.path(), passphrase).await;
+
+        (store, dir)
     }
 
     async fn get_loaded_store() -> (ReadOnlyAccount, SqliteStore, tempfile::TempDir) {
@@ -1954,516 +2689,213 @@ mod test {
         (account, store, dir)
     }
 
-    fn get_account() -> ReadOnlyAccount {
-        ReadOnlyAccount::new(&alice_id(), &alice_device_id())
-    }
-
-    async fn get_account_and_session() -> (ReadOnlyAccount, Session) {
-        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
-        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
-
-        bob.generate_one_time_keys_helper(1).await;
-        let one_time_key = bob
-            .one_time_keys()
-            .await
-            .curve25519()
-            .iter()
-            .next()
-            .unwrap()
-            .1
-            .to_owned();
-        let one_time_key = SignedKey {
-            key: one_time_key,
-            signatures: BTreeMap::new(),
-        };
-        let sender_key = bob.identity_keys().curve25519().to_owned();
-        let session = alice
-            .create_outbound_session_helper(&sender_key, &one_time_key)
-            .await
-            .unwrap();
-
-        (alice, session)
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn create_store() {
-        let tmpdir = tempdir().unwrap();
-        let tmpdir_path = tmpdir.path().to_str().unwrap();
-        let _ = SqliteStore::open(&alice_id(), &alice_device_id(), tmpdir_path)
-            .await
-            .expect("Can't create store");
-    }
+    crate::cryptostore_integration_tests!(open_store, persistent);
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn save_account() {
-        let (store, _dir) = get_store(None).await;
-        assert!(store.load_account().await.unwrap().is_none());
-        let account = get_account();
-
+    async fn wrong_passphrase_is_rejected() {
+        let (store, dir) = get_store(Some("secret_passphrase")).await;
         store
-            .save_account(account)
+            .change_passphrase("secret_passphrase", "new_passphrase", true)
             .await
-            .expect("Can't save account");
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn load_account() {
-        let (store, _dir) = get_store(None).await;
-        let account = get_account();
-
-        store
-            .save_account(account.clone())
-            .await
-            .expect("Can't save account");
-
-        let loaded_account = store.load_account().await.expect("Can't load account");
-        let loaded_account = loaded_account.unwrap();
-
-        assert_eq!(account, loaded_account);
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn load_account_with_passphrase() {
-        let (store, _dir) = get_store(Some("secret_passphrase")).await;
-        let account = get_account();
-
-        store
-            .save_account(account.clone())
-            .await
-            .expect("Can't save account");
-
-        let loaded_account = store.load_account().await.expect("Can't load account");
-        let loaded_account = loaded_account.unwrap();
+            .unwrap();
+        drop(store);
 
-        assert_eq!(account, loaded_account);
+        assert!(SqliteStore::open_with_passphrase(
+            &alice_id(),
+            &alice_device_id(),
+            dir.path(),
+            "secret_passphrase"
+        )
+        .await
+        .is_err());
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn save_and_share_account() {
-        let (store, _dir) = get_store(None).await;
+    async fn unversioned_database_is_migrated() {
+        let dir = tempdir().unwrap();
         let account = get_account();
 
-        store
-            .save_account(account.clone())
-            .await
-            .expect("Can't save account");
-
-        account.mark_as_shared();
-        account.update_uploaded_key_count(50);
-
-        store
-            .save_account(account.clone())
-            .await
-            .expect("Can't save account");
-
-        let loaded_account = store.load_account().await.expect("Can't load account");
-        let loaded_account = loaded_account.unwrap();
-
-        assert_eq!(account, loaded_account);
-        assert_eq!(
-            account.uploaded_key_count(),
-            loaded_account.uploaded_key_count()
-        );
-    }
+        // Databases created before the schema was versioned only have the
+        // tables of the first version, and no version table.
+        {
+            let options = SqliteConnectOptions::new()
+                .create_if_missing(true)
+                .filename(dir.path().join(DATABASE_NAME));
+            let mut connection = SqliteConnection::connect_with(&options).await.unwrap();
+            SqliteStore::create_tables(&mut connection).await.unwrap();
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn save_session() {
-        let (store, _dir) = get_store(None).await;
-        let (account, session) = get_account_and_session().await;
+            let pickle = account.pickle(PickleKey::unencrypted().pickle_mode()).await;
 
-        assert!(store.save_sessions(&[session.clone()]).await.is_err());
-
-        store
-            .save_account(account.clone())
-            .await
-            .expect("Can't save account");
-
-        store.save_sessions(&[session]).await.unwrap();
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn load_sessions() {
-        let (store, _dir) = get_store(None).await;
-        let (account, session) = get_account_and_session().await;
-        store
-            .save_account(account.clone())
-            .await
-            .expect("Can't save account");
-        store.save_sessions(&[session.clone()]).await.unwrap();
-
-        let sessions = store
-            .load_sessions_for(&session.sender_key)
-            .await
-            .expect("Can't load sessions");
-        let loaded_session = &sessions[0];
-
-        assert_eq!(&session, loaded_session);
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn add_and_save_session() {
-        let (store, dir) = get_store(None).await;
-        let (account, session) = get_account_and_session().await;
-        let sender_key = session.sender_key.to_owned();
-        let session_id = session.session_id().to_owned();
-
-        store
-            .save_account(account.clone())
+            query(
+                "INSERT INTO accounts (
+                    user_id, device_id, pickle, shared, uploaded_key_count
+                 ) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(pickle.user_id.as_str())
+            .bind(pickle.device_id.as_str())
+            .bind(pickle.pickle.as_str())
+            .bind(pickle.shared)
+            .bind(pickle.uploaded_signed_key_count)
+            .execute(&mut connection)
             .await
-            .expect("Can't save account");
-        store.save_sessions(&[session]).await.unwrap();
-
-        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
-        let sessions_lock = sessions.lock().await;
-        let session = &sessions_lock[0];
-
-        assert_eq!(session_id, session.session_id());
-
-        drop(store);
+            .unwrap();
+        }
 
         let store = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path())
             .await
-            .expect("Can't create store");
+            .expect("Can't open an unversioned store");
+        assert_eq!(store.load_account().await.unwrap(), Some(account));
 
-        let loaded_account = store.load_account().await.unwrap().unwrap();
-        assert_eq!(account, loaded_account);
-
-        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
-        let sessions_lock = sessions.lock().await;
-        let session = &sessions_lock[0];
-
-        assert_eq!(session_id, session.session_id());
+        let version: (i64,) = query_as("SELECT version FROM schema_version")
+            .fetch_one(&mut *store.connection.lock().await)
+            .await
+            .unwrap();
+        assert_eq!(version.0, DATABASE_VERSION as i64);
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn save_inbound_group_session() {
-        let (account, store, _dir) = get_loaded_store().await;
+    async fn megolm_message_indices_are_migrated() {
+        let dir = tempdir().unwrap();
+        let options = SqliteConnectOptions::new()
+            .create_if_missing(true)
+            .filename(dir.path().join(DATABASE_NAME));
+        let mut connection = SqliteConnection::connect_with(&options).await.unwrap();
+        SqliteStore::create_tables(&mut connection).await.unwrap();
 
-        let identity_keys = account.identity_keys();
-        let outbound_session = OlmOutboundGroupSession::new();
-        let session = InboundGroupSession::new(
-            identity_keys.curve25519(),
-            identity_keys.ed25519(),
-            &room_id!("!test:localhost"),
-            GroupSessionKey(outbound_session.session_key()),
+        query(
+            "INSERT INTO accounts (
+                user_id, device_id, pickle, shared, uploaded_key_count
+             ) VALUES ('@alice:example.org', 'ALICEDEVICE', '', 0, 0)",
         )
-        .expect("Can't create session");
-
-        store
-            .save_inbound_group_sessions_test(&[session])
-            .await
-            .expect("Can't save group session");
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn load_inbound_group_session() {
-        let (account, store, dir) = get_loaded_store().await;
-
-        let identity_keys = account.identity_keys();
-        let outbound_session = OlmOutboundGroupSession::new();
-        let session = InboundGroupSession::new(
-            identity_keys.curve25519(),
-            identity_keys.ed25519(),
-            &room_id!("!test:localhost"),
-            GroupSessionKey(outbound_session.session_key()),
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        query(
+            "INSERT INTO inbound_group_sessions (
+                session_id, account_id, sender_key, room_id, pickle, imported
+             ) VALUES ('session', 1, 'sender_key', '!room:example.org', '', 0)",
         )
-        .expect("Can't create session");
-
-        let mut export = session.export().await;
-
-        export.forwarding_curve25519_key_chain = vec!["some_chain".to_owned()];
-
-        let session = InboundGroupSession::from_export(export).unwrap();
-
-        store
-            .save_inbound_group_sessions_test(&[session.clone()])
-            .await
-            .expect("Can't save group session");
-
-        let store = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path())
-            .await
-            .expect("Can't create store");
-
-        store.load_account().await.unwrap();
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        query(
+            "INSERT INTO megolm_message_indices (
+                account_id, session_id, message_index, event_id, origin_server_ts
+             ) VALUES (1, 'session', 0, '$event:example.org', 0),
+                      (1, 'unknown_session', 0, '$other_event:example.org', 0)",
+        )
+        .execute(&mut connection)
+        .await
+        .unwrap();
 
-        let loaded_session = store
-            .get_inbound_group_session(&session.room_id, &session.sender_key, session.session_id())
+        SqliteStore::key_megolm_message_indices_by_sender(&mut connection)
             .await
-            .unwrap()
             .unwrap();
-        assert_eq!(session, loaded_session);
-        let export = loaded_session.export().await;
-        assert!(!export.forwarding_curve25519_key_chain.is_empty())
+
+        // Indices of sessions we don't have can't be attributed to a sender
+        // and are dropped.
+        let rows: Vec<(String, String, String)> =
+            query_as("SELECT room_id, sender_key, session_id FROM megolm_message_indices")
+                .fetch_all(&mut connection)
+                .await
+                .unwrap();
+        assert_eq!(
+            rows,
+            vec![(
+                "!room:example.org".to_owned(),
+                "sender_key".to_owned(),
+                "session".to_owned()
+            )]
+        );
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn test_tracked_users() {
-        let (_account, store, dir) = get_loaded_store().await;
-        let device = get_device();
-
-        assert!(store
-            .update_tracked_user(device.user_id(), false)
-            .await
-            .unwrap());
-        assert!(!store
-            .update_tracked_user(device.user_id(), false)
-            .await
-            .unwrap());
-
-        assert!(store.is_user_tracked(device.user_id()));
-        assert!(!store.users_for_key_query().contains(device.user_id()));
-        assert!(!store
-            .update_tracked_user(device.user_id(), true)
-            .await
-            .unwrap());
-        assert!(store.users_for_key_query().contains(device.user_id()));
-        drop(store);
-
-        let store = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path())
-            .await
-            .expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        assert!(store.is_user_tracked(device.user_id()));
-        assert!(store.users_for_key_query().contains(device.user_id()));
-
-        store
-            .update_tracked_user(device.user_id(), false)
-            .await
-            .unwrap();
-        assert!(!store.users_for_key_query().contains(device.user_id()));
-
-        let store = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path())
-            .await
-            .expect("Can't create store");
+    async fn pending_fallback_key_is_persisted() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let loaded = store.load_account().await.unwrap().unwrap();
+        assert!(!loaded.fallback_key_pending());
 
-        store.load_account().await.unwrap();
+        account.generate_fallback_key().await;
+        store.save_account(account.clone()).await.unwrap();
 
-        assert!(!store.users_for_key_query().contains(device.user_id()));
+        let loaded = store.load_account().await.unwrap().unwrap();
+        assert!(loaded.fallback_key_pending());
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn device_saving() {
-        let (_account, store, dir) = get_loaded_store().await;
-        let device = get_device();
+    async fn newer_pickle_is_rejected() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let session = get_session(&account).await;
+        let inbound = get_inbound_group_session(&account);
 
         let changes = Changes {
-            devices: DeviceChanges {
-                changed: vec![device.clone()],
-                ..Default::default()
-            },
+            sessions: vec![session.clone()],
+            inbound_group_sessions: vec![inbound.clone()],
             ..Default::default()
         };
-
         store.save_changes(changes).await.unwrap();
 
-        drop(store);
-
-        let store = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path())
-            .await
-            .expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        let loaded_device = store
-            .get_device(device.user_id(), device.device_id())
-            .await
-            .unwrap()
-            .unwrap();
-
-        assert_eq!(device, loaded_device);
-
-        for algorithm in loaded_device.algorithms() {
-            assert!(device.algorithms().contains(algorithm));
+        for table in &["accounts", "sessions", "inbound_group_sessions"] {
+            query(&format!("UPDATE {} SET pickle_version = ?", table))
+                .bind(PICKLE_VERSION as i64 + 1)
+                .execute(&mut *store.connection.lock().await)
+                .await
+                .unwrap();
         }
-        assert_eq!(device.algorithms().len(), loaded_device.algorithms().len());
-        assert_eq!(device.keys(), loaded_device.keys());
 
-        let user_devices = store.get_user_devices(device.user_id()).await.unwrap();
-        assert_eq!(&**user_devices.keys().next().unwrap(), device.device_id());
-        assert_eq!(user_devices.values().next().unwrap(), &device);
+        assert!(matches!(
+            store.load_account().await,
+            Err(CryptoStoreError::UnsupportedPickleVersion(v, PICKLE_VERSION))
+                if v == PICKLE_VERSION + 1
+        ));
+        assert!(matches!(
+            store.load_sessions_for(&session.sender_key).await,
+            Err(CryptoStoreError::UnsupportedPickleVersion(_, _))
+        ));
+        assert!(matches!(
+            store
+                .get_inbound_group_session(
+                    inbound.room_id(),
+                    inbound.sender_key(),
+                    inbound.session_id()
+                )
+                .await,
+            Err(CryptoStoreError::UnsupportedPickleVersion(_, _))
+        ));
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn device_deleting() {
-        let (_account, store, dir) = get_loaded_store().await;
-        let device = get_device();
-
-        let changes = Changes {
-            devices: DeviceChanges {
-                changed: vec![device.clone()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
-
-        let changes = Changes {
-            devices: DeviceChanges {
-                deleted: vec![device.clone()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
-
-        let store = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path())
-            .await
-            .expect("Can't create store");
-
-        store.load_account().await.unwrap();
+    async fn invalid_versions_are_rejected() {
+        let (store, dir) = get_store(None).await;
 
-        let loaded_device = store
-            .get_device(device.user_id(), device.device_id())
+        query("UPDATE schema_version SET version = ?")
+            .bind(-1)
+            .execute(&mut *store.connection.lock().await)
             .await
             .unwrap();
+        drop(store);
 
-        assert!(loaded_device.is_none());
+        let result = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path()).await;
+        assert!(matches!(result, Err(CryptoStoreError::InvalidVersion(-1))));
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn user_saving() {
-        let dir = tempdir().unwrap();
-        let tmpdir_path = dir.path().to_str().unwrap();
-
-        let user_id = user_id!("@example:localhost");
-        let device_id: &DeviceId = "WSKKLTJZCL".into();
-
-        let store = SqliteStore::open(&user_id, &device_id, tmpdir_path)
-            .await
-            .expect("Can't create store");
-
-        let account = ReadOnlyAccount::new(&user_id, &device_id);
-
-        store
-            .save_account(account.clone())
-            .await
-            .expect("Can't save account");
-
-        let own_identity = get_own_identity();
-
-        let changes = Changes {
-            identities: IdentityChanges {
-                changed: vec![own_identity.clone().into()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store
-            .save_changes(changes)
-            .await
-            .expect("Can't save identity");
-
-        drop(store);
-
-        let store = SqliteStore::open(&user_id, &device_id, dir.path())
-            .await
-            .expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        let loaded_user = store
-            .get_user_identity(own_identity.user_id())
-            .await
-            .unwrap()
-            .unwrap();
-
-        assert_eq!(loaded_user.master_key(), own_identity.master_key());
-        assert_eq!(
-            loaded_user.self_signing_key(),
-            own_identity.self_signing_key()
-        );
-        assert_eq!(loaded_user, own_identity.clone().into());
-
-        let other_identity = get_other_identity();
-
-        let changes = Changes {
-            identities: IdentityChanges {
-                changed: vec![other_identity.clone().into()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
+    async fn newer_database_is_rejected() {
+        let (store, dir) = get_store(None).await;
 
-        let loaded_user = store
-            .load_user(other_identity.user_id())
+        query("UPDATE schema_version SET version = ?")
+            .bind(DATABASE_VERSION as i64 + 1)
+            .execute(&mut *store.connection.lock().await)
             .await
-            .unwrap()
             .unwrap();
+        drop(store);
 
-        assert_eq!(loaded_user.master_key(), other_identity.master_key());
-        assert_eq!(
-            loaded_user.self_signing_key(),
-            other_identity.self_signing_key()
-        );
-        assert_eq!(loaded_user, other_identity.into());
-
-        own_identity.mark_as_verified();
-
-        let changes = Changes {
-            identities: IdentityChanges {
-                changed: vec![own_identity.into()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
-        let loaded_user = store.load_user(&user_id).await.unwrap().unwrap();
-        assert!(loaded_user.own().unwrap().is_verified())
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn private_identity_saving() {
-        let (_, store, _dir) = get_loaded_store().await;
-        assert!(store.load_identity().await.unwrap().is_none());
-        let identity = PrivateCrossSigningIdentity::new((&*store.user_id).clone()).await;
-
-        let changes = Changes {
-            private_identity: Some(identity.clone()),
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
-        let loaded_identity = store.load_identity().await.unwrap().unwrap();
-        assert_eq!(identity.user_id(), loaded_identity.user_id());
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn key_value_saving() {
-        let (_, store, _dir) = get_loaded_store().await;
-        let key = "test_key".to_string();
-        let value = "secret value".to_string();
-
-        store.save_value(key.clone(), value.clone()).await.unwrap();
-        let stored_value = store.get_value(&key).await.unwrap().unwrap();
-
-        assert_eq!(value, stored_value);
-
-        store.remove_value(&key).await.unwrap();
-        assert!(store.get_value(&key).await.unwrap().is_none());
-    }
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn olm_hash_saving() {
-        let (_, store, _dir) = get_loaded_store().await;
-
-        let hash = OlmMessageHash {
-            sender_key: "test_sender".to_owned(),
-            hash: "test_hash".to_owned(),
-        };
-
-        let mut changes = Changes::default();
-        changes.message_hashes.push(hash.clone());
+        let result = SqliteStore::open(&alice_id(), &alice_device_id(), dir.path()).await;
 
-        assert!(!store.is_message_known(&hash).await.unwrap());
-        store.save_changes(changes).await.unwrap();
-        assert!(store.is_message_known(&hash).await.unwrap());
+        assert!(matches!(
+            result,
+            Err(CryptoStoreError::UnsupportedDatabaseVersion(v, DATABASE_VERSION))
+                if v == DATABASE_VERSION + 1
+        ));
     }
 }
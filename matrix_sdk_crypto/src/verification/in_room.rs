@@ -0,0 +1,332 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for interactive verifications that are done inside of a room.
+//!
+//! In-room verification events carry a `m.relates_to` field referencing the
+//! `m.key.verification.request` room message instead of a transaction id. The
+//! SAS state machine works with the to-device form of the events, so the
+//! events are converted when they enter or leave the verification machine.
+//! The flow id of an in-room verification is the event id of the request.
+
+use matrix_sdk_common::{
+    events::{
+        key::verification::{
+            accept::{AcceptEventContent, AcceptToDeviceEventContent},
+            cancel::{CancelEventContent, CancelToDeviceEventContent},
+            done::{DoneEventContent, DoneToDeviceEventContent},
+            key::{KeyEventContent, KeyToDeviceEventContent},
+            mac::{MacEventContent, MacToDeviceEventContent},
+            ready::ReadyEventContent,
+            start::{StartEventContent, StartToDeviceEventContent},
+            Relation,
+        },
+        room::message::{KeyVerificationRequestEventContent, MessageEventContent},
+        AnyMessageEventContent, AnySyncMessageEvent, AnyToDeviceEvent, AnyToDeviceEventContent,
+        ToDeviceEvent,
+    },
+    identifiers::{DeviceId, EventId, RoomId, UserId},
+};
+
+use super::requests::SUPPORTED_METHODS;
+
+/// The room and request event id that identify an in-room verification flow.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomFlowId {
+    pub room_id: RoomId,
+    pub event_id: EventId,
+}
+
+/// Create the content for a `m.key.verification.request` room message.
+///
+/// # Arguments
+///
+/// * `own_user_id` - The user id of our own user, used for the fallback body.
+///
+/// * `own_device_id` - The device id of our own device.
+///
+/// * `other_user_id` - The user that we want to verify.
+pub fn request_content(
+    own_user_id: &UserId,
+    own_device_id: &DeviceId,
+    other_user_id: &UserId,
+) -> MessageEventContent {
+    MessageEventContent::VerificationRequest(KeyVerificationRequestEventContent {
+        body: format!(
+            "{} is requesting to verify your key, but your client does not \
+            support in-chat key verification. You will need to use legacy key \
+            verification to verify keys.",
+            own_user_id
+        ),
+        methods: SUPPORTED_METHODS.to_vec(),
+        from_device: own_device_id.into(),
+        to: other_user_id.clone(),
+    })
+}
+
+/// Create the content for a `m.key.verification.ready` room event.
+pub fn ready_content(own_device_id: &DeviceId, flow_id: &EventId) -> AnyMessageEventContent {
+    AnyMessageEventContent::KeyVerificationReady(ReadyEventContent {
+        from_device: own_device_id.into(),
+        methods: SUPPORTED_METHODS.to_vec(),
+        relation: Relation {
+            event_id: flow_id.clone(),
+        },
+    })
+}
+
+/// Create the content for a `m.key.verification.done` room event.
+pub fn done_content(flow_id: &EventId) -> AnyMessageEventContent {
+    AnyMessageEventContent::KeyVerificationDone(DoneEventContent {
+        relation: Relation {
+            event_id: flow_id.clone(),
+        },
+    })
+}
+
+/// Get the flow id of an in-room verification event.
+///
+/// For the request room message the flow id is the event id of the event
+/// itself, for all the other events it's the event id of the request the event
+/// relates to.
+pub fn flow_id(event: &AnySyncMessageEvent) -> Option<&EventId> {
+    match event {
+        AnySyncMessageEvent::RoomMessage(e) => {
+            if let MessageEventContent::VerificationRequest(_) = &e.content {
+                Some(&e.event_id)
+            } else {
+                None
+            }
+        }
+        AnySyncMessageEvent::KeyVerificationReady(e) => Some(&e.content.relation.event_id),
+        AnySyncMessageEvent::KeyVerificationStart(e) => Some(&e.content.relation.event_id),
+        AnySyncMessageEvent::KeyVerificationAccept(e) => Some(&e.content.relation.event_id),
+        AnySyncMessageEvent::KeyVerificationKey(e) => Some(&e.content.relation.event_id),
+        AnySyncMessageEvent::KeyVerificationMac(e) => Some(&e.content.relation.event_id),
+        AnySyncMessageEvent::KeyVerificationCancel(e) => Some(&e.content.relation.event_id),
+        AnySyncMessageEvent::KeyVerificationDone(e) => Some(&e.content.relation.event_id),
+        _ => None,
+    }
+}
+
+/// Convert the to-device content the SAS state machine produced into the
+/// in-room equivalent.
+///
+/// # Arguments
+///
+/// * `flow_id` - The event id of the verification request the content should
+/// relate to.
+///
+/// * `content` - The to-device content that should be converted.
+///
+/// Returns `None` if the content isn't part of a verification flow.
+pub fn to_room_content(
+    flow_id: &EventId,
+    content: AnyToDeviceEventContent,
+) -> Option<AnyMessageEventContent> {
+    let relation = Relation {
+        event_id: flow_id.clone(),
+    };
+
+    Some(match content {
+        AnyToDeviceEventContent::KeyVerificationStart(c) => {
+            AnyMessageEventContent::KeyVerificationStart(StartEventContent {
+                from_device: c.from_device,
+                method: c.method,
+                relation,
+            })
+        }
+        AnyToDeviceEventContent::KeyVerificationAccept(c) => {
+            AnyMessageEventContent::KeyVerificationAccept(AcceptEventContent {
+                method: c.method,
+                relation,
+            })
+        }
+        AnyToDeviceEventContent::KeyVerificationKey(c) => {
+            AnyMessageEventContent::KeyVerificationKey(KeyEventContent {
+                key: c.key,
+                relation,
+            })
+        }
+        AnyToDeviceEventContent::KeyVerificationMac(c) => {
+            AnyMessageEventContent::KeyVerificationMac(MacEventContent {
+                mac: c.mac,
+                keys: c.keys,
+                relation,
+            })
+        }
+        AnyToDeviceEventContent::KeyVerificationCancel(c) => {
+            AnyMessageEventContent::KeyVerificationCancel(CancelEventContent {
+                reason: c.reason,
+                code: c.code,
+                relation,
+            })
+        }
+        AnyToDeviceEventContent::KeyVerificationDone(_) => {
+            AnyMessageEventContent::KeyVerificationDone(DoneEventContent { relation })
+        }
+        _ => return None,
+    })
+}
+
+/// Convert an in-room verification event into the to-device form the SAS
+/// state machine understands.
+///
+/// The transaction id of the resulting event will be the event id of the
+/// verification request. Returns `None` if the event isn't part of a SAS or
+/// QR code flow.
+pub fn to_device_event(event: &AnySyncMessageEvent) -> Option<AnyToDeviceEvent> {
+    Some(match event {
+        AnySyncMessageEvent::KeyVerificationStart(e) => {
+            AnyToDeviceEvent::KeyVerificationStart(ToDeviceEvent {
+                sender: e.sender.clone(),
+                content: StartToDeviceEventContent {
+                    from_device: e.content.from_device.clone(),
+                    transaction_id: e.content.relation.event_id.to_string(),
+                    method: e.content.method.clone(),
+                },
+            })
+        }
+        AnySyncMessageEvent::KeyVerificationAccept(e) => {
+            AnyToDeviceEvent::KeyVerificationAccept(ToDeviceEvent {
+                sender: e.sender.clone(),
+                content: AcceptToDeviceEventContent {
+                    transaction_id: e.content.relation.event_id.to_string(),
+                    method: e.content.method.clone(),
+                },
+            })
+        }
+        AnySyncMessageEvent::KeyVerificationKey(e) => {
+            AnyToDeviceEvent::KeyVerificationKey(ToDeviceEvent {
+                sender: e.sender.clone(),
+                content: KeyToDeviceEventContent {
+                    transaction_id: e.content.relation.event_id.to_string(),
+                    key: e.content.key.clone(),
+                },
+            })
+        }
+        AnySyncMessageEvent::KeyVerificationMac(e) => {
+            AnyToDeviceEvent::KeyVerificationMac(ToDeviceEvent {
+                sender: e.sender.clone(),
+                content: MacToDeviceEventContent {
+                    transaction_id: e.content.relation.event_id.to_string(),
+                    mac: e.content.mac.clone(),
+                    keys: e.content.keys.clone(),
+                },
+            })
+        }
+        AnySyncMessageEvent::KeyVerificationCancel(e) => {
+            AnyToDeviceEvent::KeyVerificationCancel(ToDeviceEvent {
+                sender: e.sender.clone(),
+                content: CancelToDeviceEventContent {
+                    transaction_id: e.content.relation.event_id.to_string(),
+                    reason: e.content.reason.clone(),
+                    code: e.content.code.clone(),
+                },
+            })
+        }
+        AnySyncMessageEvent::KeyVerificationDone(e) => {
+            AnyToDeviceEvent::KeyVerificationDone(ToDeviceEvent {
+                sender: e.sender.clone(),
+                content: DoneToDeviceEventContent {
+                    transaction_id: e.content.relation.event_id.to_string(),
+                },
+            })
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use matrix_sdk_common::{
+        events::{
+            key::verification::{cancel::CancelCode, key::KeyToDeviceEventContent},
+            AnyMessageEventContent, AnySyncMessageEvent, AnyToDeviceEvent, AnyToDeviceEventContent,
+        },
+        identifiers::{EventId, UserId},
+    };
+    use serde_json::json;
+
+    use super::{flow_id, to_device_event, to_room_content};
+
+    fn room_event(
+        sender: &UserId,
+        event_type: &str,
+        content: AnyMessageEventContent,
+    ) -> AnySyncMessageEvent {
+        serde_json::from_value(json!({
+            "type": event_type,
+            "event_id": "$key:example.org",
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn key_content_round_trip() {
+        let sender = UserId::try_from("@alice:example.org").unwrap();
+        let flow = EventId::try_from("$request:example.org").unwrap();
+
+        let content = AnyToDeviceEventContent::KeyVerificationKey(KeyToDeviceEventContent {
+            transaction_id: flow.to_string(),
+            key: "public_key".to_owned(),
+        });
+
+        let room_content = to_room_content(&flow, content).unwrap();
+        let json = serde_json::to_value(&room_content).unwrap();
+
+        assert!(json.get("transaction_id").is_none());
+        assert_eq!(json["m.relates_to"]["rel_type"], "m.reference");
+        assert_eq!(json["m.relates_to"]["event_id"], flow.as_str());
+
+        let event = room_event(&sender, "m.key.verification.key", room_content);
+        assert_eq!(flow_id(&event), Some(&flow));
+
+        match to_device_event(&event).unwrap() {
+            AnyToDeviceEvent::KeyVerificationKey(e) => {
+                assert_eq!(e.sender, sender);
+                assert_eq!(e.content.transaction_id, flow.as_str());
+                assert_eq!(e.content.key, "public_key");
+            }
+            _ => panic!("Invalid to-device event"),
+        }
+    }
+
+    #[test]
+    fn cancel_conversion() {
+        let flow = EventId::try_from("$request:example.org").unwrap();
+
+        let content = AnyToDeviceEventContent::KeyVerificationCancel(
+            serde_json::from_value(json!({
+                "transaction_id": flow.as_str(),
+                "reason": "User canceled",
+                "code": "m.user",
+            }))
+            .unwrap(),
+        );
+
+        match to_room_content(&flow, content).unwrap() {
+            AnyMessageEventContent::KeyVerificationCancel(c) => {
+                assert_eq!(c.code, CancelCode::User);
+                assert_eq!(c.relation.event_id, flow);
+            }
+            _ => panic!("Invalid room content"),
+        }
+    }
+}
@@ -17,19 +17,35 @@ use std::sync::Arc;
 use dashmap::DashMap;
 
 use matrix_sdk_common::locks::Mutex;
-use tracing::{trace, warn};
+use tracing::{info, trace, warn};
 
 use matrix_sdk_common::{
-    events::{AnyToDeviceEvent, AnyToDeviceEventContent},
-    identifiers::{DeviceId, UserId},
+    api::r0::keys::upload_signatures::Request as SignatureUploadRequest,
+    events::{
+        key::verification::{
+            start::{StartMethod, StartToDeviceEventContent},
+            VerificationMethod,
+        },
+        room::message::MessageEventContent,
+        AnyMessageEventContent, AnySyncMessageEvent, AnyToDeviceEvent, AnyToDeviceEventContent,
+    },
+    identifiers::{DeviceId, RoomId, UserId},
     uuid::Uuid,
 };
 
-use super::sas::{content_to_request, Sas, VerificationResult};
+use super::{
+    in_room::{self, RoomFlowId},
+    qrcode::{QrVerification, QrVerificationData},
+    requests::VerificationRequest,
+    sas::{content_to_request, Sas},
+    IdentitiesBeingVerified, VerificationResult,
+};
 use crate::{
+    error::IdentityVerificationError,
+    identities::{OwnUserIdentity, UserIdentity},
     olm::PrivateCrossSigningIdentity,
-    requests::{OutgoingRequest, ToDeviceRequest},
-    store::{CryptoStore, CryptoStoreError},
+    requests::{OutgoingRequest, OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest},
+    store::{Changes, CryptoStore, CryptoStoreError},
     ReadOnlyAccount, ReadOnlyDevice,
 };
 
@@ -39,7 +55,10 @@ pub struct VerificationMachine {
     private_identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
     pub(crate) store: Arc<Box<dyn CryptoStore>>,
     verifications: Arc<DashMap<String, Sas>>,
+    qr_verifications: Arc<DashMap<String, QrVerification>>,
+    requests: Arc<DashMap<String, VerificationRequest>>,
     outgoing_to_device_messages: Arc<DashMap<Uuid, OutgoingRequest>>,
+    outgoing_room_messages: Arc<DashMap<Uuid, OutgoingRequest>>,
 }
 
 impl VerificationMachine {
@@ -53,7 +72,10 @@ impl VerificationMachine {
             private_identity: identity,
             store,
             verifications: Arc::new(DashMap::new()),
+            qr_verifications: Arc::new(DashMap::new()),
+            requests: Arc::new(DashMap::new()),
             outgoing_to_device_messages: Arc::new(DashMap::new()),
+            outgoing_room_messages: Arc::new(DashMap::new()),
         }
     }
 
@@ -70,6 +92,7 @@ impl VerificationMachine {
             device.clone(),
             self.store.clone(),
             identity,
+            None,
         );
 
         let request = content_to_request(
@@ -96,24 +119,86 @@ impl VerificationMachine {
         content: AnyToDeviceEventContent,
     ) {
         let request = content_to_request(recipient, recipient_device, content);
-        let request_id = request.txn_id;
+        self.queue_up_request(request.into());
+    }
+
+    fn queue_up_room_message(&self, room_id: &RoomId, content: AnyMessageEventContent) {
+        let request = RoomMessageRequest {
+            room_id: room_id.clone(),
+            txn_id: Uuid::new_v4(),
+            content,
+        };
+
+        self.queue_up_request(request.into());
+    }
+
+    /// Sign the given identity with our user-signing key and mark it as
+    /// verified.
+    ///
+    /// The signature upload request is queued up as an outgoing request. The
+    /// identity is left untouched if it can't be signed.
+    pub(crate) async fn verify_identity(
+        &self,
+        identity: &UserIdentity,
+    ) -> Result<(), IdentityVerificationError> {
+        let request = self
+            .private_identity
+            .lock()
+            .await
+            .sign_user(identity)
+            .await?;
+
+        identity.mark_as_verified();
+
+        let mut changes = Changes::default();
+        changes.identities.changed.push(identity.clone().into());
+        self.store.save_changes(changes).await?;
+
+        self.queue_up_signature_upload(request);
+
+        Ok(())
+    }
+
+    fn queue_up_signature_upload(&self, request: SignatureUploadRequest) {
+        let request_id = Uuid::new_v4();
+
+        self.outgoing_to_device_messages.insert(
+            request_id,
+            OutgoingRequest {
+                request_id,
+                request: Arc::new(request.into()),
+            },
+        );
+    }
+
+    fn queue_up_request(&self, request: OutgoingVerificationRequest) {
+        let request_id = request.request_id();
+
+        let messages = match request {
+            OutgoingVerificationRequest::ToDevice(_) => &self.outgoing_to_device_messages,
+            OutgoingVerificationRequest::InRoom(_) => &self.outgoing_room_messages,
+        };
 
         let request = OutgoingRequest {
             request_id,
             request: Arc::new(request.into()),
         };
 
-        self.outgoing_to_device_messages.insert(request_id, request);
+        messages.insert(request_id, request);
     }
 
     fn receive_event_helper(&self, sas: &Sas, event: &mut AnyToDeviceEvent) {
-        if let Some(c) = sas.receive_event(event) {
-            self.queue_up_content(sas.other_user_id(), sas.other_device_id(), c);
+        if let Some(request) = sas
+            .receive_event(event)
+            .and_then(|c| sas.content_to_request(c))
+        {
+            self.queue_up_request(request);
         }
     }
 
     pub fn mark_request_as_sent(&self, uuid: &Uuid) {
         self.outgoing_to_device_messages.remove(uuid);
+        self.outgoing_room_messages.remove(uuid);
     }
 
     pub fn outgoing_to_device_requests(&self) -> Vec<OutgoingRequest> {
@@ -124,21 +209,49 @@ impl VerificationMachine {
             .collect()
     }
 
+    pub fn outgoing_room_message_requests(&self) -> Vec<OutgoingRequest> {
+        #[allow(clippy::map_clone)]
+        self.outgoing_room_messages
+            .iter()
+            .map(|r| (*r).clone())
+            .collect()
+    }
+
     pub fn garbage_collect(&self) {
+        // In-room verifications need to tell the other side that we're done
+        // before we forget about them.
+        for sas in self.verifications.iter().filter(|s| s.is_done()) {
+            if let Some(flow) = sas.room_flow() {
+                self.queue_up_room_message(&flow.room_id, in_room::done_content(&flow.event_id));
+            }
+        }
+
         self.verifications
             .retain(|_, s| !(s.is_done() || s.is_canceled()));
 
         for sas in self.verifications.iter() {
             if let Some(r) = sas.cancel_if_timed_out() {
-                self.outgoing_to_device_messages.insert(
-                    r.txn_id,
-                    OutgoingRequest {
-                        request_id: r.txn_id,
-                        request: Arc::new(r.into()),
-                    },
-                );
+                self.queue_up_request(r);
+            }
+        }
+
+        self.qr_verifications
+            .retain(|_, q| !(q.is_done() || q.is_canceled()));
+
+        for qr in self.qr_verifications.iter() {
+            if let Some(r) = qr.cancel_if_timed_out() {
+                self.queue_up_request(r);
             }
         }
+
+        for request in self.requests.iter() {
+            if let Some(r) = request.cancel_if_timed_out() {
+                self.queue_up_request(r);
+            }
+        }
+
+        self.requests
+            .retain(|_, r| !(r.is_canceled() || r.is_started()));
     }
 
     pub async fn receive_event(
@@ -148,6 +261,44 @@ impl VerificationMachine {
         trace!("Received a key verification event {:?}", event);
 
         match event {
+            AnyToDeviceEvent::KeyVerificationRequest(e) => {
+                if &e.sender == self.account.user_id()
+                    && e.content.from_device == self.account.device_id()
+                {
+                    return Ok(());
+                }
+
+                info!(
+                    "Received a verification request from {} {}",
+                    e.sender, e.content.from_device
+                );
+
+                if let Some(request) = VerificationRequest::from_request_event(
+                    self.account.clone(),
+                    &e.sender,
+                    &e.content,
+                ) {
+                    self.requests
+                        .entry(e.content.transaction_id.clone())
+                        .or_insert(request);
+                } else {
+                    warn!(
+                        "Received an expired verification request from {} {}",
+                        e.sender, e.content.from_device
+                    );
+                }
+            }
+            AnyToDeviceEvent::KeyVerificationReady(e) => {
+                if let Some(request) = self.get_flow_request(&e.content.transaction_id, None) {
+                    self.receive_ready(
+                        &request,
+                        &e.sender,
+                        &e.content.from_device,
+                        &e.content.methods,
+                    )
+                    .await?;
+                }
+            }
             AnyToDeviceEvent::KeyVerificationStart(e) => {
                 trace!(
                     "Received a m.key.verification start event from {} {}",
@@ -155,6 +306,28 @@ impl VerificationMachine {
                     e.content.from_device
                 );
 
+                let request = self.get_flow_request(&e.content.transaction_id, None);
+
+                if let Some(request) = &request {
+                    if request.other_user_id() != &e.sender
+                        || !request.can_be_started_by(&e.content.from_device)
+                    {
+                        warn!(
+                            "Received a key verification start event from {} {} \
+                            for the verification request {} which isn't ready",
+                            e.sender,
+                            e.content.from_device,
+                            request.flow_id()
+                        );
+                        return Ok(());
+                    }
+                }
+
+                if let StartMethod::ReciprocateV1(_) = &e.content.method {
+                    self.receive_reciprocation(request, &e.sender, &e.content, None);
+                    return Ok(());
+                }
+
                 if let Some(d) = self
                     .store
                     .get_device(&e.sender, &e.content.from_device)
@@ -168,8 +341,18 @@ impl VerificationMachine {
                         self.store.clone(),
                         e,
                         self.store.get_user_identity(&e.sender).await?,
+                        None,
                     ) {
                         Ok(s) => {
+                            if let Some(request) = request {
+                                request.mark_as_started();
+                                self.requests.remove(request.flow_id());
+                            }
+
+                            // The other side decided to use SAS instead of
+                            // scanning our QR code.
+                            self.qr_verifications.remove(&e.content.transaction_id);
+
                             self.verifications
                                 .insert(e.content.transaction_id.clone(), s);
                         }
@@ -189,51 +372,661 @@ impl VerificationMachine {
                 }
             }
             AnyToDeviceEvent::KeyVerificationCancel(e) => {
-                self.verifications.remove(&e.content.transaction_id);
+                if self.get_flow_sas(&e.content.transaction_id, None).is_some() {
+                    self.verifications.remove(&e.content.transaction_id);
+                }
+
+                if let Some(qr) = self.get_flow_qr(&e.content.transaction_id, None) {
+                    if qr.other_user_id() == &e.sender {
+                        qr.receive_cancel(&e.content.code);
+                        self.qr_verifications.remove(&e.content.transaction_id);
+                    }
+                }
+
+                if let Some(request) = self.get_flow_request(&e.content.transaction_id, None) {
+                    request.receive_cancel(&e.sender, &e.content.code);
+
+                    if request.is_canceled() {
+                        self.requests.remove(request.flow_id());
+                    }
+                }
             }
             AnyToDeviceEvent::KeyVerificationAccept(e) => {
-                if let Some(s) = self.get_sas(&e.content.transaction_id) {
-                    self.receive_event_helper(&s, event)
+                if let Some(s) = self.get_flow_sas(&e.content.transaction_id, None) {
+                    self.receive_sas_event(&s, event).await?;
                 };
             }
             AnyToDeviceEvent::KeyVerificationKey(e) => {
-                if let Some(s) = self.get_sas(&e.content.transaction_id) {
-                    self.receive_event_helper(&s, event)
+                if let Some(s) = self.get_flow_sas(&e.content.transaction_id, None) {
+                    self.receive_sas_event(&s, event).await?;
                 };
             }
             AnyToDeviceEvent::KeyVerificationMac(e) => {
-                if let Some(s) = self.get_sas(&e.content.transaction_id) {
-                    self.receive_event_helper(&s, event);
-
-                    if s.is_done() {
-                        match s.mark_as_done().await? {
-                            VerificationResult::Ok => (),
-                            VerificationResult::Cancel(r) => {
-                                self.outgoing_to_device_messages.insert(
-                                    r.txn_id,
-                                    OutgoingRequest {
-                                        request_id: r.txn_id,
-                                        request: Arc::new(r.into()),
-                                    },
-                                );
-                            }
-                            VerificationResult::SignatureUpload(r) => {
-                                let request_id = Uuid::new_v4();
-
-                                self.outgoing_to_device_messages.insert(
-                                    request_id,
-                                    OutgoingRequest {
-                                        request_id,
-                                        request: Arc::new(r.into()),
-                                    },
-                                );
-                            }
+                if let Some(s) = self.get_flow_sas(&e.content.transaction_id, None) {
+                    self.receive_sas_event(&s, event).await?;
+                };
+            }
+            AnyToDeviceEvent::KeyVerificationDone(e) => {
+                if let Some(qr) = self.get_flow_qr(&e.content.transaction_id, None) {
+                    self.receive_qr_done(&qr, &e.sender).await?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Get the `Sas` object with the given flow id, if it belongs to a
+    /// verification happening in the given room or, if no room is given, to a
+    /// to-device verification.
+    fn get_flow_sas(&self, flow_id: &str, room_id: Option<&RoomId>) -> Option<Sas> {
+        self.get_sas(flow_id).filter(|s| s.room_id() == room_id)
+    }
+
+    /// Get the `QrVerification` object with the given flow id, if it belongs
+    /// to a verification happening in the given room or, if no room is given,
+    /// to a to-device verification.
+    fn get_flow_qr(&self, flow_id: &str, room_id: Option<&RoomId>) -> Option<QrVerification> {
+        self.get_qr_verification(flow_id)
+            .filter(|q| q.room_id() == room_id)
+    }
+
+    /// Get the QR code verification with the given flow id.
+    pub fn get_qr_verification(&self, flow_id: &str) -> Option<QrVerification> {
+        #[allow(clippy::map_clone)]
+        self.qr_verifications.get(flow_id).map(|q| q.clone())
+    }
+
+    /// Handle a `m.key.verification.start` event using the `m.reciprocate.v1`
+    /// method, the other side scanned the QR code we're showing.
+    fn receive_reciprocation(
+        &self,
+        request: Option<VerificationRequest>,
+        sender: &UserId,
+        content: &StartToDeviceEventContent,
+        room_id: Option<&RoomId>,
+    ) {
+        let qr = self
+            .get_flow_qr(&content.transaction_id, room_id)
+            .filter(|q| {
+                !q.we_scanned()
+                    && q.other_user_id() == sender
+                    && q.other_device_id() == &*content.from_device
+            });
+
+        if let Some(qr) = qr {
+            if let Some(r) = qr.receive_reciprocation(content) {
+                self.queue_up_request(r);
+            }
+
+            if let Some(request) = request {
+                request.mark_as_started();
+                self.requests.remove(request.flow_id());
+            }
+        } else {
+            warn!(
+                "Received a QR code reciprocation from {} {} for the flow {} \
+                but we aren't showing a QR code",
+                sender, content.from_device, content.transaction_id
+            );
+        }
+    }
+
+    /// Handle a `m.key.verification.done` event of a QR code verification.
+    async fn receive_qr_done(
+        &self,
+        qr: &QrVerification,
+        sender: &UserId,
+    ) -> Result<(), CryptoStoreError> {
+        if qr.other_user_id() != sender {
+            return Ok(());
+        }
+
+        let (request, signature_request) = qr.receive_done().await?;
+
+        if let Some(r) = request {
+            self.queue_up_request(r);
+        }
+
+        if let Some(r) = signature_request {
+            self.queue_up_signature_upload(r);
+        }
+
+        Ok(())
+    }
+
+    async fn receive_sas_event(
+        &self,
+        sas: &Sas,
+        event: &mut AnyToDeviceEvent,
+    ) -> Result<(), CryptoStoreError> {
+        self.receive_event_helper(sas, event);
+
+        if let AnyToDeviceEvent::KeyVerificationMac(_) = event {
+            if sas.is_done() {
+                match sas.mark_as_done().await? {
+                    VerificationResult::Ok => (),
+                    VerificationResult::Cancel(c) => {
+                        if let Some(r) = sas.cancel_with_code(c) {
+                            self.queue_up_request(r);
                         }
                     }
+                    VerificationResult::SignatureUpload(r) => self.queue_up_signature_upload(r),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the content of a `m.key.verification.request` room message that
+    /// will start an in-room verification with the given user.
+    pub fn verification_request_content(&self, other_user_id: &UserId) -> MessageEventContent {
+        in_room::request_content(
+            self.account.user_id(),
+            self.account.device_id(),
+            other_user_id,
+        )
+    }
+
+    /// Create a new verification request that will be sent to all the devices
+    /// of the given user.
+    pub fn request_verification(
+        &self,
+        other_user_id: &UserId,
+    ) -> (VerificationRequest, ToDeviceRequest) {
+        let (request, to_device) = VerificationRequest::new(self.account.clone(), other_user_id);
+
+        self.requests
+            .insert(request.flow_id().to_owned(), request.clone());
+
+        (request, to_device)
+    }
+
+    /// Get the verification request with the given flow id.
+    pub fn get_request(&self, flow_id: &str) -> Option<VerificationRequest> {
+        #[allow(clippy::map_clone)]
+        self.requests.get(flow_id).map(|r| r.clone())
+    }
+
+    /// Get the verification request with the given flow id, if it was sent in
+    /// the given room or, if no room is given, using to-device messages.
+    fn get_flow_request(
+        &self,
+        flow_id: &str,
+        room_id: Option<&RoomId>,
+    ) -> Option<VerificationRequest> {
+        self.get_request(flow_id).filter(|r| r.room_id() == room_id)
+    }
+
+    /// Handle a `m.key.verification.ready` event for the given request.
+    ///
+    /// If the request was sent out by us, the other devices of the user get
+    /// notified that the request was accepted elsewhere. If the other side
+    /// only supports SAS, the SAS verification gets started right away,
+    /// otherwise the request stays ready until either side starts a
+    /// verification using one of the agreed upon methods.
+    async fn receive_ready(
+        &self,
+        request: &VerificationRequest,
+        sender: &UserId,
+        from_device: &DeviceId,
+        methods: &[VerificationMethod],
+    ) -> Result<(), CryptoStoreError> {
+        // Look the device up before the request changes its state, a request
+        // that is ready for a device we don't know could never be verified.
+        let device = if let Some(d) = self.store.get_device(sender, from_device).await? {
+            d
+        } else {
+            warn!(
+                "Received a key verification ready event from an unknown device {} {}",
+                sender, from_device
+            );
+            return Ok(());
+        };
+
+        if !request.receive_ready(sender, from_device, methods) {
+            return Ok(());
+        }
+
+        if request.room_id().is_none() {
+            let own_device_id = self.account.device_id();
+
+            let other_devices = self
+                .store
+                .get_user_devices(sender)
+                .await?
+                .into_iter()
+                .map(|(device_id, _)| device_id)
+                .filter(|d| &**d != from_device && &**d != own_device_id);
+
+            if let Some(r) = request.cancel_other_devices(other_devices) {
+                self.queue_up_request(r.into());
+            }
+        }
+
+        if !methods.contains(&VerificationMethod::MReciprocateV1) {
+            if let Some(request) = self.start_request_sas(request, device).await? {
+                self.queue_up_request(request);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start a SAS verification for the given request and remember it.
+    async fn start_request_sas(
+        &self,
+        request: &VerificationRequest,
+        device: ReadOnlyDevice,
+    ) -> Result<Option<OutgoingVerificationRequest>, CryptoStoreError> {
+        let private_identity = self.private_identity.lock().await.clone();
+        let identity = self.store.get_user_identity(device.user_id()).await?;
+
+        let (sas, content) = if let Some(flow) = request.room_flow() {
+            Sas::start_in_room(
+                flow.event_id.clone(),
+                flow.room_id.clone(),
+                self.account.clone(),
+                private_identity,
+                device,
+                self.store.clone(),
+                identity,
+            )
+        } else {
+            Sas::start(
+                self.account.clone(),
+                private_identity,
+                device,
+                self.store.clone(),
+                identity,
+                Some(request.flow_id().to_owned()),
+            )
+        };
+
+        request.mark_as_started();
+        self.requests.remove(request.flow_id());
+        self.qr_verifications.remove(request.flow_id());
+        self.verifications
+            .insert(sas.flow_id().to_owned(), sas.clone());
+
+        Ok(sas.content_to_request(AnyToDeviceEventContent::KeyVerificationStart(content)))
+    }
+
+    /// Get the device, the user identities and our own identity that a
+    /// verification for the given request will need.
+    ///
+    /// Returns `None` if the request isn't ready or if we don't know the
+    /// device that accepted the request.
+    async fn request_identities(
+        &self,
+        request: &VerificationRequest,
+    ) -> Result<Option<(IdentitiesBeingVerified, Option<OwnUserIdentity>)>, CryptoStoreError> {
+        let device_id = match request.other_device_id() {
+            Some(d) if request.is_ready() => d,
+            _ => return Ok(None),
+        };
+
+        let device = if let Some(d) = self
+            .store
+            .get_device(request.other_user_id(), &device_id)
+            .await?
+        {
+            d
+        } else {
+            return Ok(None);
+        };
+
+        let own_identity = self
+            .store
+            .get_user_identity(self.account.user_id())
+            .await?
+            .and_then(|i| i.own().cloned());
+
+        let identities = IdentitiesBeingVerified {
+            private_identity: self.private_identity.lock().await.clone(),
+            store: self.store.clone(),
+            identity_being_verified: self
+                .store
+                .get_user_identity(request.other_user_id())
+                .await?,
+            device_being_verified: device,
+        };
+
+        Ok(Some((identities, own_identity)))
+    }
+
+    /// Start a SAS verification for the ready verification request with the
+    /// given flow id.
+    ///
+    /// Returns the new `Sas` object and the request that needs to be sent
+    /// out, `None` if there's no such request or if it isn't ready.
+    pub async fn start_sas_for_request(
+        &self,
+        flow_id: &str,
+    ) -> Result<Option<(Sas, OutgoingVerificationRequest)>, CryptoStoreError> {
+        let request = match self.get_request(flow_id) {
+            Some(r) if r.is_ready() => r,
+            _ => return Ok(None),
+        };
+
+        let device_id = match request.other_device_id() {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+
+        let device = if let Some(d) = self
+            .store
+            .get_device(request.other_user_id(), &device_id)
+            .await?
+        {
+            d
+        } else {
+            return Ok(None);
+        };
+
+        let outgoing_request = match self.start_request_sas(&request, device).await? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+
+        Ok(self.get_sas(flow_id).map(|s| (s, outgoing_request)))
+    }
+
+    /// Create a QR code verification for the ready verification request with
+    /// the given flow id.
+    ///
+    /// The returned `QrVerification` contains the QR code that should be
+    /// shown to the other side. Returns `None` if there's no such request, if
+    /// the other side can't scan QR codes or if we don't know the keys that
+    /// the QR code needs to contain.
+    pub async fn generate_qr_code(
+        &self,
+        flow_id: &str,
+    ) -> Result<Option<QrVerification>, CryptoStoreError> {
+        let request = if let Some(r) = self.get_request(flow_id) {
+            r
+        } else {
+            return Ok(None);
+        };
+
+        if !request
+            .methods()
+            .map_or(false, |m| m.contains(&VerificationMethod::MQrCodeScanV1))
+        {
+            return Ok(None);
+        }
+
+        let (identities, own_identity) = match self.request_identities(&request).await? {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+
+        let qr = QrVerification::new(
+            self.account.clone(),
+            identities,
+            own_identity,
+            request.flow_id().to_owned(),
+            request.room_flow().cloned(),
+        );
+
+        if let Some(qr) = &qr {
+            self.qr_verifications
+                .insert(qr.flow_id().to_owned(), qr.clone());
+        }
+
+        Ok(qr)
+    }
+
+    /// Start a QR code verification by scanning the QR code the other side is
+    /// showing.
+    ///
+    /// Returns the new `QrVerification` and the request that needs to be sent
+    /// out. If the keys in the QR code don't match the keys we know about,
+    /// the verification is canceled and the request informs the other side
+    /// about it. Returns `None` if there's no ready verification request
+    /// for the QR code.
+    pub async fn scan_qr_code(
+        &self,
+        data: QrVerificationData,
+    ) -> Result<Option<(QrVerification, OutgoingVerificationRequest)>, CryptoStoreError> {
+        let request = if let Some(r) = self.get_request(data.flow_id()) {
+            r
+        } else {
+            return Ok(None);
+        };
+
+        if !request
+            .methods()
+            .map_or(false, |m| m.contains(&VerificationMethod::MQrCodeShowV1))
+        {
+            return Ok(None);
+        }
+
+        let (identities, own_identity) = match self.request_identities(&request).await? {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+
+        let (qr, outgoing_request) = QrVerification::from_scan(
+            self.account.clone(),
+            identities,
+            own_identity,
+            request.room_flow().cloned(),
+            data,
+        );
+
+        request.mark_as_started();
+        self.requests.remove(request.flow_id());
+
+        if !qr.is_canceled() {
+            self.qr_verifications
+                .insert(qr.flow_id().to_owned(), qr.clone());
+        }
+
+        Ok(outgoing_request.map(|r| (qr, r)))
+    }
+
+    /// Receive a room event that is part of an in-room verification flow.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent in.
+    ///
+    /// * `event` - The, already decrypted, room event.
+    pub async fn receive_room_event(
+        &self,
+        room_id: &RoomId,
+        event: &AnySyncMessageEvent,
+    ) -> Result<(), CryptoStoreError> {
+        let flow_id = if let Some(f) = in_room::flow_id(event) {
+            f.clone()
+        } else {
+            return Ok(());
+        };
+
+        trace!(
+            "Received an in-room verification event in {} for the flow {}: {:?}",
+            room_id,
+            flow_id,
+            event
+        );
+
+        let own_user_id = self.account.user_id();
+
+        match event {
+            AnySyncMessageEvent::RoomMessage(e) => {
+                if let MessageEventContent::VerificationRequest(r) = &e.content {
+                    let request = if &e.sender == own_user_id {
+                        // Our other devices might send out requests as well,
+                        // only remember our own.
+                        if r.from_device == self.account.device_id() {
+                            Some(VerificationRequest::new_in_room(
+                                self.account.clone(),
+                                room_id.clone(),
+                                flow_id.clone(),
+                                &r.to,
+                            ))
+                        } else {
+                            None
+                        }
+                    } else if &r.to == own_user_id {
+                        info!(
+                            "Received an in-room verification request from {} {} in {}",
+                            e.sender, r.from_device, room_id
+                        );
+
+                        Some(VerificationRequest::from_room_request(
+                            self.account.clone(),
+                            room_id.clone(),
+                            flow_id.clone(),
+                            &e.sender,
+                            r,
+                        ))
+                    } else {
+                        None
+                    };
+
+                    if let Some(request) = request {
+                        self.requests.entry(flow_id.to_string()).or_insert(request);
+                    }
+                }
+            }
+            AnySyncMessageEvent::KeyVerificationReady(e) => {
+                if let Some(request) = self.get_flow_request(flow_id.as_str(), Some(room_id)) {
+                    self.receive_ready(
+                        &request,
+                        &e.sender,
+                        &e.content.from_device,
+                        &e.content.methods,
+                    )
+                    .await?;
+                }
+            }
+            // Events from our own user are either the echo of the events we
+            // sent out or belong to a verification one of our other devices
+            // is doing.
+            e if e.sender() == own_user_id => (),
+            AnySyncMessageEvent::KeyVerificationStart(e) => {
+                let request =
+                    if let Some(r) = self.get_flow_request(flow_id.as_str(), Some(room_id)) {
+                        r
+                    } else {
+                        return Ok(());
+                    };
+
+                if request.other_user_id() != &e.sender
+                    || !request.can_be_started_by(&e.content.from_device)
+                {
+                    warn!(
+                        "Received a key verification start event from {} {} \
+                        for the in-room verification {} which isn't ready",
+                        e.sender, e.content.from_device, flow_id
+                    );
+                    return Ok(());
+                }
+
+                let start = if let Some(AnyToDeviceEvent::KeyVerificationStart(s)) =
+                    in_room::to_device_event(event)
+                {
+                    s
+                } else {
+                    return Ok(());
                 };
+
+                if let StartMethod::ReciprocateV1(_) = &start.content.method {
+                    self.receive_reciprocation(
+                        Some(request),
+                        &start.sender,
+                        &start.content,
+                        Some(room_id),
+                    );
+                    return Ok(());
+                }
+
+                if let Some(d) = self
+                    .store
+                    .get_device(&e.sender, &e.content.from_device)
+                    .await?
+                {
+                    let private_identity = self.private_identity.lock().await.clone();
+                    let room_flow = RoomFlowId {
+                        room_id: room_id.clone(),
+                        event_id: flow_id.clone(),
+                    };
+
+                    match Sas::from_start_event(
+                        self.account.clone(),
+                        private_identity,
+                        d,
+                        self.store.clone(),
+                        &start,
+                        self.store.get_user_identity(&e.sender).await?,
+                        Some(room_flow),
+                    ) {
+                        Ok(s) => {
+                            request.mark_as_started();
+                            self.requests.remove(request.flow_id());
+                            self.qr_verifications.remove(flow_id.as_str());
+                            self.verifications.insert(flow_id.to_string(), s);
+                        }
+                        Err(c) => {
+                            warn!(
+                                "Can't start key verification with {} {}, canceling: {:?}",
+                                e.sender, e.content.from_device, c
+                            );
+                            if let Some(content) = in_room::to_room_content(&flow_id, c) {
+                                self.queue_up_room_message(room_id, content);
+                            }
+                        }
+                    }
+                } else {
+                    warn!(
+                        "Received a key verification start event from an unknown device {} {}",
+                        e.sender, e.content.from_device
+                    );
+                }
+            }
+            AnySyncMessageEvent::KeyVerificationCancel(e) => {
+                if self.get_flow_sas(flow_id.as_str(), Some(room_id)).is_some() {
+                    self.verifications.remove(flow_id.as_str());
+                }
+
+                if let Some(qr) = self.get_flow_qr(flow_id.as_str(), Some(room_id)) {
+                    if qr.other_user_id() == &e.sender {
+                        qr.receive_cancel(&e.content.code);
+                        self.qr_verifications.remove(flow_id.as_str());
+                    }
+                }
+
+                if let Some(request) = self.get_flow_request(flow_id.as_str(), Some(room_id)) {
+                    request.receive_cancel(&e.sender, &e.content.code);
+
+                    if request.is_canceled() {
+                        self.requests.remove(request.flow_id());
+                    }
+                }
+            }
+            AnySyncMessageEvent::KeyVerificationDone(e) => {
+                trace!(
+                    "The in-room verification {} is done on the side of {}",
+                    flow_id,
+                    e.sender
+                );
+
+                if let Some(qr) = self.get_flow_qr(flow_id.as_str(), Some(room_id)) {
+                    self.receive_qr_done(&qr, &e.sender).await?;
+                }
+            }
+            _ => {
+                if let Some(s) = self.get_flow_sas(flow_id.as_str(), Some(room_id)) {
+                    if let Some(mut event) = in_room::to_device_event(event) {
+                        self.receive_sas_event(&s, &mut event).await?;
+                    }
+                }
             }
-            _ => (),
         }
+
         Ok(())
     }
 }
@@ -248,17 +1041,28 @@ mod test {
     };
 
     use matrix_sdk_common::{
-        events::AnyToDeviceEventContent,
-        identifiers::{DeviceId, UserId},
+        events::{
+            AnyMessageEventContent, AnySyncMessageEvent, AnyToDeviceEventContent, EventContent,
+        },
+        identifiers::{room_id, DeviceId, EventId, UserId},
         locks::Mutex,
     };
+    use serde_json::json;
 
     use super::{Sas, VerificationMachine};
     use crate::{
+        error::{IdentityVerificationError, SignatureError},
+        identities::{UserIdentities, UserIdentity, VerifiableUserIdentity},
         olm::PrivateCrossSigningIdentity,
-        requests::OutgoingRequests,
-        store::{CryptoStore, MemoryStore},
-        verification::test::{get_content_from_request, wrap_any_to_device_content},
+        requests::{OutgoingRequests, OutgoingVerificationRequest, RoomMessageRequest},
+        store::{Changes, CryptoStore, IdentityChanges, MemoryStore},
+        verification::{
+            test::{
+                get_content_from_request, outgoing_request_to_event, request_to_event,
+                wrap_any_to_device_content,
+            },
+            QrVerificationData, QrVerificationMode,
+        },
         ReadOnlyAccount, ReadOnlyDevice,
     };
 
@@ -299,6 +1103,7 @@ mod test {
             alice_device,
             bob_store,
             None,
+            None,
         );
         machine
             .receive_event(&mut wrap_any_to_device_content(
@@ -311,6 +1116,112 @@ mod test {
         (machine, bob_sas)
     }
 
+    async fn setup_room_verification_machines() -> (VerificationMachine, VerificationMachine) {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let alice_store = MemoryStore::new();
+        let bob_store = MemoryStore::new();
+
+        alice_store
+            .save_devices(vec![ReadOnlyDevice::from_account(&bob).await])
+            .await;
+        bob_store
+            .save_devices(vec![ReadOnlyDevice::from_account(&alice).await])
+            .await;
+
+        let alice_identity = Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(alice_id())));
+        let bob_identity = Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(bob_id())));
+
+        (
+            VerificationMachine::new(alice, alice_identity, Arc::new(Box::new(alice_store))),
+            VerificationMachine::new(bob, bob_identity, Arc::new(Box::new(bob_store))),
+        )
+    }
+
+    async fn setup_qr_verification_machines() -> (VerificationMachine, VerificationMachine) {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let alice_identity = PrivateCrossSigningIdentity::new(alice_id()).await;
+        let bob_identity = PrivateCrossSigningIdentity::new(bob_id()).await;
+
+        let alice_store = MemoryStore::new();
+        let bob_store = MemoryStore::new();
+
+        alice_store
+            .save_devices(vec![ReadOnlyDevice::from_account(&bob).await])
+            .await;
+        bob_store
+            .save_devices(vec![ReadOnlyDevice::from_account(&alice).await])
+            .await;
+
+        for (store, own, other) in [
+            (&alice_store, &alice_identity, &bob_identity),
+            (&bob_store, &bob_identity, &alice_identity),
+        ]
+        .iter()
+        {
+            let own: UserIdentities = own.as_public_identity().await.unwrap().into();
+            let other: UserIdentities = UserIdentity::from_private(other).await.into();
+
+            let changes = Changes {
+                identities: IdentityChanges {
+                    new: vec![own, other],
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.unwrap();
+        }
+
+        (
+            VerificationMachine::new(
+                alice,
+                Arc::new(Mutex::new(alice_identity)),
+                Arc::new(Box::new(alice_store)),
+            ),
+            VerificationMachine::new(
+                bob,
+                Arc::new(Mutex::new(bob_identity)),
+                Arc::new(Box::new(bob_store)),
+            ),
+        )
+    }
+
+    fn room_event(
+        sender: &UserId,
+        event_id: &str,
+        content: &AnyMessageEventContent,
+    ) -> AnySyncMessageEvent {
+        serde_json::from_value(json!({
+            "type": content.event_type(),
+            "event_id": event_id,
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    fn room_request_to_event(
+        sender: &UserId,
+        request: &OutgoingVerificationRequest,
+    ) -> AnySyncMessageEvent {
+        match request {
+            OutgoingVerificationRequest::InRoom(r) => {
+                room_event(sender, "$event:example.org", &r.content)
+            }
+            OutgoingVerificationRequest::ToDevice(_) => panic!("Not an in-room request"),
+        }
+    }
+
+    fn take_room_message(machine: &VerificationMachine) -> RoomMessageRequest {
+        let request = machine.outgoing_room_message_requests().pop().unwrap();
+        machine.mark_request_as_sent(request.request_id());
+
+        request.request().room_message().unwrap().clone()
+    }
+
     #[test]
     fn create() {
         let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
@@ -353,7 +1264,10 @@ mod test {
             panic!("Invalid request type");
         };
 
-        let mut event = wrap_any_to_device_content(alice.user_id(), get_content_from_request(r));
+        let mut event = wrap_any_to_device_content(
+            alice.user_id(),
+            get_content_from_request(&r.clone().into()),
+        );
         drop(request);
         alice_machine.mark_request_as_sent(&txn_id);
 
@@ -380,6 +1294,279 @@ mod test {
         assert!(bob.is_done());
     }
 
+    #[tokio::test]
+    async fn in_room_flow() {
+        let (alice_machine, bob_machine) = setup_room_verification_machines().await;
+        let room_id = room_id!("!test:example.org");
+        let flow_id = EventId::try_from("$request:example.org").unwrap();
+
+        let content = alice_machine.verification_request_content(&bob_id());
+        let event = room_event(
+            &alice_id(),
+            flow_id.as_str(),
+            &AnyMessageEventContent::RoomMessage(content),
+        );
+
+        alice_machine
+            .receive_room_event(&room_id, &event)
+            .await
+            .unwrap();
+        bob_machine
+            .receive_room_event(&room_id, &event)
+            .await
+            .unwrap();
+
+        let alice_request = alice_machine.get_request(flow_id.as_str()).unwrap();
+        assert!(alice_request.we_started());
+        assert!(alice_request.accept().is_none());
+
+        let bob_request = bob_machine.get_request(flow_id.as_str()).unwrap();
+        assert_eq!(bob_request.room_id(), Some(&room_id));
+
+        let ready = bob_request.accept().unwrap();
+        assert!(bob_request.is_ready());
+        let event = room_request_to_event(&bob_id(), &ready);
+        alice_machine
+            .receive_room_event(&room_id, &event)
+            .await
+            .unwrap();
+
+        // Bob supports QR code verification as well, so the SAS flow needs
+        // to be started explicitly.
+        assert!(alice_request.is_ready());
+        assert!(alice_machine.get_sas(flow_id.as_str()).is_none());
+
+        let (alice, start) = alice_machine
+            .start_sas_for_request(flow_id.as_str())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.room_id(), Some(&room_id));
+        assert!(alice_request.is_started());
+        assert!(alice_machine.get_request(flow_id.as_str()).is_none());
+
+        let start = match start {
+            OutgoingVerificationRequest::InRoom(r) => r,
+            OutgoingVerificationRequest::ToDevice(_) => panic!("Not an in-room request"),
+        };
+        assert_eq!(start.room_id, room_id);
+
+        let event = room_event(&alice_id(), "$start:example.org", &start.content);
+        bob_machine
+            .receive_room_event(&room_id, &event)
+            .await
+            .unwrap();
+
+        let bob = bob_machine.get_sas(flow_id.as_str()).unwrap();
+
+        let event = room_request_to_event(&bob_id(), &bob.accept().unwrap());
+        alice_machine
+            .receive_room_event(&room_id, &event)
+            .await
+            .unwrap();
+
+        let key = take_room_message(&alice_machine);
+        let event = room_event(&alice_id(), "$key:example.org", &key.content);
+        bob_machine
+            .receive_room_event(&room_id, &event)
+            .await
+            .unwrap();
+
+        let key = take_room_message(&bob_machine);
+        let event = room_event(&bob_id(), "$key:example.org", &key.content);
+        alice_machine
+            .receive_room_event(&room_id, &event)
+            .await
+            .unwrap();
+
+        assert!(alice.emoji().is_some());
+        assert_eq!(alice.emoji(), bob.emoji());
+        assert_eq!(alice.decimals(), bob.decimals());
+    }
+
+    #[tokio::test]
+    async fn request_flow() {
+        let (alice_machine, bob_machine) = setup_room_verification_machines().await;
+
+        let (alice_request, request) = alice_machine.request_verification(&bob_id());
+        let flow_id = alice_request.flow_id().to_owned();
+
+        let mut event = request_to_event(&alice_id(), &request.into());
+        bob_machine.receive_event(&mut event).await.unwrap();
+
+        let bob_request = bob_machine.get_request(&flow_id).unwrap();
+        assert!(!bob_request.we_started());
+        assert_eq!(
+            bob_request.other_device_id().as_deref(),
+            Some(&*alice_device_id())
+        );
+
+        let mut event = request_to_event(&bob_id(), &bob_request.accept().unwrap());
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        assert!(alice_request.is_ready());
+        let (alice, request) = alice_machine
+            .start_sas_for_request(&flow_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(alice_request.is_started());
+
+        let mut event = request_to_event(&alice_id(), &request);
+        bob_machine.receive_event(&mut event).await.unwrap();
+
+        assert!(bob_request.is_started());
+        let bob = bob_machine.get_sas(&flow_id).unwrap();
+
+        assert_eq!(alice.flow_id(), bob.flow_id());
+        assert!(bob.accept().is_some());
+    }
+
+    #[tokio::test]
+    async fn ready_from_unknown_device() {
+        let (_, bob_machine) = setup_room_verification_machines().await;
+
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let identity = Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(alice_id())));
+        let alice_machine =
+            VerificationMachine::new(alice, identity, Arc::new(Box::new(MemoryStore::new())));
+
+        let (alice_request, request) = alice_machine.request_verification(&bob_id());
+        let flow_id = alice_request.flow_id().to_owned();
+
+        let mut event = request_to_event(&alice_id(), &request.into());
+        bob_machine.receive_event(&mut event).await.unwrap();
+
+        let bob_request = bob_machine.get_request(&flow_id).unwrap();
+        let mut event = request_to_event(&bob_id(), &bob_request.accept().unwrap());
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        // We don't know the device of Bob, the request can't become ready for
+        // it.
+        assert!(!alice_request.is_ready());
+        assert!(alice_machine.get_sas(&flow_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn request_cancel() {
+        let (alice_machine, bob_machine) = setup_room_verification_machines().await;
+
+        let (alice_request, request) = alice_machine.request_verification(&bob_id());
+        let flow_id = alice_request.flow_id().to_owned();
+
+        let mut event = request_to_event(&alice_id(), &request.into());
+        bob_machine.receive_event(&mut event).await.unwrap();
+
+        let bob_request = bob_machine.get_request(&flow_id).unwrap();
+        let mut event = request_to_event(&bob_id(), &bob_request.cancel().unwrap());
+        assert!(bob_request.cancel().is_none());
+
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        assert!(alice_request.is_canceled());
+        assert!(alice_machine.get_request(&flow_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn qr_code_flow() {
+        let (alice_machine, bob_machine) = setup_qr_verification_machines().await;
+
+        let (alice_request, request) = alice_machine.request_verification(&bob_id());
+        let flow_id = alice_request.flow_id().to_owned();
+
+        let mut event = request_to_event(&alice_id(), &request.into());
+        bob_machine.receive_event(&mut event).await.unwrap();
+
+        let bob_request = bob_machine.get_request(&flow_id).unwrap();
+        let mut event = request_to_event(&bob_id(), &bob_request.accept().unwrap());
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        assert!(alice_request.is_ready());
+
+        let alice = alice_machine
+            .generate_qr_code(&flow_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.mode(), QrVerificationMode::Verification);
+        assert!(!alice.we_scanned());
+
+        let data = QrVerificationData::from_bytes(alice.to_bytes()).unwrap();
+        let (bob, request) = bob_machine.scan_qr_code(data).await.unwrap().unwrap();
+        assert!(bob.we_scanned());
+        assert!(!bob.is_canceled());
+        assert!(bob_request.is_started());
+
+        let mut event = request_to_event(&bob_id(), &request);
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        assert!(alice.has_been_scanned());
+        assert!(alice_request.is_started());
+
+        let (done, signature_upload) = alice.confirm_scanning().await.unwrap();
+        assert!(alice.is_done());
+        assert!(signature_upload.is_some());
+
+        let mut event = request_to_event(&alice_id(), &done.unwrap());
+        bob_machine.receive_event(&mut event).await.unwrap();
+        assert!(bob.is_done());
+
+        // Bob signed Alice's identity as well, only pick up the done event.
+        let request = bob_machine
+            .outgoing_to_device_requests()
+            .into_iter()
+            .find(|r| r.request().to_device().is_some())
+            .unwrap();
+        let mut event = outgoing_request_to_event(&bob_id(), &request);
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        let identity = alice_machine
+            .store
+            .get_user_identity(&bob_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(identity.other().unwrap().is_verified());
+    }
+
+    #[tokio::test]
+    async fn qr_code_key_mismatch() {
+        let (alice_machine, bob_machine) = setup_qr_verification_machines().await;
+
+        let (alice_request, request) = alice_machine.request_verification(&bob_id());
+        let flow_id = alice_request.flow_id().to_owned();
+
+        let mut event = request_to_event(&alice_id(), &request.into());
+        bob_machine.receive_event(&mut event).await.unwrap();
+
+        let bob_request = bob_machine.get_request(&flow_id).unwrap();
+        let mut event = request_to_event(&bob_id(), &bob_request.accept().unwrap());
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        let alice = alice_machine
+            .generate_qr_code(&flow_id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Swap the keys, Bob won't find his own master key in the expected
+        // place.
+        let mut bytes = alice.to_bytes();
+        let key_start = 10 + flow_id.len();
+        bytes[key_start..key_start + 64].rotate_left(32);
+
+        let data = QrVerificationData::from_bytes(bytes).unwrap();
+        let (bob, request) = bob_machine.scan_qr_code(data).await.unwrap().unwrap();
+        assert!(bob.is_canceled());
+        assert!(bob_machine.get_qr_verification(&flow_id).is_none());
+
+        let mut event = request_to_event(&bob_id(), &request);
+        alice_machine.receive_event(&mut event).await.unwrap();
+
+        assert!(alice.is_canceled());
+        assert!(alice_machine.get_qr_verification(&flow_id).is_none());
+    }
+
     #[cfg(target_os = "linux")]
     #[tokio::test]
     async fn timing_out() {
@@ -398,4 +1585,69 @@ mod test {
         alice_machine.garbage_collect();
         assert!(alice_machine.verifications.is_empty());
     }
+
+    #[tokio::test]
+    async fn manual_identity_verification() {
+        let (alice_machine, _) = setup_qr_verification_machines().await;
+
+        let identity = alice_machine
+            .store
+            .get_user_identity(&bob_id())
+            .await
+            .unwrap()
+            .unwrap()
+            .other()
+            .cloned()
+            .unwrap();
+
+        assert!(!identity.is_verified());
+        assert!(alice_machine.outgoing_to_device_requests().is_empty());
+
+        alice_machine.verify_identity(&identity).await.unwrap();
+
+        assert!(identity.is_verified());
+
+        let requests = alice_machine.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+
+        let request = if let OutgoingRequests::SignatureUpload(r) = requests[0].request() {
+            r
+        } else {
+            panic!("Invalid request type");
+        };
+
+        assert!(request.signed_keys.contains_key(&bob_id()));
+    }
+
+    #[tokio::test]
+    async fn manual_identity_verification_without_signing_key() {
+        let (alice_machine, _) = setup_qr_verification_machines().await;
+        *alice_machine.private_identity.lock().await =
+            PrivateCrossSigningIdentity::empty(alice_id());
+
+        let identity = alice_machine
+            .store
+            .get_user_identity(&bob_id())
+            .await
+            .unwrap()
+            .unwrap()
+            .other()
+            .cloned()
+            .unwrap();
+
+        let verifiable = VerifiableUserIdentity {
+            inner: identity.clone(),
+            verification_machine: alice_machine.clone(),
+        };
+
+        assert!(matches!(
+            verifiable.verify().await,
+            Err(IdentityVerificationError::Signature(
+                SignatureError::MissingSigningKey
+            ))
+        ));
+
+        assert!(!identity.is_verified());
+        assert!(alice_machine.outgoing_to_device_requests().is_empty());
+    }
 }
@@ -12,15 +12,328 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod in_room;
 mod machine;
+mod qrcode;
+mod requests;
 mod sas;
 
+use std::sync::Arc;
+
+use tracing::{error, info, trace, warn};
+
+use matrix_sdk_common::{
+    api::r0::keys::upload_signatures::Request as SignatureUploadRequest,
+    events::key::verification::cancel::CancelCode,
+    identifiers::{DeviceId, UserId},
+};
+
+use crate::{
+    error::SignatureError,
+    identities::{LocalTrust, ReadOnlyDevice, UserIdentities},
+    olm::PrivateCrossSigningIdentity,
+    store::{Changes, CryptoStore, CryptoStoreError, DeviceChanges},
+};
+
 pub use machine::VerificationMachine;
-pub use sas::{Sas, VerificationResult};
+pub use qrcode::{QrDecodingError, QrVerification, QrVerificationData, QrVerificationMode};
+pub use requests::VerificationRequest;
+pub use sas::Sas;
+
+#[derive(Debug)]
+/// A result of a verification flow.
+pub enum VerificationResult {
+    /// The verification succeeded, nothing needs to be done.
+    Ok,
+    /// The verification failed and needs to be canceled with the given code.
+    Cancel(CancelCode),
+    /// The verification is done and has signatures that need to be uploaded.
+    SignatureUpload(SignatureUploadRequest),
+}
+
+/// The device and user identity that an interactive verification flow is
+/// trying to verify.
+///
+/// Once a verification flow finishes, the devices and identities it managed
+/// to verify are marked as verified and persisted using this object.
+#[derive(Clone, Debug)]
+pub(crate) struct IdentitiesBeingVerified {
+    pub private_identity: PrivateCrossSigningIdentity,
+    pub store: Arc<Box<dyn CryptoStore>>,
+    pub device_being_verified: ReadOnlyDevice,
+    pub identity_being_verified: Option<UserIdentities>,
+}
+
+impl IdentitiesBeingVerified {
+    fn user_id(&self) -> &UserId {
+        self.private_identity.user_id()
+    }
+
+    pub fn other_user_id(&self) -> &UserId {
+        self.device_being_verified.user_id()
+    }
+
+    pub fn other_device_id(&self) -> &DeviceId {
+        self.device_being_verified.device_id()
+    }
+
+    pub fn other_device(&self) -> &ReadOnlyDevice {
+        &self.device_being_verified
+    }
+
+    pub fn other_identity(&self) -> Option<&UserIdentities> {
+        self.identity_being_verified.as_ref()
+    }
+
+    /// Mark the devices and identities the verification flow verified as
+    /// verified and save them.
+    ///
+    /// # Arguments
+    ///
+    /// * `verified_devices` - The devices that the flow verified, `None` if
+    /// the flow doesn't verify devices. The verification fails if the device
+    /// we're verifying isn't part of the list.
+    ///
+    /// * `verified_identities` - The user identities that the flow verified.
+    ///
+    /// The verification fails as well if nothing could be marked as verified.
+    pub async fn mark_as_done(
+        &self,
+        verified_devices: Option<&[ReadOnlyDevice]>,
+        verified_identities: Option<&[UserIdentities]>,
+    ) -> Result<VerificationResult, CryptoStoreError> {
+        let device = if let Some(devices) = verified_devices {
+            if let Some(device) = self.mark_device_as_verified(devices).await? {
+                Some(device)
+            } else {
+                return Ok(VerificationResult::Cancel(CancelCode::KeyMismatch));
+            }
+        } else {
+            None
+        };
+
+        let identity = self.mark_identity_as_verified(verified_identities).await?;
+
+        if device.is_none() && identity.is_none() {
+            return Ok(VerificationResult::Cancel(CancelCode::KeyMismatch));
+        }
+
+        let mut changes = Changes::default();
+
+        // We only sign devices of our own user here.
+        let signature_request = if let Some(device) = device {
+            let request = if device.user_id() == self.user_id() {
+                match self.private_identity.sign_device(&device).await {
+                    Ok(r) => Some(r),
+                    Err(SignatureError::MissingSigningKey) => {
+                        warn!(
+                            "Can't sign the device keys for {} {}, \
+                                  no private user signing key found",
+                            device.user_id(),
+                            device.device_id(),
+                        );
+
+                        None
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error signing device keys for {} {} {:?}",
+                            device.user_id(),
+                            device.device_id(),
+                            e
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            changes.devices = DeviceChanges {
+                changed: vec![device],
+                ..Default::default()
+            };
+
+            request
+        } else {
+            None
+        };
+
+        let identity_signature_request = if let Some(i) = identity {
+            // We only sign other users here.
+            let request = if let Some(i) = i.other() {
+                // Signing can fail if the user signing key is missing.
+                match self.private_identity.sign_user(&i).await {
+                    Ok(r) => Some(r),
+                    Err(SignatureError::MissingSigningKey) => {
+                        warn!(
+                            "Can't sign the public cross signing keys for {}, \
+                              no private user signing key found",
+                            i.user_id()
+                        );
+                        None
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error signing the public cross signing keys for {} {:?}",
+                            i.user_id(),
+                            e
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            changes.identities.changed.push(i);
+
+            request
+        } else {
+            None
+        };
+
+        // If there are two signature upload requests, merge them. Otherwise
+        // use the one we have or None.
+        //
+        // Realistically at most one reuqest will be used but let's make
+        // this future proof.
+        let merged_request = if let Some(mut r) = signature_request {
+            if let Some(user_request) = identity_signature_request {
+                r.signed_keys.extend(user_request.signed_keys);
+                Some(r)
+            } else {
+                Some(r)
+            }
+        } else if let Some(r) = identity_signature_request {
+            Some(r)
+        } else {
+            None
+        };
+
+        // TODO store the request as well.
+        self.store.save_changes(changes).await?;
+        Ok(merged_request
+            .map(VerificationResult::SignatureUpload)
+            .unwrap_or(VerificationResult::Ok))
+    }
+
+    async fn mark_identity_as_verified(
+        &self,
+        verified_identities: Option<&[UserIdentities]>,
+    ) -> Result<Option<UserIdentities>, CryptoStoreError> {
+        // If there wasn't an identity available during the verification flow
+        // return early as there's nothing to do.
+        if self.identity_being_verified.is_none() {
+            return Ok(None);
+        }
+
+        // TODO signal an error, e.g. when the identity got deleted so we don't
+        // verify/save the device either.
+        let identity = self.store.get_user_identity(self.other_user_id()).await?;
+
+        if let Some(identity) = identity {
+            if self
+                .identity_being_verified
+                .as_ref()
+                .map_or(false, |i| i.master_key() == identity.master_key())
+            {
+                if verified_identities.map_or(false, |i| i.contains(&identity)) {
+                    trace!(
+                        "Marking user identity of {} as verified.",
+                        identity.user_id(),
+                    );
+
+                    match &identity {
+                        UserIdentities::Own(i) => i.mark_as_verified(),
+                        UserIdentities::Other(i) => i.mark_as_verified(),
+                    }
+
+                    Ok(Some(identity))
+                } else {
+                    info!(
+                        "The interactive verification process didn't verify \
+                        the user identity of {} {:?}",
+                        identity.user_id(),
+                        verified_identities,
+                    );
+
+                    Ok(None)
+                }
+            } else {
+                warn!(
+                    "The master keys of {} have changed while an interactive \
+                      verification was going on, not marking the identity as verified.",
+                    identity.user_id(),
+                );
+
+                Ok(None)
+            }
+        } else {
+            info!(
+                "The identity for {} was deleted while an interactive \
+                  verification was going on.",
+                self.other_user_id(),
+            );
+            Ok(None)
+        }
+    }
+
+    async fn mark_device_as_verified(
+        &self,
+        verified_devices: &[ReadOnlyDevice],
+    ) -> Result<Option<ReadOnlyDevice>, CryptoStoreError> {
+        let device = self
+            .store
+            .get_device(self.other_user_id(), self.other_device_id())
+            .await?;
+
+        if let Some(device) = device {
+            if device.keys() == self.device_being_verified.keys() {
+                if verified_devices.contains(&device) {
+                    trace!(
+                        "Marking device {} {} as verified.",
+                        device.user_id(),
+                        device.device_id()
+                    );
+
+                    device.set_trust_state(LocalTrust::Verified);
+
+                    Ok(Some(device))
+                } else {
+                    info!(
+                        "The interactive verification process didn't verify \
+                        the device {} {}",
+                        device.user_id(),
+                        device.device_id()
+                    );
+
+                    Ok(None)
+                }
+            } else {
+                warn!(
+                    "The device keys of {} {} have changed while an interactive \
+                      verification was going on, not marking the device as verified.",
+                    device.user_id(),
+                    device.device_id()
+                );
+                Ok(None)
+            }
+        } else {
+            info!(
+                "The device {} {} was deleted while an interactive \
+                  verification was going on.",
+                self.other_user_id(),
+                self.other_device_id()
+            );
+            Ok(None)
+        }
+    }
+}
 
 #[cfg(test)]
 pub(crate) mod test {
-    use crate::requests::{OutgoingRequest, OutgoingRequests, ToDeviceRequest};
+    use crate::requests::{OutgoingRequest, OutgoingRequests, OutgoingVerificationRequest};
     use serde_json::Value;
 
     use matrix_sdk_common::{
@@ -28,7 +341,10 @@ pub(crate) mod test {
         identifiers::UserId,
     };
 
-    pub(crate) fn request_to_event(sender: &UserId, request: &ToDeviceRequest) -> AnyToDeviceEvent {
+    pub(crate) fn request_to_event(
+        sender: &UserId,
+        request: &OutgoingVerificationRequest,
+    ) -> AnyToDeviceEvent {
         let content = get_content_from_request(request);
         wrap_any_to_device_content(sender, content)
     }
@@ -38,7 +354,7 @@ pub(crate) mod test {
         request: &OutgoingRequest,
     ) -> AnyToDeviceEvent {
         match request.request() {
-            OutgoingRequests::ToDeviceRequest(r) => request_to_event(sender, r),
+            OutgoingRequests::ToDeviceRequest(r) => request_to_event(sender, &r.clone().into()),
             _ => panic!("Unsupported outgoing request"),
         }
     }
@@ -72,12 +388,44 @@ pub(crate) mod test {
                     content: c,
                 })
             }
+            AnyToDeviceEventContent::KeyVerificationRequest(c) => {
+                AnyToDeviceEvent::KeyVerificationRequest(ToDeviceEvent {
+                    sender: sender.clone(),
+                    content: c,
+                })
+            }
+            AnyToDeviceEventContent::KeyVerificationReady(c) => {
+                AnyToDeviceEvent::KeyVerificationReady(ToDeviceEvent {
+                    sender: sender.clone(),
+                    content: c,
+                })
+            }
+            AnyToDeviceEventContent::KeyVerificationCancel(c) => {
+                AnyToDeviceEvent::KeyVerificationCancel(ToDeviceEvent {
+                    sender: sender.clone(),
+                    content: c,
+                })
+            }
+            AnyToDeviceEventContent::KeyVerificationDone(c) => {
+                AnyToDeviceEvent::KeyVerificationDone(ToDeviceEvent {
+                    sender: sender.clone(),
+                    content: c,
+                })
+            }
 
             _ => unreachable!(),
         }
     }
 
-    pub(crate) fn get_content_from_request(request: &ToDeviceRequest) -> AnyToDeviceEventContent {
+    pub(crate) fn get_content_from_request(
+        request: &OutgoingVerificationRequest,
+    ) -> AnyToDeviceEventContent {
+        let request = if let OutgoingVerificationRequest::ToDevice(r) = request {
+            r
+        } else {
+            panic!("Unsupported outgoing verification request")
+        };
+
         let json: Value = serde_json::from_str(
             request
                 .messages
@@ -107,6 +455,15 @@ pub(crate) mod test {
             EventType::KeyVerificationCancel => AnyToDeviceEventContent::KeyVerificationCancel(
                 serde_json::from_value(json).unwrap(),
             ),
+            EventType::KeyVerificationRequest => AnyToDeviceEventContent::KeyVerificationRequest(
+                serde_json::from_value(json).unwrap(),
+            ),
+            EventType::KeyVerificationReady => {
+                AnyToDeviceEventContent::KeyVerificationReady(serde_json::from_value(json).unwrap())
+            }
+            EventType::KeyVerificationDone => {
+                AnyToDeviceEventContent::KeyVerificationDone(serde_json::from_value(json).unwrap())
+            }
             _ => unreachable!(),
         }
     }
@@ -0,0 +1,348 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    string::FromUtf8Error,
+};
+
+use getrandom::getrandom;
+use thiserror::Error;
+
+use crate::utilities::{decode, encode, DecodeError};
+
+/// The header every QR code used for verification starts with.
+const HEADER: &[u8] = b"MATRIX";
+/// The version of the QR code format we support.
+const VERSION: u8 = 0x02;
+/// The length of an ed25519 key in bytes.
+const KEY_LENGTH: usize = 32;
+/// The minimal length of the shared secret in bytes.
+const MIN_SECRET_LENGTH: usize = 8;
+/// The length of the shared secret we generate in bytes.
+const SECRET_LENGTH: usize = 16;
+
+/// Error type describing why a QR code couldn't be decoded.
+#[derive(Error, Debug)]
+pub enum QrDecodingError {
+    /// The QR code doesn't start with the `MATRIX` header.
+    #[error("the QR code doesn't start with the MATRIX header")]
+    Header,
+    /// The QR code uses a version we don't support.
+    #[error("the QR code has an unsupported version {0}")]
+    Version(u8),
+    /// The QR code uses an unknown mode.
+    #[error("the QR code has an unknown mode {0}")]
+    Mode(u8),
+    /// The QR code is shorter than its content claims.
+    #[error("the QR code is truncated")]
+    Truncated,
+    /// The flow id of the QR code isn't valid UTF-8.
+    #[error("the flow id of the QR code isn't valid UTF-8: {0}")]
+    FlowId(#[from] FromUtf8Error),
+    /// The shared secret of the QR code is too short.
+    #[error("the shared secret of the QR code is too short, got {0} bytes")]
+    SharedSecret(usize),
+    /// One of the keys isn't a valid base64 encoded ed25519 key.
+    #[error("the QR code contains an invalid key")]
+    Key,
+    /// One of the base64 encoded values couldn't be decoded.
+    #[error(transparent)]
+    Base64(#[from] DecodeError),
+}
+
+/// The mode of a verification QR code, it defines the meaning of the two
+/// keys the QR code contains.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QrVerificationMode {
+    /// Verifying another user. The first key is the master key of the user
+    /// showing the QR code, the second key is the master key of the user
+    /// scanning it, as the showing side sees it.
+    Verification,
+    /// Self-verification where the device showing the QR code trusts our
+    /// master key. The first key is our master key, the second key is the
+    /// ed25519 key of the scanning device, as the showing device sees it.
+    SelfVerification,
+    /// Self-verification where the device showing the QR code doesn't trust
+    /// our master key yet. The first key is the ed25519 key of the showing
+    /// device, the second key is our master key, as the showing device sees
+    /// it.
+    SelfVerificationNoMasterKey,
+}
+
+impl QrVerificationMode {
+    fn as_byte(self) -> u8 {
+        match self {
+            QrVerificationMode::Verification => 0x00,
+            QrVerificationMode::SelfVerification => 0x01,
+            QrVerificationMode::SelfVerificationNoMasterKey => 0x02,
+        }
+    }
+}
+
+impl TryFrom<u8> for QrVerificationMode {
+    type Error = QrDecodingError;
+
+    fn try_from(mode: u8) -> Result<Self, Self::Error> {
+        match mode {
+            0x00 => Ok(QrVerificationMode::Verification),
+            0x01 => Ok(QrVerificationMode::SelfVerification),
+            0x02 => Ok(QrVerificationMode::SelfVerificationNoMasterKey),
+            m => Err(QrDecodingError::Mode(m)),
+        }
+    }
+}
+
+/// The data that is encoded in a verification QR code.
+///
+/// Use [`to_bytes()`](#method.to_bytes) to get the payload that should be
+/// rendered as a QR code and [`from_bytes()`](#method.from_bytes) to parse a
+/// scanned QR code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QrVerificationData {
+    mode: QrVerificationMode,
+    flow_id: String,
+    first_key: [u8; KEY_LENGTH],
+    second_key: [u8; KEY_LENGTH],
+    shared_secret: Vec<u8>,
+}
+
+impl QrVerificationData {
+    /// Create new QR code data with a random shared secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - The mode of the QR code.
+    ///
+    /// * `flow_id` - The transaction id or the event id of the verification
+    /// request this QR code belongs to.
+    ///
+    /// * `first_key` - The base64 encoded first key of the QR code.
+    ///
+    /// * `second_key` - The base64 encoded second key of the QR code.
+    pub(crate) fn new(
+        mode: QrVerificationMode,
+        flow_id: String,
+        first_key: &str,
+        second_key: &str,
+    ) -> Result<Self, QrDecodingError> {
+        let mut shared_secret = vec![0u8; SECRET_LENGTH];
+        getrandom(&mut shared_secret).expect("Can't generate randomness");
+
+        Ok(Self {
+            mode,
+            flow_id,
+            first_key: decode_key(first_key)?,
+            second_key: decode_key(second_key)?,
+            shared_secret,
+        })
+    }
+
+    /// Parse the data of a scanned QR code.
+    pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<Self, QrDecodingError> {
+        let bytes = bytes.as_ref();
+
+        let mut reader = Reader(bytes);
+
+        if reader.take(HEADER.len())? != HEADER {
+            return Err(QrDecodingError::Header);
+        }
+
+        let version = reader.take(1)?[0];
+
+        if version != VERSION {
+            return Err(QrDecodingError::Version(version));
+        }
+
+        let mode = QrVerificationMode::try_from(reader.take(1)?[0])?;
+
+        let flow_id_length = reader.take(2)?;
+        let flow_id_length = u16::from_be_bytes([flow_id_length[0], flow_id_length[1]]);
+        let flow_id = String::from_utf8(reader.take(flow_id_length as usize)?.to_vec())?;
+
+        let first_key = reader
+            .take(KEY_LENGTH)?
+            .try_into()
+            .expect("The key has the correct length");
+        let second_key = reader
+            .take(KEY_LENGTH)?
+            .try_into()
+            .expect("The key has the correct length");
+
+        let shared_secret = reader.0.to_vec();
+
+        if shared_secret.len() < MIN_SECRET_LENGTH {
+            return Err(QrDecodingError::SharedSecret(shared_secret.len()));
+        }
+
+        Ok(Self {
+            mode,
+            flow_id,
+            first_key,
+            second_key,
+            shared_secret,
+        })
+    }
+
+    /// Encode the data into the binary payload of a QR code.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let flow_id_length = self.flow_id.len() as u16;
+
+        let mut bytes = Vec::with_capacity(
+            HEADER.len() + 4 + self.flow_id.len() + 2 * KEY_LENGTH + self.shared_secret.len(),
+        );
+
+        bytes.extend_from_slice(HEADER);
+        bytes.push(VERSION);
+        bytes.push(self.mode.as_byte());
+        bytes.extend_from_slice(&flow_id_length.to_be_bytes());
+        bytes.extend_from_slice(self.flow_id.as_bytes());
+        bytes.extend_from_slice(&self.first_key);
+        bytes.extend_from_slice(&self.second_key);
+        bytes.extend_from_slice(&self.shared_secret);
+
+        bytes
+    }
+
+    /// Get the mode of the QR code.
+    pub fn mode(&self) -> QrVerificationMode {
+        self.mode
+    }
+
+    /// Get the flow id of the verification request the QR code belongs to.
+    pub fn flow_id(&self) -> &str {
+        &self.flow_id
+    }
+
+    /// Get the base64 encoded first key of the QR code.
+    pub fn first_key(&self) -> String {
+        encode(self.first_key)
+    }
+
+    /// Get the base64 encoded second key of the QR code.
+    pub fn second_key(&self) -> String {
+        encode(self.second_key)
+    }
+
+    /// Get the base64 encoded shared secret of the QR code.
+    ///
+    /// The scanning side sends the secret back to prove that it scanned the
+    /// QR code.
+    pub fn secret(&self) -> String {
+        encode(&self.shared_secret)
+    }
+}
+
+fn decode_key(key: &str) -> Result<[u8; KEY_LENGTH], QrDecodingError> {
+    decode(key)?
+        .as_slice()
+        .try_into()
+        .map_err(|_| QrDecodingError::Key)
+}
+
+/// Helper to read the QR code payload piece by piece.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], QrDecodingError> {
+        if self.0.len() < count {
+            Err(QrDecodingError::Truncated)
+        } else {
+            let (taken, rest) = self.0.split_at(count);
+            self.0 = rest;
+            Ok(taken)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{QrDecodingError, QrVerificationData, QrVerificationMode};
+
+    const FIRST_KEY: &str = "kS/gh5pzNq5H6ISH2XV6HLPHn0tD2unxwkBvSnJwWxY";
+    const SECOND_KEY: &str = "a8Xg0N6CbIq9HP5H2Ny0a/yhhFN9VkTTkX5chJ15N0I";
+
+    #[test]
+    fn round_trip() {
+        let data = QrVerificationData::new(
+            QrVerificationMode::Verification,
+            "$request:example.org".to_owned(),
+            FIRST_KEY,
+            SECOND_KEY,
+        )
+        .unwrap();
+
+        let bytes = data.to_bytes();
+        assert!(bytes.starts_with(b"MATRIX\x02\x00\x00\x14$request:example.org"));
+
+        let decoded = QrVerificationData::from_bytes(&bytes).unwrap();
+
+        assert_eq!(data, decoded);
+        assert_eq!(decoded.mode(), QrVerificationMode::Verification);
+        assert_eq!(decoded.flow_id(), "$request:example.org");
+        assert_eq!(decoded.first_key(), FIRST_KEY);
+        assert_eq!(decoded.second_key(), SECOND_KEY);
+    }
+
+    #[test]
+    fn invalid_payloads() {
+        let data = QrVerificationData::new(
+            QrVerificationMode::SelfVerification,
+            "flow_id".to_owned(),
+            FIRST_KEY,
+            SECOND_KEY,
+        )
+        .unwrap();
+        let bytes = data.to_bytes();
+
+        let mut header = bytes.clone();
+        header[0] = b'N';
+        assert!(matches!(
+            QrVerificationData::from_bytes(header),
+            Err(QrDecodingError::Header)
+        ));
+
+        let mut version = bytes.clone();
+        version[6] = 0x01;
+        assert!(matches!(
+            QrVerificationData::from_bytes(version),
+            Err(QrDecodingError::Version(0x01))
+        ));
+
+        let mut mode = bytes.clone();
+        mode[7] = 0x03;
+        assert!(matches!(
+            QrVerificationData::from_bytes(mode),
+            Err(QrDecodingError::Mode(0x03))
+        ));
+
+        assert!(matches!(
+            QrVerificationData::from_bytes(&bytes[..30]),
+            Err(QrDecodingError::Truncated)
+        ));
+
+        assert!(matches!(
+            QrVerificationData::from_bytes(&bytes[..bytes.len() - 10]),
+            Err(QrDecodingError::SharedSecret(6))
+        ));
+
+        assert!(QrVerificationData::new(
+            QrVerificationMode::Verification,
+            "flow_id".to_owned(),
+            "AAAA",
+            SECOND_KEY,
+        )
+        .is_err());
+    }
+}
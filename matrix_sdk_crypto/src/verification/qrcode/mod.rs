@@ -0,0 +1,584 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interactive verification by scanning a QR code.
+//!
+//! One side shows a QR code containing the keys it expects the other side to
+//! have, the other side scans it, checks the keys and replies with a
+//! `m.key.verification.start` event using the `m.reciprocate.v1` method that
+//! contains the shared secret of the QR code. Once the user on the showing
+//! side confirms that the QR code was scanned, both sides mark the keys as
+//! verified and send out a `m.key.verification.done` event.
+
+mod data;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{trace, warn};
+
+use matrix_sdk_common::{
+    api::r0::keys::upload_signatures::Request as SignatureUploadRequest,
+    events::{
+        key::verification::{
+            cancel::{CancelCode, CancelToDeviceEventContent},
+            done::DoneToDeviceEventContent,
+            start::{ReciprocateV1Content, StartMethod, StartToDeviceEventContent},
+        },
+        AnyToDeviceEventContent,
+    },
+    identifiers::{DeviceId, DeviceKeyAlgorithm, RoomId, UserId},
+    uuid::Uuid,
+};
+
+use super::{
+    in_room::{to_room_content, RoomFlowId},
+    sas::content_to_request,
+    IdentitiesBeingVerified, VerificationResult,
+};
+use crate::{
+    identities::{OwnUserIdentity, ReadOnlyDevice, UserIdentities},
+    requests::{OutgoingVerificationRequest, RoomMessageRequest},
+    store::CryptoStoreError,
+    ReadOnlyAccount,
+};
+
+pub use data::{QrDecodingError, QrVerificationData, QrVerificationMode};
+
+/// The time after which an unfinished QR code verification will be canceled.
+const QR_TIMEOUT: Duration = Duration::from_secs(60 * 10);
+
+#[derive(Clone, Debug, PartialEq)]
+enum QrState {
+    /// We're showing the QR code and are waiting for the other side to scan
+    /// it.
+    Created,
+    /// The other side scanned our QR code, our user needs to confirm it.
+    Scanned,
+    /// We scanned the QR code of the other side and are waiting for them to
+    /// confirm it.
+    Reciprocated,
+    /// The verification finished successfully.
+    Done,
+    /// The verification was canceled.
+    Canceled(CancelCode),
+}
+
+/// An interactive verification flow using a QR code.
+///
+/// The flow is either created by showing a QR code, in which case
+/// [`to_bytes()`](#method.to_bytes) returns the payload that should be
+/// rendered, or by scanning the QR code the other side is showing.
+#[derive(Clone, Debug)]
+pub struct QrVerification {
+    account: ReadOnlyAccount,
+    identities_being_verified: IdentitiesBeingVerified,
+    flow_id: Arc<str>,
+    room_flow: Option<Arc<RoomFlowId>>,
+    data: Arc<QrVerificationData>,
+    we_scanned: bool,
+    creation_time: Arc<Instant>,
+    state: Arc<Mutex<QrState>>,
+}
+
+impl QrVerification {
+    /// Create a new QR code verification that shows a QR code to the other
+    /// side.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - Our own account.
+    ///
+    /// * `identities` - The device and identity we're going to verify.
+    ///
+    /// * `own_identity` - The public cross signing identity of our own user.
+    ///
+    /// * `flow_id` - The flow id of the verification request this QR code
+    /// belongs to.
+    ///
+    /// * `room_flow` - The room and request event id if the verification is
+    /// happening inside a room.
+    ///
+    /// Returns `None` if we don't know the keys that are required to build a
+    /// QR code, e.g. if one of the users doesn't have a cross signing identity.
+    pub(crate) fn new(
+        account: ReadOnlyAccount,
+        identities: IdentitiesBeingVerified,
+        own_identity: Option<OwnUserIdentity>,
+        flow_id: String,
+        room_flow: Option<RoomFlowId>,
+    ) -> Option<Self> {
+        let own_master_key = own_identity
+            .as_ref()
+            .and_then(|i| i.master_key().get_first_key())
+            .map(|k| k.to_owned());
+
+        let (mode, first_key, second_key) = if identities.other_user_id() == account.user_id() {
+            let own_master_key = own_master_key?;
+
+            if own_identity.map_or(false, |i| i.is_verified()) {
+                let other_device_key = identities
+                    .other_device()
+                    .get_key(DeviceKeyAlgorithm::Ed25519)?
+                    .to_owned();
+
+                (
+                    QrVerificationMode::SelfVerification,
+                    own_master_key,
+                    other_device_key,
+                )
+            } else {
+                (
+                    QrVerificationMode::SelfVerificationNoMasterKey,
+                    account.identity_keys().ed25519().to_owned(),
+                    own_master_key,
+                )
+            }
+        } else {
+            let other_master_key = identities
+                .other_identity()
+                .and_then(|i| i.master_key().get_first_key())?
+                .to_owned();
+
+            (
+                QrVerificationMode::Verification,
+                own_master_key?,
+                other_master_key,
+            )
+        };
+
+        let data = QrVerificationData::new(mode, flow_id.clone(), &first_key, &second_key).ok()?;
+
+        Some(Self::new_helper(
+            account,
+            identities,
+            flow_id,
+            room_flow,
+            data,
+            false,
+            QrState::Created,
+        ))
+    }
+
+    /// Create a new QR code verification from a QR code the other side is
+    /// showing us.
+    ///
+    /// The keys of the QR code are checked against the keys we know about.
+    ///
+    /// Returns the new `QrVerification` and the request that needs to be sent
+    /// out. If the keys match the request is a `m.key.verification.start`
+    /// event using the `m.reciprocate.v1` method, otherwise the verification
+    /// is canceled and the request informs the other side about it.
+    pub(crate) fn from_scan(
+        account: ReadOnlyAccount,
+        identities: IdentitiesBeingVerified,
+        own_identity: Option<OwnUserIdentity>,
+        room_flow: Option<RoomFlowId>,
+        data: QrVerificationData,
+    ) -> (Self, Option<OutgoingVerificationRequest>) {
+        let own_master_key = own_identity
+            .as_ref()
+            .and_then(|i| i.master_key().get_first_key())
+            .map(|k| k.to_owned());
+        let other_master_key = identities
+            .other_identity()
+            .and_then(|i| i.master_key().get_first_key())
+            .map(|k| k.to_owned());
+        let other_device_key = identities
+            .other_device()
+            .get_key(DeviceKeyAlgorithm::Ed25519)
+            .cloned();
+        let own_device_key = account.identity_keys().ed25519().to_owned();
+
+        let self_verification = identities.other_user_id() == account.user_id();
+        let first_key = Some(data.first_key());
+        let second_key = Some(data.second_key());
+
+        let keys_match = match data.mode() {
+            QrVerificationMode::Verification => {
+                !self_verification && first_key == other_master_key && second_key == own_master_key
+            }
+            QrVerificationMode::SelfVerification => {
+                self_verification
+                    && first_key == own_master_key
+                    && second_key == Some(own_device_key)
+            }
+            QrVerificationMode::SelfVerificationNoMasterKey => {
+                self_verification && first_key == other_device_key && second_key == own_master_key
+            }
+        };
+
+        let flow_id = data.flow_id().to_owned();
+
+        let qr = Self::new_helper(
+            account,
+            identities,
+            flow_id,
+            room_flow,
+            data,
+            true,
+            QrState::Reciprocated,
+        );
+
+        let request = if keys_match {
+            let content =
+                AnyToDeviceEventContent::KeyVerificationStart(StartToDeviceEventContent {
+                    from_device: qr.account.device_id().into(),
+                    transaction_id: qr.flow_id.to_string(),
+                    method: StartMethod::ReciprocateV1(ReciprocateV1Content::new(qr.data.secret())),
+                });
+
+            qr.content_to_request(content)
+        } else {
+            warn!(
+                "The keys of the scanned QR code for the verification {} with {} {} don't match",
+                qr.flow_id,
+                qr.other_user_id(),
+                qr.other_device_id()
+            );
+
+            qr.cancel_with_code(CancelCode::KeyMismatch)
+        };
+
+        (qr, request)
+    }
+
+    fn new_helper(
+        account: ReadOnlyAccount,
+        identities: IdentitiesBeingVerified,
+        flow_id: String,
+        room_flow: Option<RoomFlowId>,
+        data: QrVerificationData,
+        we_scanned: bool,
+        state: QrState,
+    ) -> Self {
+        Self {
+            account,
+            identities_being_verified: identities,
+            flow_id: flow_id.into(),
+            room_flow: room_flow.map(Arc::new),
+            data: Arc::new(data),
+            we_scanned,
+            creation_time: Arc::new(Instant::now()),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Get our own user id.
+    pub fn user_id(&self) -> &UserId {
+        self.account.user_id()
+    }
+
+    /// Get the user id of the other side.
+    pub fn other_user_id(&self) -> &UserId {
+        self.identities_being_verified.other_user_id()
+    }
+
+    /// Get the device id of the other side.
+    pub fn other_device_id(&self) -> &DeviceId {
+        self.identities_being_verified.other_device_id()
+    }
+
+    /// Get the device of the other user.
+    pub fn other_device(&self) -> ReadOnlyDevice {
+        self.identities_being_verified.other_device().clone()
+    }
+
+    /// Get the unique ID that identifies this verification flow.
+    pub fn flow_id(&self) -> &str {
+        &self.flow_id
+    }
+
+    /// Get the id of the room the verification is happening in.
+    ///
+    /// Returns `None` if the verification is done using to-device messages.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.room_flow().map(|f| &f.room_id)
+    }
+
+    pub(crate) fn room_flow(&self) -> Option<&RoomFlowId> {
+        self.room_flow.as_deref()
+    }
+
+    /// Get the mode of the QR code.
+    pub fn mode(&self) -> QrVerificationMode {
+        self.data.mode()
+    }
+
+    /// Did we scan the QR code of the other side, or are we the side showing
+    /// the QR code.
+    pub fn we_scanned(&self) -> bool {
+        self.we_scanned
+    }
+
+    /// Get the binary payload of the QR code.
+    ///
+    /// This is the payload that should be rendered as a QR code and shown to
+    /// the other side.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.to_bytes()
+    }
+
+    /// Has the other side scanned our QR code.
+    ///
+    /// If this is true, our user should be asked if the other side scanned
+    /// the QR code successfully and [`confirm_scanning()`] should be called.
+    ///
+    /// [`confirm_scanning()`]: #method.confirm_scanning
+    pub fn has_been_scanned(&self) -> bool {
+        *self.state.lock().unwrap() == QrState::Scanned
+    }
+
+    /// Is the QR code verification done.
+    pub fn is_done(&self) -> bool {
+        *self.state.lock().unwrap() == QrState::Done
+    }
+
+    /// Has the QR code verification been canceled.
+    pub fn is_canceled(&self) -> bool {
+        matches!(&*self.state.lock().unwrap(), QrState::Canceled(_))
+    }
+
+    /// Has the QR code verification timed out.
+    pub fn timed_out(&self) -> bool {
+        self.creation_time.elapsed() > QR_TIMEOUT
+    }
+
+    /// Confirm that the other side scanned our QR code.
+    ///
+    /// This marks the keys of the other side as verified. Does nothing if the
+    /// QR code wasn't scanned yet, otherwise returns a `m.key.verification.done`
+    /// request that needs to be sent out and, if we were able to sign the
+    /// verified keys, a signature upload request.
+    pub async fn confirm_scanning(
+        &self,
+    ) -> Result<
+        (
+            Option<OutgoingVerificationRequest>,
+            Option<SignatureUploadRequest>,
+        ),
+        CryptoStoreError,
+    > {
+        if !self.has_been_scanned() {
+            return Ok((None, None));
+        }
+
+        self.mark_as_done().await
+    }
+
+    /// Cancel the verification.
+    ///
+    /// This cancels the verification with the `CancelCode::User`.
+    ///
+    /// Returns None if the verification is already done or canceled, otherwise
+    /// it returns a request that needs to be sent out.
+    pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
+        self.cancel_with_code(CancelCode::User)
+    }
+
+    pub(crate) fn cancel_with_code(&self, code: CancelCode) -> Option<OutgoingVerificationRequest> {
+        {
+            let mut state = self.state.lock().unwrap();
+
+            if let QrState::Done | QrState::Canceled(_) = &*state {
+                return None;
+            }
+
+            *state = QrState::Canceled(code.clone());
+        }
+
+        let reason = match code {
+            CancelCode::User => "Canceled by user",
+            CancelCode::Timeout => "The verification timed out",
+            CancelCode::KeyMismatch => "The keys of the QR code didn't match",
+            _ => "Unknown cancel reason",
+        };
+
+        let content = AnyToDeviceEventContent::KeyVerificationCancel(CancelToDeviceEventContent {
+            transaction_id: self.flow_id.to_string(),
+            reason: reason.to_owned(),
+            code,
+        });
+
+        self.content_to_request(content)
+    }
+
+    pub(crate) fn cancel_if_timed_out(&self) -> Option<OutgoingVerificationRequest> {
+        if self.timed_out() {
+            self.cancel_with_code(CancelCode::Timeout)
+        } else {
+            None
+        }
+    }
+
+    /// Receive a `m.key.verification.cancel` event.
+    pub(crate) fn receive_cancel(&self, code: &CancelCode) {
+        let mut state = self.state.lock().unwrap();
+
+        if *state != QrState::Done {
+            *state = QrState::Canceled(code.clone());
+        }
+    }
+
+    /// Receive a `m.key.verification.start` event using the
+    /// `m.reciprocate.v1` method.
+    ///
+    /// Returns a cancellation request if the shared secret of the event
+    /// doesn't match the secret of our QR code.
+    pub(crate) fn receive_reciprocation(
+        &self,
+        content: &StartToDeviceEventContent,
+    ) -> Option<OutgoingVerificationRequest> {
+        let secret = if let StartMethod::ReciprocateV1(c) = &content.method {
+            &c.secret
+        } else {
+            return None;
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+
+            if *state != QrState::Created {
+                return None;
+            }
+
+            if *secret == self.data.secret() {
+                trace!(
+                    "{} {} scanned the QR code for the verification {}",
+                    self.other_user_id(),
+                    self.other_device_id(),
+                    self.flow_id
+                );
+
+                *state = QrState::Scanned;
+                return None;
+            }
+        }
+
+        warn!(
+            "{} {} sent an invalid shared secret for the QR code verification {}",
+            self.other_user_id(),
+            self.other_device_id(),
+            self.flow_id
+        );
+
+        self.cancel_with_code(CancelCode::KeyMismatch)
+    }
+
+    /// Receive a `m.key.verification.done` event.
+    ///
+    /// If we scanned the QR code of the other side, this marks the keys of the
+    /// other side as verified and returns our own `m.key.verification.done`
+    /// request as well as the signature upload request if we were able to
+    /// sign the verified keys.
+    pub(crate) async fn receive_done(
+        &self,
+    ) -> Result<
+        (
+            Option<OutgoingVerificationRequest>,
+            Option<SignatureUploadRequest>,
+        ),
+        CryptoStoreError,
+    > {
+        if *self.state.lock().unwrap() != QrState::Reciprocated {
+            return Ok((None, None));
+        }
+
+        self.mark_as_done().await
+    }
+
+    async fn mark_as_done(
+        &self,
+    ) -> Result<
+        (
+            Option<OutgoingVerificationRequest>,
+            Option<SignatureUploadRequest>,
+        ),
+        CryptoStoreError,
+    > {
+        let (devices, identities) = self.verified_identities();
+
+        let result = self
+            .identities_being_verified
+            .mark_as_done(devices.as_deref(), identities.as_deref())
+            .await?;
+
+        let signature_request = match result {
+            VerificationResult::Ok => None,
+            VerificationResult::SignatureUpload(r) => Some(r),
+            VerificationResult::Cancel(c) => return Ok((self.cancel_with_code(c), None)),
+        };
+
+        *self.state.lock().unwrap() = QrState::Done;
+
+        let content = AnyToDeviceEventContent::KeyVerificationDone(DoneToDeviceEventContent {
+            transaction_id: self.flow_id.to_string(),
+        });
+
+        Ok((self.content_to_request(content), signature_request))
+    }
+
+    /// Get the devices and user identities the QR code verifies.
+    ///
+    /// What gets verified depends on the mode of the QR code and on the side
+    /// of the verification we're on.
+    fn verified_identities(&self) -> (Option<Vec<ReadOnlyDevice>>, Option<Vec<UserIdentities>>) {
+        let identity = || {
+            Some(
+                self.identities_being_verified
+                    .other_identity()
+                    .cloned()
+                    .into_iter()
+                    .collect(),
+            )
+        };
+        let device = || Some(vec![self.identities_being_verified.other_device().clone()]);
+
+        match (self.mode(), self.we_scanned) {
+            (QrVerificationMode::Verification, _) => (None, identity()),
+            // The device showing the QR code trusts our master key, so it
+            // verifies the scanning device while the scanning device verifies
+            // our master key.
+            (QrVerificationMode::SelfVerification, false) => (device(), None),
+            (QrVerificationMode::SelfVerification, true) => (None, identity()),
+            // The device showing the QR code doesn't trust our master key, the
+            // other way around.
+            (QrVerificationMode::SelfVerificationNoMasterKey, false) => (None, identity()),
+            (QrVerificationMode::SelfVerificationNoMasterKey, true) => (device(), None),
+        }
+    }
+
+    /// Convert the given content into a request that sends the content to
+    /// the other side.
+    ///
+    /// Returns `None` if the content can't be sent as part of an in-room
+    /// verification.
+    fn content_to_request(
+        &self,
+        content: AnyToDeviceEventContent,
+    ) -> Option<OutgoingVerificationRequest> {
+        Some(if let Some(flow) = &self.room_flow {
+            RoomMessageRequest {
+                room_id: flow.room_id.clone(),
+                txn_id: Uuid::new_v4(),
+                content: to_room_content(&flow.event_id, content)?,
+            }
+            .into()
+        } else {
+            content_to_request(self.other_user_id(), self.other_device_id(), content).into()
+        })
+    }
+}
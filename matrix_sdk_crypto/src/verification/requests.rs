@@ -0,0 +1,677 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
+    events::{
+        key::verification::{
+            cancel::{CancelCode, CancelToDeviceEventContent},
+            ready::ReadyToDeviceEventContent,
+            request::RequestEventContent,
+            VerificationMethod,
+        },
+        room::message::KeyVerificationRequestEventContent,
+        AnyToDeviceEventContent, EventContent, EventType,
+    },
+    identifiers::{DeviceId, DeviceIdBox, EventId, RoomId, UserId},
+    uuid::Uuid,
+};
+
+use super::in_room::{self, RoomFlowId};
+use crate::{
+    requests::{OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest},
+    ReadOnlyAccount,
+};
+
+/// The verification methods we support.
+pub const SUPPORTED_METHODS: &[VerificationMethod] = &[
+    VerificationMethod::MSasV1,
+    VerificationMethod::MQrCodeShowV1,
+    VerificationMethod::MQrCodeScanV1,
+    VerificationMethod::MReciprocateV1,
+];
+
+/// The time after which a verification request that didn't lead to a started
+/// verification will be canceled.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60 * 10);
+
+/// The time a to-device verification request may be from the future, to
+/// account for clock skew between the devices.
+const MAX_REQUEST_SKEW: Duration = Duration::from_secs(60 * 5);
+
+#[derive(Clone, Debug, PartialEq)]
+enum RequestState {
+    /// We sent out the request and are waiting for one of the devices of the
+    /// other user to answer.
+    Created,
+    /// The other side sent us a request, we need to accept it.
+    Requested {
+        other_device_id: DeviceIdBox,
+        methods: Vec<VerificationMethod>,
+    },
+    /// Both sides are ready, a verification using one of the methods can be
+    /// started.
+    Ready {
+        other_device_id: DeviceIdBox,
+        methods: Vec<VerificationMethod>,
+    },
+    /// Another one of our devices has answered the request.
+    Passive,
+    /// A verification for this request was started.
+    Started,
+    /// The request was canceled.
+    Canceled(CancelCode),
+}
+
+/// An object negotiating the verification method with the other side before
+/// an interactive verification is started.
+///
+/// The request is sent out to all the devices of the other user, or as a
+/// message to a room. Once a device of the other user accepts it, the
+/// remaining devices are told that the request was accepted elsewhere.
+#[derive(Clone, Debug)]
+pub struct VerificationRequest {
+    account: ReadOnlyAccount,
+    other_user_id: Arc<UserId>,
+    flow_id: Arc<str>,
+    room_flow: Option<Arc<RoomFlowId>>,
+    we_started: bool,
+    creation_time: Arc<Instant>,
+    state: Arc<Mutex<RequestState>>,
+}
+
+impl VerificationRequest {
+    fn new_helper(
+        account: ReadOnlyAccount,
+        other_user_id: UserId,
+        flow_id: String,
+        room_flow: Option<RoomFlowId>,
+        we_started: bool,
+        state: RequestState,
+    ) -> Self {
+        Self {
+            account,
+            other_user_id: Arc::new(other_user_id),
+            flow_id: flow_id.into(),
+            room_flow: room_flow.map(Arc::new),
+            we_started,
+            creation_time: Arc::new(Instant::now()),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Create a new verification request that will be sent to all the devices
+    /// of the given user.
+    ///
+    /// Returns the new `VerificationRequest` and the to-device request that
+    /// needs to be sent out.
+    pub(crate) fn new(account: ReadOnlyAccount, other_user_id: &UserId) -> (Self, ToDeviceRequest) {
+        let flow_id = Uuid::new_v4().to_string();
+
+        let content = AnyToDeviceEventContent::KeyVerificationRequest(RequestEventContent {
+            from_device: account.device_id().into(),
+            transaction_id: flow_id.clone(),
+            methods: SUPPORTED_METHODS.to_vec(),
+            timestamp: SystemTime::now(),
+        });
+
+        let request = to_device_request(
+            other_user_id,
+            vec![DeviceIdOrAllDevices::AllDevices],
+            content,
+        );
+
+        let verification_request = Self::new_helper(
+            account,
+            other_user_id.clone(),
+            flow_id,
+            None,
+            true,
+            RequestState::Created,
+        );
+
+        (verification_request, request)
+    }
+
+    /// Create a verification request from the `m.key.verification.request`
+    /// room message that we sent out.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The event id of the request message.
+    pub(crate) fn new_in_room(
+        account: ReadOnlyAccount,
+        room_id: RoomId,
+        flow_id: EventId,
+        other_user_id: &UserId,
+    ) -> Self {
+        Self::new_helper(
+            account,
+            other_user_id.clone(),
+            flow_id.to_string(),
+            Some(RoomFlowId {
+                room_id,
+                event_id: flow_id,
+            }),
+            true,
+            RequestState::Created,
+        )
+    }
+
+    /// Create a verification request from a to-device
+    /// `m.key.verification.request` event that was sent to us.
+    ///
+    /// Returns `None` if the request is too old or too far in the future.
+    pub(crate) fn from_request_event(
+        account: ReadOnlyAccount,
+        sender: &UserId,
+        content: &RequestEventContent,
+    ) -> Option<Self> {
+        let now = SystemTime::now();
+
+        let expired = now
+            .duration_since(content.timestamp)
+            .map(|age| age > REQUEST_TIMEOUT)
+            .unwrap_or(false);
+        let in_future = content
+            .timestamp
+            .duration_since(now)
+            .map(|skew| skew > MAX_REQUEST_SKEW)
+            .unwrap_or(false);
+
+        if expired || in_future {
+            None
+        } else {
+            Some(Self::new_helper(
+                account,
+                sender.clone(),
+                content.transaction_id.clone(),
+                None,
+                false,
+                RequestState::Requested {
+                    other_device_id: content.from_device.clone(),
+                    methods: content.methods.clone(),
+                },
+            ))
+        }
+    }
+
+    /// Create a verification request from a `m.key.verification.request`
+    /// room message that was sent to us.
+    pub(crate) fn from_room_request(
+        account: ReadOnlyAccount,
+        room_id: RoomId,
+        flow_id: EventId,
+        sender: &UserId,
+        content: &KeyVerificationRequestEventContent,
+    ) -> Self {
+        Self::new_helper(
+            account,
+            sender.clone(),
+            flow_id.to_string(),
+            Some(RoomFlowId {
+                room_id,
+                event_id: flow_id,
+            }),
+            false,
+            RequestState::Requested {
+                other_device_id: content.from_device.clone(),
+                methods: content.methods.clone(),
+            },
+        )
+    }
+
+    /// Get the unique id of this verification flow.
+    ///
+    /// This is the transaction id for to-device requests, or the event id of
+    /// the request message for in-room requests.
+    pub fn flow_id(&self) -> &str {
+        &self.flow_id
+    }
+
+    /// Get the id of the user that sent or received the request.
+    pub fn other_user_id(&self) -> &UserId {
+        &self.other_user_id
+    }
+
+    /// Get the id of the device of the other user that takes part in the
+    /// verification.
+    ///
+    /// Returns `None` if we sent out the request and no device has answered
+    /// yet.
+    pub fn other_device_id(&self) -> Option<DeviceIdBox> {
+        match &*self.state.lock().unwrap() {
+            RequestState::Requested {
+                other_device_id, ..
+            }
+            | RequestState::Ready {
+                other_device_id, ..
+            } => Some(other_device_id.clone()),
+            _ => None,
+        }
+    }
+
+    /// Get the id of the room the request was sent to.
+    ///
+    /// Returns `None` if the request was sent using to-device messages.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.room_flow.as_ref().map(|f| &f.room_id)
+    }
+
+    pub(crate) fn room_flow(&self) -> Option<&RoomFlowId> {
+        self.room_flow.as_deref()
+    }
+
+    /// Did we send out the request.
+    pub fn we_started(&self) -> bool {
+        self.we_started
+    }
+
+    /// Get the verification methods that are offered by the other side.
+    ///
+    /// Once the request is ready this contains only the methods both sides
+    /// support.
+    pub fn methods(&self) -> Option<Vec<VerificationMethod>> {
+        match &*self.state.lock().unwrap() {
+            RequestState::Requested { methods, .. } | RequestState::Ready { methods, .. } => {
+                Some(methods.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Is the request ready for a verification to be started.
+    pub fn is_ready(&self) -> bool {
+        matches!(&*self.state.lock().unwrap(), RequestState::Ready { .. })
+    }
+
+    /// Has another one of our devices answered the request.
+    pub fn is_passive(&self) -> bool {
+        *self.state.lock().unwrap() == RequestState::Passive
+    }
+
+    /// Has a verification been started for this request.
+    pub fn is_started(&self) -> bool {
+        *self.state.lock().unwrap() == RequestState::Started
+    }
+
+    /// Has the request been canceled.
+    pub fn is_canceled(&self) -> bool {
+        matches!(&*self.state.lock().unwrap(), RequestState::Canceled(_))
+    }
+
+    /// Has the request timed out.
+    pub fn timed_out(&self) -> bool {
+        self.creation_time.elapsed() > REQUEST_TIMEOUT
+    }
+
+    /// Accept the verification request.
+    ///
+    /// Returns the request that tells the other side that we're ready, `None`
+    /// if we already accepted the request or if it wasn't sent to us.
+    pub fn accept(&self) -> Option<OutgoingVerificationRequest> {
+        let mut state = self.state.lock().unwrap();
+
+        let (other_device_id, methods) = if let RequestState::Requested {
+            other_device_id,
+            methods,
+        } = &*state
+        {
+            let methods: Vec<VerificationMethod> = methods
+                .iter()
+                .filter(|m| SUPPORTED_METHODS.contains(m))
+                .cloned()
+                .collect();
+
+            (other_device_id.clone(), methods)
+        } else {
+            return None;
+        };
+
+        let request = if let Some(flow) = &self.room_flow {
+            RoomMessageRequest {
+                room_id: flow.room_id.clone(),
+                txn_id: Uuid::new_v4(),
+                content: in_room::ready_content(self.account.device_id(), &flow.event_id),
+            }
+            .into()
+        } else {
+            let content =
+                AnyToDeviceEventContent::KeyVerificationReady(ReadyToDeviceEventContent {
+                    from_device: self.account.device_id().into(),
+                    methods: SUPPORTED_METHODS.to_vec(),
+                    transaction_id: self.flow_id.to_string(),
+                });
+
+            to_device_request(
+                &self.other_user_id,
+                vec![DeviceIdOrAllDevices::DeviceId(other_device_id.clone())],
+                content,
+            )
+            .into()
+        };
+
+        *state = RequestState::Ready {
+            other_device_id,
+            methods,
+        };
+
+        Some(request)
+    }
+
+    /// Cancel the verification request.
+    ///
+    /// Returns the request that informs the other side about the cancellation,
+    /// `None` if the request was already canceled or a verification was
+    /// started.
+    pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
+        self.cancel_helper(CancelCode::User)
+    }
+
+    pub(crate) fn cancel_if_timed_out(&self) -> Option<OutgoingVerificationRequest> {
+        if self.timed_out() {
+            self.cancel_helper(CancelCode::Timeout)
+        } else {
+            None
+        }
+    }
+
+    fn cancel_helper(&self, code: CancelCode) -> Option<OutgoingVerificationRequest> {
+        let mut state = self.state.lock().unwrap();
+
+        let recipient = match &*state {
+            RequestState::Started | RequestState::Canceled(_) => return None,
+            RequestState::Passive => {
+                *state = RequestState::Canceled(code);
+                return None;
+            }
+            RequestState::Created => DeviceIdOrAllDevices::AllDevices,
+            RequestState::Requested {
+                other_device_id, ..
+            }
+            | RequestState::Ready {
+                other_device_id, ..
+            } => DeviceIdOrAllDevices::DeviceId(other_device_id.clone()),
+        };
+
+        *state = RequestState::Canceled(code.clone());
+
+        let content = CancelToDeviceEventContent {
+            transaction_id: self.flow_id.to_string(),
+            reason: cancel_reason(&code).to_owned(),
+            code,
+        };
+        let content = AnyToDeviceEventContent::KeyVerificationCancel(content);
+
+        Some(if let Some(flow) = &self.room_flow {
+            RoomMessageRequest {
+                room_id: flow.room_id.clone(),
+                txn_id: Uuid::new_v4(),
+                content: in_room::to_room_content(&flow.event_id, content)?,
+            }
+            .into()
+        } else {
+            to_device_request(&self.other_user_id, vec![recipient], content).into()
+        })
+    }
+
+    /// Create a request that tells the given devices of the other user that
+    /// the request was accepted by another device.
+    pub(crate) fn cancel_other_devices(
+        &self,
+        device_ids: impl Iterator<Item = DeviceIdBox>,
+    ) -> Option<ToDeviceRequest> {
+        let devices: Vec<DeviceIdOrAllDevices> =
+            device_ids.map(DeviceIdOrAllDevices::DeviceId).collect();
+
+        if devices.is_empty() || self.room_flow.is_some() {
+            return None;
+        }
+
+        let content = AnyToDeviceEventContent::KeyVerificationCancel(CancelToDeviceEventContent {
+            transaction_id: self.flow_id.to_string(),
+            reason: cancel_reason(&CancelCode::Accepted).to_owned(),
+            code: CancelCode::Accepted,
+        });
+
+        Some(to_device_request(&self.other_user_id, devices, content))
+    }
+
+    /// Receive a `m.key.verification.ready` event.
+    ///
+    /// Returns true if the request became ready because the other user
+    /// accepted a request we sent out.
+    pub(crate) fn receive_ready(
+        &self,
+        sender: &UserId,
+        from_device: &DeviceId,
+        methods: &[VerificationMethod],
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if sender == self.account.user_id() && from_device != self.account.device_id() {
+            // One of our other devices accepted a request that was sent to
+            // us, there's nothing more for us to do.
+            if let RequestState::Requested { .. } = &*state {
+                *state = RequestState::Passive;
+            }
+
+            return false;
+        }
+
+        if sender != &*self.other_user_id || *state != RequestState::Created {
+            return false;
+        }
+
+        let methods: Vec<VerificationMethod> = methods
+            .iter()
+            .filter(|m| SUPPORTED_METHODS.contains(m))
+            .cloned()
+            .collect();
+
+        if methods.is_empty() {
+            return false;
+        }
+
+        *state = RequestState::Ready {
+            other_device_id: from_device.into(),
+            methods,
+        };
+
+        true
+    }
+
+    /// Receive a `m.key.verification.cancel` event.
+    ///
+    /// Once the request is ready only the device that accepted it takes part
+    /// in the verification. Cancel events don't tell us which device sent
+    /// them, but a `m.accepted` cancel can't come from the device that
+    /// accepted the request, so those are ignored from that point on.
+    pub(crate) fn receive_cancel(&self, sender: &UserId, code: &CancelCode) {
+        if sender != &*self.other_user_id {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if code == &CancelCode::Accepted
+            && matches!(&*state, RequestState::Ready { .. } | RequestState::Started)
+        {
+            return;
+        }
+
+        *state = RequestState::Canceled(code.clone());
+    }
+
+    /// Mark the request as done, a verification was started for it.
+    pub(crate) fn mark_as_started(&self) {
+        *self.state.lock().unwrap() = RequestState::Started;
+    }
+
+    /// Check if the given device of the other user is allowed to start a
+    /// verification for this request.
+    pub(crate) fn can_be_started_by(&self, device_id: &DeviceId) -> bool {
+        if let RequestState::Ready {
+            other_device_id, ..
+        } = &*self.state.lock().unwrap()
+        {
+            &**other_device_id == device_id
+        } else {
+            false
+        }
+    }
+}
+
+fn cancel_reason(code: &CancelCode) -> &'static str {
+    match code {
+        CancelCode::User => "Canceled by user",
+        CancelCode::Timeout => "The verification request timed out",
+        CancelCode::Accepted => "The request was accepted by a different device",
+        _ => "Unknown cancel reason",
+    }
+}
+
+fn to_device_request(
+    recipient: &UserId,
+    devices: Vec<DeviceIdOrAllDevices>,
+    content: AnyToDeviceEventContent,
+) -> ToDeviceRequest {
+    let event_type = EventType::from(content.event_type());
+    let content =
+        serde_json::value::to_raw_value(&content).expect("Can't serialize to-device content");
+
+    let user_messages = devices.into_iter().map(|d| (d, content.clone())).collect();
+
+    let mut messages = BTreeMap::new();
+    messages.insert(recipient.clone(), user_messages);
+
+    ToDeviceRequest {
+        txn_id: Uuid::new_v4(),
+        event_type,
+        messages,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::TryFrom,
+        time::{Duration, SystemTime},
+    };
+
+    use matrix_sdk_common::{
+        api::r0::to_device::DeviceIdOrAllDevices,
+        events::key::verification::{
+            cancel::CancelCode, request::RequestEventContent, VerificationMethod,
+        },
+        identifiers::{DeviceId, UserId},
+    };
+
+    use super::{VerificationRequest, SUPPORTED_METHODS};
+    use crate::{requests::OutgoingVerificationRequest, ReadOnlyAccount};
+
+    fn alice_id() -> UserId {
+        UserId::try_from("@alice:example.org").unwrap()
+    }
+
+    fn alice_device_id() -> Box<DeviceId> {
+        "JLAFKJWSCS".into()
+    }
+
+    fn bob_id() -> UserId {
+        UserId::try_from("@bob:example.org").unwrap()
+    }
+
+    fn bob_account() -> ReadOnlyAccount {
+        let device_id: Box<DeviceId> = "BOBDEVCIE".into();
+        ReadOnlyAccount::new(&bob_id(), &device_id)
+    }
+
+    fn request_content(timestamp: SystemTime) -> RequestEventContent {
+        RequestEventContent {
+            from_device: alice_device_id(),
+            transaction_id: "flow_id".to_owned(),
+            methods: vec![VerificationMethod::MSasV1],
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn expired_request() {
+        let content = request_content(SystemTime::now() - Duration::from_secs(60 * 20));
+        assert!(
+            VerificationRequest::from_request_event(bob_account(), &alice_id(), &content).is_none()
+        );
+
+        let content = request_content(SystemTime::now() + Duration::from_secs(60 * 20));
+        assert!(
+            VerificationRequest::from_request_event(bob_account(), &alice_id(), &content).is_none()
+        );
+    }
+
+    #[test]
+    fn accept_request() {
+        let content = request_content(SystemTime::now());
+        let request =
+            VerificationRequest::from_request_event(bob_account(), &alice_id(), &content).unwrap();
+
+        assert_eq!(request.flow_id(), "flow_id");
+        assert!(!request.we_started());
+        assert!(!request.is_ready());
+
+        let ready = request.accept().unwrap();
+
+        if let OutgoingVerificationRequest::ToDevice(r) = ready {
+            assert!(r.messages[&alice_id()]
+                .contains_key(&DeviceIdOrAllDevices::DeviceId(alice_device_id())));
+        } else {
+            panic!("Invalid verification request type");
+        }
+
+        assert!(request.is_ready());
+        assert_eq!(request.methods(), Some(vec![VerificationMethod::MSasV1]));
+        assert!(request.can_be_started_by(&alice_device_id()));
+        assert!(request.accept().is_none());
+
+        assert!(request.cancel().is_some());
+        assert!(request.is_canceled());
+        assert!(request.cancel().is_none());
+    }
+
+    #[test]
+    fn receive_ready() {
+        let (request, _) = VerificationRequest::new(bob_account(), &alice_id());
+
+        assert!(!request.receive_ready(&bob_id(), &alice_device_id(), SUPPORTED_METHODS));
+        assert!(!request.is_ready());
+
+        assert!(request.receive_ready(&alice_id(), &alice_device_id(), SUPPORTED_METHODS));
+        assert!(request.is_ready());
+        assert_eq!(request.other_device_id(), Some(alice_device_id()));
+
+        // Another device of the other user telling us that the request was
+        // accepted elsewhere doesn't cancel it for the device that did.
+        request.receive_cancel(&alice_id(), &CancelCode::Accepted);
+        assert!(request.is_ready());
+
+        request.receive_cancel(&alice_id(), &CancelCode::User);
+        assert!(request.is_canceled());
+    }
+}
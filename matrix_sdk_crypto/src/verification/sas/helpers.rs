@@ -14,6 +14,7 @@
 
 use std::{collections::BTreeMap, convert::TryInto};
 
+use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use tracing::{trace, warn};
 
@@ -56,10 +57,43 @@ pub struct SasIds {
 /// * `content` - The `m.key.verification.start` event content that started the
 /// interactive verification process.
 pub fn calculate_commitment(public_key: &str, content: &StartToDeviceEventContent) -> String {
-    let json_content: CanonicalJsonValue = serde_json::to_value(content)
-        .expect("Can't serialize content")
-        .try_into()
-        .expect("Can't canonicalize content");
+    let json_content = serde_json::to_value(content).expect("Can't serialize content");
+    commitment_from_json(public_key, json_content)
+}
+
+/// Calculate the commitment for a accept event of an in-room verification.
+///
+/// In-room start events don't contain a transaction id, the flow is instead
+/// identified by a `m.relates_to` field referencing the verification request.
+///
+/// # Arguments
+///
+/// * `public_key` - Our own ephemeral public key that is used for the
+/// interactive verification.
+///
+/// * `content` - The `m.key.verification.start` event content, converted into
+/// the to-device form, where the transaction id is the flow id.
+pub fn calculate_room_commitment(public_key: &str, content: &StartToDeviceEventContent) -> String {
+    let mut json_content = serde_json::to_value(content).expect("Can't serialize content");
+
+    if let Some(object) = json_content.as_object_mut() {
+        if let Some(flow_id) = object.remove("transaction_id") {
+            object.insert(
+                "m.relates_to".to_owned(),
+                json!({
+                    "rel_type": "m.reference",
+                    "event_id": flow_id,
+                }),
+            );
+        }
+    }
+
+    commitment_from_json(public_key, json_content)
+}
+
+fn commitment_from_json(public_key: &str, json_content: Value) -> String {
+    let json_content: CanonicalJsonValue =
+        json_content.try_into().expect("Can't canonicalize content");
 
     encode(
         Sha256::new()
@@ -509,6 +543,7 @@ pub fn content_to_request(
         AnyToDeviceEventContent::KeyVerificationKey(_) => EventType::KeyVerificationKey,
         AnyToDeviceEventContent::KeyVerificationMac(_) => EventType::KeyVerificationMac,
         AnyToDeviceEventContent::KeyVerificationCancel(_) => EventType::KeyVerificationCancel,
+        AnyToDeviceEventContent::KeyVerificationDone(_) => EventType::KeyVerificationDone,
         _ => unreachable!(),
     };
 
@@ -527,7 +562,7 @@ mod test {
 
     use super::{
         bytes_to_decimal, bytes_to_emoji, bytes_to_emoji_index, calculate_commitment,
-        emoji_from_index,
+        calculate_room_commitment, commitment_from_json, emoji_from_index,
     };
 
     #[test]
@@ -551,6 +586,44 @@ mod test {
         assert_eq!(commitment, &calculated_commitment);
     }
 
+    #[test]
+    fn room_commitment_calculation() {
+        let public_key = "Q/NmNFEUS1fS+YeEmiZkjjblKTitrKOAk7cPEumcMlg";
+        let content = json!({
+            "from_device":"XOWLHHFSWM",
+            "transaction_id":"$bYxBsirjUJO9osar6ST4i2M2NjrYLA7l:example.org",
+            "method":"m.sas.v1",
+            "key_agreement_protocols":["curve25519-hkdf-sha256","curve25519"],
+            "hashes":["sha256"],
+            "message_authentication_codes":["hkdf-hmac-sha256","hmac-sha256"],
+            "short_authentication_string":["decimal","emoji"]
+        });
+
+        let room_content = json!({
+            "from_device":"XOWLHHFSWM",
+            "m.relates_to": {
+                "rel_type": "m.reference",
+                "event_id": "$bYxBsirjUJO9osar6ST4i2M2NjrYLA7l:example.org",
+            },
+            "method":"m.sas.v1",
+            "key_agreement_protocols":["curve25519-hkdf-sha256","curve25519"],
+            "hashes":["sha256"],
+            "message_authentication_codes":["hkdf-hmac-sha256","hmac-sha256"],
+            "short_authentication_string":["decimal","emoji"]
+        });
+
+        let content: StartToDeviceEventContent = serde_json::from_value(content).unwrap();
+
+        assert_eq!(
+            calculate_room_commitment(public_key, &content),
+            commitment_from_json(public_key, room_content)
+        );
+        assert_ne!(
+            calculate_room_commitment(public_key, &content),
+            calculate_commitment(public_key, &content)
+        );
+    }
+
     #[test]
     fn emoji_generation() {
         let bytes = vec![0, 0, 0, 0, 0, 0];
@@ -19,7 +19,6 @@ mod sas_state;
 use std::time::Instant;
 
 use std::sync::{Arc, Mutex};
-use tracing::{error, info, trace, warn};
 
 use matrix_sdk_common::{
     api::r0::keys::upload_signatures::Request as SignatureUploadRequest,
@@ -30,15 +29,20 @@ use matrix_sdk_common::{
         },
         AnyToDeviceEvent, AnyToDeviceEventContent, ToDeviceEvent,
     },
-    identifiers::{DeviceId, UserId},
+    identifiers::{DeviceId, EventId, RoomId, UserId},
+    uuid::Uuid,
 };
 
+use super::{
+    in_room::{to_room_content, RoomFlowId},
+    IdentitiesBeingVerified, VerificationResult,
+};
 use crate::{
-    error::SignatureError,
-    identities::{LocalTrust, ReadOnlyDevice, UserIdentities},
+    identities::{ReadOnlyDevice, UserIdentities},
     olm::PrivateCrossSigningIdentity,
-    store::{Changes, CryptoStore, CryptoStoreError, DeviceChanges},
-    ReadOnlyAccount, ToDeviceRequest,
+    requests::{OutgoingVerificationRequest, RoomMessageRequest},
+    store::{CryptoStore, CryptoStoreError},
+    ReadOnlyAccount,
 };
 
 pub use helpers::content_to_request;
@@ -46,27 +50,14 @@ use sas_state::{
     Accepted, Canceled, Confirmed, Created, Done, KeyReceived, MacReceived, SasState, Started,
 };
 
-#[derive(Debug)]
-/// A result of a verification flow.
-pub enum VerificationResult {
-    /// The verification succeeded, nothing needs to be done.
-    Ok,
-    /// The verification was canceled.
-    Cancel(ToDeviceRequest),
-    /// The verification is done and has signatures that need to be uploaded.
-    SignatureUpload(SignatureUploadRequest),
-}
-
 #[derive(Clone, Debug)]
 /// Short authentication string object.
 pub struct Sas {
     inner: Arc<Mutex<InnerSas>>,
-    store: Arc<Box<dyn CryptoStore>>,
     account: ReadOnlyAccount,
-    private_identity: PrivateCrossSigningIdentity,
-    other_device: ReadOnlyDevice,
-    other_identity: Option<UserIdentities>,
+    identities_being_verified: IdentitiesBeingVerified,
     flow_id: Arc<str>,
+    room_flow: Option<Arc<RoomFlowId>>,
 }
 
 impl Sas {
@@ -82,24 +73,38 @@ impl Sas {
 
     /// Get the user id of the other side.
     pub fn other_user_id(&self) -> &UserId {
-        self.other_device.user_id()
+        self.identities_being_verified.other_user_id()
     }
 
     /// Get the device id of the other side.
     pub fn other_device_id(&self) -> &DeviceId {
-        self.other_device.device_id()
+        self.identities_being_verified.other_device_id()
     }
 
     /// Get the device of the other user.
     pub fn other_device(&self) -> ReadOnlyDevice {
-        self.other_device.clone()
+        self.identities_being_verified.other_device().clone()
     }
 
     /// Get the unique ID that identifies this SAS verification flow.
+    ///
+    /// For in-room verifications this is the event id of the
+    /// `m.key.verification.request` room message.
     pub fn flow_id(&self) -> &str {
         &self.flow_id
     }
 
+    /// Get the id of the room the verification is happening in.
+    ///
+    /// Returns `None` if the verification is done using to-device messages.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.room_flow().map(|f| &f.room_id)
+    }
+
+    pub(crate) fn room_flow(&self) -> Option<&RoomFlowId> {
+        self.room_flow.as_deref()
+    }
+
     #[cfg(test)]
     #[allow(dead_code)]
     pub(crate) fn set_creation_time(&self, time: Instant) {
@@ -114,6 +119,10 @@ impl Sas {
     ///
     /// * `other_device` - The other device which we are going to verify.
     ///
+    /// * `transaction_id` - The transaction id of the verification request
+    /// that preceded this verification, a new one will be generated if this
+    /// is `None`.
+    ///
     /// Returns the new `Sas` object and a `StartEventContent` that needs to be
     /// sent out through the server to the other device.
     pub(crate) fn start(
@@ -122,22 +131,78 @@ impl Sas {
         other_device: ReadOnlyDevice,
         store: Arc<Box<dyn CryptoStore>>,
         other_identity: Option<UserIdentities>,
+        transaction_id: Option<String>,
     ) -> (Sas, StartToDeviceEventContent) {
         let (inner, content) = InnerSas::start(
             account.clone(),
             other_device.clone(),
             other_identity.clone(),
+            transaction_id,
         );
         let flow_id = inner.verification_flow_id();
 
         let sas = Sas {
             inner: Arc::new(Mutex::new(inner)),
             account,
-            private_identity,
-            store,
-            other_device,
+            identities_being_verified: IdentitiesBeingVerified {
+                private_identity,
+                store,
+                device_being_verified: other_device,
+                identity_being_verified: other_identity,
+            },
             flow_id,
-            other_identity,
+            room_flow: None,
+        };
+
+        (sas, content)
+    }
+
+    /// Start a new SAS auth flow with the given device inside the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The event id of the `m.key.verification.request` room
+    /// message that started the verification.
+    ///
+    /// * `room_id` - The room the verification is happening in.
+    ///
+    /// * `account` - Our own account.
+    ///
+    /// * `other_device` - The other device which we are going to verify.
+    ///
+    /// Returns the new `Sas` object and a `StartEventContent` that needs to be
+    /// sent out through the server to the other device, use
+    /// [`content_to_request`] to convert it into a room message.
+    pub(crate) fn start_in_room(
+        flow_id: EventId,
+        room_id: RoomId,
+        account: ReadOnlyAccount,
+        private_identity: PrivateCrossSigningIdentity,
+        other_device: ReadOnlyDevice,
+        store: Arc<Box<dyn CryptoStore>>,
+        other_identity: Option<UserIdentities>,
+    ) -> (Sas, StartToDeviceEventContent) {
+        let (inner, content) = InnerSas::start_in_room(
+            &flow_id,
+            account.clone(),
+            other_device.clone(),
+            other_identity.clone(),
+        );
+
+        let sas = Sas {
+            inner: Arc::new(Mutex::new(inner)),
+            account,
+            identities_being_verified: IdentitiesBeingVerified {
+                private_identity,
+                store,
+                device_being_verified: other_device,
+                identity_being_verified: other_identity,
+            },
+            flow_id: flow_id.as_str().into(),
+            room_flow: Some(Arc::new(RoomFlowId {
+                room_id,
+                event_id: flow_id,
+            })),
         };
 
         (sas, content)
@@ -153,6 +218,10 @@ impl Sas {
     ///
     /// * `event` - The m.key.verification.start event that was sent to us by
     /// the other side.
+    ///
+    /// * `room_flow` - The room and request event id if the start event was
+    /// sent to us as a room event, the event needs to be converted into the
+    /// to-device form with the request event id as the transaction id.
     pub(crate) fn from_start_event(
         account: ReadOnlyAccount,
         private_identity: PrivateCrossSigningIdentity,
@@ -160,23 +229,28 @@ impl Sas {
         store: Arc<Box<dyn CryptoStore>>,
         event: &ToDeviceEvent<StartToDeviceEventContent>,
         other_identity: Option<UserIdentities>,
+        room_flow: Option<RoomFlowId>,
     ) -> Result<Sas, AnyToDeviceEventContent> {
         let inner = InnerSas::from_start_event(
             account.clone(),
             other_device.clone(),
             event,
             other_identity.clone(),
+            room_flow.is_some(),
         )?;
         let flow_id = inner.verification_flow_id();
 
         Ok(Sas {
             inner: Arc::new(Mutex::new(inner)),
             account,
-            private_identity,
-            other_device,
-            other_identity,
-            store,
+            identities_being_verified: IdentitiesBeingVerified {
+                private_identity,
+                store,
+                device_being_verified: other_device,
+                identity_being_verified: other_identity,
+            },
             flow_id,
+            room_flow: room_flow.map(Arc::new),
         })
     }
 
@@ -184,8 +258,8 @@ impl Sas {
     ///
     /// This does nothing if the verification was already accepted, otherwise it
     /// returns an `AcceptEventContent` that needs to be sent out.
-    pub fn accept(&self) -> Option<ToDeviceRequest> {
-        self.inner.lock().unwrap().accept().map(|c| {
+    pub fn accept(&self) -> Option<OutgoingVerificationRequest> {
+        self.inner.lock().unwrap().accept().and_then(|c| {
             let content = AnyToDeviceEventContent::KeyVerificationAccept(c);
             self.content_to_request(content)
         })
@@ -200,7 +274,13 @@ impl Sas {
     /// the server.
     pub async fn confirm(
         &self,
-    ) -> Result<(Option<ToDeviceRequest>, Option<SignatureUploadRequest>), CryptoStoreError> {
+    ) -> Result<
+        (
+            Option<OutgoingVerificationRequest>,
+            Option<SignatureUploadRequest>,
+        ),
+        CryptoStoreError,
+    > {
         let (content, done) = {
             let mut guard = self.inner.lock().unwrap();
             let sas: InnerSas = (*guard).clone();
@@ -211,11 +291,11 @@ impl Sas {
         };
 
         let mac_request = content
-            .map(|c| self.content_to_request(AnyToDeviceEventContent::KeyVerificationMac(c)));
+            .and_then(|c| self.content_to_request(AnyToDeviceEventContent::KeyVerificationMac(c)));
 
         if done {
             match self.mark_as_done().await? {
-                VerificationResult::Cancel(r) => Ok((Some(r), None)),
+                VerificationResult::Cancel(c) => Ok((self.cancel_with_code(c), None)),
                 VerificationResult::Ok => Ok((mac_request, None)),
                 VerificationResult::SignatureUpload(r) => Ok((mac_request, Some(r))),
             }
@@ -225,225 +305,12 @@ impl Sas {
     }
 
     pub(crate) async fn mark_as_done(&self) -> Result<VerificationResult, CryptoStoreError> {
-        if let Some(device) = self.mark_device_as_verified().await? {
-            let identity = self.mark_identity_as_verified().await?;
-
-            // We only sign devices of our own user here.
-            let signature_request = if device.user_id() == self.user_id() {
-                match self.private_identity.sign_device(&device).await {
-                    Ok(r) => Some(r),
-                    Err(SignatureError::MissingSigningKey) => {
-                        warn!(
-                            "Can't sign the device keys for {} {}, \
-                                  no private user signing key found",
-                            device.user_id(),
-                            device.device_id(),
-                        );
-
-                        None
-                    }
-                    Err(e) => {
-                        error!(
-                            "Error signing device keys for {} {} {:?}",
-                            device.user_id(),
-                            device.device_id(),
-                            e
-                        );
-                        None
-                    }
-                }
-            } else {
-                None
-            };
-
-            let mut changes = Changes {
-                devices: DeviceChanges {
-                    changed: vec![device],
-                    ..Default::default()
-                },
-                ..Default::default()
-            };
-
-            let identity_signature_request = if let Some(i) = identity {
-                // We only sign other users here.
-                let request = if let Some(i) = i.other() {
-                    // Signing can fail if the user signing key is missing.
-                    match self.private_identity.sign_user(&i).await {
-                        Ok(r) => Some(r),
-                        Err(SignatureError::MissingSigningKey) => {
-                            warn!(
-                                "Can't sign the public cross signing keys for {}, \
-                                  no private user signing key found",
-                                i.user_id()
-                            );
-                            None
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error signing the public cross signing keys for {} {:?}",
-                                i.user_id(),
-                                e
-                            );
-                            None
-                        }
-                    }
-                } else {
-                    None
-                };
-
-                changes.identities.changed.push(i);
-
-                request
-            } else {
-                None
-            };
-
-            // If there are two signature upload requests, merge them. Otherwise
-            // use the one we have or None.
-            //
-            // Realistically at most one reuqest will be used but let's make
-            // this future proof.
-            let merged_request = if let Some(mut r) = signature_request {
-                if let Some(user_request) = identity_signature_request {
-                    r.signed_keys.extend(user_request.signed_keys);
-                    Some(r)
-                } else {
-                    Some(r)
-                }
-            } else if let Some(r) = identity_signature_request {
-                Some(r)
-            } else {
-                None
-            };
-
-            // TODO store the request as well.
-            self.store.save_changes(changes).await?;
-            Ok(merged_request
-                .map(VerificationResult::SignatureUpload)
-                .unwrap_or(VerificationResult::Ok))
-        } else {
-            Ok(self
-                .cancel()
-                .map(VerificationResult::Cancel)
-                .unwrap_or(VerificationResult::Ok))
-        }
-    }
-
-    pub(crate) async fn mark_identity_as_verified(
-        &self,
-    ) -> Result<Option<UserIdentities>, CryptoStoreError> {
-        // If there wasn't an identity available during the verification flow
-        // return early as there's nothing to do.
-        if self.other_identity.is_none() {
-            return Ok(None);
-        }
-
-        // TODO signal an error, e.g. when the identity got deleted so we don't
-        // verify/save the device either.
-        let identity = self.store.get_user_identity(self.other_user_id()).await?;
-
-        if let Some(identity) = identity {
-            if self
-                .other_identity
-                .as_ref()
-                .map_or(false, |i| i.master_key() == identity.master_key())
-            {
-                if self
-                    .verified_identities()
-                    .map_or(false, |i| i.contains(&identity))
-                {
-                    trace!(
-                        "Marking user identity of {} as verified.",
-                        identity.user_id(),
-                    );
-
-                    if let UserIdentities::Own(i) = &identity {
-                        i.mark_as_verified();
-                    }
-
-                    Ok(Some(identity))
-                } else {
-                    info!(
-                        "The interactive verification process didn't contain a \
-                        MAC for the user identity of {} {:?}",
-                        identity.user_id(),
-                        self.verified_identities(),
-                    );
-
-                    Ok(None)
-                }
-            } else {
-                warn!(
-                    "The master keys of {} have changed while an interactive \
-                      verification was going on, not marking the identity as verified.",
-                    identity.user_id(),
-                );
-
-                Ok(None)
-            }
-        } else {
-            info!(
-                "The identity for {} was deleted while an interactive \
-                  verification was going on.",
-                self.other_user_id(),
-            );
-            Ok(None)
-        }
-    }
-
-    pub(crate) async fn mark_device_as_verified(
-        &self,
-    ) -> Result<Option<ReadOnlyDevice>, CryptoStoreError> {
-        let device = self
-            .store
-            .get_device(self.other_user_id(), self.other_device_id())
-            .await?;
-
-        if let Some(device) = device {
-            if device.keys() == self.other_device.keys() {
-                if self
-                    .verified_devices()
-                    .map_or(false, |v| v.contains(&device))
-                {
-                    trace!(
-                        "Marking device {} {} as verified.",
-                        device.user_id(),
-                        device.device_id()
-                    );
-
-                    device.set_trust_state(LocalTrust::Verified);
-
-                    Ok(Some(device))
-                } else {
-                    info!(
-                        "The interactive verification process didn't contain a \
-                        MAC for the device {} {}",
-                        device.user_id(),
-                        device.device_id()
-                    );
-
-                    Ok(None)
-                }
-            } else {
-                warn!(
-                    "The device keys of {} {} have changed while an interactive \
-                      verification was going on, not marking the device as verified.",
-                    device.user_id(),
-                    device.device_id()
-                );
-                Ok(None)
-            }
-        } else {
-            let device = self.other_device();
-
-            info!(
-                "The device {} {} was deleted while an interactive \
-                  verification was going on.",
-                device.user_id(),
-                device.device_id()
-            );
-            Ok(None)
-        }
+        self.identities_being_verified
+            .mark_as_done(
+                self.verified_devices().as_deref(),
+                self.verified_identities().as_deref(),
+            )
+            .await
     }
 
     /// Cancel the verification.
@@ -452,24 +319,24 @@ impl Sas {
     ///
     /// Returns None if the `Sas` object is already in a canceled state,
     /// otherwise it returns a request that needs to be sent out.
-    pub fn cancel(&self) -> Option<ToDeviceRequest> {
+    pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
+        self.cancel_with_code(CancelCode::User)
+    }
+
+    pub(crate) fn cancel_with_code(&self, code: CancelCode) -> Option<OutgoingVerificationRequest> {
         let mut guard = self.inner.lock().unwrap();
         let sas: InnerSas = (*guard).clone();
-        let (sas, content) = sas.cancel(CancelCode::User);
+        let (sas, content) = sas.cancel(code);
         *guard = sas;
 
-        content.map(|c| self.content_to_request(c))
+        content.and_then(|c| self.content_to_request(c))
     }
 
-    pub(crate) fn cancel_if_timed_out(&self) -> Option<ToDeviceRequest> {
+    pub(crate) fn cancel_if_timed_out(&self) -> Option<OutgoingVerificationRequest> {
         if self.is_canceled() || self.is_done() {
             None
         } else if self.timed_out() {
-            let mut guard = self.inner.lock().unwrap();
-            let sas: InnerSas = (*guard).clone();
-            let (sas, content) = sas.cancel(CancelCode::Timeout);
-            *guard = sas;
-            content.map(|c| self.content_to_request(c))
+            self.cancel_with_code(CancelCode::Timeout)
         } else {
             None
         }
@@ -532,8 +399,26 @@ impl Sas {
         self.inner.lock().unwrap().verified_identities()
     }
 
-    pub(crate) fn content_to_request(&self, content: AnyToDeviceEventContent) -> ToDeviceRequest {
-        content_to_request(self.other_user_id(), self.other_device_id(), content)
+    /// Convert the given content into a request that sends the content to
+    /// the other side.
+    ///
+    /// This will be a to-device request or a room message request depending on
+    /// where the verification is happening. Returns `None` if the content
+    /// can't be sent as part of an in-room verification.
+    pub(crate) fn content_to_request(
+        &self,
+        content: AnyToDeviceEventContent,
+    ) -> Option<OutgoingVerificationRequest> {
+        Some(if let Some(flow) = &self.room_flow {
+            RoomMessageRequest {
+                room_id: flow.room_id.clone(),
+                txn_id: Uuid::new_v4(),
+                content: to_room_content(&flow.event_id, content)?,
+            }
+            .into()
+        } else {
+            content_to_request(self.other_user_id(), self.other_device_id(), content).into()
+        })
     }
 }
 
@@ -554,8 +439,24 @@ impl InnerSas {
         account: ReadOnlyAccount,
         other_device: ReadOnlyDevice,
         other_identity: Option<UserIdentities>,
+        transaction_id: Option<String>,
+    ) -> (InnerSas, StartToDeviceEventContent) {
+        let sas = if let Some(t) = transaction_id {
+            SasState::<Created>::new_with_transaction_id(t, account, other_device, other_identity)
+        } else {
+            SasState::<Created>::new(account, other_device, other_identity)
+        };
+        let content = sas.as_content();
+        (InnerSas::Created(sas), content)
+    }
+
+    fn start_in_room(
+        flow_id: &EventId,
+        account: ReadOnlyAccount,
+        other_device: ReadOnlyDevice,
+        other_identity: Option<UserIdentities>,
     ) -> (InnerSas, StartToDeviceEventContent) {
-        let sas = SasState::<Created>::new(account, other_device, other_identity);
+        let sas = SasState::<Created>::new_in_room(flow_id, account, other_device, other_identity);
         let content = sas.as_content();
         (InnerSas::Created(sas), content)
     }
@@ -565,8 +466,15 @@ impl InnerSas {
         other_device: ReadOnlyDevice,
         event: &ToDeviceEvent<StartToDeviceEventContent>,
         other_identity: Option<UserIdentities>,
+        in_room: bool,
     ) -> Result<InnerSas, AnyToDeviceEventContent> {
-        match SasState::<Started>::from_start_event(account, other_device, event, other_identity) {
+        match SasState::<Started>::from_start_event(
+            account,
+            other_device,
+            event,
+            other_identity,
+            in_room,
+        ) {
             Ok(s) => Ok(InnerSas::Started(s)),
             Err(s) => Err(s.as_content()),
         }
@@ -819,7 +727,7 @@ mod test {
         let event = wrap_to_device_event(alice_sas.user_id(), start_content);
 
         let bob_sas =
-            SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None);
+            SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None, false);
 
         (alice_sas, bob_sas.unwrap())
     }
@@ -911,6 +819,7 @@ mod test {
             bob_device,
             alice_store,
             None,
+            None,
         );
         let event = wrap_to_device_event(alice.user_id(), content);
 
@@ -921,6 +830,7 @@ mod test {
             bob_store,
             &event,
             None,
+            None,
         )
         .unwrap();
         let mut event = wrap_any_to_device_content(
@@ -37,13 +37,14 @@ use matrix_sdk_common::{
         },
         AnyToDeviceEventContent, ToDeviceEvent,
     },
-    identifiers::{DeviceId, UserId},
+    identifiers::{DeviceId, EventId, UserId},
     uuid::Uuid,
 };
 use tracing::error;
 
 use super::helpers::{
-    calculate_commitment, get_decimal, get_emoji, get_mac_content, receive_mac_event, SasIds,
+    calculate_commitment, calculate_room_commitment, get_decimal, get_emoji, get_mac_content,
+    receive_mac_event, SasIds,
 };
 
 use crate::{
@@ -165,6 +166,7 @@ impl<S: Clone + std::fmt::Debug> std::fmt::Debug for SasState<S> {
 #[derive(Clone, Debug)]
 pub struct Created {
     protocol_definitions: MSasV1ContentInit,
+    in_room: bool,
 }
 
 /// The initial SAS state if the other side started the SAS verification.
@@ -181,6 +183,7 @@ pub struct Accepted {
     accepted_protocols: Arc<AcceptedProtocols>,
     start_content: Arc<StartToDeviceEventContent>,
     commitment: String,
+    in_room: bool,
 }
 
 /// The SAS state we're going to be in after we received the public key of the
@@ -296,6 +299,66 @@ impl SasState<Created> {
     ) -> SasState<Created> {
         let verification_flow_id = Uuid::new_v4().to_string();
 
+        Self::new_helper(
+            verification_flow_id,
+            false,
+            account,
+            other_device,
+            other_identity,
+        )
+    }
+
+    /// Create a new SAS verification flow that uses the transaction id of a
+    /// preceding verification request.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction_id` - The transaction id of the verification request.
+    ///
+    /// * `account` - Our own account.
+    ///
+    /// * `other_device` - The other device which we are going to verify.
+    pub fn new_with_transaction_id(
+        transaction_id: String,
+        account: ReadOnlyAccount,
+        other_device: ReadOnlyDevice,
+        other_identity: Option<UserIdentities>,
+    ) -> SasState<Created> {
+        Self::new_helper(transaction_id, false, account, other_device, other_identity)
+    }
+
+    /// Create a new SAS verification flow that is done in a room.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The event id of the `m.key.verification.request` event
+    /// that started the verification.
+    ///
+    /// * `account` - Our own account.
+    ///
+    /// * `other_device` - The other device which we are going to verify.
+    pub fn new_in_room(
+        flow_id: &EventId,
+        account: ReadOnlyAccount,
+        other_device: ReadOnlyDevice,
+        other_identity: Option<UserIdentities>,
+    ) -> SasState<Created> {
+        Self::new_helper(
+            flow_id.to_string(),
+            true,
+            account,
+            other_device,
+            other_identity,
+        )
+    }
+
+    fn new_helper(
+        verification_flow_id: String,
+        in_room: bool,
+        account: ReadOnlyAccount,
+        other_device: ReadOnlyDevice,
+        other_identity: Option<UserIdentities>,
+    ) -> SasState<Created> {
         SasState {
             inner: Arc::new(Mutex::new(OlmSas::new())),
             ids: SasIds {
@@ -315,6 +378,7 @@ impl SasState<Created> {
                     message_authentication_codes: MACS.to_vec(),
                     hashes: HASHES.to_vec(),
                 },
+                in_room,
             }),
         }
     }
@@ -363,6 +427,7 @@ impl SasState<Created> {
                     start_content,
                     commitment: content.commitment.clone(),
                     accepted_protocols: accepted_protocols.into(),
+                    in_room: self.state.in_room,
                 }),
             })
         } else {
@@ -385,17 +450,25 @@ impl SasState<Started> {
     ///
     /// * `event` - The m.key.verification.start event that was sent to us by
     /// the other side.
+    ///
+    /// * `in_room` - Was the event sent to us as a room event, the transaction
+    /// id of the event needs to be the event id of the verification request.
     pub fn from_start_event(
         account: ReadOnlyAccount,
         other_device: ReadOnlyDevice,
         event: &ToDeviceEvent<StartToDeviceEventContent>,
         other_identity: Option<UserIdentities>,
+        in_room: bool,
     ) -> Result<SasState<Started>, SasState<Canceled>> {
         if let StartMethod::MSasV1(content) = &event.content.method {
             let sas = OlmSas::new();
 
             let pubkey = sas.public_key();
-            let commitment = calculate_commitment(&pubkey, &event.content);
+            let commitment = if in_room {
+                calculate_room_commitment(&pubkey, &event.content)
+            } else {
+                calculate_commitment(&pubkey, &event.content)
+            };
 
             error!(
                 "Calculated commitment for pubkey {} and content {:?} {}",
@@ -544,7 +617,11 @@ impl SasState<Accepted> {
         self.check_event(&event.sender, &event.content.transaction_id)
             .map_err(|c| self.clone().cancel(c))?;
 
-        let commitment = calculate_commitment(&event.content.key, &self.state.start_content);
+        let commitment = if self.state.in_room {
+            calculate_room_commitment(&event.content.key, &self.state.start_content)
+        } else {
+            calculate_commitment(&event.content.key, &self.state.start_content)
+        };
 
         if self.state.commitment != commitment {
             Err(self.cancel(CancelCode::InvalidMessage))
@@ -850,7 +927,7 @@ mod test {
             },
             EventContent, ToDeviceEvent,
         },
-        identifiers::{DeviceId, UserId},
+        identifiers::{DeviceId, EventId, UserId},
     };
 
     use super::{Accepted, Created, SasState, Started};
@@ -891,7 +968,29 @@ mod test {
         let event = wrap_to_device_event(alice_sas.user_id(), start_content);
 
         let bob_sas =
-            SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None);
+            SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None, false);
+
+        (alice_sas, bob_sas.unwrap())
+    }
+
+    async fn get_room_sas_pair() -> (SasState<Created>, SasState<Started>) {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+
+        let flow_id = EventId::try_from("$request:example.org").unwrap();
+
+        let alice_sas = SasState::<Created>::new_in_room(&flow_id, alice.clone(), bob_device, None);
+
+        let start_content = alice_sas.as_content();
+        assert_eq!(start_content.transaction_id, flow_id.as_str());
+
+        let event = wrap_to_device_event(alice_sas.user_id(), start_content);
+
+        let bob_sas =
+            SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None, true);
 
         (alice_sas, bob_sas.unwrap())
     }
@@ -929,6 +1028,56 @@ mod test {
         assert_eq!(alice.get_emoji(), bob.get_emoji());
     }
 
+    #[tokio::test]
+    async fn sas_room_key_share() {
+        let (alice, bob) = get_room_sas_pair().await;
+
+        let event = wrap_to_device_event(bob.user_id(), bob.as_content());
+
+        let alice: SasState<Accepted> = alice.into_accepted(&event).unwrap();
+        let mut event = wrap_to_device_event(alice.user_id(), alice.as_content());
+
+        let bob = bob.into_key_received(&mut event).unwrap();
+
+        let mut event = wrap_to_device_event(bob.user_id(), bob.as_content());
+
+        let alice = alice.into_key_received(&mut event).unwrap();
+
+        assert_eq!(alice.get_decimal(), bob.get_decimal());
+        assert_eq!(alice.get_emoji(), bob.get_emoji());
+    }
+
+    #[tokio::test]
+    async fn sas_room_commitment_mismatch() {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+
+        let flow_id = EventId::try_from("$request:example.org").unwrap();
+
+        let alice_sas = SasState::<Created>::new_in_room(&flow_id, alice.clone(), bob_device, None);
+        let event = wrap_to_device_event(alice_sas.user_id(), alice_sas.as_content());
+
+        // Bob thinks this is a to-device verification, so the commitment
+        // he calculates won't match the one Alice expects.
+        let bob_sas =
+            SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None, false)
+                .unwrap();
+
+        let event = wrap_to_device_event(bob_sas.user_id(), bob_sas.as_content());
+        let alice_sas: SasState<Accepted> = alice_sas.into_accepted(&event).unwrap();
+        let mut event = wrap_to_device_event(alice_sas.user_id(), alice_sas.as_content());
+
+        let bob_sas = bob_sas.into_key_received(&mut event).unwrap();
+        let mut event = wrap_to_device_event(bob_sas.user_id(), bob_sas.as_content());
+
+        alice_sas
+            .into_key_received(&mut event)
+            .expect_err("Didn't cancel on a commitment calculated over the wrong content");
+    }
+
     #[tokio::test]
     async fn sas_full() {
         let (alice, bob) = get_sas_pair().await;
@@ -1054,8 +1203,14 @@ mod test {
         }
 
         let event = wrap_to_device_event(alice_sas.user_id(), start_content);
-        SasState::<Started>::from_start_event(bob.clone(), alice_device.clone(), &event, None)
-            .expect_err("Didn't cancel on invalid MAC method");
+        SasState::<Started>::from_start_event(
+            bob.clone(),
+            alice_device.clone(),
+            &event,
+            None,
+            false,
+        )
+        .expect_err("Didn't cancel on invalid MAC method");
 
         let mut start_content = alice_sas.as_content();
 
@@ -1065,7 +1220,7 @@ mod test {
         });
 
         let event = wrap_to_device_event(alice_sas.user_id(), start_content);
-        SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None)
+        SasState::<Started>::from_start_event(bob.clone(), alice_device, &event, None, false)
             .expect_err("Didn't cancel on unknown sas method");
     }
 }
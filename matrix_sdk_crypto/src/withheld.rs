@@ -0,0 +1,145 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for the `m.room_key.withheld` event, used to tell other devices
+//! that we intentionally didn't share a room key with them.
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
+use thiserror::Error;
+
+use matrix_sdk_common::identifiers::{EventEncryptionAlgorithm, RoomId, UserId};
+
+/// The event type of room key withheld notifications.
+pub(crate) const ROOM_KEY_WITHHELD_EVENT_TYPE: &str = "m.room_key.withheld";
+
+/// The reason why a room key was withheld from a device.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WithheldCode {
+    /// The sender has blacklisted the device.
+    #[serde(rename = "m.blacklisted")]
+    #[error("the sender blocked this device")]
+    Blacklisted,
+    /// The sender only shares room keys with verified devices and this device
+    /// isn't verified.
+    #[serde(rename = "m.unverified")]
+    #[error("the sender doesn't share room keys with unverified devices")]
+    Unverified,
+    /// The device isn't allowed to see the messages, e.g. it requested a room
+    /// key for a message it shouldn't have access to.
+    #[serde(rename = "m.unauthorised")]
+    #[error("the device isn't authorized to receive the room key")]
+    Unauthorised,
+    /// The sender doesn't have the room key anymore, or never had it.
+    #[serde(rename = "m.unavailable")]
+    #[error("the room key isn't available")]
+    Unavailable,
+    /// The sender couldn't establish an Olm session with the device.
+    #[serde(rename = "m.no_olm")]
+    #[error("the sender couldn't establish an Olm session with this device")]
+    NoOlm,
+}
+
+/// The content of a `m.room_key.withheld` event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RoomKeyWithheldContent {
+    pub algorithm: EventEncryptionAlgorithm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<RoomId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    pub sender_key: String,
+    pub code: WithheldCode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl RoomKeyWithheldContent {
+    /// Create a new withheld notification for the given Megolm session.
+    pub fn new(room_id: &RoomId, session_id: &str, sender_key: &str, code: WithheldCode) -> Self {
+        Self {
+            algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
+            room_id: Some(room_id.clone()),
+            session_id: Some(session_id.to_owned()),
+            sender_key: sender_key.to_owned(),
+            code,
+            reason: Some(code.to_string()),
+        }
+    }
+}
+
+/// The parts of a `m.room_key.withheld` to-device event that we care about.
+#[derive(Clone, Debug, Deserialize)]
+struct RoomKeyWithheldEvent {
+    sender: UserId,
+    content: RoomKeyWithheldContent,
+}
+
+/// Parse a raw `m.room_key.withheld` to-device event.
+///
+/// Returns the sender and the content of the event, or `None` if the event
+/// is malformed.
+pub(crate) fn parse_withheld_event(
+    event: &RawJsonValue,
+) -> Option<(UserId, RoomKeyWithheldContent)> {
+    serde_json::from_str::<RoomKeyWithheldEvent>(event.get())
+        .ok()
+        .map(|e| (e.sender, e.content))
+}
+
+/// The key under which we store withheld info for the given Megolm session.
+///
+/// The key includes the sender of the notice, so a notice from one user can't
+/// be mistaken for a notice about a session of another user.
+pub(crate) fn withheld_key(
+    room_id: &RoomId,
+    sender: &UserId,
+    sender_key: &str,
+    session_id: &str,
+) -> String {
+    format!(
+        "room_key_withheld|{}|{}|{}|{}",
+        room_id, sender, sender_key, session_id
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::identifiers::room_id;
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{parse_withheld_event, RoomKeyWithheldContent, WithheldCode};
+
+    #[test]
+    fn withheld_event_parsing() {
+        let room_id = room_id!("!test:example.org");
+        let content =
+            RoomKeyWithheldContent::new(&room_id, "session_id", "sender_key", WithheldCode::NoOlm);
+
+        let event = json!({
+            "sender": "@alice:example.org",
+            "type": "m.room_key.withheld",
+            "content": content,
+        });
+
+        assert_eq!(event["content"]["code"], "m.no_olm");
+
+        let (sender, parsed) = parse_withheld_event(&to_raw_value(&event).unwrap()).unwrap();
+
+        assert_eq!(sender.as_str(), "@alice:example.org");
+        assert_eq!(parsed.code, WithheldCode::NoOlm);
+        assert_eq!(parsed.room_id, Some(room_id));
+        assert_eq!(parsed.session_id.as_deref(), Some("session_id"));
+    }
+}